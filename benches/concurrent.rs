@@ -0,0 +1,93 @@
+//! Drives `SHIELD.absorb` from many connections at once, since sequential
+//! numbers from a single connection hide contention.
+//!
+//! Requires a Redis server with the module loaded:
+//!
+//!     REDIS_URL=redis://127.0.0.1:6379/1 cargo bench --bench concurrent
+//!
+//! `SHIELD_BENCH_THREADS` (8 by default) connections send
+//! `SHIELD_BENCH_REQUESTS` (10000 by default) requests each, first to a single
+//! hot key, then spread over `SHIELD_BENCH_KEYS` (10000 by default) keys.
+
+use std::env;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const KEY_PREFIX: &str = "redis-shield::bench";
+const CAPACITY: i64 = 1_000_000_000;
+const PERIOD: i64 = 60;
+
+fn main() {
+    let url = env::var("REDIS_URL").expect("REDIS_URL must point to a server with the module");
+    let threads = setting("SHIELD_BENCH_THREADS", 8);
+    let requests = setting("SHIELD_BENCH_REQUESTS", 10_000);
+    let keys = setting("SHIELD_BENCH_KEYS", 10_000);
+
+    println!("{threads} connections, {requests} requests each");
+    run(&url, "hot key", threads, requests, |_, _| {
+        format!("{KEY_PREFIX}:hot")
+    });
+    run(
+        &url,
+        "distinct keys",
+        threads,
+        requests,
+        move |thread, request| format!("{KEY_PREFIX}:{}", (thread * requests + request) % keys),
+    );
+}
+
+fn setting(name: &str, default: usize) -> usize {
+    env::var(name).map_or(default, |value| {
+        value
+            .parse()
+            .unwrap_or_else(|_| panic!("{name} must be a number"))
+    })
+}
+
+// Runs a scenario, naming the key of every request by the thread and its sequence number
+fn run(
+    url: &str,
+    scenario: &str,
+    threads: usize,
+    requests: usize,
+    key: impl Fn(usize, usize) -> String + Copy + Send + 'static,
+) {
+    let client = redis::Client::open(url).unwrap();
+    let started = Instant::now();
+    let workers: Vec<_> = (0..threads)
+        .map(|thread| {
+            let mut con = client.get_connection().unwrap();
+            thread::spawn(move || {
+                let mut latencies = Vec::with_capacity(requests);
+                for request in 0..requests {
+                    let sent = Instant::now();
+                    let _: i64 = redis::cmd("SHIELD.absorb")
+                        .arg(key(thread, request))
+                        .arg(CAPACITY)
+                        .arg(PERIOD)
+                        .query(&mut con)
+                        .unwrap();
+                    latencies.push(sent.elapsed());
+                }
+                latencies
+            })
+        })
+        .collect();
+
+    let mut latencies: Vec<Duration> = workers
+        .into_iter()
+        .flat_map(|worker| worker.join().unwrap())
+        .collect();
+    let elapsed = started.elapsed();
+    latencies.sort_unstable();
+
+    let percentile = |percent: usize| latencies[(latencies.len() - 1) * percent / 1000].as_micros();
+    println!(
+        "{scenario:>14}: {:>9.0} req/s, p50 {} us, p99 {} us, p99.9 {} us, max {} us",
+        latencies.len() as f64 / elapsed.as_secs_f64(),
+        percentile(500),
+        percentile(990),
+        percentile(999),
+        percentile(1000),
+    );
+}
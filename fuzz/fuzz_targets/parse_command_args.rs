@@ -0,0 +1,10 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use redis_shield::parse_command_args;
+
+// Arguments are separated by NUL bytes, the first one standing for the command name
+fuzz_target!(|data: &[u8]| {
+    let args: Vec<&[u8]> = data.split(|byte| *byte == 0).collect();
+    let _ = parse_command_args(&args);
+});
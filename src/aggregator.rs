@@ -0,0 +1,132 @@
+use crate::config;
+use redis_module::RedisString;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{LazyLock, Mutex, OnceLock};
+use std::thread;
+
+// Number of decisions waiting for the aggregator, further ones are dropped
+const QUEUE_CAPACITY: usize = 65536;
+
+// Sending end of the queue, created along with the aggregator by the first decision
+static QUEUE: OnceLock<SyncSender<Decision>> = OnceLock::new();
+// Number of decisions dropped because the queue was full
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+static TOP: LazyLock<Mutex<HashMap<Vec<u8>, Count>>> = LazyLock::new(Default::default);
+
+/// Decision made for a key, as queued for the aggregator.
+struct Decision {
+    key: Vec<u8>,
+    allowed: bool,
+}
+
+/// Numbers of decisions made for a key while it was tracked.
+#[derive(Clone, Copy, Default)]
+pub struct Count {
+    pub denied: u64,
+    pub allowed: u64,
+}
+
+/// Queues a decision made for `key` for the background aggregator, which
+/// tracks the keys with the most denials if `shield.top-keys` is set.
+///
+/// The aggregator runs on its own thread, so the command path only pays
+/// for copying the key into a bounded lock-free queue. Decisions that don't
+/// fit into the queue are dropped rather than slowing the command down.
+pub fn record(key: &RedisString, allowed: bool) {
+    if config::top_keys() == 0 {
+        return;
+    }
+    let decision = Decision {
+        key: key.as_slice().to_vec(),
+        allowed,
+    };
+    if QUEUE.get_or_init(spawn).try_send(decision).is_err() {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Returns up to `count` tracked keys with the most denials, the most denied first.
+pub fn top(count: usize) -> Vec<(Vec<u8>, Count)> {
+    let mut keys: Vec<_> = TOP
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(key, count)| (key.clone(), *count))
+        .collect();
+    keys.sort_unstable_by(|(_, a), (_, b)| (b.denied, b.allowed).cmp(&(a.denied, a.allowed)));
+    keys.truncate(count);
+    keys
+}
+
+/// Forgets the tracked keys, e.g. once the keyspace is wiped.
+///
+/// Decisions still queued are counted afterwards.
+pub fn clear() {
+    TOP.lock().unwrap().clear();
+}
+
+/// Forgets the counts of `key`, returning `true` if it was tracked.
+pub fn forget(key: &[u8]) -> bool {
+    TOP.lock().unwrap().remove(key).is_some()
+}
+
+/// Returns the number of decisions dropped because the aggregator fell behind.
+pub fn dropped() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+pub fn reset_dropped() {
+    DROPPED.store(0, Ordering::Relaxed);
+}
+
+fn spawn() -> SyncSender<Decision> {
+    let (sender, receiver) = mpsc::sync_channel(QUEUE_CAPACITY);
+    thread::Builder::new()
+        .name("shield-aggregator".to_string())
+        .spawn(move || aggregate(receiver))
+        .expect("failed to spawn the aggregator thread");
+    sender
+}
+
+// Counts the decisions per key with the Space-Saving algorithm: once
+// `shield.top-keys` keys are tracked, a new key replaces the one with
+// the fewest decisions and inherits its counts, so frequent keys are
+// never missed, while the counts of rare ones may be overestimated.
+fn aggregate(decisions: Receiver<Decision>) {
+    while let Ok(decision) = decisions.recv() {
+        let capacity = usize::try_from(config::top_keys()).unwrap_or(usize::MAX);
+        let mut top = TOP.lock().unwrap();
+        for decision in std::iter::once(decision).chain(decisions.try_iter()) {
+            let count = match top.get_mut(&decision.key) {
+                Some(count) => count,
+                None => {
+                    let mut inherited = Count::default();
+                    while capacity > 0 && top.len() >= capacity {
+                        inherited = evict(&mut top);
+                    }
+                    top.entry(decision.key).or_insert(inherited)
+                }
+            };
+            if decision.allowed {
+                count.allowed += 1;
+            } else {
+                count.denied += 1;
+            }
+        }
+        // The setting may have been lowered or disabled meanwhile
+        while top.len() > capacity {
+            evict(&mut top);
+        }
+    }
+}
+
+// Removes the key with the fewest decisions, returning its counts
+fn evict(top: &mut HashMap<Vec<u8>, Count>) -> Count {
+    let key = top
+        .iter()
+        .min_by_key(|(_, count)| (count.denied, count.allowed))
+        .map(|(key, _)| key.clone());
+    key.and_then(|key| top.remove(&key)).unwrap_or_default()
+}
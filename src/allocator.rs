@@ -0,0 +1,42 @@
+use std::alloc::{GlobalAlloc, Layout};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[cfg(not(any(test, feature = "fuzzing")))]
+use redis_module::alloc::RedisAlloc as Inner;
+
+// Outside of Redis there is no allocator to delegate to
+#[cfg(any(test, feature = "fuzzing"))]
+use std::alloc::System as Inner;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+/// Global allocator of the module, delegating to Redis' allocator
+/// while counting the allocations, e.g. for `SHIELD.bench`.
+pub struct Counting;
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        Inner.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        Inner.alloc_zeroed(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        Inner.dealloc(ptr, layout)
+    }
+
+    // Forwarded, so growing a `Vec` or `String` doesn't copy it on every resize
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        Inner.realloc(ptr, layout, new_size)
+    }
+}
+
+/// Returns the number of allocations made by the module since it was loaded.
+pub fn allocations() -> u64 {
+    ALLOCATIONS.load(Ordering::Relaxed)
+}
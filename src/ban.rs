@@ -0,0 +1,144 @@
+use crate::keys::{all_bans_keys, bans_key, companion_key};
+use crate::recovery;
+use crate::state;
+use crate::strings;
+use redis_module::{Context, RedisError, RedisString, RedisValue};
+
+pub const BAN_PART: &[u8] = b"ban";
+const ANY_KEY: &str = "*";
+const SCAN_COUNT: &str = "1000";
+const FIRST_CURSOR: &str = "0";
+
+/// Manual ban of a key, denying all its requests before any bucket is read,
+/// e.g. while a security team investigates an abusive client.
+///
+/// A ban is stored under `<key>:ban`, which expires when the ban is lifted.
+/// Banned keys are also tracked by a sorted set in their cluster slot, e.g.
+/// `<prefix>:bans:{1234}`, scored by the Unix time in milliseconds their bans
+/// expire at, so they can be listed without scanning the whole keyspace.
+/// In a cluster, the bans are listed per node.
+pub struct Ban;
+
+impl Ban {
+    /// Bans `key` for `ttl` milliseconds, replacing its existing ban.
+    pub fn add(ctx: &Context, key: &RedisString, ttl: i64) -> Result<(), RedisError> {
+        let now = state::now(ctx)?;
+        ctx.call(
+            "PSETEX",
+            &[
+                &companion_key(key, &[BAN_PART]),
+                &RedisString::create(None, ttl.to_string().as_str()),
+                strings::one(),
+            ],
+        )?;
+
+        let bans = bans_key(key.as_slice());
+        let until = now.saturating_add(ttl).to_string();
+        recovery::call(
+            ctx,
+            "ZADD",
+            &[&bans, &RedisString::create(None, until.as_str()), key],
+        )?;
+        prune(ctx, &bans, now)
+    }
+
+    /// Lifts the ban of `key`. Returns `true` if it was banned.
+    pub fn remove(ctx: &Context, key: &RedisString) -> Result<bool, RedisError> {
+        let deleted = ctx.call("DEL", &[&companion_key(key, &[BAN_PART])])?;
+        recovery::call(ctx, "ZREM", &[&bans_key(key.as_slice()), key])?;
+        Ok(deleted == RedisValue::Integer(1))
+    }
+
+    /// Returns the number of milliseconds until the ban of `key` expires,
+    /// or `None` if it isn't banned.
+    pub fn ttl(ctx: &Context, key: &RedisString) -> Result<Option<i64>, RedisError> {
+        match ctx.call("PTTL", &[&companion_key(key, &[BAN_PART])])? {
+            RedisValue::Integer(ttl) if ttl > 0 => Ok(Some(ttl)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Returns the banned keys stored on this node matching the glob-style
+    /// `pattern` (all if omitted), with the number of milliseconds until their
+    /// bans expire.
+    pub fn list(
+        ctx: &Context,
+        pattern: Option<&RedisString>,
+    ) -> Result<Vec<(RedisString, i64)>, RedisError> {
+        let now = state::now(ctx)?;
+        let pattern = pattern.map_or(ANY_KEY.to_string(), RedisString::to_string_lossy);
+        let mut banned = Vec::new();
+        for bans in all_bans_keys() {
+            // Most slots have no bans, or aren't served by this node
+            if ctx.call("EXISTS", &[&bans])? == RedisValue::Integer(1) {
+                prune(ctx, &bans, now)?;
+                Self::scan(ctx, &bans, &pattern, &mut banned)?;
+            }
+        }
+        Ok(banned)
+    }
+
+    // Appends the banned keys tracked by `bans` matching `pattern` to `banned`
+    fn scan(
+        ctx: &Context,
+        bans: &RedisString,
+        pattern: &str,
+        banned: &mut Vec<(RedisString, i64)>,
+    ) -> Result<(), RedisError> {
+        let mut cursor = FIRST_CURSOR.to_string();
+        loop {
+            let args = [
+                bans,
+                &RedisString::create(None, cursor.as_str()),
+                strings::match_option(),
+                &RedisString::create(None, pattern),
+                strings::count_option(),
+                &RedisString::create(None, SCAN_COUNT),
+            ];
+            let (next, entries) = match recovery::call(ctx, "ZSCAN", &args)? {
+                RedisValue::Array(mut reply) if reply.len() == 2 => {
+                    let entries = reply.pop();
+                    (reply.pop(), entries)
+                }
+                _ => return Err(RedisError::Str("ERR unexpected ZSCAN reply")),
+            };
+
+            // Members alternate with their scores
+            if let Some(RedisValue::Array(entries)) = entries {
+                for member in entries.into_iter().step_by(2) {
+                    let key = match member {
+                        RedisValue::SimpleString(key) => RedisString::create(None, key.as_str()),
+                        RedisValue::BulkRedisString(key) => key,
+                        RedisValue::StringBuffer(key) => {
+                            RedisString::create_from_slice(std::ptr::null_mut(), &key)
+                        }
+                        _ => continue,
+                    };
+                    // The ban may have been removed behind the module's back
+                    if let Some(ttl) = Self::ttl(ctx, &key)? {
+                        banned.push((key, ttl));
+                    }
+                }
+            }
+
+            match next {
+                Some(RedisValue::SimpleString(next)) if next != FIRST_CURSOR => cursor = next,
+                _ => return Ok(()),
+            }
+        }
+    }
+}
+
+// Removes the keys whose bans expired by `now`
+fn prune(ctx: &Context, bans: &RedisString, now: i64) -> Result<(), RedisError> {
+    recovery::call(
+        ctx,
+        "ZREMRANGEBYSCORE",
+        &[
+            bans,
+            strings::min_score(),
+            &RedisString::create(None, now.to_string().as_str()),
+        ],
+    )?;
+    Ok(())
+}
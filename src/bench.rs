@@ -0,0 +1,68 @@
+use crate::allocator;
+use crate::bucket::Bucket;
+use crate::command_parser::{check_cap, parse_positive_integer};
+use crate::config::MAX_BENCH_ITERATIONS;
+use crate::error::{self, error};
+use crate::keys::bench_key;
+use redis_module::{Context, RedisError, RedisString};
+use std::time::{Duration, Instant};
+
+const ALGORITHM: &str = "token_bucket";
+// Number of distinct keys the requests are spread over
+const SYNTHETIC_KEYS: i64 = 1000;
+// Limit of the synthetic buckets, high enough to admit every request
+const CAPACITY: i64 = 1_000_000_000;
+const PERIOD: i64 = 60;
+
+/// Synthetic workload run in-process, so the cost of an algorithm can be
+/// measured on the server's own hardware without an external load generator.
+///
+/// Every iteration takes a token from one of the buckets stored under
+/// `<prefix>:bench:<n>`, which are removed before and after the run.
+/// The server doesn't serve other clients meanwhile.
+pub struct Bench {
+    // Number of requests to evaluate
+    iterations: i64,
+}
+
+/// Outcome of a [`Bench`] run.
+pub struct Report {
+    pub iterations: i64,
+    pub elapsed: Duration,
+    // Number of allocations made by the module during the run
+    pub allocations: u64,
+}
+
+impl Bench {
+    /// Parses the algorithm and the number of iterations, e.g. `token_bucket 100000`.
+    pub fn parse(algorithm: &RedisString, iterations: &RedisString) -> Result<Self, RedisError> {
+        if algorithm.to_string_lossy() != ALGORITHM {
+            return Err(error(error::BAD_ALGO, "unsupported algorithm"));
+        }
+        let iterations = parse_positive_integer("iterations", iterations)?;
+        check_cap("iterations", iterations, &MAX_BENCH_ITERATIONS)?;
+        Ok(Self { iterations })
+    }
+
+    pub fn run(&self, ctx: &Context) -> Result<Report, RedisError> {
+        let keys: Vec<RedisString> = (0..self.iterations.min(SYNTHETIC_KEYS))
+            .map(bench_key)
+            .collect();
+        let args: Vec<&RedisString> = keys.iter().collect();
+        ctx.call("DEL", args.as_slice())?;
+
+        let allocations = allocator::allocations();
+        let started = Instant::now();
+        for key in keys.iter().cycle().take(self.iterations as usize) {
+            Bucket::new(ctx, key, CAPACITY, PERIOD)?.pour(1)?;
+        }
+        let report = Report {
+            iterations: self.iterations,
+            elapsed: started.elapsed(),
+            allocations: allocator::allocations() - allocations,
+        };
+
+        ctx.call("DEL", args.as_slice())?;
+        Ok(report)
+    }
+}
@@ -1,8 +1,11 @@
+use crate::command_parser::Limit;
+use crate::curve::Curve;
+use crate::math::{millis, mul_div_ceil, MAX_MILLIS};
+use crate::state::{self, State};
 use num::clamp;
-use redis_module::{Context, RedisError, RedisString, RedisValue};
+use redis_module::{Context, RedisError, RedisString};
 use std::cmp::{max, min};
 
-const MILLS_IN_SEC: i64 = 1000;
 const MIN_TTL: i64 = 0;
 const MIN_TOKENS: i64 = 0;
 const OVERFLOWN_RESPONSE: i64 = -1;
@@ -19,12 +22,43 @@ const OVERFLOWN_RESPONSE: i64 = -1;
 pub struct Bucket<'a> {
     // Unique bucket key used to store its details in redis
     pub key: &'a RedisString,
+    // Field of the hash `key` the bucket is stored in, `None` if it's stored
+    // under `key` itself
+    field: Option<&'a RedisString>,
     // Maximum bucket's capacity
     pub capacity: i64,
     // Replenish period in which `capacity` number of tokens is refilled
     pub period: i64,
     // Number of tokens left in the bucket. When a bucket is created, `tokens = capacity`
     pub tokens: i64,
+    // Number of tokens the request is not allowed to take, e.g. headroom
+    // reserved for high-priority requests
+    pub reserved: i64,
+    // Number of tokens the request may take on credit, driving the bucket negative
+    pub overdraft: i64,
+    // Milliseconds after which the bucket's key expires if it isn't written to
+    pub max_idle: i64,
+    // Whether the bucket's key is stored without an expire, ignoring `max_idle`.
+    // `None` keeps a stored key the way it is, e.g. for admin commands.
+    pub persistent: Option<bool>,
+    // Limit the bucket is checked against, with the period in seconds
+    limit: Limit,
+    // Limit the bucket was stored with by the last write, `None` if unknown
+    stored_limit: Option<Limit>,
+    // Whether the bucket was found in redis when it was instantiated
+    stored: bool,
+    // Number of tokens stored in redis by the last write
+    stored_tokens: i64,
+    // Milliseconds elapsed since the last write
+    elapsed: i64,
+    // Milliseconds in which tokens are refilled at once, `0` if they are refilled continuously
+    refill_step: i64,
+    // Milliseconds of the refill step in progress, carried over by the next write
+    pending: i64,
+    // Shape of the refill across the period
+    curve: Curve,
+    // Unix time in milliseconds the bucket is observed at
+    now: i64,
     // Redis context used to perform redis commands
     ctx: &'a Context,
 }
@@ -33,7 +67,7 @@ impl<'a> Bucket<'a> {
     /// Instantiates a new bucket.
     ///
     /// If the key already exists in redis:
-    ///     * Fetches info about tokens left and TTL
+    ///     * Fetches info about tokens left and the time of the last write
     ///     * Sanitizes the fetched numbers
     ///     * Adds tokens tokens refilled since the last request.
     pub fn new(
@@ -41,13 +75,49 @@ impl<'a> Bucket<'a> {
         key: &'a RedisString,
         capacity: i64,
         period: i64,
+    ) -> Result<Self, RedisError> {
+        Self::stored_in(ctx, key, None, capacity, period)
+    }
+
+    /// Instantiates a new bucket stored in `field` of the hash `key`,
+    /// next to the buckets of other limits of the same entity.
+    pub fn in_field(
+        ctx: &'a Context,
+        key: &'a RedisString,
+        field: &'a RedisString,
+        capacity: i64,
+        period: i64,
+    ) -> Result<Self, RedisError> {
+        Self::stored_in(ctx, key, Some(field), capacity, period)
+    }
+
+    fn stored_in(
+        ctx: &'a Context,
+        key: &'a RedisString,
+        field: Option<&'a RedisString>,
+        capacity: i64,
+        period: i64,
     ) -> Result<Self, RedisError> {
         let mut bucket = Self {
             ctx,
             key,
+            field,
             capacity,
-            period: period * MILLS_IN_SEC,
+            period: millis(period),
             tokens: MIN_TOKENS,
+            reserved: MIN_TOKENS,
+            overdraft: MIN_TOKENS,
+            max_idle: MAX_MILLIS,
+            persistent: None,
+            limit: Limit { capacity, period },
+            stored_limit: None,
+            stored: false,
+            stored_tokens: MIN_TOKENS,
+            elapsed: MIN_TTL,
+            refill_step: MIN_TTL,
+            pending: MIN_TTL,
+            curve: Curve::Linear,
+            now: state::now(ctx)?,
         };
         bucket.fetch_tokens()?;
         Ok(bucket)
@@ -55,44 +125,234 @@ impl<'a> Bucket<'a> {
 
     /// Attempts to remove requested number of `tokens` from the bucket.
     ///
-    /// If the bucket doesn't contain sufficient tokens, no tokens are
-    /// remove and `-1` is returned.
+    /// If the bucket doesn't contain sufficient tokens (not counting the
+    /// `reserved` ones, but counting the `overdraft`), no tokens are remove
+    /// and `-1` is returned.
     ///
     /// If the bucket contains enough tokens, `tokens` are removed from the bucket,
-    /// and the number of tokens left is returned.
+    /// and the number of tokens left is returned. A bucket in debt has no tokens left.
     pub fn pour(&mut self, tokens: i64) -> Result<i64, RedisError> {
-        if tokens > self.tokens {
-            Ok(OVERFLOWN_RESPONSE)
+        let remaining_tokens = self.stage(tokens);
+        if remaining_tokens != OVERFLOWN_RESPONSE {
+            self.commit()?;
+        }
+        Ok(remaining_tokens)
+    }
+
+    /// Removes `tokens` like `pour` does, but only in memory, until `commit`
+    /// writes the bucket, so several buckets can be decided on before any
+    /// of them is written.
+    pub fn stage(&mut self, tokens: i64) -> i64 {
+        if tokens > self.available() {
+            OVERFLOWN_RESPONSE
         } else {
             self.tokens -= tokens;
-            self.ctx.call(
-                "PSETEX",
-                &[
-                    self.key,
-                    &RedisString::create(None, self.period.to_string().as_str()),
-                    &RedisString::create(None, self.tokens.to_string().as_str()),
-                ],
-            )?;
-            Ok(self.tokens)
+            max(self.tokens, MIN_TOKENS)
         }
     }
 
-    fn fetch_tokens(&mut self) -> Result<(), RedisError> {
-        // Starting with Redis 2.8 the return value of PTTL in case of error changed:
-        //     - The command returns -2 if the key does not exist.
-        //     - The command returns -1 if the key exists but has no associated expire.
-        let current_ttl = match self.ctx.call("PTTL", &[self.key])? {
-            RedisValue::Integer(ttl) => clamp(ttl, MIN_TTL, self.period),
-            _ => MIN_TTL,
+    /// Writes the tokens removed by `stage`.
+    pub fn commit(&mut self) -> Result<(), RedisError> {
+        self.persist()
+    }
+
+    /// Attempts `count` sequential removals of `tokens_each` tokens.
+    ///
+    /// Stops at the first removal the bucket can't satisfy, like `count` separate
+    /// calls of `pour` would. Returns the number of admitted removals and
+    /// the number of tokens left afterwards.
+    pub fn pour_batch(&mut self, count: i64, tokens_each: i64) -> Result<(i64, i64), RedisError> {
+        let admitted = min(count, max(self.available(), MIN_TOKENS) / tokens_each);
+        if admitted > 0 {
+            self.tokens -= admitted * tokens_each;
+            self.persist()?;
+        }
+        Ok((admitted, max(self.tokens, MIN_TOKENS)))
+    }
+
+    /// Returns the number of milliseconds until `tokens` can be removed from the bucket.
+    ///
+    /// `0` means the tokens are available right away, `-1` means they never will be,
+    /// because the bucket's capacity is too small.
+    pub fn retry_after(&self, tokens: i64) -> i64 {
+        let tokens = tokens
+            .saturating_add(self.reserved)
+            .saturating_sub(self.overdraft);
+        if tokens <= self.tokens {
+            return 0;
+        }
+        if tokens > self.capacity {
+            return OVERFLOWN_RESPONSE;
+        }
+        self.wait_for(tokens)
+    }
+
+    /// Returns the number of milliseconds until the bucket is full again.
+    pub fn refill_after(&self) -> i64 {
+        if self.tokens >= self.capacity {
+            0
+        } else {
+            self.wait_for(self.capacity)
+        }
+    }
+
+    /// Returns the Unix time in milliseconds at which the bucket is full again.
+    pub fn reset_at(&self) -> i64 {
+        self.now.saturating_add(self.refill_after())
+    }
+
+    /// Changes how the bucket refills: in discrete steps of `step` milliseconds,
+    /// e.g. 10 tokens every second rather than one every 100 milliseconds,
+    /// or continuously if `step` is `0`, and along `curve`.
+    ///
+    /// The time elapsed in an unfinished step isn't lost by writes.
+    pub fn set_refill(&mut self, step: i64, curve: Curve) {
+        self.refill_step = step;
+        self.curve = curve;
+        self.refill(self.stored_tokens, self.period - self.elapsed);
+    }
+
+    /// Limits the number of tokens left to `capacity`, e.g. while the bucket warms up.
+    pub fn cap(&mut self, capacity: i64) {
+        self.tokens = min(self.tokens, capacity);
+    }
+
+    /// Overrides the number of tokens left in the bucket.
+    ///
+    /// `tokens` is capped at the bucket's capacity. The refill starts over,
+    /// so exactly the returned number of tokens is available right after the call.
+    pub fn set(&mut self, tokens: i64) -> Result<i64, RedisError> {
+        self.tokens = clamp(tokens, MIN_TOKENS, self.capacity);
+        self.pending = MIN_TTL;
+        self.persist()?;
+        Ok(self.tokens)
+    }
+
+    /// Returns `tokens` previously removed from the bucket, e.g. by an aborted request.
+    ///
+    /// The bucket can't exceed its capacity. Returns the number of tokens left.
+    pub fn refund(&mut self, tokens: i64) -> Result<i64, RedisError> {
+        self.tokens = min(self.capacity, self.tokens.saturating_add(tokens));
+        self.persist()?;
+        Ok(max(self.tokens, MIN_TOKENS))
+    }
+
+    /// Removes all tokens left in the bucket.
+    ///
+    /// The refill starts over even if the bucket is already empty, so draining
+    /// repeatedly keeps the bucket locked out. Returns the number of removed tokens.
+    pub fn drain(&mut self) -> Result<i64, RedisError> {
+        let drained = max(self.tokens, MIN_TOKENS);
+        self.tokens = MIN_TOKENS;
+        self.pending = MIN_TTL;
+        self.persist()?;
+        Ok(drained)
+    }
+
+    /// Returns the number of milliseconds elapsed since the last write,
+    /// capped at the period.
+    pub fn elapsed(&self) -> i64 {
+        self.elapsed
+    }
+
+    /// Returns `true` if the bucket was found in redis rather than created.
+    pub fn is_stored(&self) -> bool {
+        self.stored
+    }
+
+    /// Returns `true` if the bucket was stored with a different capacity or period.
+    pub fn conflicts(&self) -> bool {
+        self.stored_limit.is_some_and(|limit| limit != self.limit)
+    }
+
+    /// Discards the stored state, so the bucket starts over full.
+    pub fn reset(&mut self) {
+        self.stored_limit = None;
+        self.refill(MIN_TOKENS, MIN_TTL);
+    }
+
+    fn available(&self) -> i64 {
+        self.tokens
+            .saturating_sub(self.reserved)
+            .saturating_add(self.overdraft)
+    }
+
+    fn persist(&mut self) -> Result<(), RedisError> {
+        let state = State {
+            tokens: self.tokens,
+            expires_at: self.now.saturating_add(self.period - self.pending),
+            limit: Some(self.limit),
         };
-        let delta = (self.period - current_ttl) as f64 / self.period as f64;
-        let refilled_tokens = (delta * self.capacity as f64) as i64;
-        let remaining_tokens = match self.ctx.call("GET", &[self.key])? {
-            RedisValue::SimpleString(tokens) => max(MIN_TOKENS, tokens.parse::<i64>()?),
-            _ => MIN_TOKENS,
+        let persistent = match self.persistent {
+            Some(persistent) => persistent,
+            None => self.stored && self.field.is_none() && !state::has_expire(self.ctx, self.key)?,
         };
+        match (self.field, persistent) {
+            (Some(field), true) => state.save_field_persistent(self.ctx, self.key, field)?,
+            (Some(field), false) => {
+                state.save_field(self.ctx, self.key, field, self.now, self.max_idle)?
+            }
+            (None, true) => state.save_persistent(self.ctx, self.key)?,
+            (None, false) => state.save_capped(self.ctx, self.key, self.now, self.max_idle)?,
+        }
+        // The refill starts over with the write, except for the step in progress
+        self.stored_tokens = self.tokens;
+        self.stored_limit = Some(self.limit);
+        self.elapsed = self.pending;
+        Ok(())
+    }
+
+    fn wait_for(&self, tokens: i64) -> i64 {
+        // Smallest time since the last write in which the missing tokens are refilled
+        let mut required = self
+            .curve
+            .wait(self.stored_tokens, tokens, self.capacity, self.period);
+        if self.refill_step > 0 {
+            // Tokens are only refilled once a step is complete
+            required = mul_div_ceil(required.into(), 1, self.refill_step.into())
+                .saturating_mul(self.refill_step);
+        }
+
+        max(required.saturating_sub(self.elapsed), 0)
+    }
 
-        self.tokens = min(self.capacity, remaining_tokens + refilled_tokens);
+    fn fetch_tokens(&mut self) -> Result<(), RedisError> {
+        // The stored number is negative while the bucket pays down an overdraft
+        let stored = match self.field {
+            Some(field) => State::load_field(self.ctx, self.key, field)?,
+            None => State::load(self.ctx, self.key, self.now)?,
+        };
+        self.stored = stored.is_some();
+        let (remaining_tokens, current_ttl) = match stored {
+            Some(state) => {
+                self.stored_limit = state.limit;
+                (state.tokens, min(state.ttl(self.now), self.period))
+            }
+            None => (MIN_TOKENS, MIN_TTL),
+        };
+        self.refill(remaining_tokens, current_ttl);
         Ok(())
     }
+
+    fn refill(&mut self, remaining_tokens: i64, current_ttl: i64) {
+        self.elapsed = self.period - current_ttl;
+        // A whole period refills the bucket, even if it isn't a multiple of the step
+        let credited = match self.refill_step {
+            0 => self.elapsed,
+            _ if self.elapsed >= self.period => self.elapsed,
+            step => self.elapsed - self.elapsed % step,
+        };
+        let tokens = self
+            .curve
+            .refill(remaining_tokens, credited, self.capacity, self.period);
+
+        self.stored_tokens = remaining_tokens;
+        // A full bucket has nothing to carry over
+        self.pending = if tokens < self.capacity {
+            self.elapsed - credited
+        } else {
+            MIN_TTL
+        };
+        self.tokens = min(self.capacity, tokens);
+    }
 }
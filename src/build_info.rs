@@ -0,0 +1,28 @@
+//! Metadata of the build reported by `SHIELD.version`.
+
+/// Semantic version of the crate the module was built from.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Abbreviated hash of the commit, `unknown` when built outside a git checkout.
+pub const COMMIT: &str = env!("SHIELD_GIT_COMMIT");
+
+/// Cargo profile of the build, e.g. `debug` or `release`.
+pub const PROFILE: &str = env!("SHIELD_BUILD_PROFILE");
+
+/// Cargo features the module was compiled with.
+pub fn features() -> Vec<&'static str> {
+    let mut features = Vec::new();
+    if cfg!(feature = "embedded-redis") {
+        features.push("embedded-redis");
+    }
+    if cfg!(feature = "fuzzing") {
+        features.push("fuzzing");
+    }
+    if cfg!(feature = "plugins") {
+        features.push("plugins");
+    }
+    if cfg!(feature = "tracing") {
+        features.push("tracing");
+    }
+    features
+}
@@ -0,0 +1,61 @@
+use crate::aggregator;
+use crate::history::History;
+use crate::policy;
+use linkme::distributed_slice;
+use redis_module::server_events::{
+    FlushSubevent, LoadingSubevent, FLUSH_SERVER_EVENTS_LIST, LOADING_SERVER_EVENTS_LIST,
+};
+use redis_module::{Context, NotifyEvent, RedisString, Status};
+
+// Companion keys without a TTL of their own, e.g. the decision history,
+// would outlive the bucket they belong to. They are removed once the
+// bucket's key expires.
+
+/// Handles the `expired` keyspace event.
+///
+/// Writing from within a keyspace notification is unsafe, so the cleanup
+/// runs as a post-notification job (Redis 7.2 or later). Since the event
+/// fires for every key of the server, the job is only scheduled for the
+/// keys having a history to clean up. Reading is safe from the notification.
+pub fn on_expired(ctx: &Context, _event_type: NotifyEvent, _event: &str, key: &[u8]) {
+    let key = RedisString::create_from_slice(std::ptr::null_mut(), key);
+    if !History::exists(ctx, &key) {
+        return;
+    }
+    let status = ctx.add_post_notification_job(move |ctx| {
+        if let Err(err) = History::remove(ctx, &key) {
+            ctx.log_warning(&format!(
+                "redis-shield: failed to clean up after {} expired: {}",
+                key, err
+            ));
+        }
+    });
+    if !matches!(status, Ok(Status::Ok)) {
+        ctx.log_debug("redis-shield: post-notification jobs are not supported");
+    }
+}
+
+// The in-memory state derived from the keyspace, i.e. the cached policies and
+// the tracked keys, is dropped when the keyspace is wiped by `FLUSHALL` or
+// `FLUSHDB`, which don't notify about the removed keys, or replaced by loading
+// a dataset, e.g. during a full sync of a replica.
+
+#[distributed_slice(FLUSH_SERVER_EVENTS_LIST)]
+fn on_flush(_ctx: &Context, subevent: FlushSubevent) {
+    if subevent == FlushSubevent::Ended {
+        reset();
+    }
+}
+
+#[distributed_slice(LOADING_SERVER_EVENTS_LIST)]
+fn on_loading(_ctx: &Context, subevent: LoadingSubevent) {
+    if matches!(subevent, LoadingSubevent::Ended | LoadingSubevent::Failed) {
+        reset();
+    }
+}
+
+fn reset() {
+    policy::clear_cache();
+    policy::clear_usage();
+    aggregator::clear();
+}
@@ -0,0 +1,847 @@
+use crate::config::{self, MAX_CAPACITY, MAX_PERIOD, MAX_TOKENS_PER_CALL};
+use crate::curve::Curve;
+use crate::error::{self, bad_argument};
+use crate::math::millis;
+use crate::plugin::TOKEN_BUCKET;
+use crate::trace;
+use redis_module::{RedisError, RedisString};
+use std::borrow::Cow;
+use std::sync::atomic::AtomicI64;
+
+const MIN_ARGS_LEN: usize = 4;
+const TOKENS_INDEX: usize = 4;
+const DEFAULT_TOKENS: i64 = 1;
+const DEFAULT_THRESHOLD: i64 = 80;
+const MAX_THRESHOLD: i64 = 100;
+const TIER_OPTION: &str = "TIER";
+const PRIORITY_OPTION: &str = "PRIORITY";
+const THRESHOLD_OPTION: &str = "THRESHOLD";
+const OVERDRAFT_OPTION: &str = "OVERDRAFT";
+const WARMUP_OPTION: &str = "WARMUP";
+const IDEMPOTENCY_OPTION: &str = "IDEMPOTENCY";
+const SOFT_OPTION: &str = "SOFT";
+const UNIT_OPTION: &str = "UNIT";
+const KIND_OPTION: &str = "KIND";
+const BUDGET_OPTION: &str = "BUDGET";
+const DEFAULT_BUDGET: i64 = 10;
+const GROUP_OPTION: &str = "GROUP";
+const HISTORY_OPTION: &str = "HISTORY";
+const NOTIFY_OPTION: &str = "NOTIFY";
+const OUTPUT_OPTION: &str = "OUTPUT";
+const MININTERVAL_OPTION: &str = "MININTERVAL";
+const MAXIDLE_OPTION: &str = "MAXIDLE";
+const NX_OPTION: &str = "NX";
+const STRICTCONFIG_OPTION: &str = "STRICTCONFIG";
+const POLICY_OPTION: &str = "POLICY";
+const PENALTY_OPTION: &str = "PENALTY";
+const GREYLIST_OPTION: &str = "GREYLIST";
+const REFILL_STEP_OPTION: &str = "REFILL_STEP";
+const CURVE_OPTION: &str = "CURVE";
+const PERSISTENT_OPTION: &str = "PERSISTENT";
+const FIELD_OPTION: &str = "FIELD";
+const SHARDS_OPTION: &str = "SHARDS";
+const PARENT_OPTION: &str = "PARENT";
+const COST_OPTION: &str = "COST";
+const ATTR_OPTION: &str = "ATTR";
+const ALGORITHM_OPTION: &str = "ALGORITHM";
+const ONDENY_OPTION: &str = "ONDENY";
+const OPTIONS: [&str; 31] = [
+    TIER_OPTION,
+    PRIORITY_OPTION,
+    THRESHOLD_OPTION,
+    OVERDRAFT_OPTION,
+    WARMUP_OPTION,
+    IDEMPOTENCY_OPTION,
+    SOFT_OPTION,
+    UNIT_OPTION,
+    KIND_OPTION,
+    BUDGET_OPTION,
+    GROUP_OPTION,
+    HISTORY_OPTION,
+    NOTIFY_OPTION,
+    OUTPUT_OPTION,
+    MININTERVAL_OPTION,
+    MAXIDLE_OPTION,
+    NX_OPTION,
+    STRICTCONFIG_OPTION,
+    POLICY_OPTION,
+    PENALTY_OPTION,
+    GREYLIST_OPTION,
+    REFILL_STEP_OPTION,
+    CURVE_OPTION,
+    PERSISTENT_OPTION,
+    FIELD_OPTION,
+    SHARDS_OPTION,
+    PARENT_OPTION,
+    COST_OPTION,
+    ATTR_OPTION,
+    ALGORITHM_OPTION,
+    ONDENY_OPTION,
+];
+// Multipliers of size suffixes, following the notation of redis.conf
+const SIZE_SUFFIXES: [(&str, i64); 7] = [
+    ("b", 1),
+    ("k", 1000),
+    ("kb", 1024),
+    ("m", 1000 * 1000),
+    ("mb", 1024 * 1024),
+    ("g", 1000 * 1000 * 1000),
+    ("gb", 1024 * 1024 * 1024),
+];
+
+/// Rate limit enforced by a bucket: `capacity` tokens per `period` seconds.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Limit {
+    pub capacity: i64,
+    pub period: i64,
+}
+
+/// Priority class of a request.
+///
+/// Low-priority requests may only use `threshold` percent of a bucket's capacity,
+/// the rest is reserved for normal and high-priority requests.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    High,
+    Normal,
+    Low,
+}
+
+/// Kind of a request checked against a retry budget.
+#[derive(Clone, Copy)]
+pub enum Kind {
+    Primary,
+    Retry,
+}
+
+/// Shape of the reply of `SHIELD.absorb`.
+#[derive(Clone, Copy)]
+pub enum Output {
+    // Number of tokens left, followed by the warning of the soft limit if requested
+    Tokens,
+    // Names and values of the rate limit HTTP headers
+    Headers,
+    // `1` if the request was allowed, `0` if it was denied, in place of the tokens
+    Simple,
+}
+
+/// Reply of `SHIELD.absorb` to a denied request.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Denial {
+    // `-1` in place of the number of tokens left
+    Sentinel,
+    // `THROTTLED <key> <retry_after_ms>` error
+    Error,
+}
+
+/// How a bucket stored with a different limit than the requested one is treated.
+#[derive(Clone, Copy)]
+pub enum StrictConfig {
+    // The request fails
+    Error,
+    // The bucket starts over with the requested limit
+    Reset,
+}
+
+/// What the tokens of a bucket stand for.
+#[derive(Clone, Copy)]
+enum Unit {
+    Requests,
+    Bytes,
+}
+
+/// Argument of a command: a `RedisString` when the module is called by Redis,
+/// or raw bytes, so the parser can be fuzzed without a server.
+pub trait Arg {
+    fn as_slice(&self) -> &[u8];
+}
+
+impl Arg for RedisString {
+    fn as_slice(&self) -> &[u8] {
+        RedisString::as_slice(self)
+    }
+}
+
+impl Arg for &[u8] {
+    fn as_slice(&self) -> &[u8] {
+        self
+    }
+}
+
+/// Arguments of the commands that check a request against a bucket,
+/// i.e. `SHIELD.absorb` and `SHIELD.simulate`.
+pub struct CommandArgs<'a, A = RedisString> {
+    // Unique bucket key, the name of the group if the request draws from one
+    pub key: &'a A,
+    // Key of the request within the group it draws from
+    pub member: Option<&'a A>,
+    // Limit enforced by the bucket stored under `key`
+    pub limit: Limit,
+    // Number of tokens requested
+    pub tokens: i64,
+    // Additional limits enforced for the same key, e.g. per-minute and per-hour
+    // limits on top of a per-second one
+    pub tiers: Vec<Limit>,
+    // Priority class of the request
+    pub priority: Priority,
+    // Percentage of capacity low-priority requests may use
+    pub threshold: i64,
+    // Number of tokens the bucket is allowed to go below zero by
+    pub overdraft: i64,
+    // Number of seconds a new bucket takes to ramp up to full capacity
+    pub warmup: i64,
+    // Id shared by retries of the same request
+    pub idempotency: Option<&'a A>,
+    // Percentage of capacity above which the usage is reported as a warning
+    pub soft: Option<i64>,
+    // Kind of the request, if it's checked against a retry budget
+    pub kind: Option<Kind>,
+    // Percentage of primary requests that may be retried
+    pub budget: i64,
+    // Number of the latest decisions kept for the key, `0` if they aren't kept
+    pub history: i64,
+    // Channel to publish the key to once a denied request would be admitted
+    pub notify: Option<&'a A>,
+    // Shape of the reply
+    pub output: Output,
+    // Minimum number of milliseconds between two admitted requests, `0` if not limited
+    pub min_interval: i64,
+    // Number of seconds after which untouched buckets expire, `0` if not limited
+    pub max_idle: i64,
+    // Whether the request is refused if the bucket doesn't exist yet
+    pub nx: bool,
+    // Treatment of buckets stored with a different limit, `None` if they are used as is
+    pub strict_config: Option<StrictConfig>,
+    // Name of the policy the request applies, its usage is accounted for
+    pub policy: Option<&'a A>,
+    // Longest cool-down in seconds imposed by repeated denials, `0` if not penalized
+    pub penalty: i64,
+    // Longest delay in milliseconds suggested to over-limit requests instead
+    // of denying them, `0` if they are denied
+    pub greylist: i64,
+    // Milliseconds in which tokens are refilled at once, `0` if they are refilled continuously
+    pub refill_step: i64,
+    // Shape of the refill across the period
+    pub curve: Curve,
+    // Whether the buckets are stored without an expire
+    pub persistent: bool,
+    // Field of the hash `key` the bucket is stored in, `None` if it's stored
+    // under `key` itself
+    pub field: Option<&'a A>,
+    // Number of buckets every limit is split into, `1` if it isn't split
+    pub shards: i64,
+    // Key of the parent bucket the request also draws from
+    pub parent: Option<&'a A>,
+    // Name of the function computing the tokens from the attributes
+    pub cost: Option<&'a A>,
+    // Attributes of the request passed to the cost function
+    pub attributes: Vec<&'a A>,
+    // Keyword of the algorithm of a plugin, `None` for the token bucket
+    pub algorithm: Option<&'a A>,
+    // Reply to a denied request, `None` if it follows `shield.deny-error`
+    pub denial: Option<Denial>,
+}
+
+/// Parses and validates arguments in the following format:
+///       SHIELD.absorb user123 30 60 1 TIER 300 3600
+///           ▲           ▲      ▲  ▲ ▲  ▲
+///           |           |      |  | |  └─── args[5..] options (optional)
+///           |           |      |  | └────── args[4] tokens: 1 token (default if omitted)
+///           |           |      |  └──────── args[3] period: 60 seconds
+///           |           |      └─────────── args[2] capacity: 30 tokens
+///           |           └────────────────── args[1] key: user123
+///           └────────────────────────────── args[0] command name (provided by redis)
+///
+/// Supported options:
+/// * `TIER <capacity> <period>` enforces an additional limit for the same key.
+///   Can be repeated, every tier must have a distinct period.
+/// * `PRIORITY high|normal|low` sets the priority class of the request (`normal` by default).
+/// * `THRESHOLD <percent>` sets the percentage of capacity low-priority
+///   requests may use (80 by default).
+/// * `OVERDRAFT <tokens>` admits the request even if the bucket goes negative
+///   by up to `tokens`. Future refills pay down the debt.
+/// * `WARMUP <seconds>` makes a new bucket start at a fraction of its capacity
+///   and ramp up to full capacity over `seconds`.
+/// * `IDEMPOTENCY <id>` makes retries of the same request get the original result
+///   instead of consuming tokens again.
+/// * `SOFT <percent>` reports a warning once the usage crosses `percent` of capacity.
+/// * `UNIT requests|bytes` sets what the tokens stand for (`requests` by default).
+///   In `bytes` mode the amounts of tokens may have size suffixes, e.g. `512kb` or `10gb`.
+/// * `KIND primary|retry` checks the request against a retry budget: retries
+///   admitted within a period may not exceed `BUDGET <percent>` (10 by default)
+///   of admitted primary requests.
+/// * `GROUP <name>` makes the request draw from the bucket shared by the group,
+///   while the tokens consumed by `key` are tracked in the group's statistics.
+/// * `HISTORY <n>` keeps the latest `n` decisions made for the key.
+/// * `NOTIFY <channel>` publishes the key to `channel` once a denied request
+///   would be admitted.
+/// * `OUTPUT headers` replies with the rate limit HTTP headers instead of the number
+///   of tokens left, and `OUTPUT simple` with `1` if the request is allowed or `0`.
+/// * `MININTERVAL <ms>` rejects requests arriving less than `ms` milliseconds
+///   after the last admitted one.
+/// * `MAXIDLE <seconds>` expires the buckets once they weren't written to
+///   for `seconds`, even if they aren't refilled yet.
+/// * `NX` refuses to create the bucket if it doesn't exist yet.
+/// * `STRICTCONFIG error|reset` fails the request, or resets the bucket, if it
+///   was stored with a different capacity or period.
+/// * `POLICY <name>` names the policy whose limit and options were inserted
+///   by `policy::expand`, so its usage is accounted for.
+/// * `PENALTY exponential <seconds>` denies all requests during a cool-down
+///   after a denial, which doubles with every consecutive denial up to `seconds`.
+/// * `GREYLIST <ms>` suggests over-limit requests a delay of up to `ms`
+///   milliseconds, growing with the pressure on the key, instead of denying them.
+/// * `REFILL_STEP <ms>` refills tokens in discrete steps of `ms` milliseconds
+///   instead of continuously. It may not exceed any period.
+/// * `CURVE linear|frontloaded|backloaded` shapes the refill across the period
+///   (`linear` by default), see [`Curve`].
+/// * `PERSISTENT` stores the buckets without an expire, taking precedence over `MAXIDLE`.
+/// * `FIELD <name>` stores the bucket in the field `name` of the hash `key`,
+///   and its tiers in the fields `<name>:<period>`.
+/// * `SHARDS <n>` splits every limit into `n` buckets with an equal share of
+///   the capacity, see [`Shards`](crate::shard::Shards).
+/// * `PARENT <key>` admits the request only if the bucket `key`, e.g. of the
+///   whole tenant, has sufficient tokens too, and takes them from both.
+/// * `COST <name>` takes the number of tokens computed by the cost function
+///   `name` from the request's attributes, each given as `ATTR <value>`,
+///   see [`CostFunction`](crate::cost::CostFunction). The tokens are omitted.
+/// * `ALGORITHM <keyword>` applies the algorithm a plugin registered under
+///   `keyword` instead of the token bucket, see [`plugin`](crate::plugin),
+///   or with `sample` the [`Sampler`](crate::sampler::Sampler).
+/// * `ONDENY sentinel|error` replies to a denied request with `-1`, or with
+///   a `THROTTLED` error, overriding `shield.deny-error`.
+///
+/// Nothing but the configured caps is read from Redis, so any `Arg` can be parsed.
+pub fn parse_command_args<A: Arg>(args: &[A]) -> Result<CommandArgs<A>, RedisError> {
+    trace::span!("parse");
+    if args.len() < MIN_ARGS_LEN {
+        return Err(RedisError::WrongArity);
+    }
+
+    let mut index = TOKENS_INDEX;
+    let mut tokens = None;
+    if let Some(arg) = args.get(index) {
+        if !is_option(arg) {
+            tokens = Some(arg);
+            index += 1;
+        }
+    }
+
+    // Options are collected first, since `UNIT` changes how the amounts
+    // of tokens are parsed, including the positional ones.
+    let mut options = Vec::new();
+    while let Some(option) = args.get(index) {
+        let option = text(option).to_ascii_uppercase();
+        let arity = match option.as_str() {
+            TIER_OPTION | PENALTY_OPTION => 2,
+            NX_OPTION | PERSISTENT_OPTION => 0,
+            _ if OPTIONS.contains(&option.as_str()) => 1,
+            _ => return Err(error::error(error::SYNTAX, "syntax error")),
+        };
+        options.push((option, option_values(args, index, arity)?));
+        index += arity + 1;
+    }
+
+    let mut unit = Unit::Requests;
+    for (option, values) in &options {
+        if option == UNIT_OPTION {
+            unit = parse_unit(&values[0])?;
+        }
+    }
+
+    let mut command = CommandArgs {
+        key: &args[1],
+        member: None,
+        limit: Limit {
+            capacity: parse_amount(unit, "capacity", &args[2])?,
+            period: parse_positive_integer("period", &args[3])?,
+        },
+        tokens: match tokens {
+            Some(tokens) => parse_amount(unit, "tokens", tokens)?,
+            None => DEFAULT_TOKENS,
+        },
+        tiers: Vec::new(),
+        priority: Priority::Normal,
+        threshold: DEFAULT_THRESHOLD,
+        overdraft: 0,
+        warmup: 0,
+        idempotency: None,
+        soft: None,
+        kind: None,
+        budget: DEFAULT_BUDGET,
+        history: 0,
+        notify: None,
+        output: Output::Tokens,
+        min_interval: 0,
+        max_idle: 0,
+        nx: false,
+        strict_config: None,
+        policy: None,
+        penalty: 0,
+        greylist: 0,
+        refill_step: 0,
+        curve: Curve::Linear,
+        persistent: false,
+        field: None,
+        shards: 1,
+        parent: None,
+        cost: None,
+        attributes: Vec::new(),
+        algorithm: None,
+        denial: None,
+    };
+
+    for (option, values) in options {
+        match option.as_str() {
+            TIER_OPTION => command.tiers.push(Limit {
+                capacity: parse_amount(unit, "capacity", &values[0])?,
+                period: parse_positive_integer("period", &values[1])?,
+            }),
+            PRIORITY_OPTION => command.priority = parse_priority(&values[0])?,
+            THRESHOLD_OPTION => command.threshold = parse_percentage("threshold", &values[0])?,
+            OVERDRAFT_OPTION => {
+                command.overdraft = match unit {
+                    Unit::Requests => parse_non_negative_integer("overdraft", &values[0])?,
+                    Unit::Bytes => parse_size("overdraft", &values[0], 0)?,
+                }
+            }
+            WARMUP_OPTION => command.warmup = parse_positive_integer("warmup", &values[0])?,
+            IDEMPOTENCY_OPTION => command.idempotency = Some(&values[0]),
+            SOFT_OPTION => command.soft = Some(parse_percentage("soft", &values[0])?),
+            KIND_OPTION => command.kind = Some(parse_kind(&values[0])?),
+            BUDGET_OPTION => command.budget = parse_percentage("budget", &values[0])?,
+            MININTERVAL_OPTION => {
+                command.min_interval = parse_positive_integer("mininterval", &values[0])?
+            }
+            NX_OPTION => command.nx = true,
+            PERSISTENT_OPTION => command.persistent = true,
+            FIELD_OPTION => command.field = Some(&values[0]),
+            SHARDS_OPTION => command.shards = parse_positive_integer("shards", &values[0])?,
+            PARENT_OPTION => command.parent = Some(&values[0]),
+            COST_OPTION => command.cost = Some(&values[0]),
+            ATTR_OPTION => command.attributes.push(&values[0]),
+            ALGORITHM_OPTION if text(&values[0]).eq_ignore_ascii_case(TOKEN_BUCKET) => {
+                command.algorithm = None
+            }
+            ALGORITHM_OPTION => command.algorithm = Some(&values[0]),
+            ONDENY_OPTION => command.denial = Some(parse_denial(&values[0])?),
+            POLICY_OPTION => command.policy = Some(&values[0]),
+            PENALTY_OPTION => command.penalty = parse_penalty(&values[0], &values[1])?,
+            GREYLIST_OPTION => command.greylist = parse_positive_integer("greylist", &values[0])?,
+            CURVE_OPTION => command.curve = parse_curve(&values[0])?,
+            REFILL_STEP_OPTION => {
+                command.refill_step = parse_positive_integer("refill_step", &values[0])?
+            }
+            STRICTCONFIG_OPTION => command.strict_config = Some(parse_strict_config(&values[0])?),
+            MAXIDLE_OPTION => command.max_idle = parse_positive_integer("maxidle", &values[0])?,
+            OUTPUT_OPTION => command.output = parse_output(&values[0])?,
+            NOTIFY_OPTION => command.notify = Some(&values[0]),
+            HISTORY_OPTION => command.history = parse_positive_integer("history", &values[0])?,
+            GROUP_OPTION => {
+                command.member = Some(command.key);
+                command.key = &values[0];
+            }
+            _ => {}
+        }
+    }
+
+    check_tokens(command.tokens)?;
+    for limit in std::iter::once(&command.limit).chain(&command.tiers) {
+        check_limit(limit)?;
+        if command.shards > limit.capacity {
+            return Err(bad_argument("shards", "must not exceed the capacity"));
+        }
+    }
+
+    match (command.cost, tokens) {
+        (Some(_), Some(_)) => return Err(bad_argument("tokens", "must be omitted with COST")),
+        (None, _) if !command.attributes.is_empty() => {
+            return Err(bad_argument("cost", "is required by ATTR"))
+        }
+        _ => {}
+    }
+    if command
+        .parent
+        .is_some_and(|parent| parent.as_slice() == command.key.as_slice())
+    {
+        return Err(bad_argument("parent", "must differ from the key"));
+    }
+
+    let mut periods: Vec<i64> = command.tiers.iter().map(|tier| tier.period).collect();
+    periods.push(command.limit.period);
+    periods.sort_unstable();
+    if command.refill_step > millis(periods[0]) {
+        return Err(bad_argument("refill_step", "must not exceed the period"));
+    }
+    periods.dedup();
+    if periods.len() != command.tiers.len() + 1 {
+        return Err(error::error(
+            error::BAD_TIERS,
+            "tiers must have distinct periods",
+        ));
+    }
+
+    Ok(command)
+}
+
+/// Returns the positions of the keys in the arguments of `SHIELD.absorb` and
+/// alike: the key itself and the names of the `GROUP` and the `PARENT` it draws
+/// from, if any.
+///
+/// The companion keys derived from them, e.g. of the tiers or the history,
+/// aren't arguments, and share the slot of the key they're derived from,
+/// see [`companion_key`](crate::keys::companion_key).
+///
+/// Unlike `parse_command_args`, nothing is validated, and the limit may be
+/// omitted, since Redis asks for the keys before the command is expanded.
+pub fn key_positions(args: &[impl Arg]) -> Vec<usize> {
+    let mut positions: Vec<usize> = (1..args.len().min(2)).collect();
+    positions.extend(option_value_positions(args, GROUP_OPTION));
+    positions.extend(option_value_positions(args, PARENT_OPTION));
+    positions
+}
+
+/// Returns the `FIELD` the bucket of `SHIELD.absorb` and alike is stored in,
+/// if any, without validating the arguments, like [`key_positions`].
+pub fn field<A: Arg>(args: &[A]) -> Option<&A> {
+    option_value_positions(args, FIELD_OPTION)
+        .pop()
+        .map(|position| &args[position])
+}
+
+/// Returns the positions of the values of every `name` option.
+fn option_value_positions(args: &[impl Arg], name: &str) -> Vec<usize> {
+    let mut positions = Vec::new();
+    let mut index = 2;
+    while args.get(index).is_some_and(|arg| !is_option(arg)) {
+        index += 1;
+    }
+    while let Some(option) = args.get(index) {
+        let option = text(option).to_ascii_uppercase();
+        let arity = match option.as_str() {
+            TIER_OPTION | PENALTY_OPTION => 2,
+            NX_OPTION | PERSISTENT_OPTION => 0,
+            _ => 1,
+        };
+        if option == name && index + 1 < args.len() {
+            positions.push(index + 1);
+        }
+        index += arity + 1;
+    }
+    positions
+}
+
+pub fn parse_positive_integer(name: &str, value: &impl Arg) -> Result<i64, RedisError> {
+    match parse_integer(value.as_slice()) {
+        Some(arg) if arg > 0 => Ok(arg),
+        _ => Err(bad_argument(name, "is not positive integer")),
+    }
+}
+
+pub fn parse_non_negative_integer(name: &str, value: &impl Arg) -> Result<i64, RedisError> {
+    match parse_integer(value.as_slice()) {
+        Some(arg) if arg >= 0 => Ok(arg),
+        _ => Err(bad_argument(name, "is not non-negative integer")),
+    }
+}
+
+/// Parses an integer as strictly as Redis does: an optional minus sign followed
+/// by digits, without leading zeros, whitespace or a plus sign.
+fn parse_integer(value: &[u8]) -> Option<i64> {
+    let digits = value.strip_prefix(b"-").unwrap_or(value);
+    match digits {
+        [b'0'] if digits.len() == value.len() => Some(0),
+        [b'1'..=b'9', rest @ ..] if rest.iter().all(u8::is_ascii_digit) => {
+            std::str::from_utf8(value).ok()?.parse().ok()
+        }
+        _ => None,
+    }
+}
+
+fn text(arg: &impl Arg) -> Cow<str> {
+    String::from_utf8_lossy(arg.as_slice())
+}
+
+/// Parses the capacity and period a command takes as plain arguments,
+/// e.g. `SHIELD.set`, and checks them with [`check_limit`].
+pub fn parse_limit(capacity: &impl Arg, period: &impl Arg) -> Result<Limit, RedisError> {
+    let limit = Limit {
+        capacity: parse_positive_integer("capacity", capacity)?,
+        period: parse_positive_integer("period", period)?,
+    };
+    check_limit(&limit)?;
+    Ok(limit)
+}
+
+/// Checks a limit against `shield.max-capacity` and `shield.max-period`.
+/// Every command taking a limit goes through it, so none gets around the caps.
+pub fn check_limit(limit: &Limit) -> Result<(), RedisError> {
+    check_cap("capacity", limit.capacity, &MAX_CAPACITY)?;
+    check_cap("period", limit.period, &MAX_PERIOD)
+}
+
+/// Checks the number of tokens a request takes against `shield.max-tokens-per-call`.
+pub fn check_tokens(tokens: i64) -> Result<(), RedisError> {
+    check_cap("tokens", tokens, &MAX_TOKENS_PER_CALL)
+}
+
+pub fn check_cap(name: &str, value: i64, cap: &AtomicI64) -> Result<(), RedisError> {
+    match config::cap(cap) {
+        Some(max) if value > max => Err(error::error(
+            error::TOO_LARGE,
+            format!("{} exceeds the maximum of {}", name, max),
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn parse_amount(unit: Unit, name: &str, value: &impl Arg) -> Result<i64, RedisError> {
+    match unit {
+        Unit::Requests => parse_positive_integer(name, value),
+        Unit::Bytes => parse_size(name, value, 1),
+    }
+}
+
+/// Parses a number of bytes, e.g. `1048576`, `1mb` or `1m`, which is at least `min`.
+fn parse_size(name: &str, value: &impl Arg, min: i64) -> Result<i64, RedisError> {
+    let value = text(value).to_ascii_lowercase();
+    let digits = value.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let multiplier = match &value[digits.len()..] {
+        "" => Some(1),
+        suffix => SIZE_SUFFIXES
+            .iter()
+            .find(|(known, _)| *known == suffix)
+            .map(|(_, multiplier)| *multiplier),
+    };
+
+    match (digits.parse::<i64>(), multiplier) {
+        (Ok(size), Some(multiplier)) if size >= min => size
+            .checked_mul(multiplier)
+            .ok_or_else(|| bad_argument(name, "is too large")),
+        _ => Err(bad_argument(name, "is not valid size")),
+    }
+}
+
+fn parse_output(value: &impl Arg) -> Result<Output, RedisError> {
+    match text(value).to_ascii_lowercase().as_str() {
+        "headers" => Ok(Output::Headers),
+        "simple" => Ok(Output::Simple),
+        _ => Err(bad_argument("output", "must be headers or simple")),
+    }
+}
+
+fn parse_denial(value: &impl Arg) -> Result<Denial, RedisError> {
+    match text(value).to_ascii_lowercase().as_str() {
+        "sentinel" => Ok(Denial::Sentinel),
+        "error" => Ok(Denial::Error),
+        _ => Err(bad_argument("ondeny", "must be sentinel or error")),
+    }
+}
+
+fn parse_strict_config(value: &impl Arg) -> Result<StrictConfig, RedisError> {
+    match text(value).to_ascii_lowercase().as_str() {
+        "error" => Ok(StrictConfig::Error),
+        "reset" => Ok(StrictConfig::Reset),
+        _ => Err(bad_argument("strictconfig", "must be error or reset")),
+    }
+}
+
+fn parse_curve(value: &impl Arg) -> Result<Curve, RedisError> {
+    match text(value).to_ascii_lowercase().as_str() {
+        "linear" => Ok(Curve::Linear),
+        "frontloaded" => Ok(Curve::Frontloaded),
+        "backloaded" => Ok(Curve::Backloaded),
+        _ => Err(bad_argument(
+            "curve",
+            "must be linear, frontloaded or backloaded",
+        )),
+    }
+}
+
+fn parse_penalty(shape: &impl Arg, max_cooldown: &impl Arg) -> Result<i64, RedisError> {
+    if !text(shape).eq_ignore_ascii_case("exponential") {
+        return Err(error::bad_argument("penalty", "must be exponential"));
+    }
+    parse_positive_integer("penalty", max_cooldown)
+}
+
+fn parse_kind(value: &impl Arg) -> Result<Kind, RedisError> {
+    match text(value).to_ascii_lowercase().as_str() {
+        "primary" => Ok(Kind::Primary),
+        "retry" => Ok(Kind::Retry),
+        _ => Err(bad_argument("kind", "must be primary or retry")),
+    }
+}
+
+fn parse_unit(value: &impl Arg) -> Result<Unit, RedisError> {
+    match text(value).to_ascii_lowercase().as_str() {
+        "requests" => Ok(Unit::Requests),
+        "bytes" => Ok(Unit::Bytes),
+        _ => Err(bad_argument("unit", "must be requests or bytes")),
+    }
+}
+
+fn parse_percentage(name: &str, value: &impl Arg) -> Result<i64, RedisError> {
+    let percentage = parse_positive_integer(name, value)?;
+    if percentage > MAX_THRESHOLD {
+        return Err(bad_argument(name, "must not exceed 100"));
+    }
+    Ok(percentage)
+}
+
+fn parse_priority(value: &impl Arg) -> Result<Priority, RedisError> {
+    match text(value).to_ascii_lowercase().as_str() {
+        "high" => Ok(Priority::High),
+        "normal" => Ok(Priority::Normal),
+        "low" => Ok(Priority::Low),
+        _ => Err(bad_argument("priority", "must be high, normal or low")),
+    }
+}
+
+/// Returns `true` if the capacity and period are omitted from the arguments
+/// of `SHIELD.absorb` and alike, i.e. unless both arguments following the key
+/// are present and aren't options.
+pub fn limit_omitted(args: &[impl Arg]) -> bool {
+    !args
+        .get(2..4)
+        .is_some_and(|limit| !limit.iter().any(is_option))
+}
+
+pub fn is_option(arg: &impl Arg) -> bool {
+    let arg = text(arg);
+    OPTIONS
+        .iter()
+        .any(|option| arg.eq_ignore_ascii_case(option))
+}
+
+fn option_values<A: Arg>(args: &[A], index: usize, count: usize) -> Result<&[A], RedisError> {
+    args.get(index + 1..index + 1 + count)
+        .ok_or_else(|| error::error(error::SYNTAX, "syntax error"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&'static str]) -> Vec<&'static [u8]> {
+        args.iter().map(|arg| arg.as_bytes()).collect()
+    }
+
+    fn is_syntax_error<A>(result: Result<CommandArgs<A>, RedisError>) -> bool {
+        matches!(result, Err(RedisError::String(message)) if message.starts_with(error::SYNTAX))
+    }
+
+    #[test]
+    fn test_algorithm_in_place_of_tokens() {
+        let args = args(&[
+            "SHIELD.absorb",
+            "user123",
+            "30",
+            "60",
+            "ALGORITHM",
+            "sliding",
+        ]);
+        let command = parse_command_args(&args).unwrap();
+        assert_eq!(command.tokens, DEFAULT_TOKENS);
+        assert_eq!(command.algorithm, Some(&b"sliding".as_slice()));
+    }
+
+    #[test]
+    fn test_algorithm_after_tokens() {
+        let args = args(&[
+            "SHIELD.absorb",
+            "user123",
+            "30",
+            "60",
+            "5",
+            "algorithm",
+            "sliding",
+        ]);
+        let command = parse_command_args(&args).unwrap();
+        assert_eq!(command.tokens, 5);
+        assert_eq!(command.algorithm, Some(&b"sliding".as_slice()));
+    }
+
+    #[test]
+    fn test_algorithm_after_flag() {
+        let args = args(&[
+            "SHIELD.absorb",
+            "user123",
+            "30",
+            "60",
+            "NX",
+            "ALGORITHM",
+            "sliding",
+        ]);
+        let command = parse_command_args(&args).unwrap();
+        assert!(command.nx);
+        assert_eq!(command.tokens, DEFAULT_TOKENS);
+        assert_eq!(command.algorithm, Some(&b"sliding".as_slice()));
+    }
+
+    #[test]
+    fn test_token_bucket_algorithm_is_built_in() {
+        let args = args(&[
+            "SHIELD.absorb",
+            "user123",
+            "30",
+            "60",
+            "ALGORITHM",
+            "Token_Bucket",
+        ]);
+        let command = parse_command_args(&args).unwrap();
+        assert_eq!(command.algorithm, None);
+    }
+
+    #[test]
+    fn test_algorithm_takes_option_name_as_value() {
+        let args = args(&["SHIELD.absorb", "user123", "30", "60", "ALGORITHM", "NX"]);
+        let command = parse_command_args(&args).unwrap();
+        assert!(!command.nx);
+        assert_eq!(command.algorithm, Some(&b"NX".as_slice()));
+    }
+
+    #[test]
+    fn test_algorithm_without_value() {
+        for args in [
+            args(&["SHIELD.absorb", "user123", "30", "60", "ALGORITHM"]),
+            args(&["SHIELD.absorb", "user123", "30", "60", "1", "ALGORITHM"]),
+            args(&["SHIELD.absorb", "user123", "30", "60", "NX", "ALGORITHM"]),
+        ] {
+            assert!(is_syntax_error(parse_command_args(&args)));
+        }
+    }
+
+    #[test]
+    fn test_value_in_place_of_option() {
+        for args in [
+            args(&["SHIELD.absorb", "user123", "30", "60", "1", "2"]),
+            args(&[
+                "SHIELD.absorb",
+                "user123",
+                "30",
+                "60",
+                "ALGORITHM",
+                "sliding",
+                "fixed",
+            ]),
+            args(&[
+                "SHIELD.absorb",
+                "user123",
+                "30",
+                "60",
+                "1",
+                "sliding",
+                "ALGORITHM",
+            ]),
+        ] {
+            assert!(is_syntax_error(parse_command_args(&args)));
+        }
+    }
+
+    #[test]
+    fn test_missing_limit() {
+        let args = args(&["SHIELD.absorb", "user123", "30"]);
+        let result = parse_command_args(&args);
+        assert!(matches!(result, Err(RedisError::WrongArity)));
+    }
+}
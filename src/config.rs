@@ -0,0 +1,102 @@
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::sync::Mutex;
+
+/// Module-level caps guarding against absurd buckets, e.g. created by a buggy
+/// client passing `capacity=9e18`. Every cap is disabled when set to `0`.
+///
+/// The caps can be passed as module arguments or changed at runtime:
+///     CONFIG SET shield.max-capacity 1000000
+pub static MAX_CAPACITY: AtomicI64 = AtomicI64::new(0);
+pub static MAX_TOKENS_PER_CALL: AtomicI64 = AtomicI64::new(0);
+pub static MAX_PERIOD: AtomicI64 = AtomicI64::new(0);
+/// Unlike the other caps, enabled by default, since `SHIELD.bench` blocks the server.
+pub const DEFAULT_MAX_BENCH_ITERATIONS: i64 = 10_000_000;
+pub static MAX_BENCH_ITERATIONS: AtomicI64 = AtomicI64::new(DEFAULT_MAX_BENCH_ITERATIONS);
+
+/// Returns the value of `cap`, or `None` if it's disabled.
+pub fn cap(cap: &AtomicI64) -> Option<i64> {
+    match cap.load(Ordering::Relaxed) {
+        0 => None,
+        value => Some(value),
+    }
+}
+
+/// Percentage of a bucket's period by which the TTL of its key may be randomly
+/// extended, so millions of keys created by a traffic spike don't all expire
+/// at the same instant. The refill doesn't depend on the key's TTL.
+pub static TTL_JITTER: AtomicI64 = AtomicI64::new(0);
+
+pub fn ttl_jitter() -> i64 {
+    TTL_JITTER.load(Ordering::Relaxed)
+}
+
+/// Milliseconds a limiter evaluation has to take to be reported to the latency
+/// monitor of redis, e.g. `LATENCY HISTORY shield-absorb`. Disabled when `0`.
+pub static LATENCY_THRESHOLD: AtomicI64 = AtomicI64::new(0);
+
+pub fn latency_threshold() -> i64 {
+    LATENCY_THRESHOLD.load(Ordering::Relaxed)
+}
+
+/// Microseconds a limiter evaluation has to take to be logged with its key,
+/// like `slowlog-log-slower-than` does for commands. Disabled when `0`.
+pub static SLOWLOG_THRESHOLD: AtomicI64 = AtomicI64::new(0);
+
+pub fn slowlog_threshold() -> i64 {
+    SLOWLOG_THRESHOLD.load(Ordering::Relaxed)
+}
+
+/// Number of keys with the most denials tracked by the background aggregator.
+/// Disabled when `0`.
+pub static TOP_KEYS: AtomicI64 = AtomicI64::new(0);
+
+pub fn top_keys() -> i64 {
+    TOP_KEYS.load(Ordering::Relaxed)
+}
+
+/// Number of the last decisions kept for `SHIELD.recent`. Disabled when `0`.
+pub static RECENT_DECISIONS: AtomicI64 = AtomicI64::new(0);
+
+pub fn recent_decisions() -> i64 {
+    RECENT_DECISIONS.load(Ordering::Relaxed)
+}
+
+/// When enabled, `SHIELD.absorb` replies to denied requests with a `THROTTLED`
+/// error instead of `-1`, unless they pass `ONDENY`.
+pub static DENY_ERROR: AtomicBool = AtomicBool::new(false);
+
+pub fn deny_error() -> bool {
+    DENY_ERROR.load(Ordering::Relaxed)
+}
+
+/// When enabled, state clobbered by a foreign value, e.g. an unparsable string or
+/// a key of the wrong type, is reset and a warning is logged. Otherwise the request fails.
+pub static LENIENT_RECOVERY: AtomicBool = AtomicBool::new(false);
+
+pub fn lenient_recovery() -> bool {
+    LENIENT_RECOVERY.load(Ordering::Relaxed)
+}
+
+/// Percentage of `maxmemory` above which requests for unknown keys don't get
+/// a bucket, so an attack fanning out per-IP keys can't get redis OOM-killed.
+/// Disabled when `0`.
+pub static MEMORY_THRESHOLD: AtomicI64 = AtomicI64::new(0);
+
+pub fn memory_threshold() -> i64 {
+    MEMORY_THRESHOLD.load(Ordering::Relaxed)
+}
+
+/// When enabled, requests refused a bucket under memory pressure are admitted
+/// without tracking them. Otherwise they're denied.
+pub static MEMORY_FAIL_OPEN: AtomicBool = AtomicBool::new(false);
+
+pub fn memory_fail_open() -> bool {
+    MEMORY_FAIL_OPEN.load(Ordering::Relaxed)
+}
+
+/// Prefix of the keys owned by the module rather than a bucket, e.g. `shield:ns:api`.
+pub static KEY_PREFIX: Mutex<String> = Mutex::new(String::new());
+
+/// Separator of the parts of derived keys, e.g. `user123:warmup`. Together with
+/// the prefix it lets operators fit their keyspace conventions and ACL key patterns.
+pub static KEY_SEPARATOR: Mutex<String> = Mutex::new(String::new());
@@ -0,0 +1,138 @@
+use crate::command_parser::{check_cap, CommandArgs};
+use crate::config::MAX_TOKENS_PER_CALL;
+use crate::error::{self, bad_argument, error};
+use crate::keys::cost_key;
+use crate::recovery;
+use redis_module::{Context, RedisError, RedisString, RedisValue};
+
+const FUNCTION_FIELD: &str = "function";
+const SCRIPT_FIELD: &str = "script";
+
+/// Named function computing the number of tokens a request costs from its
+/// attributes, e.g. the payload size and the class of the endpoint, so every
+/// client prices requests the same way.
+///
+/// A cost function is stored in the `<prefix>:cost:<name>` hash with either
+/// a `function` field naming a Redis Function, or a `script` field holding
+/// the SHA1 digest of a Lua script loaded with `SCRIPT LOAD`. It's called
+/// read-only, with the key of the request as its only key and the attributes
+/// as arguments, and must return a positive integer.
+pub enum CostFunction {
+    Function(String),
+    Script(String),
+}
+
+impl CostFunction {
+    /// Parses a field-value pair, e.g. `function payload_cost`.
+    pub fn parse(args: &[RedisString]) -> Result<Self, RedisError> {
+        let [field, reference] = args else {
+            return Err(RedisError::WrongArity);
+        };
+        let reference = reference.to_string_lossy();
+        match field.to_string_lossy().to_ascii_lowercase().as_str() {
+            FUNCTION_FIELD => Ok(Self::Function(reference)),
+            SCRIPT_FIELD => Ok(Self::Script(reference)),
+            _ => Err(error(error::SYNTAX, "syntax error")),
+        }
+    }
+
+    /// Returns the cost function called `name`, or `None` if it isn't defined.
+    pub fn load(ctx: &Context, name: &[u8]) -> Result<Option<Self>, RedisError> {
+        let key = cost_key(name);
+        let fields = [
+            &key,
+            &RedisString::create(None, FUNCTION_FIELD),
+            &RedisString::create(None, SCRIPT_FIELD),
+        ];
+        let RedisValue::Array(values) = recovery::call(ctx, "HMGET", &fields)? else {
+            return Ok(None);
+        };
+
+        match (values.first(), values.get(1)) {
+            (Some(RedisValue::SimpleString(function)), _) => {
+                Ok(Some(Self::Function(function.clone())))
+            }
+            (_, Some(RedisValue::SimpleString(script))) => Ok(Some(Self::Script(script.clone()))),
+            _ if ctx.call("EXISTS", &[&key])? == RedisValue::Integer(1) => {
+                recovery::corrupted(ctx, &key)?;
+                Ok(None)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    pub fn save(&self, ctx: &Context, name: &[u8]) -> Result<(), RedisError> {
+        let key = cost_key(name);
+        ctx.call("DEL", &[&key])?;
+        let (field, reference) = self.field();
+        ctx.call(
+            "HSET",
+            &[
+                &key,
+                &RedisString::create(None, field),
+                &RedisString::create(None, reference),
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Removes the cost function called `name`. Returns `true` if it was defined.
+    pub fn delete(ctx: &Context, name: &[u8]) -> Result<bool, RedisError> {
+        let deleted = ctx.call("DEL", &[&cost_key(name)])?;
+        Ok(deleted == RedisValue::Integer(1))
+    }
+
+    /// Returns the name of the field the function is stored in and its value.
+    pub fn field(&self) -> (&'static str, &str) {
+        match self {
+            Self::Function(function) => (FUNCTION_FIELD, function),
+            Self::Script(sha1) => (SCRIPT_FIELD, sha1),
+        }
+    }
+
+    // Calls the function for the request to `key` with `attributes`
+    fn call(
+        &self,
+        ctx: &Context,
+        key: &RedisString,
+        attributes: &[&RedisString],
+    ) -> Result<RedisValue, RedisError> {
+        let (command, reference) = match self {
+            Self::Function(function) => ("FCALL_RO", function),
+            Self::Script(sha1) => ("EVALSHA_RO", sha1),
+        };
+        let reference = RedisString::create(None, reference.as_str());
+        let numkeys = RedisString::create(None, "1");
+        let mut args = vec![&reference, &numkeys, key];
+        args.extend_from_slice(attributes);
+        ctx.call(command, args.as_slice())
+    }
+}
+
+/// Replaces the tokens of a request naming a `COST` function with the ones
+/// the function computes from its `ATTR`s.
+pub fn apply(ctx: &Context, command: &mut CommandArgs) -> Result<(), RedisError> {
+    let Some(name) = command.cost else {
+        return Ok(());
+    };
+    let Some(function) = CostFunction::load(ctx, name.as_slice())? else {
+        return Err(bad_argument("cost", "function is not defined"));
+    };
+
+    let tokens = match function.call(
+        ctx,
+        command.member.unwrap_or(command.key),
+        &command.attributes,
+    )? {
+        RedisValue::Integer(tokens) if tokens > 0 => tokens,
+        _ => {
+            return Err(bad_argument(
+                "cost",
+                "function must return a positive integer",
+            ))
+        }
+    };
+    check_cap("tokens", tokens, &MAX_TOKENS_PER_CALL)?;
+    command.tokens = tokens;
+    Ok(())
+}
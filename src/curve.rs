@@ -0,0 +1,97 @@
+use crate::math::{mul_div, mul_div_ceil};
+
+const MAX_PERCENT: i128 = 100;
+
+/// Shape of a bucket's refill across its period, e.g. to shape traffic
+/// towards the start or the end of billing windows.
+///
+/// A curve describes the refill of an empty bucket: the number of tokens
+/// after a given time is `capacity * f(elapsed / period)`, where `f` runs from
+/// `0` to `1`. A bucket holding some tokens continues along the curve from
+/// the point where an empty bucket would hold as many, so the refill doesn't
+/// depend on how often the bucket is written to.
+///
+/// Curves are piecewise linear, bending in the middle of the period.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Curve {
+    // Tokens accrue at a constant rate
+    Linear,
+    // 80% of the tokens accrue in the first half of the period
+    Frontloaded,
+    // 20% of the tokens accrue in the first half of the period
+    Backloaded,
+}
+
+impl Curve {
+    /// Returns the number of tokens in a bucket holding `tokens` after
+    /// `elapsed` milliseconds more of the refill, not capped at `capacity`.
+    pub fn refill(self, tokens: i64, elapsed: i64, capacity: i64, period: i64) -> i64 {
+        match self {
+            Self::Linear => {
+                tokens.saturating_add(mul_div(elapsed.into(), capacity.into(), period.into()))
+            }
+            _ if tokens >= capacity => tokens,
+            _ => {
+                let start = self.elapsed(tokens, capacity, period);
+                self.tokens(start.saturating_add(elapsed), capacity, period)
+            }
+        }
+    }
+
+    /// Returns the number of milliseconds in which a bucket holding `from`
+    /// tokens refills to `to` tokens.
+    pub fn wait(self, from: i64, to: i64, capacity: i64, period: i64) -> i64 {
+        match self {
+            Self::Linear => {
+                let missing = i128::from(to) - i128::from(from);
+                mul_div_ceil(missing, period.into(), capacity.into())
+            }
+            _ => self
+                .elapsed(to, capacity, period)
+                .saturating_sub(self.elapsed(from, capacity, period)),
+        }
+    }
+
+    // Percentage of the tokens accruing in the first half of the period
+    fn knee(self) -> i128 {
+        match self {
+            Self::Linear => 50,
+            Self::Frontloaded => 80,
+            Self::Backloaded => 20,
+        }
+    }
+
+    // Number of tokens in an empty bucket after `elapsed` milliseconds.
+    // Times before the start follow the first half, e.g. for a bucket in debt.
+    fn tokens(self, elapsed: i64, capacity: i64, period: i64) -> i64 {
+        let (knee, elapsed, capacity, period) = (
+            self.knee(),
+            i128::from(elapsed.min(period)),
+            i128::from(capacity),
+            i128::from(period),
+        );
+        if 2 * elapsed <= period {
+            mul_div(capacity * knee, elapsed, period * MAX_PERCENT / 2)
+        } else {
+            let progress = knee * period + (MAX_PERCENT - knee) * (2 * elapsed - period);
+            mul_div(capacity, progress, period * MAX_PERCENT)
+        }
+    }
+
+    // Smallest number of milliseconds in which an empty bucket refills to
+    // `tokens`, the inverse of `tokens`.
+    fn elapsed(self, tokens: i64, capacity: i64, period: i64) -> i64 {
+        let (knee, tokens, capacity, period) = (
+            self.knee(),
+            i128::from(tokens.min(capacity)),
+            i128::from(capacity),
+            i128::from(period),
+        );
+        if tokens * MAX_PERCENT <= capacity * knee {
+            mul_div_ceil(tokens * MAX_PERCENT / 2, period, capacity * knee)
+        } else {
+            let progress = tokens * MAX_PERCENT + (MAX_PERCENT - 2 * knee) * capacity;
+            mul_div_ceil(progress, period, 2 * (MAX_PERCENT - knee) * capacity)
+        }
+    }
+}
@@ -0,0 +1,144 @@
+use crate::bucket::Bucket;
+use crate::error::{self, error};
+use crate::math::millis;
+use crate::recovery;
+use crate::state::{self, State};
+use crate::strings;
+use redis_module::{Context, RedisError, RedisString, RedisValue};
+
+const ALGORITHM_OPTION: &str = "algorithm";
+const ALGORITHM: &str = "token_bucket";
+// Versions of the stored value's format: a bare number of tokens,
+// `<tokens>:<expires_at>` and `<tokens>:<expires_at>:<capacity>:<period>`
+const BARE_ENCODING: i64 = 0;
+const TIMESTAMP_ENCODING: i64 = 1;
+const LIMIT_ENCODING: i64 = 2;
+
+/// Troubleshooting view of a bucket, e.g. to answer why a request was denied
+/// without decoding the stored value by hand.
+pub struct Inspector;
+
+impl Inspector {
+    /// Parses option-value pairs, e.g. `ALGORITHM token_bucket`.
+    pub fn parse(args: &[RedisString]) -> Result<Self, RedisError> {
+        if args.len() % 2 != 0 {
+            return Err(error(error::SYNTAX, "syntax error"));
+        }
+
+        for pair in args.chunks(2) {
+            let option = pair[0].to_string_lossy().to_ascii_lowercase();
+            match option.as_str() {
+                ALGORITHM_OPTION if pair[1].to_string_lossy() == ALGORITHM => {}
+                ALGORITHM_OPTION => return Err(error(error::BAD_ALGO, "unsupported algorithm")),
+                _ => return Err(error(error::SYNTAX, "syntax error")),
+            }
+        }
+        Ok(Self)
+    }
+
+    /// Returns the decoded state of the bucket stored under `key` as
+    /// field-value pairs, or `None` if the bucket doesn't exist.
+    ///
+    /// Besides the stored fields, the key's actual TTL is reported, and,
+    /// if the bucket's limit is known, the time elapsed since the last write
+    /// and the number of tokens refilled by now. Unknown fields are nil.
+    pub fn state(
+        &self,
+        ctx: &Context,
+        key: &RedisString,
+    ) -> Result<Option<Vec<RedisValue>>, RedisError> {
+        let now = state::now(ctx)?;
+        let state = match State::load(ctx, key, now)? {
+            Some(state) => state,
+            None => return Ok(None),
+        };
+        let pttl = ctx.call("PTTL", &[key])?;
+
+        let (mut capacity, mut period, mut elapsed, mut tokens) = (
+            RedisValue::Null,
+            RedisValue::Null,
+            RedisValue::Null,
+            RedisValue::Null,
+        );
+        if let Some(limit) = state.limit {
+            let bucket = Bucket::new(ctx, key, limit.capacity, limit.period)?;
+            capacity = limit.capacity.into();
+            period = limit.period.into();
+            elapsed = bucket.elapsed().into();
+            tokens = bucket.tokens.into();
+        }
+
+        Ok(Some(vec![
+            RedisValue::SimpleStringStatic("stored_tokens"),
+            state.tokens.into(),
+            RedisValue::SimpleStringStatic("expires_at"),
+            state.expires_at.into(),
+            RedisValue::SimpleStringStatic("ttl"),
+            state.ttl(now).into(),
+            RedisValue::SimpleStringStatic("pttl"),
+            pttl,
+            RedisValue::SimpleStringStatic("capacity"),
+            capacity,
+            RedisValue::SimpleStringStatic("period"),
+            period,
+            RedisValue::SimpleStringStatic("elapsed"),
+            elapsed,
+            RedisValue::SimpleStringStatic("tokens"),
+            tokens,
+        ]))
+    }
+
+    /// Returns low-level details of the value stored under `key` as
+    /// field-value pairs, like `DEBUG OBJECT`, or `None` if it doesn't exist.
+    ///
+    /// The age is the number of milliseconds since the last write, which is
+    /// only known for values stored with their limit. The idle time requires
+    /// an LRU `maxmemory-policy` (or none), it's nil otherwise.
+    pub fn object(
+        &self,
+        ctx: &Context,
+        key: &RedisString,
+    ) -> Result<Option<Vec<RedisValue>>, RedisError> {
+        // Checked before reading the value, which counts as an access
+        let idle = match ctx.call("OBJECT", &[strings::idletime_subcommand(), key]) {
+            Ok(RedisValue::Integer(seconds)) => millis(seconds).into(),
+            _ => RedisValue::Null,
+        };
+
+        let value = match recovery::call(ctx, "GET", &[key])? {
+            RedisValue::SimpleString(value) => value,
+            _ => return Ok(None),
+        };
+        let (encoding, age) = match State::decode(&value) {
+            Some(State {
+                expires_at,
+                limit: Some(limit),
+                ..
+            }) => {
+                let written_at = expires_at.saturating_sub(millis(limit.period));
+                let age = state::now(ctx)?.saturating_sub(written_at).max(0);
+                (LIMIT_ENCODING, age.into())
+            }
+            Some(_) => (TIMESTAMP_ENCODING, RedisValue::Null),
+            None if value.parse::<i64>().is_ok() => (BARE_ENCODING, RedisValue::Null),
+            None => {
+                recovery::corrupted(ctx, key)?;
+                return Ok(None);
+            }
+        };
+        let size = ctx.call("MEMORY", &[strings::usage_subcommand(), key])?;
+
+        Ok(Some(vec![
+            RedisValue::SimpleStringStatic("encoding_version"),
+            encoding.into(),
+            RedisValue::SimpleStringStatic("size"),
+            size,
+            RedisValue::SimpleStringStatic("algorithm"),
+            RedisValue::SimpleStringStatic(ALGORITHM),
+            RedisValue::SimpleStringStatic("age"),
+            age,
+            RedisValue::SimpleStringStatic("idle"),
+            idle,
+        ]))
+    }
+}
@@ -0,0 +1,37 @@
+use redis_module::RedisError;
+use std::fmt::Display;
+
+// Error codes are the first word of an error reply. They are stable,
+// so clients can branch on the class of an error, while the rest
+// of the reply is a human readable message.
+pub const SYNTAX: &str = "SHIELD_SYNTAX";
+pub const BAD_TIERS: &str = "SHIELD_BADTIERS";
+pub const TOO_LARGE: &str = "SHIELD_TOOLARGE";
+pub const CORRUPT: &str = "SHIELD_CORRUPT";
+pub const BAD_SNAPSHOT: &str = "SHIELD_BADSNAPSHOT";
+pub const BAD_ALGO: &str = "SHIELD_BADALGO";
+pub const CONFLICT: &str = "SHIELD_CONFLICT";
+pub const HASH: &str = "SHIELD_HASH";
+pub const UNKNOWN_COMMAND: &str = "SHIELD_UNKNOWNCOMMAND";
+// Replied to denied requests that ask for an error, followed by the key and
+// the milliseconds to wait. It's a decision rather than a failure, so it
+// isn't coded like the errors of the module.
+pub const THROTTLED: &str = "THROTTLED";
+
+pub fn error(code: &str, message: impl Display) -> RedisError {
+    RedisError::String(format!("{} {}", code, message))
+}
+
+/// Returns `true` if `err` is the `THROTTLED` reply to a denied request.
+pub fn is_throttled(err: &RedisError) -> bool {
+    matches!(err, RedisError::String(message) if message.starts_with(&format!("{} ", THROTTLED)))
+}
+
+/// Returns an error about an invalid value of the argument `name`,
+/// coded as `SHIELD_BAD<NAME>`, e.g. `SHIELD_BADCAPACITY`.
+pub fn bad_argument(name: &str, message: &str) -> RedisError {
+    error(
+        &format!("SHIELD_BAD{}", name.to_ascii_uppercase()),
+        format!("{} {}", name, message),
+    )
+}
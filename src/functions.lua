@@ -0,0 +1,115 @@
+#!lua name=shield
+
+-- Token buckets of the redis-shield module for servers that can't load it,
+-- e.g. managed Redis. Buckets are stored the way the module stores them,
+-- `<tokens>:<expires_at>:<capacity>:<period>` under the key itself, so both
+-- can serve the same keys.
+--
+--     FCALL shield_absorb 1 user123 30 60 1
+--     FCALL_RO shield_check 1 user123 30 60
+
+local function now()
+  local time = redis.call('TIME')
+  return tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+end
+
+local function positive_integer(name, value)
+  local number = value and tonumber(value)
+  if number and number > 0 and number % 1 == 0 then
+    return number
+  end
+  return nil, redis.error_reply('SHIELD_BAD' .. string.upper(name) .. ' ' .. name .. ' is not positive integer')
+end
+
+-- Returns the tokens stored under `key` and the milliseconds left of its TTL
+local function load(key, now_ms)
+  local value = redis.call('GET', key)
+  if not value then
+    return 0, 0
+  end
+  local tokens, expires_at = string.match(value, '^(%-?%d+):(%d+)$')
+  if not tokens then
+    tokens, expires_at = string.match(value, '^(%-?%d+):(%d+):%d+:%d+$')
+  end
+  if not tokens then
+    tokens, expires_at = string.match(value, '^(%-?%d+):(%d+):%d+:%d+:tb$')
+  end
+  if tokens then
+    return tonumber(tokens), math.max(tonumber(expires_at) - now_ms, 0)
+  end
+  -- A bare number of tokens, as written by older versions of the module
+  if string.match(value, '^%-?%d+$') then
+    return tonumber(value), math.max(redis.call('PTTL', key), 0)
+  end
+  return nil, nil, redis.error_reply('SHIELD_CORRUPT invalid value stored under ' .. key)
+end
+
+-- Returns the tokens in the bucket after the refill since the last write
+local function refill(key, capacity, period, now_ms)
+  local stored, ttl, err = load(key, now_ms)
+  if err then
+    return nil, err
+  end
+  local elapsed = period - math.min(ttl, period)
+  return math.min(capacity, stored + math.floor(elapsed * capacity / period))
+end
+
+local function parse(args)
+  local capacity, err = positive_integer('capacity', args[1])
+  if err then
+    return nil, nil, nil, err
+  end
+  local period
+  period, err = positive_integer('period', args[2])
+  if err then
+    return nil, nil, nil, err
+  end
+  local tokens = 1
+  if args[3] then
+    tokens, err = positive_integer('tokens', args[3])
+    if err then
+      return nil, nil, nil, err
+    end
+  end
+  return capacity, period, tokens
+end
+
+-- Takes the tokens from the bucket, returning the number of tokens left
+-- or -1 if the request is denied, like SHIELD.absorb without options
+local function absorb(keys, args)
+  local capacity, period, tokens, err = parse(args)
+  if err then
+    return err
+  end
+  local now_ms = now()
+  local available
+  available, err = refill(keys[1], capacity, period * 1000, now_ms)
+  if err then
+    return err
+  end
+  if tokens > available then
+    return -1
+  end
+  local remaining = available - tokens
+  local expires_at = now_ms + period * 1000
+  redis.call('PSETEX', keys[1], period * 1000,
+    string.format('%d:%d:%d:%d:tb', remaining, expires_at, capacity, period))
+  return math.max(remaining, 0)
+end
+
+-- Returns the number of tokens left in the bucket without changing it
+local function check(keys, args)
+  local capacity, period, _, err = parse(args)
+  if err then
+    return err
+  end
+  local available
+  available, err = refill(keys[1], capacity, period * 1000, now())
+  if err then
+    return err
+  end
+  return math.max(available, 0)
+end
+
+redis.register_function('shield_absorb', absorb)
+redis.register_function{function_name = 'shield_check', callback = check, flags = {'no-writes'}}
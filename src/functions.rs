@@ -0,0 +1,6 @@
+/// Redis Functions library implementing the token bucket of `SHIELD.absorb`
+/// in Lua, with the same key encoding, for servers that can't load the module.
+///
+/// It only covers the limit itself: none of the options of `SHIELD.absorb`,
+/// and no TTL jitter.
+pub const LIBRARY: &str = include_str!("functions.lua");
@@ -0,0 +1,153 @@
+use crate::command_parser::{parse_non_negative_integer, parse_positive_integer};
+use crate::error::{self, bad_argument, error};
+use crate::state::{self, State};
+use crate::strings;
+use redis_module::{Context, RedisError, RedisString, RedisValue};
+
+const MATCH_OPTION: &str = "match";
+const IDLE_OPTION: &str = "idle";
+const COUNT_OPTION: &str = "count";
+const ANY_KEY: &str = "*";
+// Number of keys a call checks unless given explicitly
+const DEFAULT_COUNT: i64 = 1000;
+const MILLS_IN_SEC: i64 = 1000;
+const MAXMEMORY_POLICY: &str = "maxmemory-policy";
+// Part of the names of the eviction policies tracking access frequency
+// rather than time, under which `OBJECT IDLETIME` fails
+const LFU: &str = "lfu";
+
+/// Removes bucket states that are no longer needed.
+///
+/// A bucket is collected once its TTL has run out according to the stored
+/// timestamp, which happens when something removed the key's expire,
+/// e.g. `PERSIST` or `RESTORE` without a TTL. Such a bucket is full,
+/// so removing it doesn't change any limit.
+///
+/// With `idle` set, buckets that weren't accessed for at least that many
+/// milliseconds are collected too, even if they aren't refilled yet. The idle
+/// time isn't tracked under an LFU `maxmemory-policy`, which is rejected then.
+/// Only states carrying the module's marker are touched, so foreign values
+/// that look alike, and buckets holding a bare number of tokens, are kept.
+///
+/// The keyspace is walked with a cursor, one `SCAN` step per call, so a call
+/// never blocks the server for longer than it takes to check `count` keys.
+pub struct Collector {
+    // Glob-style pattern of the keys to check
+    pattern: String,
+    // Number of keys `SCAN` is asked to return per call
+    count: i64,
+    // Milliseconds without access after which a bucket is collected
+    idle: Option<i64>,
+}
+
+impl Collector {
+    /// Parses option-value pairs, e.g. `MATCH user:* COUNT 100 IDLE 3600000`.
+    pub fn parse(args: &[RedisString]) -> Result<Self, RedisError> {
+        if args.len() % 2 != 0 {
+            return Err(error(error::SYNTAX, "syntax error"));
+        }
+
+        let mut collector = Self {
+            pattern: ANY_KEY.to_string(),
+            count: DEFAULT_COUNT,
+            idle: None,
+        };
+        for pair in args.chunks(2) {
+            let option = pair[0].to_string_lossy().to_ascii_lowercase();
+            match option.as_str() {
+                MATCH_OPTION => collector.pattern = pair[1].to_string_lossy(),
+                COUNT_OPTION => collector.count = parse_positive_integer("count", &pair[1])?,
+                IDLE_OPTION => collector.idle = Some(parse_non_negative_integer("idle", &pair[1])?),
+                _ => return Err(error(error::SYNTAX, "syntax error")),
+            }
+        }
+        Ok(collector)
+    }
+
+    /// Checks the keys of one `SCAN` step from `cursor`. Returns the cursor
+    /// to continue from, `0` once the whole keyspace was walked, and the number
+    /// of removed buckets.
+    pub fn run(&self, ctx: &Context, cursor: &RedisString) -> Result<(String, i64), RedisError> {
+        if self.idle.is_some() && tracks_frequency(ctx)? {
+            return Err(bad_argument(
+                "idle",
+                "requires an LRU maxmemory-policy (or none)",
+            ));
+        }
+        let now = state::now(ctx)?;
+        let count = self.count.to_string();
+        let args = [
+            cursor.to_string_lossy(),
+            "MATCH".to_string(),
+            self.pattern.clone(),
+            "COUNT".to_string(),
+            count,
+            "TYPE".to_string(),
+            "string".to_string(),
+        ];
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        let (next, keys) = match ctx.call("SCAN", args.as_slice())? {
+            RedisValue::Array(mut reply) if reply.len() == 2 => {
+                let keys = reply.pop();
+                (reply.pop(), keys)
+            }
+            _ => return Err(RedisError::Str("ERR unexpected SCAN reply")),
+        };
+
+        let mut collected = 0;
+        if let Some(RedisValue::Array(keys)) = keys {
+            for key in keys {
+                let key = match key {
+                    RedisValue::SimpleString(key) => RedisString::create(None, key.as_str()),
+                    RedisValue::BulkRedisString(key) => key,
+                    RedisValue::StringBuffer(key) => {
+                        RedisString::create_from_slice(std::ptr::null_mut(), &key)
+                    }
+                    _ => continue,
+                };
+                if self.collectable(ctx, &key, now)? {
+                    ctx.call("DEL", &[&key])?;
+                    collected += 1;
+                }
+            }
+        }
+
+        match next {
+            Some(RedisValue::SimpleString(next)) => Ok((next, collected)),
+            _ => Err(RedisError::Str("ERR unexpected SCAN reply")),
+        }
+    }
+
+    fn collectable(&self, ctx: &Context, key: &RedisString, now: i64) -> Result<bool, RedisError> {
+        // Checked before reading the value, which counts as an access
+        let idle = match self.idle {
+            Some(idle) => match ctx.call("OBJECT", &[strings::idletime_subcommand(), key])? {
+                RedisValue::Integer(seconds) => seconds.saturating_mul(MILLS_IN_SEC) >= idle,
+                _ => false,
+            },
+            None => false,
+        };
+
+        let state = match ctx.call("GET", &[key])? {
+            RedisValue::SimpleString(value) => State::decode(&value),
+            _ => None,
+        };
+        Ok(match state {
+            Some(state) => idle || state.ttl(now) == 0,
+            None => false,
+        })
+    }
+}
+
+// Whether the server evicts keys by their access frequency, e.g. `allkeys-lfu`
+fn tracks_frequency(ctx: &Context) -> Result<bool, RedisError> {
+    let policy = match ctx.call("CONFIG", &["GET", MAXMEMORY_POLICY])? {
+        RedisValue::Array(reply) => reply.into_iter().nth(1),
+        _ => None,
+    };
+    Ok(match policy {
+        Some(RedisValue::SimpleString(policy)) => policy.contains(LFU),
+        Some(RedisValue::StringBuffer(policy)) => String::from_utf8_lossy(&policy).contains(LFU),
+        _ => false,
+    })
+}
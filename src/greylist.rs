@@ -0,0 +1,62 @@
+use crate::command_parser::CommandArgs;
+use crate::keys::companion_key;
+use crate::recovery;
+use redis_module::{Context, RedisError, RedisString, RedisValue};
+
+pub const GREYLIST_PART: &[u8] = b"greylist";
+const NEVER_RESPONSE: i64 = -1;
+
+/// Tarpit for over-limit requests: instead of being denied, they are told
+/// to wait before proceeding, and the wait grows with the pressure on the key.
+///
+/// The pressure is the number of requests greylisted in a row, stored under
+/// `<key>:greylist`. The n-th of them waits n times as long as the bucket
+/// takes to refill the requested tokens, like in a queue, up to the maximum.
+/// The counter expires once the latest suggested delay elapses.
+pub struct Greylist {
+    // Key the pressure is stored under
+    key: RedisString,
+    // Longest suggested delay in milliseconds
+    max_delay: i64,
+}
+
+impl Greylist {
+    /// Returns `None` if `command` doesn't greylist over-limit requests.
+    pub fn new(command: &CommandArgs) -> Option<Self> {
+        if command.greylist == 0 {
+            return None;
+        }
+        Some(Self {
+            key: companion_key(command.key, &[GREYLIST_PART]),
+            max_delay: command.greylist,
+        })
+    }
+
+    /// Greylists an over-limit request, which would be admitted in `wait` milliseconds.
+    ///
+    /// Returns the number of milliseconds the request should be delayed by,
+    /// or `-1` if it has to be denied, because it would never be admitted.
+    pub fn delay(&self, ctx: &Context, wait: i64) -> Result<i64, RedisError> {
+        if wait < 0 {
+            return Ok(NEVER_RESPONSE);
+        }
+
+        let mut pressure = 1;
+        if let RedisValue::SimpleString(value) = recovery::call(ctx, "GET", &[&self.key])? {
+            match value.parse::<i64>() {
+                Ok(previous) => pressure = previous.saturating_add(1),
+                Err(_) => recovery::corrupted(ctx, &self.key)?,
+            }
+        }
+        let delay = wait.saturating_mul(pressure).clamp(1, self.max_delay);
+        ctx.call(
+            "PSETEX",
+            &[
+                &self.key,
+                &RedisString::create(None, delay.to_string().as_str()),
+                &RedisString::create(None, pressure.to_string().as_str()),
+            ],
+        )?;
+        Ok(delay)
+    }
+}
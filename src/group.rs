@@ -0,0 +1,54 @@
+use crate::command_parser::CommandArgs;
+use crate::keys::companion_key;
+use crate::math::millis;
+use crate::recovery;
+use redis_module::{Context, RedisError, RedisString};
+
+pub const MEMBERS_PART: &[u8] = b"members";
+
+/// Statistics of a member drawing from a shared bucket, e.g. a tenant
+/// of the free tier sharing 1000 requests per minute with the others.
+///
+/// The number of tokens consumed by every member is stored in the
+/// `<group>:members` hash, which expires one period after the last write.
+pub struct MemberStats<'a> {
+    // Key of the hash the statistics are stored in
+    key: RedisString,
+    // Key of the member within the group
+    member: &'a RedisString,
+    // Time to live of the statistics in milliseconds
+    ttl: i64,
+}
+
+impl<'a> MemberStats<'a> {
+    /// Returns `None` if `command` doesn't draw from a group.
+    pub fn new(command: &CommandArgs<'a>) -> Option<Self> {
+        let member = command.member?;
+        Some(Self {
+            key: companion_key(command.key, &[MEMBERS_PART]),
+            member,
+            ttl: millis(command.limit.period),
+        })
+    }
+
+    /// Adds `tokens` to the number of tokens consumed by the member.
+    pub fn record(&self, ctx: &Context, tokens: i64) -> Result<(), RedisError> {
+        recovery::call(
+            ctx,
+            "HINCRBY",
+            &[
+                &self.key,
+                self.member,
+                &RedisString::create(None, tokens.to_string().as_str()),
+            ],
+        )?;
+        ctx.call(
+            "PEXPIRE",
+            &[
+                &self.key,
+                &RedisString::create(None, self.ttl.to_string().as_str()),
+            ],
+        )?;
+        Ok(())
+    }
+}
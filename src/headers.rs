@@ -0,0 +1,60 @@
+use crate::math::mul_div_ceil;
+use redis_module::RedisValue;
+
+const MILLS_IN_SEC: i64 = 1000;
+const LIMIT_HEADER: &str = "RateLimit-Limit";
+const REMAINING_HEADER: &str = "RateLimit-Remaining";
+const RESET_HEADER: &str = "RateLimit-Reset";
+const RETRY_AFTER_HEADER: &str = "Retry-After";
+const RESET_AT_HEADER: &str = "RateLimit-Reset-At";
+
+/// Rate limit HTTP headers, as defined by the IETF draft
+/// "RateLimit header fields for HTTP", plus `Retry-After` for denied requests
+/// and `RateLimit-Reset-At`, the Unix time in milliseconds `RateLimit-Reset`
+/// points to.
+///
+/// They are replied as a flat array of names and values, so API gateways
+/// can copy them into responses as-is.
+pub struct Headers {
+    // Capacity of the bucket
+    pub limit: i64,
+    // Number of tokens left, `-1` if the request was denied
+    pub remaining: i64,
+    // Milliseconds until the buckets are full again
+    pub reset: i64,
+    // Unix time in milliseconds at which the buckets are full again
+    pub reset_at: i64,
+    // Milliseconds to wait before the request would be admitted,
+    // `-1` if it never would be
+    pub retry_after: i64,
+}
+
+impl From<Headers> for RedisValue {
+    fn from(headers: Headers) -> Self {
+        let mut fields = vec![
+            (LIMIT_HEADER, headers.limit),
+            (REMAINING_HEADER, headers.remaining.max(0)),
+            (RESET_HEADER, seconds(headers.reset)),
+            (RESET_AT_HEADER, headers.reset_at),
+        ];
+        if headers.remaining < 0 && headers.retry_after >= 0 {
+            fields.push((RETRY_AFTER_HEADER, seconds(headers.retry_after)));
+        }
+
+        let values = fields
+            .into_iter()
+            .flat_map(|(name, value)| {
+                [
+                    RedisValue::SimpleStringStatic(name),
+                    RedisValue::BulkString(value.to_string()),
+                ]
+            })
+            .collect();
+        RedisValue::Array(values)
+    }
+}
+
+// Headers carry whole seconds, rounded up so clients never retry too early
+fn seconds(millis: i64) -> i64 {
+    mul_div_ceil(millis.into(), 1, MILLS_IN_SEC.into())
+}
@@ -0,0 +1,166 @@
+use crate::command_parser::CommandArgs;
+use crate::keys::companion_key;
+use crate::recovery;
+use crate::state;
+use crate::strings;
+use redis_module::{Context, RedisError, RedisString, RedisValue};
+
+pub const HISTORY_PART: &[u8] = b"history";
+pub const MEMBERS_PART: &[u8] = b"members";
+const FIELD_SEPARATOR: char = ':';
+
+/// Latest decisions made for a key, e.g. to find out what exactly happened
+/// to a customer at 14:32.
+///
+/// Every decision is encoded as `<timestamp>:<tokens>:<allowed>` and pushed
+/// to the head of the `<key>:history` list, which is trimmed to `length` entries.
+/// The decisions of a group's member are kept under the member's key, and
+/// the members having a history are listed in the `<group>:history:members`
+/// set, so their histories can be removed along with the group's bucket.
+pub struct History {
+    // Key of the list the decisions are stored in
+    key: RedisString,
+    // Key of the set listing the members of the group, if any
+    members_key: Option<RedisString>,
+    // Member the decisions are made for, if any
+    member: Option<RedisString>,
+    // Number of decisions kept
+    length: i64,
+}
+
+/// A single decision read back from the history.
+pub struct Decision {
+    // Unix time in milliseconds when the decision was made
+    pub timestamp: i64,
+    // Number of tokens requested
+    pub tokens: i64,
+    // Whether the request was admitted
+    pub allowed: bool,
+}
+
+impl History {
+    /// Returns `None` if `command` doesn't keep a history.
+    pub fn new(command: &CommandArgs) -> Option<Self> {
+        if command.history == 0 {
+            return None;
+        }
+        Some(Self {
+            key: companion_key(command.member.unwrap_or(command.key), &[HISTORY_PART]),
+            members_key: command
+                .member
+                .map(|_| companion_key(command.key, &[HISTORY_PART, MEMBERS_PART])),
+            member: command.member.cloned(),
+            length: command.history,
+        })
+    }
+
+    /// Appends a decision about a request for `tokens`.
+    pub fn record(&self, ctx: &Context, tokens: i64, allowed: bool) -> Result<(), RedisError> {
+        let entry = format!(
+            "{}{sep}{}{sep}{}",
+            state::now(ctx)?,
+            tokens,
+            i64::from(allowed),
+            sep = FIELD_SEPARATOR
+        );
+        recovery::call(
+            ctx,
+            "LPUSH",
+            &[&self.key, &RedisString::create(None, entry.as_str())],
+        )?;
+        ctx.call(
+            "LTRIM",
+            &[
+                &self.key,
+                strings::zero(),
+                &RedisString::create(None, (self.length - 1).to_string().as_str()),
+            ],
+        )?;
+        if let (Some(members_key), Some(member)) = (&self.members_key, &self.member) {
+            recovery::call(ctx, "SADD", &[members_key, member])?;
+        }
+        Ok(())
+    }
+
+    /// Returns the decisions kept for `key`, the latest first.
+    pub fn read(ctx: &Context, key: &RedisString) -> Result<Vec<Decision>, RedisError> {
+        let key = companion_key(key, &[HISTORY_PART]);
+        let range = [&key, strings::zero(), strings::minus_one()];
+        let RedisValue::Array(entries) = recovery::call(ctx, "LRANGE", &range)? else {
+            return Ok(Vec::new());
+        };
+
+        let mut decisions = Vec::with_capacity(entries.len());
+        for entry in entries {
+            match entry {
+                RedisValue::SimpleString(entry) => match Decision::decode(&entry) {
+                    Some(decision) => decisions.push(decision),
+                    None => {
+                        recovery::corrupted(ctx, &key)?;
+                        return Ok(Vec::new());
+                    }
+                },
+                _ => continue,
+            }
+        }
+        Ok(decisions)
+    }
+
+    /// Returns `true` if there are decisions kept for `key`, or for the members
+    /// of the group under `key`, without telling them from a foreign value.
+    pub fn exists(ctx: &Context, key: &RedisString) -> bool {
+        let keys = [
+            companion_key(key, &[HISTORY_PART]),
+            companion_key(key, &[HISTORY_PART, MEMBERS_PART]),
+        ];
+        let keys: Vec<_> = keys.iter().collect();
+        matches!(ctx.call("EXISTS", keys.as_slice()), Ok(RedisValue::Integer(n)) if n > 0)
+    }
+
+    /// Removes the decisions kept for `key` and for the members of the group
+    /// under `key`, e.g. once its bucket expired.
+    ///
+    /// A list that doesn't hold decisions is left intact.
+    pub fn remove(ctx: &Context, key: &RedisString) -> Result<(), RedisError> {
+        let members_key = companion_key(key, &[HISTORY_PART, MEMBERS_PART]);
+        if let Ok(RedisValue::Array(members)) = ctx.call("SMEMBERS", &[&members_key]) {
+            for member in &members {
+                let member = match member {
+                    RedisValue::SimpleString(member) => member.as_bytes(),
+                    RedisValue::StringBuffer(member) => member.as_slice(),
+                    _ => continue,
+                };
+                remove_list(
+                    ctx,
+                    &RedisString::create_from_slice(std::ptr::null_mut(), member),
+                )?;
+            }
+            ctx.call("DEL", &[&members_key])?;
+        }
+        remove_list(ctx, key)
+    }
+}
+
+// Removes the list of decisions kept for `key`
+fn remove_list(ctx: &Context, key: &RedisString) -> Result<(), RedisError> {
+    let key = companion_key(key, &[HISTORY_PART]);
+    let head = [&key, strings::zero()];
+    if let Ok(RedisValue::SimpleString(entry)) = ctx.call("LINDEX", &head) {
+        if Decision::decode(&entry).is_some() {
+            ctx.call("DEL", &[&key])?;
+        }
+    }
+    Ok(())
+}
+
+impl Decision {
+    fn decode(entry: &str) -> Option<Self> {
+        let mut fields = entry.split(FIELD_SEPARATOR);
+        let decision = Self {
+            timestamp: fields.next()?.parse().ok()?,
+            tokens: fields.next()?.parse().ok()?,
+            allowed: fields.next()? == "1",
+        };
+        fields.next().is_none().then_some(decision)
+    }
+}
@@ -0,0 +1,66 @@
+use crate::command_parser::CommandArgs;
+use crate::keys::companion_key;
+use crate::math::millis;
+use crate::recovery;
+use redis_module::{Context, RedisError, RedisString, RedisValue};
+
+const IDEMPOTENCY_PART: &[u8] = b"idempotency";
+
+/// Result of a request remembered under its idempotency id.
+///
+/// Retried requests carry the same id, so they get the original result
+/// instead of being charged again. The result is kept under
+/// `<key>:idempotency:<id>` for one period of the bucket, where `key` is the
+/// member's key for a group, so members don't share ids. Only admissions are
+/// kept, so a retry of a denied request can be admitted once tokens refill.
+pub struct Idempotency {
+    // Key the result is stored under
+    key: RedisString,
+    // Time to live of the stored result in milliseconds
+    ttl: i64,
+}
+
+impl Idempotency {
+    /// Returns `None` if `command` doesn't carry an idempotency id.
+    pub fn new(command: &CommandArgs) -> Option<Self> {
+        let id = command.idempotency?;
+
+        Some(Self {
+            key: companion_key(
+                command.member.unwrap_or(command.key),
+                &[IDEMPOTENCY_PART, id.as_slice()],
+            ),
+            ttl: millis(command.limit.period),
+        })
+    }
+
+    /// Returns the result of the original request, if it has been seen recently.
+    pub fn recall(&self, ctx: &Context) -> Result<Option<i64>, RedisError> {
+        match recovery::call(ctx, "GET", &[&self.key])? {
+            RedisValue::SimpleString(result) => match result.parse::<i64>() {
+                Ok(result) => Ok(Some(result)),
+                Err(_) => {
+                    recovery::corrupted(ctx, &self.key)?;
+                    Ok(None)
+                }
+            },
+            _ => Ok(None),
+        }
+    }
+
+    /// Keeps the result of the original request, unless it was denied.
+    pub fn remember(&self, ctx: &Context, result: i64) -> Result<(), RedisError> {
+        if result < 0 {
+            return Ok(());
+        }
+        ctx.call(
+            "PSETEX",
+            &[
+                &self.key,
+                &RedisString::create(None, self.ttl.to_string().as_str()),
+                &RedisString::create(None, result.to_string().as_str()),
+            ],
+        )?;
+        Ok(())
+    }
+}
@@ -0,0 +1,175 @@
+use crate::config::{KEY_PREFIX, KEY_SEPARATOR};
+use redis_module::RedisString;
+use std::sync::LazyLock;
+
+const NAMESPACE_PART: &[u8] = b"ns";
+const POLICY_PART: &[u8] = b"policy";
+const RESERVATION_PART: &[u8] = b"reservation";
+const BANS_PART: &[u8] = b"bans";
+const BENCH_PART: &[u8] = b"bench";
+const COST_PART: &[u8] = b"cost";
+// Number of slots of a cluster
+const SLOTS: usize = 16384;
+
+// For every cluster slot, a short hash tag in it, e.g. to keep an index per slot
+static SLOT_TAGS: LazyLock<Vec<String>> = LazyLock::new(|| {
+    let mut tags = vec![String::new(); SLOTS];
+    let mut missing = SLOTS;
+    // Every slot is covered by numbers below ~110000
+    for n in 0u32.. {
+        let tag = n.to_string();
+        let slot = slot(tag.as_bytes());
+        if tags[slot].is_empty() {
+            tags[slot] = tag;
+            missing -= 1;
+            if missing == 0 {
+                break;
+            }
+        }
+    }
+    tags
+});
+
+/// Returns the key of a companion structure of `key`, e.g. `user123:warmup`.
+///
+/// The parts are joined with the configured separator.
+pub fn derived_key(key: &RedisString, parts: &[&[u8]]) -> RedisString {
+    let separator = KEY_SEPARATOR.lock().unwrap();
+    let mut derived = key.as_slice().to_vec();
+    for part in parts {
+        derived.extend_from_slice(separator.as_bytes());
+        derived.extend_from_slice(part);
+    }
+    RedisString::create_from_slice(std::ptr::null_mut(), &derived)
+}
+
+/// Returns the key of a companion structure of `key` stored in the same
+/// cluster slot, e.g. `{user123}:shard:0`.
+///
+/// A key that already has a hash tag keeps it, so appending the parts doesn't
+/// move it. Other keys are wrapped into one, unless they contain braces,
+/// which no tag could reproduce the slot of.
+pub fn companion_key(key: &RedisString, parts: &[&[u8]]) -> RedisString {
+    let bytes = key.as_slice();
+    if has_hash_tag(bytes) || bytes.iter().any(|byte| matches!(byte, b'{' | b'}')) {
+        return derived_key(key, parts);
+    }
+    let tagged = [b"{", bytes, b"}"].concat();
+    derived_key(
+        &RedisString::create_from_slice(std::ptr::null_mut(), &tagged),
+        parts,
+    )
+}
+
+/// Returns the part of `key` its cluster slot is computed from, i.e. its hash
+/// tag or the whole key, e.g. `user123` for both `user123` and `{user123}:shard:0`.
+///
+/// Returns `None` for a key with braces but no tag, which no tag could
+/// reproduce the slot of.
+pub fn slot_tag(key: &[u8]) -> Option<&[u8]> {
+    match hash_tag(key) {
+        Some(tag) => Some(tag),
+        None if key.iter().any(|byte| matches!(byte, b'{' | b'}')) => None,
+        None => Some(key),
+    }
+}
+
+// Whether the slot of `key` is computed from a part of it, i.e. it contains a
+// non-empty `{...}` section
+fn has_hash_tag(key: &[u8]) -> bool {
+    hash_tag(key).is_some()
+}
+
+// The non-empty `{...}` section the slot of `key` is computed from
+fn hash_tag(key: &[u8]) -> Option<&[u8]> {
+    let open = key.iter().position(|byte| *byte == b'{')?;
+    let length = key[open + 1..].iter().position(|byte| *byte == b'}')?;
+    (length > 0).then(|| &key[open + 1..open + 1 + length])
+}
+
+/// Returns the key a namespace is stored under, e.g. `shield:ns:api`.
+pub fn namespace_key(name: &[u8]) -> RedisString {
+    let prefix = RedisString::create(None, KEY_PREFIX.lock().unwrap().as_str());
+    derived_key(&prefix, &[NAMESPACE_PART, name])
+}
+
+/// Returns the key a reservation is stored under, in the slot of the bucket
+/// it was taken from, e.g. `{user123}:reservation:9f3c...` for the
+/// `{user123}9f3c...` id.
+///
+/// An id without a tag, i.e. of a key with braces but no tag, is stored under
+/// the prefix, e.g. `shield:reservation:9f3c...`.
+pub fn reservation_key(id: &[u8]) -> RedisString {
+    let tagged = id
+        .strip_prefix(b"{")
+        .and_then(|rest| Some(rest.split_at(rest.iter().position(|byte| *byte == b'}')?)));
+    match tagged {
+        Some((tag, rest)) if !tag.is_empty() => {
+            let tag = [b"{", tag, b"}"].concat();
+            derived_key(
+                &RedisString::create_from_slice(std::ptr::null_mut(), &tag),
+                &[RESERVATION_PART, &rest[1..]],
+            )
+        }
+        _ => {
+            let prefix = RedisString::create(None, KEY_PREFIX.lock().unwrap().as_str());
+            derived_key(&prefix, &[RESERVATION_PART, id])
+        }
+    }
+}
+
+/// Returns the key a policy is stored under, e.g. `shield:policy:gold`.
+pub fn policy_key(name: &[u8]) -> RedisString {
+    let prefix = RedisString::create(None, KEY_PREFIX.lock().unwrap().as_str());
+    derived_key(&prefix, &[POLICY_PART, name])
+}
+
+/// Returns the key a cost function is stored under, e.g. `shield:cost:payload`.
+pub fn cost_key(name: &[u8]) -> RedisString {
+    let prefix = RedisString::create(None, KEY_PREFIX.lock().unwrap().as_str());
+    derived_key(&prefix, &[COST_PART, name])
+}
+
+/// Returns the key of the sorted set tracking the banned keys of the cluster
+/// slot of `key`, e.g. `shield:bans:{1234}`, so it's stored along with them.
+pub fn bans_key(key: &[u8]) -> RedisString {
+    slot_bans_key(slot(key))
+}
+
+/// Returns the keys of the sorted sets tracking banned keys, one per cluster slot.
+pub fn all_bans_keys() -> impl Iterator<Item = RedisString> {
+    (0..SLOTS).map(slot_bans_key)
+}
+
+fn slot_bans_key(slot: usize) -> RedisString {
+    let prefix = RedisString::create(None, KEY_PREFIX.lock().unwrap().as_str());
+    let tag = format!("{{{}}}", SLOT_TAGS[slot]);
+    derived_key(&prefix, &[BANS_PART, tag.as_bytes()])
+}
+
+// The cluster slot of `key`, computed the way the server does
+fn slot(key: &[u8]) -> usize {
+    usize::from(crc16(hash_tag(key).unwrap_or(key))) % SLOTS
+}
+
+// CRC16-CCITT (XMODEM), the checksum cluster slots are computed with
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for byte in bytes {
+        crc ^= u16::from(*byte) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Returns the key of a synthetic bucket used by `SHIELD.bench`, e.g. `shield:bench:42`.
+pub fn bench_key(index: i64) -> RedisString {
+    let prefix = RedisString::create(None, KEY_PREFIX.lock().unwrap().as_str());
+    derived_key(&prefix, &[BENCH_PART, index.to_string().as_bytes()])
+}
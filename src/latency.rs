@@ -0,0 +1,156 @@
+use crate::config;
+use linkme::distributed_slice;
+use redis_module::raw;
+use redis_module::server_events::INFO_COMMAND_HANDLER_LIST;
+use redis_module::{Context, InfoContext, RedisResult, RedisString};
+use std::ffi::CStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Number of power-of-two buckets, the last one takes samples longer than ~3 days
+const BUCKETS: usize = 40;
+const P50: u64 = 50;
+const P99: u64 = 99;
+const MAX_PERCENT: u64 = 100;
+
+/// Latency event of `SHIELD.absorb` evaluations.
+pub const ABSORB_EVENT: &CStr = c"shield-absorb";
+/// Name of the algorithm logged with slow evaluations.
+pub const TOKEN_BUCKET_ALGORITHM: &str = "token_bucket";
+// Shortest interval between two logged slow evaluations
+const SLOWLOG_INTERVAL: Duration = Duration::from_secs(1);
+
+// Time the last slow evaluation was logged at, and the number of slow
+// evaluations that weren't logged since then, so a latency regression
+// doesn't flood the log.
+static SLOWLOG: Mutex<(Option<Instant>, u64)> = Mutex::new((None, 0));
+
+/// Time spent evaluating token bucket limiters, i.e. reading the buckets
+/// and deciding on the request, not counting the parsing of the arguments.
+pub static TOKEN_BUCKET: Histogram = Histogram::new();
+
+/// Histogram of durations in microseconds with power-of-two buckets,
+/// so percentiles are reported as the upper bound of their bucket.
+///
+/// It's updated with relaxed atomics, so it takes no locks and a concurrent
+/// read may see a sample counted in one field but not yet in another.
+pub struct Histogram {
+    // Number of samples whose microseconds have `i` significant bits
+    buckets: [AtomicU64; BUCKETS],
+    // Longest sample in microseconds
+    max: AtomicU64,
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        Self {
+            buckets: [const { AtomicU64::new(0) }; BUCKETS],
+            max: AtomicU64::new(0),
+        }
+    }
+
+    /// Accounts for a single evaluation taking `elapsed`.
+    pub fn record(&self, elapsed: Duration) {
+        let micros = u64::try_from(elapsed.as_micros()).unwrap_or(u64::MAX);
+        let index = (u64::BITS - micros.leading_zeros()) as usize;
+        self.buckets[index.min(BUCKETS - 1)].fetch_add(1, Ordering::Relaxed);
+        self.max.fetch_max(micros, Ordering::Relaxed);
+    }
+
+    /// Returns the number of recorded samples.
+    pub fn count(&self) -> u64 {
+        self.buckets
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// Returns the longest recorded sample in microseconds.
+    pub fn max(&self) -> u64 {
+        self.max.load(Ordering::Relaxed)
+    }
+
+    /// Returns the microseconds `percent` of the samples didn't exceed,
+    /// or `0` if nothing was recorded.
+    pub fn percentile(&self, percent: u64) -> u64 {
+        let count = self.count();
+        let rank = (count * percent).div_ceil(MAX_PERCENT).max(1);
+        let mut seen = 0;
+        for (index, bucket) in self.buckets.iter().enumerate() {
+            seen += bucket.load(Ordering::Relaxed);
+            if seen >= rank {
+                let upper_bound = (1u64 << index) - 1;
+                return upper_bound.min(self.max());
+            }
+        }
+        self.max()
+    }
+
+    pub fn reset(&self) {
+        for bucket in &self.buckets {
+            bucket.store(0, Ordering::Relaxed);
+        }
+        self.max.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Reports an evaluation taking `elapsed` to the latency monitor of redis as
+/// `event`, if it's slower than `shield.latency-threshold`.
+///
+/// Redis also drops samples below its own `latency-monitor-threshold`.
+pub fn report(event: &CStr, elapsed: Duration) {
+    let threshold = config::latency_threshold();
+    let millis = i64::try_from(elapsed.as_millis()).unwrap_or(i64::MAX);
+    if threshold == 0 || millis < threshold {
+        return;
+    }
+    if let Some(add_sample) = unsafe { raw::RedisModule_LatencyAddSample } {
+        unsafe { add_sample(event.as_ptr(), millis) };
+    }
+}
+
+/// Logs an evaluation for `key` taking `elapsed`, if it's slower than
+/// `shield.slowlog-threshold`.
+///
+/// At most one evaluation is logged per second, followed by the number
+/// of slow ones skipped before it.
+pub fn log_slow(ctx: &Context, key: &RedisString, algorithm: &str, elapsed: Duration) {
+    let threshold = config::slowlog_threshold();
+    let micros = i64::try_from(elapsed.as_micros()).unwrap_or(i64::MAX);
+    if threshold == 0 || micros < threshold {
+        return;
+    }
+
+    let mut slowlog = SLOWLOG.lock().unwrap();
+    let (logged_at, skipped) = &mut *slowlog;
+    if logged_at.is_some_and(|logged_at| logged_at.elapsed() < SLOWLOG_INTERVAL) {
+        *skipped += 1;
+        return;
+    }
+    ctx.log_notice(&format!(
+        "redis-shield: slow evaluation of {} ({}) took {} us, {} skipped since the last one",
+        key, algorithm, micros, skipped
+    ));
+    *logged_at = Some(Instant::now());
+    *skipped = 0;
+}
+
+/// Adds the `shield_latency` section to `INFO`, e.g.
+///
+///     token_bucket_calls:1024
+///     token_bucket_p50_usec:15
+///     token_bucket_p99_usec:63
+///     token_bucket_max_usec:112
+#[distributed_slice(INFO_COMMAND_HANDLER_LIST)]
+fn info(ctx: &InfoContext, _for_crash_report: bool) -> RedisResult<()> {
+    ctx.builder()
+        .add_section("latency")
+        .field("token_bucket_calls", TOKEN_BUCKET.count())?
+        .field("token_bucket_p50_usec", TOKEN_BUCKET.percentile(P50))?
+        .field("token_bucket_p99_usec", TOKEN_BUCKET.percentile(P99))?
+        .field("token_bucket_max_usec", TOKEN_BUCKET.max())?
+        .build_section()?
+        .build_info()?;
+    Ok(())
+}
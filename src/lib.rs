@@ -1,25 +1,171 @@
+mod aggregator;
+mod allocator;
+mod ban;
+mod bench;
 mod bucket;
+mod build_info;
+mod cleanup;
+mod command_parser;
+mod config;
+mod cost;
+mod curve;
+mod debug;
+mod error;
+mod functions;
+mod gc;
+mod greylist;
+mod group;
+mod headers;
+mod history;
+mod idempotency;
+mod keys;
+mod latency;
+mod limiter;
+mod logging;
+mod maintenance;
+mod math;
+mod memory;
+mod metrics;
+mod namespace;
+mod notification;
+mod overrides;
+mod parent;
+mod penalty;
+mod plugin;
+mod policy;
+mod recent;
+mod recovery;
+mod registry;
+mod reservation;
+mod retry_budget;
+mod sampler;
+mod shard;
+mod snapshot;
+mod spacing;
+mod state;
+mod strings;
+#[cfg(all(test, feature = "embedded-redis"))]
+mod test_server;
+mod trace;
+mod transfer;
 
+use ban::Ban;
+use bench::Bench;
 use bucket::Bucket;
-use redis_module::{redis_module, Context, RedisError, RedisResult, RedisString};
+#[cfg(not(feature = "fuzzing"))]
+use command_parser::parse_command_args;
+use command_parser::{
+    check_tokens, key_positions, parse_limit, parse_non_negative_integer, parse_positive_integer,
+    CommandArgs, Denial, Limit, Output,
+};
+use cost::CostFunction;
+use debug::Inspector;
+use gc::Collector;
+use greylist::Greylist;
+use headers::Headers;
+use history::History;
+use idempotency::Idempotency;
+use limiter::Limiter;
+use maintenance::{Mode, MAINTENANCE};
+use namespace::Namespace;
+use overrides::Override;
+use recent::Decision;
+use redis_module::configuration::ConfigurationFlags;
+use redis_module::{redis_module, Context, RedisError, RedisResult, RedisString, RedisValue};
+use registry::register_commands;
+use reservation::Reservation;
+use sampler::Sampler;
+use snapshot::Snapshot;
+use state::State;
+use std::time::{Duration, Instant};
+use transfer::Transfer;
+
+#[cfg(feature = "fuzzing")]
+pub use command_parser::{parse_command_args, Arg};
+#[cfg(feature = "plugins")]
+pub use plugin::{Algorithm, ALGORITHMS};
 
-const MIN_ARGS_LEN: usize = 4;
-const MAX_ARGS_LEN: usize = 5;
-const DEFAULT_TOKENS: i64 = 1;
 const REDIS_COMMAND: &str = "SHIELD.absorb";
+const BATCH_COMMAND: &str = "SHIELD.absorbbatch";
+const EXPORT_COMMAND: &str = "SHIELD.export";
+const IMPORT_COMMAND: &str = "SHIELD.import";
+const SET_COMMAND: &str = "SHIELD.set";
+const TOUCH_COMMAND: &str = "SHIELD.touch";
+const DRAIN_COMMAND: &str = "SHIELD.drain";
+const SIMULATE_COMMAND: &str = "SHIELD.simulate";
+const CHECK_COMMAND: &str = "SHIELD.check";
+const SAMPLE_COMMAND: &str = "SHIELD.sample";
+const NAMESPACE_COMMAND: &str = "SHIELD.ns";
+const COST_COMMAND: &str = "SHIELD.cost";
+const GC_COMMAND: &str = "SHIELD.gc";
+const RENAME_COMMAND: &str = "SHIELD.rename";
+const COPY_COMMAND: &str = "SHIELD.copy";
+const MERGE_COMMAND: &str = "SHIELD.mergekeys";
+const HISTORY_COMMAND: &str = "SHIELD.history";
+const POLICY_COMMAND: &str = "SHIELD.policy";
+const OVERRIDE_COMMAND: &str = "SHIELD.override";
+const RESERVE_COMMAND: &str = "SHIELD.reserve";
+const COMMIT_COMMAND: &str = "SHIELD.commit";
+const CANCEL_COMMAND: &str = "SHIELD.cancel";
+const BAN_COMMAND: &str = "SHIELD.ban";
+const UNBAN_COMMAND: &str = "SHIELD.unban";
+const BANLIST_COMMAND: &str = "SHIELD.banlist";
+const DEBUG_COMMAND: &str = "SHIELD.debug";
+const BENCH_COMMAND: &str = "SHIELD.bench";
+const TOP_COMMAND: &str = "SHIELD.top";
+const RECENT_COMMAND: &str = "SHIELD.recent";
+const STATS_COMMAND: &str = "SHIELD.stats";
+const MAINTENANCE_COMMAND: &str = "SHIELD.maintenance";
+const FUNCTIONS_COMMAND: &str = "SHIELD.functions";
+const VERSION_COMMAND: &str = "SHIELD.version";
+const HELP_COMMAND: &str = "SHIELD.help";
+const CONTAINER_COMMAND: &str = "SHIELD";
+// Milliseconds a reservation is held for unless given explicitly
+const DEFAULT_RESERVATION_TTL: i64 = 30000;
+const REPLACE_FLAG: &str = "REPLACE";
+const KEYS_DONE_FLAG: &str = "KEYS-DONE";
+const MATCH_FLAG: &str = "MATCH";
+const COUNT_FLAG: &str = "COUNT";
+const TTL_FLAG: &str = "TTL";
+const EXPORT_FLAG: &str = "EXPORT";
+const RESET_FLAG: &str = "RESET";
+const ALL_FLAG: &str = "ALL";
+const POLICY_FLAG: &str = "POLICY";
+// Returned instead of the number of tokens left when `NX` is given for an unknown key
+const UNKNOWN_KEY_RESPONSE: i64 = -2;
+// Returned for banned keys, like for any denied request
+const BANNED_RESPONSE: i64 = -1;
+// Returned for unknown keys while redis is short on memory, unless they fail open
+const SHED_RESPONSE: i64 = -1;
+// Returned to every request in maintenance mode, whatever its bucket holds
+const ALLOW_ALL_RESPONSE: i64 = 0;
+const DENY_ALL_RESPONSE: i64 = -1;
+// Returned by `ALGORITHM sample`, which doesn't count tokens
+const SAMPLED_RESPONSE: i64 = 0;
+const UNSAMPLED_RESPONSE: i64 = -1;
+// Delay suggested by the greylist to requests that are denied anyway
+const DENIED_DELAY: i64 = -1;
+// Number of keys returned by `SHIELD.top` by default
+const DEFAULT_TOP_COUNT: i64 = 10;
+// Number of decisions returned by `SHIELD.recent` by default
+const DEFAULT_RECENT_COUNT: i64 = 10;
 
-#[cfg(not(test))]
-macro_rules! get_allocator {
-    () => {
-        redis_module::alloc::RedisAlloc
-    };
+/// Reports the keys of `SHIELD.absorb` and alike to Redis, which can't tell
+/// the position of a `GROUP` from the command's key specification.
+fn report_keys(ctx: &Context, args: &[RedisString]) -> RedisResult {
+    for position in key_positions(args) {
+        ctx.key_at_pos(position as i32);
+    }
+    Ok(RedisValue::NoReply)
 }
 
-#[cfg(test)]
-macro_rules! get_allocator {
-    () => {
-        std::alloc::System
-    };
+/// Completes the arguments of `SHIELD.absorb` and alike with the options and
+/// limit of the request's policy, the limit of the key's namespace, or the limit
+/// its bucket was stored with, in this order.
+fn expand(ctx: &Context, args: Vec<RedisString>) -> Result<Vec<RedisString>, RedisError> {
+    let args = policy::expand(ctx, args)?;
+    let args = namespace::expand(ctx, args)?;
+    state::expand(ctx, args)
 }
 
 /// Entry point to `SHIELD.absorb` redis command.
@@ -33,272 +179,6177 @@ macro_rules! get_allocator {
 ///           |           └─────────────── args[1] key: user123
 ///           └─────────────────────────── args[0] command name (provided by redis)
 ///
+///   followed by options described in `parse_command_args`.
+///   The capacity and period are omitted for keys of a defined namespace,
+///   e.g. `SHIELD.absorb api/user123 1`, for existing buckets, which use
+///   the limit they were stored with, and for requests naming a `POLICY`.
+///
 /// * Parses and validates them
-/// * Instantiates a bucket
-/// * Attempts to remove requested number of tokens from the bucket
-/// * Returns the result of `pour` function.
+/// * Instantiates a bucket for every limit
+/// * Attempts to remove requested number of tokens from the buckets,
+///   unless the request is a retry, which gets the original result
+/// * Returns the result of `pour` function, unless:
+///     * with `SOFT` it's followed by `1` if the usage crossed the soft limit,
+///       `0` otherwise
+///     * with `OUTPUT headers` the rate limit HTTP headers are returned instead
+///     * with `NX` an unknown key gets `-2` without creating its bucket
+///     * a banned key is denied before any bucket is read
+///     * with `GREYLIST` the reply ends with the delay suggested to an over-limit
+///       request, `0` if it's admitted and `-1` if it's denied
+///     * in maintenance mode the request gets `0` or `-1` without reading any key,
+///       see `maintenance_command`
+///     * while redis uses more than `shield.memory-threshold` percent of its
+///       `maxmemory`, an unknown key is denied without creating its bucket,
+///       or admitted with `shield.memory-fail-open`
+///     * with `ALGORITHM` the request is decided by a plugin, see `plugin::absorb`
+///     * with `ONDENY error` or `shield.deny-error`, a denied request gets
+///       a `THROTTLED` error instead of `-1`.
 fn redis_command(ctx: &Context, args: Vec<RedisString>) -> RedisResult {
-    if !(MIN_ARGS_LEN..=MAX_ARGS_LEN).contains(&args.len()) {
+    if ctx.is_keys_position_request() {
+        return report_keys(ctx, &args);
+    }
+    absorb(ctx, args).inspect_err(|err| {
+        // A denial replied as an error was counted as such
+        if !error::is_throttled(err) {
+            metrics::TOKEN_BUCKET.fail()
+        }
+    })
+}
+
+fn absorb(ctx: &Context, args: Vec<RedisString>) -> RedisResult {
+    trace::span!("absorb");
+    if let Some(admitted) = static_decision() {
+        // The arguments are only parsed for the shape of the reply
+        let output = parse_command_args(&args).map_or(Output::Tokens, |command| command.output);
+        let remaining_tokens = if admitted {
+            ALLOW_ALL_RESPONSE
+        } else {
+            DENY_ALL_RESPONSE
+        };
+        return Ok(tokens_reply(output, remaining_tokens, None, None));
+    }
+    let args = expand(ctx, args)?;
+    let mut command = parse_command_args(&args)?;
+    cost::apply(ctx, &mut command)?;
+    let sampled = command.algorithm.is_some_and(Sampler::is_named);
+    if command.nx && !sampled && !bucket_exists(ctx, &command)? {
+        return Ok(UNKNOWN_KEY_RESPONSE.into());
+    }
+    if let Some(ban_ttl) = Ban::ttl(ctx, command.member.unwrap_or(command.key))? {
+        metrics::TOKEN_BUCKET.decide(false);
+        aggregator::record(command.member.unwrap_or(command.key), false);
+        if let (Output::Tokens | Output::Simple, Some(throttled)) =
+            (command.output, throttled(&command, ban_ttl))
+        {
+            return Err(throttled);
+        }
+        return Ok(match (command.output, command.soft) {
+            (Output::Headers, _) => Headers {
+                limit: command.limit.capacity,
+                remaining: BANNED_RESPONSE,
+                reset: ban_ttl,
+                reset_at: state::now(ctx)?.saturating_add(ban_ttl),
+                retry_after: ban_ttl,
+            }
+            .into(),
+            (output, soft) => tokens_reply(
+                output,
+                BANNED_RESPONSE,
+                soft.map(|_| false),
+                Greylist::new(&command).map(|_| DENIED_DELAY),
+            ),
+        });
+    }
+    if sampled {
+        return sample(ctx, &command);
+    }
+    if memory::under_pressure() && !bucket_exists(ctx, &command)? {
+        return shed(ctx, &command);
+    }
+    if let Some(algorithm) = command.algorithm {
+        return plugin::absorb(ctx, &command, algorithm);
+    }
+    let started = Instant::now();
+    let bucket_keys = Limiter::bucket_keys(&command);
+    let mut limiter = Limiter::new(ctx, &command, &bucket_keys)?;
+    metrics::TOKEN_BUCKET.look_up(limiter.is_stored());
+    let remaining_tokens = match Idempotency::new(&command) {
+        Some(idempotency) => match idempotency.recall(ctx)? {
+            Some(remaining_tokens) => remaining_tokens,
+            None => {
+                let remaining_tokens = limiter.pour(command.tokens)?;
+                idempotency.remember(ctx, remaining_tokens)?;
+                remaining_tokens
+            }
+        },
+        None => limiter.pour(command.tokens)?,
+    };
+    let elapsed = started.elapsed();
+    metrics::TOKEN_BUCKET.decide(remaining_tokens >= 0);
+    aggregator::record(command.member.unwrap_or(command.key), remaining_tokens >= 0);
+    recent::record(Decision::new(
+        command.member.unwrap_or(command.key),
+        latency::TOKEN_BUCKET_ALGORITHM,
+        remaining_tokens >= 0,
+        command.tokens,
+        elapsed,
+    ));
+    latency::TOKEN_BUCKET.record(elapsed);
+    latency::report(latency::ABSORB_EVENT, elapsed);
+    latency::log_slow(ctx, command.key, latency::TOKEN_BUCKET_ALGORITHM, elapsed);
+
+    match command.output {
+        Output::Headers => Ok(Headers {
+            limit: command.limit.capacity,
+            remaining: remaining_tokens,
+            reset: limiter.refill_after(),
+            reset_at: limiter.reset_at(),
+            retry_after: limiter.retry_after(command.tokens),
+        }
+        .into()),
+        output => {
+            if remaining_tokens < 0 {
+                if let Some(throttled) = throttled(&command, limiter.retry_after(command.tokens)) {
+                    return Err(throttled);
+                }
+            }
+            let delay = match Greylist::new(&command) {
+                Some(greylist) if remaining_tokens < 0 => {
+                    Some(greylist.delay(ctx, limiter.retry_after(command.tokens))?)
+                }
+                Some(_) => Some(0),
+                None => None,
+            };
+            let warning = command.soft.map(|soft| limiter.exceeds(soft));
+            Ok(tokens_reply(output, remaining_tokens, warning, delay))
+        }
+    }
+}
+
+/// Samples the request of `SHIELD.absorb` with `ALGORITHM sample`: the capacity
+/// is the percentage of admitted requests, the period the length of a window
+/// and `IDEMPOTENCY <id>` identifies the request. Nothing is stored, so the
+/// reply is `0` if the request is admitted and `-1` otherwise.
+fn sample(ctx: &Context, command: &CommandArgs) -> RedisResult {
+    let limit = command.limit;
+    if limit.capacity > 100 {
+        return Err(error::bad_argument(
+            "capacity",
+            "must not exceed 100 with ALGORITHM sample",
+        ));
+    }
+    let started = Instant::now();
+    let sampler = Sampler::new(command.key, limit.capacity, limit.period);
+    let admitted = sampler.admits(command.idempotency, state::now(ctx)?);
+    recent::record(Decision::new(
+        command.member.unwrap_or(command.key),
+        sampler::ALGORITHM,
+        admitted,
+        command.tokens,
+        started.elapsed(),
+    ));
+    Ok(tokens_reply(
+        command.output,
+        if admitted {
+            SAMPLED_RESPONSE
+        } else {
+            UNSAMPLED_RESPONSE
+        },
+        None,
+        None,
+    ))
+}
+
+/// Returns the canned decision of the maintenance mode, counted like any other,
+/// or `None` if requests are evaluated.
+fn static_decision() -> Option<bool> {
+    let admitted = match MAINTENANCE.mode() {
+        Mode::Off => return None,
+        Mode::AllowAll => true,
+        Mode::DenyAll => false,
+    };
+    metrics::TOKEN_BUCKET.decide(admitted);
+    Some(admitted)
+}
+
+/// Returns whether the bucket of `command` is stored, in its own key or in the
+/// field of a hash.
+fn bucket_exists(ctx: &Context, command: &CommandArgs) -> Result<bool, RedisError> {
+    if let Some(algorithm) = command.algorithm {
+        let key = plugin::state_key(command, algorithm)?;
+        return Ok(ctx.call("EXISTS", &[&key])? != RedisValue::Integer(0));
+    }
+    let exists = match command.field {
+        Some(field) => ctx.call("HEXISTS", &[command.key, field])?,
+        None => ctx.call("EXISTS", &[command.key])?,
+    };
+    Ok(exists != RedisValue::Integer(0))
+}
+
+/// Returns the reply to a request for an unknown key while redis is short on
+/// memory, without creating its bucket. It's denied, or admitted as if the
+/// bucket was full with `shield.memory-fail-open`.
+fn shed(ctx: &Context, command: &CommandArgs) -> RedisResult {
+    let admitted = memory::shed();
+    metrics::TOKEN_BUCKET.decide(admitted);
+    let remaining_tokens = if admitted {
+        (command.limit.capacity - command.tokens).max(0)
+    } else {
+        SHED_RESPONSE
+    };
+    match command.output {
+        Output::Headers => Ok(Headers {
+            limit: command.limit.capacity,
+            remaining: remaining_tokens,
+            reset: 0,
+            reset_at: state::now(ctx)?,
+            retry_after: -1,
+        }
+        .into()),
+        output => match throttled(command, -1) {
+            Some(throttled) if !admitted => Err(throttled),
+            _ => Ok(tokens_reply(
+                output,
+                remaining_tokens,
+                command.soft.map(|_| false),
+                Greylist::new(command).map(|_| if admitted { 0 } else { DENIED_DELAY }),
+            )),
+        },
+    }
+}
+
+/// Returns the `THROTTLED <key> <retry_after_ms>` error replied instead of `-1`
+/// to a denied request with `ONDENY error` or `shield.deny-error`, or `None` if
+/// it gets `-1`. A `retry_after` of `-1` means the request would never be admitted.
+///
+/// Requests with `GREYLIST` keep their reply, which suggests the delay instead.
+fn throttled(command: &CommandArgs, retry_after: i64) -> Option<RedisError> {
+    let denial = command.denial.unwrap_or(if config::deny_error() {
+        Denial::Error
+    } else {
+        Denial::Sentinel
+    });
+    if denial == Denial::Sentinel || command.greylist > 0 {
+        return None;
+    }
+    Some(error::error(
+        error::THROTTLED,
+        format!("{} {}", command.member.unwrap_or(command.key), retry_after),
+    ))
+}
+
+/// Returns the reply of `SHIELD.absorb` without `OUTPUT headers`: the number
+/// of tokens left, or with `OUTPUT simple` whether the request was allowed,
+/// followed by the warning of the soft limit and the delay suggested by the
+/// greylist, if they are requested.
+fn tokens_reply(
+    output: Output,
+    remaining_tokens: i64,
+    warning: Option<bool>,
+    delay: Option<i64>,
+) -> RedisValue {
+    let decision = match output {
+        Output::Simple => i64::from(remaining_tokens >= 0),
+        _ => remaining_tokens,
+    };
+    if warning.is_none() && delay.is_none() {
+        return decision.into();
+    }
+    let mut reply = vec![decision];
+    reply.extend(warning.map(i64::from));
+    reply.extend(delay);
+    reply.into()
+}
+
+/// Entry point to `SHIELD.absorbbatch` redis command.
+///
+/// * Accepts arguments in the following format:
+///       SHIELD.absorbbatch user123 30 60 10 2
+///           ▲                ▲     ▲  ▲  ▲  ▲
+///           |                |     |  |  |  └─── args[5] tokens_each: 2 tokens per request (1 if omitted)
+///           |                |     |  |  └────── args[4] count: 10 requests
+///           |                |     |  └───────── args[3] period: 60 seconds
+///           |                |     └──────────── args[2] capacity: 30 tokens
+///           |                └────────────────── args[1] key: user123
+///           └─────────────────────────────────── args[0] command name (provided by redis)
+///
+/// * Parses and validates them
+/// * Absorbs `count` requests one after another, e.g. a batch of messages,
+///   stopping at the first one that doesn't conform. The batch counts as a
+///   single decision, allowed if all of its requests are admitted
+/// * Returns an array of the number of admitted requests and the number
+///   of tokens left in the bucket, `-1` if the key is banned.
+fn batch_command(ctx: &Context, args: Vec<RedisString>) -> RedisResult {
+    if args.len() != 5 && args.len() != 6 {
         return Err(RedisError::WrongArity);
     }
 
-    let capacity = parse_positive_integer("capacity", &args[2])?;
-    let period = parse_positive_integer("period", &args[3])?;
-    let tokens = match args.len() {
-        MAX_ARGS_LEN => parse_positive_integer("tokens", &args[4])?,
-        _ => DEFAULT_TOKENS,
+    let limit = parse_limit(&args[2], &args[3])?;
+    let count = parse_non_negative_integer("count", &args[4])?;
+    let tokens_each = match args.get(5) {
+        Some(tokens_each) => parse_positive_integer("tokens_each", tokens_each)?,
+        None => 1,
+    };
+    check_tokens(tokens_each)?;
+    if let Some(admitted) = static_decision() {
+        return Ok(vec![if admitted { count } else { 0 }, 0].into());
+    }
+    if Ban::ttl(ctx, &args[1])?.is_some() {
+        metrics::TOKEN_BUCKET.decide(false);
+        aggregator::record(&args[1], false);
+        return Ok(vec![0, BANNED_RESPONSE].into());
+    }
+    let started = Instant::now();
+    let mut bucket = Bucket::new(ctx, &args[1], limit.capacity, limit.period)?;
+    metrics::TOKEN_BUCKET.look_up(bucket.is_stored());
+    let (admitted, remaining_tokens) = bucket.pour_batch(count, tokens_each)?;
+    let elapsed = started.elapsed();
+    let allowed = admitted == count;
+    metrics::TOKEN_BUCKET.decide(allowed);
+    aggregator::record(&args[1], allowed);
+    recent::record(Decision::new(
+        &args[1],
+        latency::TOKEN_BUCKET_ALGORITHM,
+        allowed,
+        count.saturating_mul(tokens_each),
+        elapsed,
+    ));
+    latency::TOKEN_BUCKET.record(elapsed);
+    latency::report(latency::ABSORB_EVENT, elapsed);
+    latency::log_slow(ctx, &args[1], latency::TOKEN_BUCKET_ALGORITHM, elapsed);
+
+    Ok(vec![admitted, remaining_tokens].into())
+}
+
+/// Entry point to `SHIELD.reserve` redis command.
+///
+/// * Accepts arguments in the following format:
+///       SHIELD.reserve user123 30 60 5 10000
+///           ▲            ▲     ▲  ▲  ▲   ▲
+///           |            |     |  |  |   └─── args[5] ttl: hold for 10000 milliseconds (30000 if omitted)
+///           |            |     |  |  └─────── args[4] tokens: reserve 5 tokens
+///           |            |     |  └────────── args[3] period: 60 seconds
+///           |            |     └───────────── args[2] capacity: 30 tokens
+///           |            └─────────────────── args[1] key: user123
+///           └──────────────────────────────── args[0] command name (provided by redis)
+///
+/// * Parses and validates them
+/// * Removes the tokens from the bucket and holds them until the reservation
+///   is committed, canceled or expires
+/// * Returns the id of the reservation, which starts with the hash tag of the
+///   bucket, e.g. `{user123}9f3c61a2b0d4e857`, or nil if the bucket doesn't
+///   contain sufficient tokens.
+fn reserve_command(ctx: &Context, args: Vec<RedisString>) -> RedisResult {
+    if args.len() != 5 && args.len() != 6 {
+        return Err(RedisError::WrongArity);
+    }
+
+    let limit = parse_limit(&args[2], &args[3])?;
+    let tokens = parse_positive_integer("tokens", &args[4])?;
+    let ttl = match args.get(5) {
+        Some(ttl) => parse_positive_integer("ttl", ttl)?,
+        None => DEFAULT_RESERVATION_TTL,
     };
-    let mut bucket = Bucket::new(ctx, &args[1], capacity, period)?;
-    let remaining_tokens = bucket.pour(tokens)?;
+    check_tokens(tokens)?;
+
+    match Reservation::reserve(ctx, &args[1], limit.capacity, limit.period, tokens, ttl)? {
+        Some(id) => Ok(RedisValue::StringBuffer(id)),
+        None => Ok(RedisValue::Null),
+    }
+}
+
+/// Entry point to `SHIELD.commit` redis command.
+///
+/// * Accepts arguments in the following format:
+///       SHIELD.commit {user123}9f3c61a2b0d4e857
+///           ▲             ▲
+///           |             └─────── args[1] id: returned by `SHIELD.reserve`
+///           └───────────────────── args[0] command name (provided by redis)
+///
+/// * Keeps the reserved tokens consumed
+/// * Returns `1` if the reservation existed, `0` otherwise.
+fn commit_command(ctx: &Context, args: Vec<RedisString>) -> RedisResult {
+    if args.len() != 2 {
+        return Err(RedisError::WrongArity);
+    }
+
+    let committed = Reservation::take(ctx, &args[1])?.is_some();
+    Ok(i64::from(committed).into())
+}
+
+/// Entry point to `SHIELD.cancel` redis command.
+///
+/// * Accepts arguments in the following format:
+///       SHIELD.cancel {user123}9f3c61a2b0d4e857
+///           ▲             ▲
+///           |             └─────── args[1] id: returned by `SHIELD.reserve`
+///           └───────────────────── args[0] command name (provided by redis)
+///
+/// * Returns the reserved tokens to the bucket
+/// * Returns the number of tokens left in the bucket, or `-1` if the reservation
+///   doesn't exist, e.g. because it was committed or expired.
+fn cancel_command(ctx: &Context, args: Vec<RedisString>) -> RedisResult {
+    if args.len() != 2 {
+        return Err(RedisError::WrongArity);
+    }
+
+    match Reservation::take(ctx, &args[1])? {
+        Some(reservation) => Ok(reservation.cancel(ctx)?.into()),
+        None => Ok(RedisValue::Integer(-1)),
+    }
+}
+
+/// Entry point to `SHIELD.set` redis command.
+///
+/// * Accepts arguments in the following format:
+///       SHIELD.set user123 30 60 10
+///           ▲        ▲     ▲  ▲  ▲
+///           |        |     |  |  └─── args[4] remaining: leave 10 tokens in the bucket
+///           |        |     |  └────── args[3] period: 60 seconds
+///           |        |     └───────── args[2] capacity: 30 tokens
+///           |        └─────────────── args[1] key: user123
+///           └──────────────────────── args[0] command name (provided by redis)
+///
+/// * Parses and validates them
+/// * Overrides the number of tokens left in the bucket, e.g. to grant a goodwill
+///   credit or to lock a key out with `0`
+/// * Returns the number of tokens left in the bucket.
+fn set_command(ctx: &Context, args: Vec<RedisString>) -> RedisResult {
+    if args.len() != 5 {
+        return Err(RedisError::WrongArity);
+    }
+
+    let limit = parse_limit(&args[2], &args[3])?;
+    let remaining = parse_non_negative_integer("remaining", &args[4])?;
+    let mut bucket = Bucket::new(ctx, &args[1], limit.capacity, limit.period)?;
+    let remaining_tokens = bucket.set(remaining)?;
 
     Ok(remaining_tokens.into())
 }
 
-fn parse_positive_integer(name: &str, value: &RedisString) -> Result<i64, RedisError> {
-    match value.parse_integer() {
-        Ok(arg) if arg > 0 => Ok(arg),
-        _ => Err(RedisError::String(format!(
-            "ERR {} is not positive integer",
-            name
-        ))),
+/// Entry point to `SHIELD.touch` redis command.
+///
+/// * Accepts arguments in the following format:
+///       SHIELD.touch user123 90000
+///           ▲          ▲       ▲
+///           |          |       └─── args[2] ttl: expire the bucket in 90000 milliseconds
+///           |          └─────────── args[1] key: user123
+///           └────────────────────── args[0] command name (provided by redis)
+///
+/// * Moves the time the bucket is full again, stored in its state, to `ttl`
+///   milliseconds from now without consuming tokens, and expires the key along
+///   with it. The refill is derived from that time, so a later one prolongs
+///   a cooldown (no tokens are refilled while it's more than a period away),
+///   and `0` removes the bucket right away, which leaves it full. A `PERSISTENT`
+///   bucket keeps its key without an expire, and only the refill moves
+/// * Returns `1` if the bucket exists, `0` otherwise.
+fn touch_command(ctx: &Context, args: Vec<RedisString>) -> RedisResult {
+    if args.len() != 3 {
+        return Err(RedisError::WrongArity);
+    }
+
+    let ttl = parse_non_negative_integer("ttl", &args[2])?;
+    let now = state::now(ctx)?;
+    match State::load(ctx, &args[1], now)? {
+        Some(mut state) => {
+            state.expires_at = now + ttl.min(math::MAX_MILLIS);
+            if state::has_expire(ctx, &args[1])? {
+                state.save(ctx, &args[1], now)?;
+            } else {
+                state.save_persistent(ctx, &args[1])?;
+            }
+            Ok(RedisValue::Integer(1))
+        }
+        None => Ok(RedisValue::Integer(0)),
     }
 }
 
-redis_module! {
-    name: "SHIELD",
-    version: 1,
-    allocator: (get_allocator!(), get_allocator!()),
-    data_types: [],
-    commands: [
-        [REDIS_COMMAND, redis_command, "", 0, 0, 0],
-    ],
+/// Entry point to `SHIELD.drain` redis command.
+///
+/// * Accepts arguments in the following format:
+///       SHIELD.drain user123 30 60
+///           ▲          ▲     ▲  ▲
+///           |          |     |  └─── args[3] period: 60 seconds
+///           |          |     └────── args[2] capacity: 30 tokens
+///           |          └──────────── args[1] key: user123
+///           └─────────────────────── args[0] command name (provided by redis)
+///
+/// * Parses and validates them
+/// * Removes all tokens left in the bucket
+/// * Returns the number of removed tokens.
+fn drain_command(ctx: &Context, args: Vec<RedisString>) -> RedisResult {
+    if args.len() != 4 {
+        return Err(RedisError::WrongArity);
+    }
+
+    let limit = parse_limit(&args[2], &args[3])?;
+    let mut bucket = Bucket::new(ctx, &args[1], limit.capacity, limit.period)?;
+    let drained_tokens = bucket.drain()?;
+
+    Ok(drained_tokens.into())
 }
 
-//////////////////////////////////////////////////////////////////////
+/// Entry point to `SHIELD.simulate` redis command.
+///
+/// * Accepts the same arguments as `SHIELD.absorb`:
+///       SHIELD.simulate user123 30 60 1
+///           ▲             ▲      ▲  ▲ ▲
+///           |             |      |  | └─── args[4] tokens: 1 token (default if omitted)
+///           |             |      |  └───── args[3] period: 60 seconds
+///           |             |      └──────── args[2] capacity: 30 tokens
+///           |             └─────────────── args[1] key: user123
+///           └───────────────────────────── args[0] command name (provided by redis)
+///
+/// * Parses and validates them
+/// * Checks the request against the current state of the buckets without changing them,
+///   failing with `SHIELD_CORRUPT` on a foreign value even in lenient mode
+/// * Returns an array of:
+///     * `1` if the request would be allowed, `0` otherwise
+///     * the number of tokens that would be left in the most restrictive bucket
+///     * milliseconds to wait before the request would be allowed
+///       (`-1` if it never would, because `tokens` exceeds a capacity)
+///     * Unix time in milliseconds at which the buckets are full again.
+fn simulate_command(ctx: &Context, args: Vec<RedisString>) -> RedisResult {
+    if ctx.is_keys_position_request() {
+        return report_keys(ctx, &args);
+    }
+    recovery::read_only(|| {
+        let args = expand(ctx, args)?;
+        let mut command = parse_command_args(&args)?;
+        cost::apply(ctx, &mut command)?;
+        if command.algorithm.is_some() {
+            return Err(error::error(error::BAD_ALGO, "unsupported algorithm"));
+        }
+        let bucket_keys = Limiter::bucket_keys(&command);
+        let limiter = Limiter::new(ctx, &command, &bucket_keys)?;
+        let retry_after = limiter.retry_after(command.tokens);
+        let (allowed, remaining_tokens) = match retry_after {
+            0 => (1, (limiter.tokens() - command.tokens).max(0)),
+            _ => (0, limiter.tokens()),
+        };
 
-#[cfg(test)]
-mod tests {
-    extern crate redis;
-    use redis::Commands;
-    use std::env;
-    use std::{thread, time};
+        Ok(vec![allowed, remaining_tokens, retry_after, limiter.reset_at()].into())
+    })
+}
 
-    fn establish_connection() -> redis::Connection {
-        let redis_url = env::var("REDIS_URL").unwrap();
-        let client = redis::Client::open(redis_url).unwrap();
-        client.get_connection().unwrap()
+/// Entry point to `SHIELD.check` redis command.
+///
+/// * Accepts arguments in the following format:
+///       SHIELD.check user1 user2 KEYS-DONE 30 60
+///           ▲          ▲     ▲       ▲     ▲  ▲
+///           |          |     |       |     |  └─── last arg period: 60 seconds
+///           |          |     |       |     └────── capacity: 30 tokens
+///           |          |     |       └──────────── end of the keys
+///           |          └─────┴──────────────────── args[1..] keys: user1, user2
+///           └───────────────────────────────────── args[0] command name (provided by redis)
+///
+/// * Parses and validates them
+/// * Returns an array of the number of tokens left in each bucket, in the order
+///   of the keys, without changing them. A foreign value fails the request even
+///   in lenient mode.
+fn check_command(ctx: &Context, args: Vec<RedisString>) -> RedisResult {
+    if args.len() < 5 {
+        return Err(RedisError::WrongArity);
     }
 
-    #[test]
-    #[should_panic(
-        expected = "An error was signalled by the server - ResponseError: wrong number of arguments for 'SHIELD.absorb' command"
-    )]
-    fn test_wrong_arity() {
-        let mut con = establish_connection();
+    let end = args.len() - 3;
+    if !args[end]
+        .to_string_lossy()
+        .eq_ignore_ascii_case(KEYS_DONE_FLAG)
+    {
+        return Err(error::error(error::SYNTAX, "syntax error"));
+    }
 
-        let _: () = redis::cmd(super::REDIS_COMMAND).query(&mut con).unwrap();
+    let limit = parse_limit(&args[end + 1], &args[end + 2])?;
+    let remaining_tokens = recovery::read_only(|| {
+        args[1..end]
+            .iter()
+            .map(|key| {
+                Ok(Bucket::new(ctx, key, limit.capacity, limit.period)?
+                    .tokens
+                    .max(0))
+            })
+            .collect::<Result<Vec<i64>, RedisError>>()
+    })?;
+
+    Ok(remaining_tokens.into())
+}
+
+/// Entry point to `SHIELD.sample` redis command.
+///
+/// * Accepts arguments in the following format:
+///       SHIELD.sample user123 10 60 req-42
+///           ▲           ▲     ▲  ▲    ▲
+///           |           |     |  |    └─── args[4] id: request id (optional)
+///           |           |     |  └──────── args[3] period: 60 seconds
+///           |           |     └─────────── args[2] percent: admit 10% of requests
+///           |           └───────────────── args[1] key: user123
+///           └───────────────────────────── args[0] command name (provided by redis)
+///
+/// * Parses and validates them
+/// * Returns `1` if the request is admitted in the current window, `0` otherwise.
+fn sample_command(ctx: &Context, args: Vec<RedisString>) -> RedisResult {
+    if args.len() != 4 && args.len() != 5 {
+        return Err(RedisError::WrongArity);
     }
 
-    #[test]
-    #[should_panic(
-        expected = "An error was signalled by the server - ResponseError: capacity is not positive integer"
-    )]
-    fn test_capacity_is_string() {
-        let mut con = establish_connection();
-        let bucket_key = "redis-shield::test_key_new";
+    let percent = parse_non_negative_integer("percent", &args[2])?;
+    if percent > 100 {
+        return Err(error::bad_argument("percent", "must not exceed 100"));
+    }
+    let period = parse_positive_integer("period", &args[3])?;
+    let sampler = Sampler::new(&args[1], percent, period);
 
-        let _: () = redis::cmd(super::REDIS_COMMAND)
-            .arg(bucket_key)
-            .arg("abc")
-            .arg(60)
-            .query(&mut con)
-            .unwrap();
+    Ok(i64::from(sampler.admits(args.get(4), state::now(ctx)?)).into())
+}
+
+/// Entry point to `SHIELD.ns` redis command.
+///
+/// * Accepts arguments in the following format:
+///       SHIELD.ns SET api capacity 100 period 60
+///           ▲      ▲   ▲         ▲
+///           |      |   |         └─── args[3..] fields: required by `SET` only
+///           |      |   └───────────── args[2] name: api
+///           |      └───────────────── args[1] subcommand: SET, GET or DEL
+///           └──────────────────────── args[0] command name (provided by redis)
+///
+/// * `SET` defines a namespace and returns OK
+/// * `GET` returns the namespace's fields, or nil if it isn't defined
+/// * `DEL` removes a namespace and returns `1` if it was defined, `0` otherwise.
+fn namespace_command(ctx: &Context, args: Vec<RedisString>) -> RedisResult {
+    if args.len() < 3 {
+        return Err(RedisError::WrongArity);
     }
 
-    #[test]
-    #[should_panic(
-        expected = "An error was signalled by the server - ResponseError: capacity is not positive integer"
-    )]
-    fn test_capacity_is_float() {
-        let mut con = establish_connection();
-        let bucket_key = "redis-shield::test_key_new";
+    let name = args[2].as_slice();
+    let subcommand = args[1].to_string_lossy().to_ascii_uppercase();
+    match (subcommand.as_str(), args.len()) {
+        ("SET", _) => {
+            Namespace::parse(&args[3..])?.save(ctx, name)?;
+            Ok(RedisValue::SimpleStringStatic("OK"))
+        }
+        ("GET", 3) => match Namespace::load(ctx, name)? {
+            Some(namespace) => Ok(RedisValue::Array(vec![
+                RedisValue::SimpleStringStatic("capacity"),
+                namespace.capacity.into(),
+                RedisValue::SimpleStringStatic("period"),
+                namespace.period.into(),
+            ])),
+            None => Ok(RedisValue::Null),
+        },
+        ("DEL", 3) => Ok(i64::from(Namespace::delete(ctx, name)?).into()),
+        ("GET" | "DEL", _) => Err(RedisError::WrongArity),
+        _ => Err(error::error(error::SYNTAX, "syntax error")),
+    }
+}
 
-        let _: () = redis::cmd(super::REDIS_COMMAND)
-            .arg(bucket_key)
-            .arg(1.2)
-            .arg(60)
-            .query(&mut con)
-            .unwrap();
+/// Entry point to `SHIELD.cost` redis command.
+///
+/// * Accepts arguments in the following format:
+///       SHIELD.cost SET payload function payload_cost
+///           ▲        ▲     ▲        ▲
+///           |        |     |        └─── args[3..] field: `function` or `script` with its reference, required by `SET` only
+///           |        |     └──────────── args[2] name: payload
+///           |        └────────────────── args[1] subcommand: SET, GET or DEL
+///           └─────────────────────────── args[0] command name (provided by redis)
+///
+/// * `SET` defines a cost function and returns OK
+/// * `GET` returns the cost function's field, or nil if it isn't defined
+/// * `DEL` removes a cost function and returns `1` if it was defined, `0` otherwise.
+fn cost_command(ctx: &Context, args: Vec<RedisString>) -> RedisResult {
+    if args.len() < 3 {
+        return Err(RedisError::WrongArity);
     }
 
-    #[test]
-    #[should_panic(
-        expected = "An error was signalled by the server - ResponseError: capacity is not positive integer"
-    )]
-    fn test_capacity_is_zero() {
-        let mut con = establish_connection();
-        let bucket_key = "redis-shield::test_key_new";
+    let name = args[2].as_slice();
+    let subcommand = args[1].to_string_lossy().to_ascii_uppercase();
+    match (subcommand.as_str(), args.len()) {
+        ("SET", _) => {
+            CostFunction::parse(&args[3..])?.save(ctx, name)?;
+            Ok(RedisValue::SimpleStringStatic("OK"))
+        }
+        ("GET", 3) => match CostFunction::load(ctx, name)? {
+            Some(function) => {
+                let (field, reference) = function.field();
+                Ok(RedisValue::Array(vec![
+                    RedisValue::SimpleStringStatic(field),
+                    RedisValue::SimpleString(reference.to_string()),
+                ]))
+            }
+            None => Ok(RedisValue::Null),
+        },
+        ("DEL", 3) => Ok(i64::from(CostFunction::delete(ctx, name)?).into()),
+        ("GET" | "DEL", _) => Err(RedisError::WrongArity),
+        _ => Err(error::error(error::SYNTAX, "syntax error")),
+    }
+}
 
-        let _: () = redis::cmd(super::REDIS_COMMAND)
-            .arg(bucket_key)
-            .arg(0)
-            .arg(60)
+/// Entry point to `SHIELD.policy` redis command.
+///
+/// * Accepts arguments in the following format:
+///       SHIELD.policy SET gold capacity 100 period 60
+///           ▲          ▲   ▲      ▲
+///           |          |   |      └─── args[3..] fields: required by `SET` only
+///           |          |   └────────── args[2] name: gold
+///           |          └────────────── args[1] subcommand: SET
+///           └───────────────────────── args[0] command name (provided by redis)
+///
+/// * `SET` validates the fields and replaces the policy with them, returning `OK`
+/// * `INFO` returns the number of keys currently applying the policy
+///   and the numbers of requests allowed and denied under it.
+fn policy_command(ctx: &Context, args: Vec<RedisString>) -> RedisResult {
+    if args.len() < 3 {
+        return Err(RedisError::WrongArity);
+    }
+
+    let subcommand = args[1].to_string_lossy().to_ascii_uppercase();
+    match (subcommand.as_str(), args.len()) {
+        ("SET", _) => {
+            policy::Policy::parse(&args[3..])?.save(ctx, args[2].as_slice())?;
+            Ok(RedisValue::SimpleStringStatic("OK"))
+        }
+        ("INFO", 3) => {
+            let (keys, allowed, denied) = policy::Usage::info(ctx, args[2].as_slice())?;
+            Ok(RedisValue::Array(vec![
+                RedisValue::SimpleStringStatic("keys"),
+                keys.into(),
+                RedisValue::SimpleStringStatic("allowed"),
+                allowed.into(),
+                RedisValue::SimpleStringStatic("denied"),
+                denied.into(),
+            ]))
+        }
+        ("INFO", _) => Err(RedisError::WrongArity),
+        _ => Err(error::error(error::SYNTAX, "syntax error")),
+    }
+}
+
+/// Entry point to `SHIELD.override` redis command.
+///
+/// * Accepts arguments in the following format:
+///       SHIELD.override SET user123 capacity 500
+///           ▲            ▲     ▲        ▲
+///           |            |     |        └─── args[3..] fields: required by `SET` only
+///           |            |     └──────────── args[2] key: user123
+///           |            └────────────────── args[1] subcommand: SET, GET or DEL
+///           └─────────────────────────────── args[0] command name (provided by redis)
+///
+/// * `SET` overrides the given fields of the policies applied to the key
+///   and returns OK
+/// * `GET` returns the overridden fields, or nil if the key has no override
+/// * `DEL` removes the override and returns `1` if the key had one, `0` otherwise.
+fn override_command(ctx: &Context, args: Vec<RedisString>) -> RedisResult {
+    if args.len() < 3 {
+        return Err(RedisError::WrongArity);
+    }
+
+    let key = &args[2];
+    let subcommand = args[1].to_string_lossy().to_ascii_uppercase();
+    match (subcommand.as_str(), args.len()) {
+        ("SET", _) => {
+            Override::parse(&args[3..])?.save(ctx, key)?;
+            Ok(RedisValue::SimpleStringStatic("OK"))
+        }
+        ("GET", 3) => match Override::load(ctx, key)? {
+            Some(fields) => {
+                let mut reply = Vec::new();
+                if let Some(capacity) = fields.capacity {
+                    reply.push(RedisValue::SimpleStringStatic("capacity"));
+                    reply.push(capacity.into());
+                }
+                if let Some(period) = fields.period {
+                    reply.push(RedisValue::SimpleStringStatic("period"));
+                    reply.push(period.into());
+                }
+                if let Some(options) = fields.options {
+                    reply.push(RedisValue::SimpleStringStatic("options"));
+                    reply.push(RedisValue::BulkString(options));
+                }
+                Ok(RedisValue::Array(reply))
+            }
+            None => Ok(RedisValue::Null),
+        },
+        ("DEL", 3) => Ok(i64::from(Override::delete(ctx, key)?).into()),
+        ("GET" | "DEL", _) => Err(RedisError::WrongArity),
+        _ => Err(error::error(error::SYNTAX, "syntax error")),
+    }
+}
+
+/// Entry point to `SHIELD.ban` redis command.
+///
+/// * Accepts arguments in the following format:
+///       SHIELD.ban user123 3600000
+///           ▲        ▲        ▲
+///           |        |        └─── args[2] ttl: ban for 3600000 milliseconds
+///           |        └──────────── args[1] key: user123
+///           └───────────────────── args[0] command name (provided by redis)
+///
+/// * Denies all requests of the key until the ban expires, replacing its existing ban.
+fn ban_command(ctx: &Context, args: Vec<RedisString>) -> RedisResult {
+    if args.len() != 3 {
+        return Err(RedisError::WrongArity);
+    }
+
+    let ttl = parse_positive_integer("ttl", &args[2])?;
+    Ban::add(ctx, &args[1], ttl.min(math::MAX_MILLIS))?;
+    Ok(RedisValue::SimpleStringStatic("OK"))
+}
+
+/// Entry point to `SHIELD.unban` redis command.
+///
+/// * Accepts arguments in the following format:
+///       SHIELD.unban user123
+///           ▲          ▲
+///           |          └─── args[1] key: user123
+///           └────────────── args[0] command name (provided by redis)
+///
+/// * Lifts the ban of the key
+/// * Returns `1` if the key was banned, `0` otherwise.
+fn unban_command(ctx: &Context, args: Vec<RedisString>) -> RedisResult {
+    if args.len() != 2 {
+        return Err(RedisError::WrongArity);
+    }
+
+    Ok(i64::from(Ban::remove(ctx, &args[1])?).into())
+}
+
+/// Entry point to `SHIELD.banlist` redis command.
+///
+/// * Accepts arguments in the following format:
+///       SHIELD.banlist MATCH user:*
+///           ▲            ▲      ▲
+///           |            |      └─── args[2] pattern: glob-style (optional)
+///           |            └────────── args[1] MATCH (optional)
+///           └─────────────────────── args[0] command name (provided by redis)
+///
+/// * Returns the banned keys matching the pattern. Each is an array of the key
+///   and the number of milliseconds until its ban expires.
+fn banlist_command(ctx: &Context, args: Vec<RedisString>) -> RedisResult {
+    let pattern = match args.len() {
+        1 => None,
+        3 if args[1].to_string_lossy().eq_ignore_ascii_case(MATCH_FLAG) => Some(&args[2]),
+        3 => return Err(error::error(error::SYNTAX, "syntax error")),
+        _ => return Err(RedisError::WrongArity),
+    };
+
+    let banned = Ban::list(ctx, pattern)?
+        .into_iter()
+        .map(|(key, ttl)| RedisValue::Array(vec![RedisValue::BulkRedisString(key), ttl.into()]))
+        .collect();
+    Ok(RedisValue::Array(banned))
+}
+
+/// Entry point to `SHIELD.gc` redis command.
+///
+/// * Accepts arguments in the following format:
+///       SHIELD.gc 0 MATCH user:* COUNT 100 IDLE 3600000
+///           ▲     ▲       ▲           ▲          ▲
+///           |     |       |           |          └─── idle: also collect buckets unused for an hour (optional)
+///           |     |       |           └────────────── count: keys to check per call, 1000 by default (optional)
+///           |     |       └────────────────────────── pattern: keys to check, all by default (optional)
+///           |     └────────────────────────────────── args[1] cursor: 0 to start a walk of the keyspace
+///           └──────────────────────────────────────── args[0] command name (provided by redis)
+///
+/// * Checks one `SCAN` step of keys for buckets whose TTL has run out according
+///   to their state, and buckets idle for longer than `idle` milliseconds
+/// * Removes them and returns an array of the cursor to call again with,
+///   `0` once the walk is over, and the number of removed buckets.
+fn gc_command(ctx: &Context, args: Vec<RedisString>) -> RedisResult {
+    if args.len() < 2 {
+        return Err(RedisError::WrongArity);
+    }
+    let collector = Collector::parse(&args[2..])?;
+    let (cursor, collected) = collector.run(ctx, &args[1])?;
+    Ok(RedisValue::Array(vec![
+        RedisValue::BulkString(cursor),
+        collected.into(),
+    ]))
+}
+
+/// Entry point to `SHIELD.rename` redis command.
+///
+/// * Accepts arguments in the following format:
+///       SHIELD.rename user@example.com user123 TIER 3600
+///           ▲             ▲                ▲        ▲
+///           |             |                |        └─── args[3..] options: tiers and shards to move (optional)
+///           |             |                └──────────── args[2] new key: user123
+///           |             └───────────────────────────── args[1] old key: user@example.com
+///           └─────────────────────────────────────────── args[0] command name (provided by redis)
+///
+/// * Moves the bucket and its companion keys, preserving their TTLs
+/// * Returns `1` if the bucket was moved, `0` if the old key doesn't exist.
+fn rename_command(ctx: &Context, args: Vec<RedisString>) -> RedisResult {
+    if args.len() < 3 {
+        return Err(RedisError::WrongArity);
+    }
+
+    let transfer = Transfer::parse(&args[3..])?;
+    Ok(i64::from(transfer.rename(ctx, &args[1], &args[2])?).into())
+}
+
+/// Entry point to `SHIELD.copy` redis command.
+///
+/// * Accepts arguments in the following format:
+///       SHIELD.copy user123 shadow:user123 TIER 3600
+///           ▲         ▲          ▲             ▲
+///           |         |          |             └─── args[3..] options: tiers and shards to copy (optional)
+///           |         |          └───────────────── args[2] destination key: shadow:user123
+///           |         └──────────────────────────── args[1] source key: user123
+///           └────────────────────────────────────── args[0] command name (provided by redis)
+///
+/// * Copies the bucket and its companion keys, preserving their TTLs
+/// * Returns `1` if the bucket was copied, `0` if the source key doesn't exist.
+fn copy_command(ctx: &Context, args: Vec<RedisString>) -> RedisResult {
+    if args.len() < 3 {
+        return Err(RedisError::WrongArity);
+    }
+
+    let transfer = Transfer::parse(&args[3..])?;
+    Ok(i64::from(transfer.copy(ctx, &args[1], &args[2])?).into())
+}
+
+/// Entry point to `SHIELD.mergekeys` redis command.
+///
+/// * Accepts arguments in the following format:
+///       SHIELD.mergekeys user1 user2 account42 30 60
+///           ▲              ▲     ▲     ▲       ▲  ▲
+///           |              |     |     |       |  └─── args[5] period: 60 seconds
+///           |              |     |     |       └────── args[4] capacity: 30 tokens
+///           |              |     |     └────────────── args[3] destination key: account42
+///           |              |     └──────────────────── args[2] second source key: user2
+///           |              └────────────────────────── args[1] first source key: user1
+///           └───────────────────────────────────────── args[0] command name (provided by redis)
+///
+/// * Parses and validates them
+/// * Sums the tokens used in both source buckets, capped at the capacity,
+///   and leaves the rest in the destination bucket, e.g. after an account merge
+/// * Returns the number of tokens left in the destination bucket.
+fn merge_command(ctx: &Context, args: Vec<RedisString>) -> RedisResult {
+    if args.len() != 6 {
+        return Err(RedisError::WrongArity);
+    }
+
+    let Limit { capacity, period } = parse_limit(&args[4], &args[5])?;
+    let sources = [
+        Bucket::new(ctx, &args[1], capacity, period)?,
+        Bucket::new(ctx, &args[2], capacity, period)?,
+    ];
+    let used = sources
+        .iter()
+        .map(|bucket| capacity.saturating_sub(bucket.tokens))
+        .fold(0, i64::saturating_add);
+    let mut destination = Bucket::new(ctx, &args[3], capacity, period)?;
+    let remaining_tokens = destination.set(capacity.saturating_sub(used))?;
+
+    Ok(remaining_tokens.into())
+}
+
+/// Entry point to `SHIELD.history` redis command.
+///
+/// * Accepts arguments in the following format:
+///       SHIELD.history user123
+///           ▲            ▲
+///           |            └─────── args[1] key: user123
+///           └──────────────────── args[0] command name (provided by redis)
+///
+/// * Returns the decisions kept by the `HISTORY` option, the latest first.
+///   Each is an array of the Unix time in milliseconds, the number of requested
+///   tokens and `1` if the request was admitted, `0` otherwise.
+fn history_command(ctx: &Context, args: Vec<RedisString>) -> RedisResult {
+    if args.len() != 2 {
+        return Err(RedisError::WrongArity);
+    }
+
+    let decisions = History::read(ctx, &args[1])?
+        .into_iter()
+        .map(|decision| {
+            vec![
+                decision.timestamp,
+                decision.tokens,
+                i64::from(decision.allowed),
+            ]
+            .into()
+        })
+        .collect();
+    Ok(RedisValue::Array(decisions))
+}
+
+/// Entry point to `SHIELD.export` redis command.
+///
+/// * Accepts arguments in the following format:
+///       SHIELD.export user123
+///           ▲           ▲
+///           |           └─────── args[1] key: user123
+///           └─────────────────── args[0] command name (provided by redis)
+///
+/// * Returns the bucket's state as a JSON document, or nil if the bucket doesn't exist.
+fn export_command(ctx: &Context, args: Vec<RedisString>) -> RedisResult {
+    if args.len() != 2 {
+        return Err(RedisError::WrongArity);
+    }
+
+    match Snapshot::capture(ctx, &args[1])? {
+        Some(snapshot) => Ok(RedisValue::BulkString(snapshot.to_json()?)),
+        None => Ok(RedisValue::Null),
+    }
+}
+
+/// Entry point to `SHIELD.import` redis command.
+///
+/// * Accepts arguments in the following format:
+///       SHIELD.import user123 '{"version":1,...}' REPLACE
+///           ▲           ▲            ▲             ▲
+///           |           |            |             └─── args[3] overwrite an existing key (optional)
+///           |           |            └───────────────── args[2] state produced by `SHIELD.export`
+///           |           └────────────────────────────── args[1] key: user123
+///           └────────────────────────────────────────── args[0] command name (provided by redis)
+///
+/// * Refuses to overwrite an existing key unless `REPLACE` is given
+/// * Writes the state, accounting for the time elapsed since the export.
+fn import_command(ctx: &Context, args: Vec<RedisString>) -> RedisResult {
+    if !(3..=4).contains(&args.len()) {
+        return Err(RedisError::WrongArity);
+    }
+
+    let replace = match args.get(3) {
+        Some(flag) if flag.to_string().eq_ignore_ascii_case(REPLACE_FLAG) => true,
+        Some(_) => return Err(error::error(error::SYNTAX, "syntax error")),
+        None => false,
+    };
+    let snapshot = Snapshot::from_json(args[2].try_as_str()?)?;
+
+    if !replace && ctx.call("EXISTS", &[&args[1]])? == RedisValue::Integer(1) {
+        return Err(RedisError::Str("BUSYKEY Target key name already exists."));
+    }
+    snapshot.restore(ctx, &args[1])?;
+
+    Ok(RedisValue::SimpleStringStatic("OK"))
+}
+
+/// Entry point to `SHIELD.debug` redis command.
+///
+/// * Accepts arguments in the following format:
+///       SHIELD.debug STATE user123 ALGORITHM token_bucket
+///           ▲          ▲      ▲       ▲
+///           |          |      |       └─── args[3..] options: algorithm of the bucket (optional)
+///           |          |      └─────────── args[2] key: user123
+///           |          └────────────────── args[1] subcommand: STATE or OBJECT
+///           └───────────────────────────── args[0] command name (provided by redis)
+///
+/// * `STATE` returns the decoded fields of the bucket, or nil if it doesn't exist
+/// * `OBJECT` returns the format version, memory usage, algorithm, age and idle time
+///   of the stored value, or nil if it doesn't exist.
+fn debug_command(ctx: &Context, args: Vec<RedisString>) -> RedisResult {
+    if args.len() < 3 {
+        return Err(RedisError::WrongArity);
+    }
+
+    let inspector = Inspector::parse(&args[3..])?;
+    let subcommand = args[1].to_string_lossy().to_ascii_uppercase();
+    match subcommand.as_str() {
+        "STATE" => match inspector.state(ctx, &args[2])? {
+            Some(fields) => Ok(RedisValue::Array(fields)),
+            None => Ok(RedisValue::Null),
+        },
+        "OBJECT" => match inspector.object(ctx, &args[2])? {
+            Some(fields) => Ok(RedisValue::Array(fields)),
+            None => Ok(RedisValue::Null),
+        },
+        _ => Err(error::error(error::SYNTAX, "syntax error")),
+    }
+}
+
+/// Entry point to `SHIELD.bench` redis command.
+///
+/// * Accepts arguments in the following format:
+///       SHIELD.bench token_bucket 100000
+///           ▲            ▲          ▲
+///           |            |          └─── args[2] iterations: 100000 requests
+///           |            └────────────── args[1] algorithm: token_bucket
+///           └─────────────────────────── args[0] command name (provided by redis)
+///
+/// * Evaluates the requests against synthetic buckets and returns the number
+///   of iterations, the time they took, the resulting throughput and the number
+///   of allocations made.
+fn bench_command(ctx: &Context, args: Vec<RedisString>) -> RedisResult {
+    if args.len() != 3 {
+        return Err(RedisError::WrongArity);
+    }
+
+    let report = Bench::parse(&args[1], &args[2])?.run(ctx)?;
+    let elapsed = report.elapsed.as_micros().max(1);
+    Ok(RedisValue::Array(vec![
+        RedisValue::SimpleStringStatic("iterations"),
+        report.iterations.into(),
+        RedisValue::SimpleStringStatic("elapsed_usec"),
+        i64::try_from(elapsed).unwrap_or(i64::MAX).into(),
+        RedisValue::SimpleStringStatic("ops_per_sec"),
+        i64::try_from(report.iterations as u128 * 1_000_000 / elapsed)
+            .unwrap_or(i64::MAX)
+            .into(),
+        RedisValue::SimpleStringStatic("allocations"),
+        i64::try_from(report.allocations).unwrap_or(i64::MAX).into(),
+    ]))
+}
+
+/// Entry point to `SHIELD.top` redis command.
+///
+/// * Accepts arguments in the following format:
+///       SHIELD.top COUNT 10
+///           ▲        ▲
+///           |        └─── args[1..] options: number of keys, 10 by default (optional)
+///           └──────────── args[0] command name (provided by redis)
+///
+/// * Returns the keys with the most denials tracked by the background aggregator,
+///   each as an array of the key and its numbers of denied and allowed requests.
+fn top_command(_ctx: &Context, args: Vec<RedisString>) -> RedisResult {
+    let count = match args.len() {
+        1 => DEFAULT_TOP_COUNT,
+        3 if args[1].to_string_lossy().eq_ignore_ascii_case(COUNT_FLAG) => {
+            parse_positive_integer("count", &args[2])?
+        }
+        3 => return Err(error::error(error::SYNTAX, "syntax error")),
+        _ => return Err(RedisError::WrongArity),
+    };
+
+    let keys = aggregator::top(usize::try_from(count).unwrap_or(usize::MAX))
+        .into_iter()
+        .map(|(key, count)| {
+            RedisValue::Array(vec![
+                RedisValue::StringBuffer(key),
+                i64::try_from(count.denied).unwrap_or(i64::MAX).into(),
+                i64::try_from(count.allowed).unwrap_or(i64::MAX).into(),
+            ])
+        })
+        .collect();
+    Ok(RedisValue::Array(keys))
+}
+
+/// Entry point to `SHIELD.recent` redis command.
+///
+/// * Accepts arguments in the following format:
+///       SHIELD.recent 20
+///           ▲         ▲
+///           |         └─── args[1] count: number of decisions, 10 by default (optional)
+///           └───────────── args[0] command name (provided by redis)
+///
+/// * Returns the last decisions kept with `shield.recent-decisions`, the most
+///   recent first, each as an array of the key, the algorithm, `1` if the
+///   request was allowed or `0` if it was denied, the tokens it asked for
+///   and the microseconds the limiter took.
+fn recent_command(_ctx: &Context, args: Vec<RedisString>) -> RedisResult {
+    let count = match args.len() {
+        1 => DEFAULT_RECENT_COUNT,
+        2 => parse_positive_integer("count", &args[1])?,
+        _ => return Err(RedisError::WrongArity),
+    };
+
+    let decisions = recent::last(usize::try_from(count).unwrap_or(usize::MAX))
+        .into_iter()
+        .map(|decision| {
+            RedisValue::Array(vec![
+                RedisValue::StringBuffer(decision.key),
+                RedisValue::SimpleStringStatic(decision.algorithm),
+                i64::from(decision.allowed).into(),
+                decision.tokens.into(),
+                i64::try_from(decision.latency.as_micros())
+                    .unwrap_or(i64::MAX)
+                    .into(),
+            ])
+        })
+        .collect();
+    Ok(RedisValue::Array(decisions))
+}
+
+/// Entry point to `SHIELD.stats` redis command.
+///
+/// * Accepts arguments in the following format:
+///       SHIELD.stats RESET user123
+///           ▲        ▲     ▲
+///           |        |     └─── args[2..] target: ALL (default), a key or POLICY <name>
+///           |        └───────── args[1] subcommand: RESET
+///           └────────────────── args[0] command name (provided by redis)
+///
+/// * `RESET` or `RESET ALL` zeroes every counter of the module and forgets
+///   the keys tracked for `SHIELD.top`, returning `OK`
+/// * `RESET <key>` forgets the counts of a single tracked key, and
+///   `RESET POLICY <name>` zeroes the numbers of allowed and denied requests
+///   of a policy, returning `1` if anything was counted, `0` otherwise.
+fn stats_command(_ctx: &Context, args: Vec<RedisString>) -> RedisResult {
+    if args.len() < 2 || args.len() > 4 {
+        return Err(RedisError::WrongArity);
+    }
+    if !args[1].to_string_lossy().eq_ignore_ascii_case(RESET_FLAG) {
+        return Err(error::error(error::SYNTAX, "syntax error"));
+    }
+
+    match args.len() {
+        3 if !args[2].to_string_lossy().eq_ignore_ascii_case(ALL_FLAG) => {
+            Ok(i64::from(aggregator::forget(args[2].as_slice())).into())
+        }
+        4 if args[2].to_string_lossy().eq_ignore_ascii_case(POLICY_FLAG) => {
+            Ok(i64::from(policy::Usage::reset(args[3].as_slice())).into())
+        }
+        4 => Err(error::error(error::SYNTAX, "syntax error")),
+        _ => {
+            metrics::reset();
+            Ok(RedisValue::SimpleStringStatic("OK"))
+        }
+    }
+}
+
+/// Entry point to `SHIELD.maintenance` redis command.
+///
+/// * Accepts arguments in the following format:
+///       SHIELD.maintenance deny TTL 300
+///           ▲               ▲    ▲
+///           |               |    └─── args[2..] options: seconds until the mode ends (optional)
+///           |               └──────── args[1] mode: allow, deny or off (optional)
+///           └──────────────────────── args[0] command name (provided by redis)
+///
+/// * Switches the module to the mode, like `CONFIG SET shield.maintenance`
+///   does for good
+/// * Without a mode, returns an array of the current mode and the seconds
+///   until it ends, `-1` if it doesn't.
+fn maintenance_command(_ctx: &Context, args: Vec<RedisString>) -> RedisResult {
+    let ttl = match args.len() {
+        1 => {
+            let (mode, left) = MAINTENANCE.status();
+            let seconds = left.map_or(-1, |left| left.as_secs_f64().ceil() as i64);
+            return Ok(RedisValue::Array(vec![
+                RedisValue::SimpleStringStatic(mode.name()),
+                seconds.into(),
+            ]));
+        }
+        2 => None,
+        4 if args[2].to_string_lossy().eq_ignore_ascii_case(TTL_FLAG) => {
+            Some(parse_positive_integer("ttl", &args[3])?)
+        }
+        4 => return Err(error::error(error::SYNTAX, "syntax error")),
+        _ => return Err(RedisError::WrongArity),
+    };
+
+    let mode = Mode::parse(&args[1].to_string_lossy())?;
+    MAINTENANCE.enter(mode, ttl.map(|ttl| Duration::from_secs(ttl as u64)));
+    Ok(RedisValue::SimpleStringStatic("OK"))
+}
+
+/// Entry point to `SHIELD.functions` redis command.
+///
+/// * Accepts arguments in the following format:
+///       SHIELD.functions EXPORT
+///           ▲              ▲
+///           |              └─── args[1] subcommand: EXPORT
+///           └────────────────── args[0] command name (provided by redis)
+///
+/// * Returns the source of the Redis Functions library implementing the token
+///   bucket, ready for `FUNCTION LOAD`.
+fn functions_command(_ctx: &Context, args: Vec<RedisString>) -> RedisResult {
+    if args.len() != 2 {
+        return Err(RedisError::WrongArity);
+    }
+    if !args[1].to_string_lossy().eq_ignore_ascii_case(EXPORT_FLAG) {
+        return Err(error::error(error::SYNTAX, "syntax error"));
+    }
+
+    Ok(RedisValue::BulkString(functions::LIBRARY.to_string()))
+}
+
+/// Entry point to `SHIELD.version` redis command.
+///
+/// * Accepts no arguments:
+///       SHIELD.version
+///
+/// * Returns the semantic version of the module, the commit and the cargo
+///   profile it was built from and the enabled cargo features.
+fn version_command(_ctx: &Context, args: Vec<RedisString>) -> RedisResult {
+    if args.len() != 1 {
+        return Err(RedisError::WrongArity);
+    }
+
+    Ok(RedisValue::Array(vec![
+        RedisValue::SimpleStringStatic("version"),
+        RedisValue::SimpleStringStatic(build_info::VERSION),
+        RedisValue::SimpleStringStatic("commit"),
+        RedisValue::SimpleStringStatic(build_info::COMMIT),
+        RedisValue::SimpleStringStatic("profile"),
+        RedisValue::SimpleStringStatic(build_info::PROFILE),
+        RedisValue::SimpleStringStatic("features"),
+        RedisValue::Array(
+            build_info::features()
+                .into_iter()
+                .map(RedisValue::SimpleStringStatic)
+                .collect(),
+        ),
+    ]))
+}
+
+/// Entry point to `SHIELD.help` redis command.
+///
+/// * Accepts arguments in the following format:
+///       SHIELD.help absorb
+///           ▲        ▲
+///           |        └─── args[1] command: with or without the `SHIELD.` prefix (optional)
+///           └──────────── args[0] command name (provided by redis)
+///
+/// * Without a command returns the name and summary of every command
+/// * With a command returns its usage, summary, arguments and examples.
+fn help_command(_ctx: &Context, args: Vec<RedisString>) -> RedisResult {
+    match args.len() {
+        1 => Ok(RedisValue::Array(
+            registry::COMMANDS
+                .iter()
+                .map(|command| {
+                    RedisValue::Array(vec![
+                        RedisValue::SimpleStringStatic(command.name),
+                        RedisValue::SimpleStringStatic(command.summary),
+                    ])
+                })
+                .collect(),
+        )),
+        2 => {
+            let name = args[1].to_string_lossy();
+            let command = registry::find(&name).ok_or_else(|| {
+                error::error(
+                    error::UNKNOWN_COMMAND,
+                    format!("unknown command '{}'", name),
+                )
+            })?;
+            Ok(RedisValue::Array(vec![
+                RedisValue::SimpleStringStatic("usage"),
+                RedisValue::SimpleStringStatic(command.usage),
+                RedisValue::SimpleStringStatic("summary"),
+                RedisValue::SimpleStringStatic(command.summary),
+                RedisValue::SimpleStringStatic("arguments"),
+                RedisValue::Array(
+                    command
+                        .arguments
+                        .iter()
+                        .map(|argument| {
+                            RedisValue::Array(vec![
+                                RedisValue::SimpleStringStatic(argument.name),
+                                RedisValue::SimpleStringStatic(argument.description),
+                            ])
+                        })
+                        .collect(),
+                ),
+                RedisValue::SimpleStringStatic("examples"),
+                RedisValue::Array(
+                    command
+                        .examples
+                        .iter()
+                        .map(|example| RedisValue::SimpleStringStatic(example))
+                        .collect(),
+                ),
+            ]))
+        }
+        _ => Err(RedisError::WrongArity),
+    }
+}
+
+/// Entry point to the subcommands of `SHIELD` redis command.
+///
+/// * Accepts arguments in the following format:
+///       SHIELD ABSORB user123 30 60
+///         ▲      ▲       ▲
+///         |      |       └─── args[2..] arguments of the subcommand
+///         |      └─────────── args[1] subcommand: a `SHIELD.*` command without the prefix
+///         └────────────────── args[0] command name (provided by redis)
+///
+/// * Looks the subcommand up in the registry and checks its arity, which
+///   Redis doesn't know for module commands. Its flags are checked by Redis,
+///   see `registry::register_commands`
+/// * Returns the reply of the subcommand, exactly as its `SHIELD.*` command does.
+fn container_command(ctx: &Context, mut args: Vec<RedisString>) -> RedisResult {
+    if args.len() < 2 {
+        return Err(RedisError::WrongArity);
+    }
+
+    let name = args[1].to_string_lossy();
+    let command = registry::find_subcommand(&name).ok_or_else(|| {
+        error::error(
+            error::UNKNOWN_COMMAND,
+            format!("unknown subcommand '{}'. Try SHIELD HELP.", name),
+        )
+    })?;
+    // The subcommand takes the place of the command name
+    args.remove(0);
+
+    if ctx.is_keys_position_request() {
+        for position in command.key_positions(&args) {
+            ctx.key_at_pos(position as i32 + 1);
+        }
+        return Ok(RedisValue::NoReply);
+    }
+    if !command.accepts(&args) {
+        return Err(RedisError::WrongArity);
+    }
+    (command.handler)(ctx, args)
+}
+
+redis_module! {
+    name: "SHIELD",
+    version: 1,
+    allocator: (allocator::Counting, allocator::Counting),
+    data_types: [],
+    // Created from `registry::COMMANDS`, along with the `SHIELD` container command
+    init: register_commands,
+    commands: [],
+    event_handlers: [
+        [@EXPIRED: cleanup::on_expired],
+        [@GENERIC @HASH @STRING @EXPIRED @EVICTED: policy::on_changed],
+    ],
+    configurations: [
+        i64: [
+            ["max-capacity", &config::MAX_CAPACITY, 0, 0, i64::MAX, ConfigurationFlags::DEFAULT, None],
+            ["max-tokens-per-call", &config::MAX_TOKENS_PER_CALL, 0, 0, i64::MAX, ConfigurationFlags::DEFAULT, None],
+            ["max-period", &config::MAX_PERIOD, 0, 0, i64::MAX, ConfigurationFlags::DEFAULT, None],
+            ["max-bench-iterations", &config::MAX_BENCH_ITERATIONS, config::DEFAULT_MAX_BENCH_ITERATIONS, 0, i64::MAX, ConfigurationFlags::DEFAULT, None],
+            ["ttl-jitter", &config::TTL_JITTER, 0, 0, 100, ConfigurationFlags::DEFAULT, None],
+            ["latency-threshold", &config::LATENCY_THRESHOLD, 0, 0, i64::MAX, ConfigurationFlags::DEFAULT, None],
+            ["slowlog-threshold", &config::SLOWLOG_THRESHOLD, 0, 0, i64::MAX, ConfigurationFlags::DEFAULT, None],
+            ["top-keys", &config::TOP_KEYS, 0, 0, 1_000_000, ConfigurationFlags::DEFAULT, None],
+            ["recent-decisions", &config::RECENT_DECISIONS, 0, 0, 1_000_000, ConfigurationFlags::DEFAULT, None],
+            ["memory-threshold", &config::MEMORY_THRESHOLD, 0, 0, 100, ConfigurationFlags::DEFAULT, None],
+        ],
+        string: [
+            ["key-prefix", &config::KEY_PREFIX, "shield", ConfigurationFlags::DEFAULT, Some(Box::new(policy::on_prefix_changed))],
+            ["key-separator", &config::KEY_SEPARATOR, ":", ConfigurationFlags::DEFAULT, Some(Box::new(policy::on_prefix_changed))],
+            ["maintenance", &MAINTENANCE, "off", ConfigurationFlags::DEFAULT, None],
+        ],
+        bool: [
+            ["lenient-recovery", &config::LENIENT_RECOVERY, false, ConfigurationFlags::DEFAULT, None],
+            ["memory-fail-open", &config::MEMORY_FAIL_OPEN, false, ConfigurationFlags::DEFAULT, None],
+            ["deny-error", &config::DENY_ERROR, false, ConfigurationFlags::DEFAULT, None],
+        ],
+        enum: [],
+        module_args_as_configuration: true,
+    ]
+}
+
+//////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+// The TTL checks predate the lint
+#[allow(clippy::manual_range_contains)]
+mod tests {
+    extern crate redis;
+    use redis::Commands;
+    use std::env;
+    use std::{thread, time};
+
+    fn establish_connection() -> redis::Connection {
+        let client = redis::Client::open(redis_url()).unwrap();
+        client.get_connection().unwrap()
+    }
+
+    #[cfg(not(feature = "embedded-redis"))]
+    fn redis_url() -> String {
+        env::var("REDIS_URL").unwrap()
+    }
+
+    // Falls back to a server started by the tests themselves
+    #[cfg(feature = "embedded-redis")]
+    fn redis_url() -> String {
+        env::var("REDIS_URL").unwrap_or_else(|_| super::test_server::url())
+    }
+
+    // Returns the Unix time in milliseconds according to the server
+    fn server_time(con: &mut redis::Connection) -> i64 {
+        let (seconds, micros): (i64, i64) = redis::cmd("TIME").query(con).unwrap();
+        seconds * 1000 + micros / 1000
+    }
+
+    // Reads the number of tokens stored by the last write to a bucket
+    fn stored_tokens(con: &mut redis::Connection, key: &str) -> i64 {
+        let value: String = con.get(key).unwrap();
+        let tokens = value.split(':').next().unwrap();
+        tokens.parse().unwrap()
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "An error was signalled by the server - ResponseError: wrong number of arguments for 'SHIELD.absorb' command"
+    )]
+    fn test_wrong_arity() {
+        let mut con = establish_connection();
+
+        let _: () = redis::cmd(super::REDIS_COMMAND).query(&mut con).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "SHIELD_BADCAPACITY: capacity is not positive integer")]
+    fn test_capacity_is_string() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_new";
+
+        let _: () = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg("abc")
+            .arg(60)
+            .query(&mut con)
+            .unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "SHIELD_BADCAPACITY: capacity is not positive integer")]
+    fn test_capacity_is_float() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_new";
+
+        let _: () = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(1.2)
+            .arg(60)
+            .query(&mut con)
+            .unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "SHIELD_BADCAPACITY: capacity is not positive integer")]
+    fn test_capacity_is_zero() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_new";
+
+        let _: () = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(0)
+            .arg(60)
+            .query(&mut con)
+            .unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "SHIELD_BADCAPACITY: capacity is not positive integer")]
+    fn test_capacity_is_negative_integer() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_new";
+
+        let _: () = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(-2)
+            .arg(60)
+            .query(&mut con)
+            .unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "SHIELD_BADPERIOD: period is not positive integer")]
+    fn test_period_is_string() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_new";
+
+        let _: () = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(10)
+            .arg("abc")
+            .query(&mut con)
+            .unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "SHIELD_BADPERIOD: period is not positive integer")]
+    fn test_period_is_float() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_new";
+
+        let _: () = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(10)
+            .arg(6.0)
+            .query(&mut con)
+            .unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "SHIELD_BADPERIOD: period is not positive integer")]
+    fn test_period_is_zero() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_new";
+
+        let _: () = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(10)
+            .arg(0)
+            .query(&mut con)
+            .unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "SHIELD_BADPERIOD: period is not positive integer")]
+    fn test_period_is_negative_integer() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_new";
+
+        let _: () = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(10)
+            .arg(-4)
+            .query(&mut con)
+            .unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "SHIELD_BADTOKENS: tokens is not positive integer")]
+    fn test_tokens_is_string() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_new";
+
+        let _: () = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(10)
+            .arg(60)
+            .arg("abc")
+            .query(&mut con)
+            .unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "SHIELD_BADTOKENS: tokens is not positive integer")]
+    fn test_tokens_is_float() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_new";
+
+        let _: () = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(10)
+            .arg(60)
+            .arg(3.1)
+            .query(&mut con)
+            .unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "SHIELD_BADTOKENS: tokens is not positive integer")]
+    fn test_tokens_is_zero() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_new";
+
+        let _: () = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(10)
+            .arg(60)
+            .arg(0)
+            .query(&mut con)
+            .unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "SHIELD_BADTOKENS: tokens is not positive integer")]
+    fn test_tokens_is_negative_integer() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_new";
+
+        let _: () = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(10)
+            .arg(60)
+            .arg(-9)
+            .query(&mut con)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_bucket_does_not_exist() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_new";
+
+        let _: () = con.del(bucket_key).unwrap();
+
+        let remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(30)
+            .arg(60)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 29);
+
+        let ttl: i64 = con.pttl(bucket_key).unwrap();
+        assert!(ttl >= 59900 && ttl <= 60000);
+    }
+
+    #[test]
+    fn test_bucket_exists_but_has_no_ttl() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_no_expire";
+
+        let _: () = con.del(bucket_key).unwrap();
+        let _: () = con.set(bucket_key, 2).unwrap();
+
+        let remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(30)
+            .arg(60)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 29);
+
+        let ttl: i64 = con.pttl(bucket_key).unwrap();
+        assert!(ttl >= 59900 && ttl <= 60000);
+    }
+
+    #[test]
+    fn test_refill_ignores_external_ttl_changes() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_external_ttl";
+
+        let _: () = con.del(bucket_key).unwrap();
+
+        let mut remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(30)
+            .arg(60)
+            .arg(10)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 20);
+
+        let _: () = con.persist(bucket_key).unwrap();
+
+        remaining_tokens = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(30)
+            .arg(60)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 19);
+
+        let ttl: i64 = con.pttl(bucket_key).unwrap();
+        assert!((59900..=60000).contains(&ttl));
+    }
+
+    #[test]
+    fn test_dump_and_restore_keep_refill() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_dump_restore";
+
+        let _: () = con.del(bucket_key).unwrap();
+
+        let mut remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(30)
+            .arg(60)
+            .arg(10)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 20);
+
+        let dump: Vec<u8> = redis::cmd("DUMP").arg(bucket_key).query(&mut con).unwrap();
+        // A restored key has no TTL unless it's passed explicitly, like after MIGRATE
+        // with a TTL that is recreated by the target instance
+        let _: () = redis::cmd("RESTORE")
+            .arg(bucket_key)
+            .arg(0)
+            .arg(dump)
+            .arg("REPLACE")
+            .query(&mut con)
+            .unwrap();
+
+        remaining_tokens = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(30)
+            .arg(60)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 19);
+    }
+
+    #[test]
+    fn test_multiple_tokens_requested() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_multiple_tokens";
+
+        let _: () = con.del(bucket_key).unwrap();
+
+        let remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(30)
+            .arg(60)
+            .arg(25)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 5);
+    }
+
+    #[test]
+    fn test_bucket_is_overflown() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_overflown";
+
+        let _: () = con.del(bucket_key).unwrap();
+
+        let remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(30)
+            .arg(60)
+            .arg(31)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, -1);
+    }
+
+    #[test]
+    fn test_sequential_requests() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_sequential_requests";
+        let tokens = 2;
+        let period = 60;
+
+        let _: () = con.del(bucket_key).unwrap();
+
+        let mut remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(tokens)
+            .arg(period)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 1);
+
+        let mut ttl: i64 = con.pttl(bucket_key).unwrap();
+        assert!(ttl >= 59900 && ttl <= 60000);
+
+        remaining_tokens = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(tokens)
+            .arg(period)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 0);
+
+        ttl = con.pttl(bucket_key).unwrap();
+        assert!(ttl >= 59900 && ttl <= 60000);
+
+        remaining_tokens = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(tokens)
+            .arg(period)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, -1);
+
+        ttl = con.pttl(bucket_key).unwrap();
+        assert!(ttl >= 59900 && ttl <= 60000);
+    }
+
+    #[test]
+    fn test_bucket_refills_with_time() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_refill";
+        let tokens = 3;
+        let period = 6;
+
+        let _: () = con.del(bucket_key).unwrap();
+
+        let mut remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(tokens)
+            .arg(period)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 2);
+
+        thread::sleep(time::Duration::from_secs(period / 3 + 1));
+
+        remaining_tokens = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(tokens)
+            .arg(period)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 2);
+
+        remaining_tokens = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(tokens)
+            .arg(period)
+            .arg(2)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 0);
+
+        thread::sleep(time::Duration::from_secs(6));
+
+        remaining_tokens = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(tokens)
+            .arg(period)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 2);
+    }
+
+    #[test]
+    fn test_debug_state() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_debug_state";
+
+        let _: () = con.del(bucket_key).unwrap();
+
+        let fields: Option<Vec<redis::Value>> = redis::cmd(super::DEBUG_COMMAND)
+            .arg("STATE")
+            .arg(bucket_key)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(fields, None);
+
+        let remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(30)
+            .arg(60)
+            .arg(5)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 25);
+
+        let fields: Vec<redis::Value> = redis::cmd(super::DEBUG_COMMAND)
+            .arg("state")
+            .arg(bucket_key)
+            .arg("ALGORITHM")
+            .arg("token_bucket")
+            .query(&mut con)
+            .unwrap();
+        let field = |name: &str| -> i64 {
+            let index = fields
+                .iter()
+                .position(|field| *field == redis::Value::SimpleString(name.to_string()))
+                .unwrap();
+            redis::from_redis_value(&fields[index + 1]).unwrap()
+        };
+        assert_eq!(field("stored_tokens"), 25);
+        assert!((59000..=60000).contains(&field("ttl")));
+        assert!(field("pttl") >= field("ttl"));
+        assert_eq!(field("capacity"), 30);
+        assert_eq!(field("period"), 60);
+        assert!((0..=1000).contains(&field("elapsed")));
+        assert_eq!(field("tokens"), 25);
+
+        // The limit of a bare number of tokens is unknown
+        let _: () = con.set_ex(bucket_key, 7, 60).unwrap();
+        let fields: Vec<redis::Value> = redis::cmd(super::DEBUG_COMMAND)
+            .arg("STATE")
+            .arg(bucket_key)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(fields[1], redis::Value::Int(7));
+        assert_eq!(fields[9], redis::Value::Nil);
+        assert_eq!(fields[15], redis::Value::Nil);
+    }
+
+    #[test]
+    fn test_debug_object() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_debug_object";
+
+        let _: () = con.del(bucket_key).unwrap();
+
+        let remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(30)
+            .arg(60)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 29);
+
+        let fields: Vec<redis::Value> = redis::cmd(super::DEBUG_COMMAND)
+            .arg("OBJECT")
+            .arg(bucket_key)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(fields[1], redis::Value::Int(2));
+        assert!(matches!(fields[3], redis::Value::Int(size) if size > 0));
+        assert_eq!(fields[5], redis::Value::SimpleString("token_bucket".into()));
+        assert!(matches!(fields[7], redis::Value::Int(age) if (0..=1000).contains(&age)));
+
+        let _: () = con.set(bucket_key, 7).unwrap();
+        let fields: Vec<redis::Value> = redis::cmd(super::DEBUG_COMMAND)
+            .arg("OBJECT")
+            .arg(bucket_key)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(fields[1], redis::Value::Int(0));
+        assert_eq!(fields[7], redis::Value::Nil);
+    }
+
+    #[test]
+    #[should_panic(expected = "SHIELD_BADALGO: unsupported algorithm")]
+    fn test_debug_unsupported_algorithm() {
+        let mut con = establish_connection();
+
+        let _: Option<Vec<redis::Value>> = redis::cmd(super::DEBUG_COMMAND)
+            .arg("STATE")
+            .arg("redis-shield::test_key_debug_algorithm")
+            .arg("ALGORITHM")
+            .arg("sliding_window")
+            .query(&mut con)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_bench() {
+        let mut con = establish_connection();
+
+        let report: Vec<redis::Value> = redis::cmd(super::BENCH_COMMAND)
+            .arg("token_bucket")
+            .arg(2000)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(report[1], redis::Value::Int(2000));
+        assert!(matches!(report[5], redis::Value::Int(ops) if ops > 0));
+        assert!(matches!(report[7], redis::Value::Int(allocations) if allocations > 0));
+
+        // The synthetic buckets are removed
+        let exists: bool = con.exists("shield:bench:0").unwrap();
+        assert!(!exists);
+    }
+
+    #[test]
+    #[should_panic(expected = "SHIELD_BADALGO: unsupported algorithm")]
+    fn test_bench_unsupported_algorithm() {
+        let mut con = establish_connection();
+
+        let _: Vec<redis::Value> = redis::cmd(super::BENCH_COMMAND)
+            .arg("fixed_window")
+            .arg(10)
+            .query(&mut con)
+            .unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "SHIELD_TOOLARGE: iterations exceeds the maximum of 10000000")]
+    fn test_bench_iterations_are_capped() {
+        let mut con = establish_connection();
+
+        let _: Vec<redis::Value> = redis::cmd(super::BENCH_COMMAND)
+            .arg("token_bucket")
+            .arg(10_000_001)
+            .query(&mut con)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_command_flags() {
+        let mut con = establish_connection();
+
+        let flags = |con: &mut redis::Connection, command: &str| -> Vec<String> {
+            let info: Vec<Vec<redis::Value>> = redis::cmd("COMMAND")
+                .arg("INFO")
+                .arg(command)
+                .query(con)
+                .unwrap();
+            redis::from_redis_value(&info[0][2]).unwrap()
+        };
+        let absorb = flags(&mut con, super::REDIS_COMMAND);
+        for flag in ["write", "denyoom", "fast"] {
+            assert!(absorb.iter().any(|f| f == flag), "{flag} missing");
+        }
+        let simulate = flags(&mut con, super::SIMULATE_COMMAND);
+        assert!(simulate.iter().any(|f| f == "readonly"));
+        assert!(!simulate.iter().any(|f| f == "write"));
+
+        let keys: Vec<String> = redis::cmd("COMMAND")
+            .arg("GETKEYS")
+            .arg(super::REDIS_COMMAND)
+            .arg("redis-shield::test_key_flags")
+            .arg(30)
+            .arg(60)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(keys, ["redis-shield::test_key_flags"]);
+
+        // The group is found without the limit, which is filled in later
+        let keys: Vec<String> = redis::cmd("COMMAND")
+            .arg("GETKEYS")
+            .arg(super::REDIS_COMMAND)
+            .arg("redis-shield::test_key_flags")
+            .arg("TIER")
+            .arg(100)
+            .arg(3600)
+            .arg("group")
+            .arg("redis-shield::test_key_flags_group")
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(
+            keys,
+            [
+                "redis-shield::test_key_flags",
+                "redis-shield::test_key_flags_group"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_registry_matches_registration() {
+        let mut con = establish_connection();
+
+        for command in super::registry::COMMANDS {
+            let info: Vec<Vec<redis::Value>> = redis::cmd("COMMAND")
+                .arg("INFO")
+                .arg(command.name)
+                .query(&mut con)
+                .unwrap();
+            let flags: Vec<String> = redis::from_redis_value(&info[0][2]).unwrap();
+            for flag in command.flags.split(' ') {
+                let flag = match flag {
+                    "deny-oom" => "denyoom",
+                    "getkeys-api" => "movablekeys",
+                    flag => flag,
+                };
+                assert!(flags.iter().any(|f| f == flag), "{} {}", command.name, flag);
+            }
+            let range: (i64, i64, i64) = (
+                redis::from_redis_value(&info[0][3]).unwrap(),
+                redis::from_redis_value(&info[0][4]).unwrap(),
+                redis::from_redis_value(&info[0][5]).unwrap(),
+            );
+            let expected = match command.keys {
+                super::registry::Keys::None => (0, 0, 0),
+                super::registry::Keys::Range(first, last, step) => {
+                    (first.into(), last.into(), step.into())
+                }
+                super::registry::Keys::Parsed => (1, 1, 1),
+            };
+            assert_eq!(range, expected, "{}", command.name);
+        }
+    }
+
+    #[test]
+    fn test_container_command() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_container";
+
+        let _: () = con.del(bucket_key).unwrap();
+        let remaining_tokens: i64 = redis::cmd(super::CONTAINER_COMMAND)
+            .arg("ABSORB")
+            .arg(bucket_key)
+            .arg(30)
+            .arg(60)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 29);
+        let remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(30)
+            .arg(60)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 28);
+        let state: Option<String> = redis::cmd(super::CONTAINER_COMMAND)
+            .arg("export")
+            .arg(bucket_key)
+            .query(&mut con)
+            .unwrap();
+        assert!(state.is_some());
+
+        let keys: Vec<String> = redis::cmd("COMMAND")
+            .arg("GETKEYS")
+            .arg(super::CONTAINER_COMMAND)
+            .arg("ABSORB")
+            .arg(bucket_key)
+            .arg("GROUP")
+            .arg("redis-shield::test_key_container_group")
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(keys, [bucket_key, "redis-shield::test_key_container_group"]);
+        let keys: Vec<String> = redis::cmd("COMMAND")
+            .arg("GETKEYS")
+            .arg(super::CONTAINER_COMMAND)
+            .arg("CHECK")
+            .arg("redis-shield::test_key_container1")
+            .arg("redis-shield::test_key_container2")
+            .arg("KEYS-DONE")
+            .arg(30)
+            .arg(60)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(
+            keys,
+            [
+                "redis-shield::test_key_container1",
+                "redis-shield::test_key_container2"
+            ]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "unknown subcommand 'unknown'. Try SHIELD HELP.")]
+    fn test_container_unknown_subcommand() {
+        let mut con = establish_connection();
+
+        let _: () = redis::cmd(super::CONTAINER_COMMAND)
+            .arg("unknown")
+            .query(&mut con)
+            .unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "wrong number of arguments for 'SHIELD|commit' command")]
+    fn test_container_checks_arity() {
+        let mut con = establish_connection();
+
+        let _: () = redis::cmd(super::CONTAINER_COMMAND)
+            .arg("COMMIT")
+            .query(&mut con)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_container_subcommand_flags() {
+        let mut con = establish_connection();
+
+        let flags = |con: &mut redis::Connection, command: &str| -> Vec<String> {
+            let info: Vec<Vec<redis::Value>> = redis::cmd("COMMAND")
+                .arg("INFO")
+                .arg(command)
+                .query(con)
+                .unwrap();
+            redis::from_redis_value(&info[0][2]).unwrap()
+        };
+        let export = flags(&mut con, "SHIELD|export");
+        assert!(export.iter().any(|f| f == "readonly"));
+        assert!(!export.iter().any(|f| f == "write"));
+        let absorb = flags(&mut con, "SHIELD|absorb");
+        for flag in ["write", "denyoom", "fast"] {
+            assert!(absorb.iter().any(|f| f == flag), "{flag} missing");
+        }
+    }
+
+    #[test]
+    fn test_export_missing_bucket() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_export_missing";
+
+        let _: () = con.del(bucket_key).unwrap();
+
+        let state: Option<String> = redis::cmd(super::EXPORT_COMMAND)
+            .arg(bucket_key)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(state, None);
+    }
+
+    #[test]
+    fn test_export_import_roundtrip() {
+        let mut con = establish_connection();
+        let source_key = "redis-shield::test_key_export_source";
+        let target_key = "redis-shield::test_key_import_target";
+
+        let _: () = con.del(source_key).unwrap();
+        let _: () = con.del(target_key).unwrap();
+
+        let remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(source_key)
+            .arg(30)
+            .arg(60)
+            .arg(5)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 25);
+
+        let state: String = redis::cmd(super::EXPORT_COMMAND)
+            .arg(source_key)
+            .query(&mut con)
+            .unwrap();
+        assert!(state.contains("\"algorithm\":\"token_bucket\""));
+        assert!(state.contains("\"tokens\":25"));
+        assert!(state.contains("\"capacity\":30,\"period\":60"));
+
+        let _: () = redis::cmd(super::IMPORT_COMMAND)
+            .arg(target_key)
+            .arg(&state)
+            .query(&mut con)
+            .unwrap();
+
+        let ttl: i64 = con.pttl(target_key).unwrap();
+        assert!((59000..=60000).contains(&ttl));
+
+        let remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(target_key)
+            .arg(30)
+            .arg(60)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 24);
+    }
+
+    #[test]
+    fn test_import_into_existing_key() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_import_existing";
+
+        let _: () = con.del(bucket_key).unwrap();
+        let _: () = con.set(bucket_key, 7).unwrap();
+
+        let state =
+            r#"{"version":1,"algorithm":"token_bucket","tokens":3,"ttl":60000,"exported_at":0}"#;
+        let result: redis::RedisResult<()> = redis::cmd(super::IMPORT_COMMAND)
+            .arg(bucket_key)
+            .arg(state)
+            .query(&mut con);
+        assert_eq!(result.unwrap_err().code(), Some("BUSYKEY"));
+
+        let tokens: i64 = con.get(bucket_key).unwrap();
+        assert_eq!(tokens, 7);
+    }
+
+    #[test]
+    #[should_panic(expected = "SHIELD_BADSNAPSHOT: invalid snapshot")]
+    fn test_import_invalid_snapshot() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_import_invalid";
+
+        let _: () = con.del(bucket_key).unwrap();
+        for limit in [
+            r#""capacity":0,"period":60"#,
+            r#""capacity":10,"period":0"#,
+            r#""capacity":10,"period":-60"#,
+            r#""capacity":3,"period":60"#,
+            r#""capacity":10"#,
+            r#""period":60"#,
+        ] {
+            let snapshot = format!(
+                r#"{{"version":1,"algorithm":"token_bucket","tokens":5,"ttl":1000,"exported_at":0,{}}}"#,
+                limit
+            );
+            let result: redis::RedisResult<()> = redis::cmd(super::IMPORT_COMMAND)
+                .arg(bucket_key)
+                .arg(snapshot)
+                .query(&mut con);
+            assert_eq!(result.unwrap_err().code(), Some("SHIELD_BADSNAPSHOT"));
+        }
+        let exists: bool = con.exists(bucket_key).unwrap();
+        assert!(!exists);
+
+        let _: () = redis::cmd(super::IMPORT_COMMAND)
+            .arg(bucket_key)
+            .arg("not a snapshot")
+            .query(&mut con)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_batch_stops_at_first_denied_request() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_batch";
+
+        let _: () = con.del(bucket_key).unwrap();
+
+        let reply: Vec<i64> = redis::cmd(super::BATCH_COMMAND)
+            .arg(bucket_key)
+            .arg(30)
+            .arg(60)
+            .arg(4)
+            .arg(5)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(reply, vec![4, 10]);
+
+        let reply: Vec<i64> = redis::cmd(super::BATCH_COMMAND)
+            .arg(bucket_key)
+            .arg(30)
+            .arg(60)
+            .arg(4)
+            .arg(3)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(reply, vec![3, 1]);
+        assert_eq!(stored_tokens(&mut con, bucket_key), 1);
+
+        let reply: Vec<i64> = redis::cmd(super::BATCH_COMMAND)
+            .arg(bucket_key)
+            .arg(30)
+            .arg(60)
+            .arg(2)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(reply, vec![1, 0]);
+
+        let reply: Vec<i64> = redis::cmd(super::BATCH_COMMAND)
+            .arg(bucket_key)
+            .arg(30)
+            .arg(60)
+            .arg(2)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(reply, vec![0, 0]);
+    }
+
+    #[test]
+    fn test_batch_of_banned_key() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_batch_banned";
+
+        let _: () = con.del(bucket_key).unwrap();
+        let _: () = redis::cmd(super::BAN_COMMAND)
+            .arg(bucket_key)
+            .arg(60000)
+            .query(&mut con)
+            .unwrap();
+
+        let reply: Vec<i64> = redis::cmd(super::BATCH_COMMAND)
+            .arg(bucket_key)
+            .arg(30)
+            .arg(60)
+            .arg(4)
+            .query(&mut con)
+            .unwrap();
+        let _: i64 = redis::cmd(super::UNBAN_COMMAND)
+            .arg(bucket_key)
+            .query(&mut con)
+            .unwrap();
+
+        assert_eq!(reply, vec![0, -1]);
+        let exists: bool = con.exists(bucket_key).unwrap();
+        assert!(!exists);
+    }
+
+    #[test]
+    #[should_panic(expected = "SHIELD_BADCOUNT: count is not non-negative integer")]
+    fn test_batch_count_is_negative_integer() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_batch_negative";
+
+        let _: () = redis::cmd(super::BATCH_COMMAND)
+            .arg(bucket_key)
+            .arg(30)
+            .arg(60)
+            .arg(-1)
+            .query(&mut con)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_reservation_commit_and_cancel() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_reservation";
+
+        let _: () = con.del(bucket_key).unwrap();
+
+        let committed_id: String = redis::cmd(super::RESERVE_COMMAND)
+            .arg(bucket_key)
+            .arg(30)
+            .arg(60)
+            .arg(10)
+            .query(&mut con)
+            .unwrap();
+        let canceled_id: String = redis::cmd(super::RESERVE_COMMAND)
+            .arg(bucket_key)
+            .arg(30)
+            .arg(60)
+            .arg(15)
+            .arg(1000)
+            .query(&mut con)
+            .unwrap();
+        assert_ne!(committed_id, canceled_id);
+        assert_eq!(stored_tokens(&mut con, bucket_key), 5);
+
+        let denied_id: Option<String> = redis::cmd(super::RESERVE_COMMAND)
+            .arg(bucket_key)
+            .arg(30)
+            .arg(60)
+            .arg(10)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(denied_id, None);
+
+        let committed: i64 = redis::cmd(super::COMMIT_COMMAND)
+            .arg(&committed_id)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(committed, 1);
+
+        let remaining_tokens: i64 = redis::cmd(super::CANCEL_COMMAND)
+            .arg(&canceled_id)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 20);
+
+        for id in [&committed_id, &canceled_id] {
+            let remaining_tokens: i64 = redis::cmd(super::CANCEL_COMMAND)
+                .arg(id)
+                .query(&mut con)
+                .unwrap();
+            assert_eq!(remaining_tokens, -1);
+        }
+    }
+
+    #[test]
+    fn test_reservation_of_binary_key() {
+        let mut con = establish_connection();
+        let bucket_key: &[u8] = b"redis-shield::test_key_reservation_\xff\xfe";
+
+        let _: () = con.del(bucket_key).unwrap();
+
+        let id: Vec<u8> = redis::cmd(super::RESERVE_COMMAND)
+            .arg(bucket_key)
+            .arg(30)
+            .arg(60)
+            .arg(10)
+            .query(&mut con)
+            .unwrap();
+        assert!(id.starts_with(b"{redis-shield::test_key_reservation_\xff\xfe}"));
+        let remaining_tokens: i64 = redis::cmd(super::CANCEL_COMMAND)
+            .arg(&id)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 30);
+
+        let value: Vec<u8> = con.get(bucket_key).unwrap();
+        assert!(value.starts_with(b"30:"));
+    }
+
+    #[test]
+    fn test_reservation_is_stored_in_slot_of_bucket() {
+        let mut con = establish_connection();
+        let bucket_key = "{redis-shield::test_key_reservation_tag}:orders";
+
+        let _: () = con.del(bucket_key).unwrap();
+
+        let id: String = redis::cmd(super::RESERVE_COMMAND)
+            .arg(bucket_key)
+            .arg(30)
+            .arg(60)
+            .arg(10)
+            .query(&mut con)
+            .unwrap();
+        let hash = id
+            .strip_prefix("{redis-shield::test_key_reservation_tag}")
+            .unwrap();
+        let reservation_key =
+            format!("{{redis-shield::test_key_reservation_tag}}:reservation:{hash}");
+        let reserved_key: String = con.hget(&reservation_key, "key").unwrap();
+        assert_eq!(reserved_key, bucket_key);
+
+        let committed: i64 = redis::cmd(super::COMMIT_COMMAND)
+            .arg(&id)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(committed, 1);
+        let exists: bool = con.exists(&reservation_key).unwrap();
+        assert!(!exists);
+    }
+
+    #[test]
+    fn test_reservation_is_capped() {
+        let mut con = establish_connection();
+
+        // Set way above what other tests use, since they share the server
+        let _: () = redis::cmd("CONFIG")
+            .arg("SET")
+            .arg("shield.max-capacity")
+            .arg(1_000_000)
+            .query(&mut con)
+            .unwrap();
+        let result: redis::RedisResult<Option<String>> = redis::cmd(super::RESERVE_COMMAND)
+            .arg("redis-shield::test_key_reservation_capped")
+            .arg(1_000_001)
+            .arg(60)
+            .arg(1)
+            .query(&mut con);
+        let _: () = redis::cmd("CONFIG")
+            .arg("SET")
+            .arg("shield.max-capacity")
+            .arg(0)
+            .query(&mut con)
+            .unwrap();
+
+        assert_eq!(result.unwrap_err().code(), Some("SHIELD_TOOLARGE"));
+    }
+
+    #[test]
+    fn test_set_zeroes_out_bucket() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_set_zero";
+
+        let _: () = con.del(bucket_key).unwrap();
+
+        let remaining_tokens: i64 = redis::cmd(super::SET_COMMAND)
+            .arg(bucket_key)
+            .arg(30)
+            .arg(60)
+            .arg(0)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 0);
+
+        let ttl: i64 = con.pttl(bucket_key).unwrap();
+        assert!((59900..=60000).contains(&ttl));
+
+        let remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(30)
+            .arg(60)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, -1);
+    }
+
+    #[test]
+    fn test_set_tops_up_bucket() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_set_top_up";
+
+        let _: () = con.del(bucket_key).unwrap();
+
+        let remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(30)
+            .arg(60)
+            .arg(30)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 0);
+
+        let remaining_tokens: i64 = redis::cmd(super::SET_COMMAND)
+            .arg(bucket_key)
+            .arg(30)
+            .arg(60)
+            .arg(20)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 20);
+
+        let remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(30)
+            .arg(60)
+            .arg(5)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 15);
+    }
+
+    #[test]
+    fn test_set_is_capped_at_capacity() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_set_capped";
+
+        let _: () = con.del(bucket_key).unwrap();
+
+        let remaining_tokens: i64 = redis::cmd(super::SET_COMMAND)
+            .arg(bucket_key)
+            .arg(30)
+            .arg(60)
+            .arg(100)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 30);
+    }
+
+    #[test]
+    #[should_panic(expected = "SHIELD_BADREMAINING: remaining is not non-negative integer")]
+    fn test_set_remaining_is_negative_integer() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_set_negative";
+
+        let _: () = redis::cmd(super::SET_COMMAND)
+            .arg(bucket_key)
+            .arg(30)
+            .arg(60)
+            .arg(-1)
+            .query(&mut con)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_touch_prolongs_cooldown() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_touch_prolong";
+
+        let _: () = con.del(bucket_key).unwrap();
+
+        let remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(2)
+            .arg(2)
+            .arg(2)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 0);
+
+        let touched: i64 = redis::cmd(super::TOUCH_COMMAND)
+            .arg(bucket_key)
+            .arg(10000)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(touched, 1);
+
+        let ttl: i64 = con.pttl(bucket_key).unwrap();
+        assert!((9900..=10000).contains(&ttl));
+
+        thread::sleep(time::Duration::from_secs(2));
+
+        let remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(2)
+            .arg(2)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, -1);
+    }
+
+    #[test]
+    fn test_touch_with_zero_ttl_refills_bucket() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_touch_zero";
+
+        let _: () = con.del(bucket_key).unwrap();
+
+        let remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(30)
+            .arg(60)
+            .arg(30)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 0);
+
+        let touched: i64 = redis::cmd(super::TOUCH_COMMAND)
+            .arg(bucket_key)
+            .arg(0)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(touched, 1);
+
+        let remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(30)
+            .arg(60)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 29);
+    }
+
+    #[test]
+    fn test_touch_missing_bucket() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_touch_missing";
+
+        let _: () = con.del(bucket_key).unwrap();
+
+        let touched: i64 = redis::cmd(super::TOUCH_COMMAND)
+            .arg(bucket_key)
+            .arg(1000)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(touched, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "SHIELD_BADTTL: ttl is not non-negative integer")]
+    fn test_touch_ttl_is_string() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_touch_string";
+
+        let _: () = redis::cmd(super::TOUCH_COMMAND)
+            .arg(bucket_key)
+            .arg("abc")
+            .query(&mut con)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_drain_empties_bucket() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_drain";
+
+        let _: () = con.del(bucket_key).unwrap();
+
+        let remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(30)
+            .arg(60)
+            .arg(12)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 18);
+
+        let drained_tokens: i64 = redis::cmd(super::DRAIN_COMMAND)
+            .arg(bucket_key)
+            .arg(30)
+            .arg(60)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(drained_tokens, 18);
+
+        let ttl: i64 = con.pttl(bucket_key).unwrap();
+        assert!((59900..=60000).contains(&ttl));
+
+        let remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(30)
+            .arg(60)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, -1);
+    }
+
+    #[test]
+    fn test_drain_empty_bucket() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_drain_empty";
+
+        let _: () = con.del(bucket_key).unwrap();
+
+        let mut drained_tokens: i64 = redis::cmd(super::DRAIN_COMMAND)
+            .arg(bucket_key)
+            .arg(30)
+            .arg(60)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(drained_tokens, 30);
+
+        drained_tokens = redis::cmd(super::DRAIN_COMMAND)
+            .arg(bucket_key)
+            .arg(30)
+            .arg(60)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(drained_tokens, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "SHIELD_BADCAPACITY: capacity is not positive integer")]
+    fn test_drain_capacity_is_zero() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_drain_invalid";
+
+        let _: () = redis::cmd(super::DRAIN_COMMAND)
+            .arg(bucket_key)
+            .arg(0)
+            .arg(60)
+            .query(&mut con)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_simulate_allowed_request() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_simulate_allowed";
+
+        let _: () = con.del(bucket_key).unwrap();
+
+        let started_at = server_time(&mut con);
+        let result: Vec<i64> = redis::cmd(super::SIMULATE_COMMAND)
+            .arg(bucket_key)
+            .arg(30)
+            .arg(60)
+            .arg(10)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(result[0..3], [1, 20, 0]);
+        // An unknown bucket is full already
+        assert!((started_at..=server_time(&mut con)).contains(&result[3]));
+
+        let exists: bool = con.exists(bucket_key).unwrap();
+        assert!(!exists);
+    }
+
+    #[test]
+    fn test_simulate_denied_request() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_simulate_denied";
+
+        let _: () = con.del(bucket_key).unwrap();
+
+        let remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(30)
+            .arg(60)
+            .arg(28)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 2);
+
+        let result: Vec<i64> = redis::cmd(super::SIMULATE_COMMAND)
+            .arg(bucket_key)
+            .arg(30)
+            .arg(60)
+            .arg(5)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(result[0..2], [0, 2]);
+        // 3 missing tokens are refilled in 6 seconds
+        assert!((5900..=6000).contains(&result[2]));
+
+        let remaining_tokens = stored_tokens(&mut con, bucket_key);
+        assert_eq!(remaining_tokens, 2);
+    }
+
+    #[test]
+    fn test_simulate_takes_part_in_client_tracking() {
+        let mut con = establish_connection();
+        let mut tracked = establish_connection();
+        let mut subscriber = establish_connection();
+        let bucket_key = "redis-shield::test_key_tracking";
+
+        let _: () = con.del(bucket_key).unwrap();
+
+        let subscriber_id: i64 = redis::cmd("CLIENT")
+            .arg("ID")
+            .query(&mut subscriber)
+            .unwrap();
+        let mut pubsub = subscriber.as_pubsub();
+        pubsub.subscribe("__redis__:invalidate").unwrap();
+        pubsub
+            .set_read_timeout(Some(time::Duration::from_secs(1)))
+            .unwrap();
+
+        let _: () = redis::cmd("CLIENT")
+            .arg("TRACKING")
+            .arg("ON")
+            .arg("REDIRECT")
+            .arg(subscriber_id)
+            .query(&mut tracked)
+            .unwrap();
+        let _: Vec<i64> = redis::cmd(super::SIMULATE_COMMAND)
+            .arg(bucket_key)
+            .arg(10)
+            .arg(60)
+            .query(&mut tracked)
+            .unwrap();
+
+        let _: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(10)
+            .arg(60)
+            .query(&mut con)
+            .unwrap();
+
+        let message = pubsub.get_message().unwrap();
+        let keys: Vec<String> = message.get_payload().unwrap();
+        assert_eq!(keys, vec![bucket_key]);
+    }
+
+    #[test]
+    fn test_simulate_request_exceeding_capacity() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_simulate_exceeding";
+
+        let _: () = con.del(bucket_key).unwrap();
+
+        let result: Vec<i64> = redis::cmd(super::SIMULATE_COMMAND)
+            .arg(bucket_key)
+            .arg(30)
+            .arg(60)
+            .arg(31)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(result[0..3], [0, 30, -1]);
+    }
+
+    #[test]
+    fn test_check_many_keys() {
+        let mut con = establish_connection();
+        let first_key = "redis-shield::test_key_check_first";
+        let second_key = "redis-shield::test_key_check_second";
+
+        let _: () = con.del(&[first_key, second_key]).unwrap();
+
+        let remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(first_key)
+            .arg(30)
+            .arg(60)
+            .arg(12)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 18);
+
+        let result: Vec<i64> = redis::cmd(super::CHECK_COMMAND)
+            .arg(first_key)
+            .arg(second_key)
+            .arg("KEYS-DONE")
+            .arg(30)
+            .arg(60)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(result, vec![18, 30]);
+
+        let exists: bool = con.exists(second_key).unwrap();
+        assert!(!exists);
+    }
+
+    #[test]
+    #[should_panic(expected = "SHIELD_SYNTAX: syntax error")]
+    fn test_check_without_keys_done() {
+        let mut con = establish_connection();
+        let _: Vec<i64> = redis::cmd(super::CHECK_COMMAND)
+            .arg("redis-shield::test_key_check_first")
+            .arg("redis-shield::test_key_check_second")
+            .arg(30)
+            .arg(60)
+            .query(&mut con)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_info_reports_latency() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_info_latency";
+
+        let _: () = con.del(bucket_key).unwrap();
+        let _: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(30)
+            .arg(60)
+            .query(&mut con)
+            .unwrap();
+
+        let info: String = redis::cmd("INFO").arg("shield").query(&mut con).unwrap();
+        assert!(info.contains("# shield_latency"));
+        for field in ["calls", "p50_usec", "p99_usec", "max_usec"] {
+            assert!(info.contains(&format!("token_bucket_{}:", field)));
+        }
+    }
+
+    #[test]
+    fn test_info_reports_decisions() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_info_decisions";
+
+        let counters = |con: &mut redis::Connection| -> Vec<u64> {
+            let info: String = redis::cmd("INFO").arg("shield").query(con).unwrap();
+            ["allowed", "denied", "errors", "hits", "misses"]
+                .iter()
+                .map(|field| {
+                    let prefix = format!("token_bucket_{}:", field);
+                    let line = info.lines().find(|line| line.starts_with(&prefix));
+                    line.unwrap()[prefix.len()..].trim().parse().unwrap()
+                })
+                .collect()
+        };
+
+        let _: () = con.del(bucket_key).unwrap();
+        let before = counters(&mut con);
+        for _ in 0..2 {
+            let _: i64 = redis::cmd(super::REDIS_COMMAND)
+                .arg(bucket_key)
+                .arg(1)
+                .arg(60)
+                .query(&mut con)
+                .unwrap();
+        }
+        let failed: redis::RedisResult<i64> = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(0)
+            .arg(60)
+            .query(&mut con);
+        assert!(failed.is_err());
+
+        // Other tests may run meanwhile
+        let after = counters(&mut con);
+        for (before, after) in before.iter().zip(&after) {
+            assert!(after > before);
+        }
+    }
+
+    #[test]
+    fn test_top_keys() {
+        let mut con = establish_connection();
+
+        // Keys are only tracked with `shield.top-keys` set, the most denied first
+        let keys: Vec<(Vec<u8>, i64, i64)> = redis::cmd(super::TOP_COMMAND)
+            .arg("COUNT")
+            .arg(5)
+            .query(&mut con)
+            .unwrap();
+        assert!(keys.len() <= 5);
+        assert!(keys.windows(2).all(|pair| pair[0].1 >= pair[1].1));
+    }
+
+    #[test]
+    #[should_panic(expected = "SHIELD_SYNTAX: syntax error")]
+    fn test_top_keys_unknown_option() {
+        let mut con = establish_connection();
+
+        let _: Vec<redis::Value> = redis::cmd(super::TOP_COMMAND)
+            .arg("LIMIT")
+            .arg(5)
+            .query(&mut con)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_stats_reset_untracked_key() {
+        let mut con = establish_connection();
+
+        // Resetting every counter would race the tests checking them
+        let reset: i64 = redis::cmd(super::STATS_COMMAND)
+            .arg("RESET")
+            .arg("redis-shield::test_key_stats_untracked")
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(reset, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "SHIELD_SYNTAX: syntax error")]
+    fn test_stats_unknown_subcommand() {
+        let mut con = establish_connection();
+
+        let _: redis::Value = redis::cmd(super::STATS_COMMAND)
+            .arg("CLEAR")
+            .query(&mut con)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_recent_decisions() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_recent_decisions";
+
+        let _: () = con.del(bucket_key).unwrap();
+        let _: () = redis::cmd("CONFIG")
+            .arg("SET")
+            .arg("shield.recent-decisions")
+            .arg(1000)
+            .query(&mut con)
+            .unwrap();
+        let _: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(10)
+            .arg(60)
+            .arg(3)
+            .query(&mut con)
+            .unwrap();
+
+        // Other tests may run meanwhile
+        let decisions: Vec<(String, String, i64, i64, i64)> = redis::cmd(super::RECENT_COMMAND)
+            .arg(1000)
+            .query(&mut con)
+            .unwrap();
+        let decision = decisions.iter().find(|decision| decision.0 == bucket_key);
+        let (_, algorithm, allowed, tokens, latency) = decision.unwrap();
+        assert_eq!(algorithm, "token_bucket");
+        assert_eq!((*allowed, *tokens), (1, 3));
+        assert!(*latency >= 0);
+    }
+
+    #[test]
+    fn test_version() {
+        let mut con = establish_connection();
+
+        let reply: Vec<redis::Value> = redis::cmd(super::VERSION_COMMAND).query(&mut con).unwrap();
+        assert_eq!(reply.len(), 8);
+        assert_eq!(reply[0], redis::Value::SimpleString("version".to_owned()));
+        assert_eq!(
+            reply[1],
+            redis::Value::SimpleString(env!("CARGO_PKG_VERSION").to_owned())
+        );
+        assert_eq!(reply[2], redis::Value::SimpleString("commit".to_owned()));
+        assert_eq!(reply[6], redis::Value::SimpleString("features".to_owned()));
+    }
+
+    #[test]
+    fn test_maintenance_status() {
+        let mut con = establish_connection();
+
+        // Other tests run against the same server, so the mode stays off
+        let _: () = redis::cmd(super::MAINTENANCE_COMMAND)
+            .arg("off")
+            .arg("TTL")
+            .arg(60)
+            .query(&mut con)
+            .unwrap();
+        let status: (String, i64) = redis::cmd(super::MAINTENANCE_COMMAND)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(status, ("off".to_string(), -1));
+        let config: (String, String) = redis::cmd("CONFIG")
+            .arg("GET")
+            .arg("shield.maintenance")
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(config.1, "off");
+    }
+
+    #[test]
+    #[should_panic(expected = "SHIELD_SYNTAX: maintenance mode must be allow, deny or off")]
+    fn test_maintenance_unknown_mode() {
+        let mut con = establish_connection();
+
+        let _: () = redis::cmd(super::MAINTENANCE_COMMAND)
+            .arg("drain")
+            .query(&mut con)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_exported_functions_share_buckets() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_functions";
+
+        let _: () = con.del(bucket_key).unwrap();
+        let library: String = redis::cmd(super::FUNCTIONS_COMMAND)
+            .arg("EXPORT")
+            .query(&mut con)
+            .unwrap();
+        let _: String = redis::cmd("FUNCTION")
+            .arg("LOAD")
+            .arg("REPLACE")
+            .arg(library)
+            .query(&mut con)
+            .unwrap();
+
+        let fcall = |con: &mut redis::Connection, function: &str| -> i64 {
+            redis::cmd("FCALL")
+                .arg(function)
+                .arg(1)
+                .arg(bucket_key)
+                .arg(10)
+                .arg(60)
+                .query(con)
+                .unwrap()
+        };
+        assert_eq!(fcall(&mut con, "shield_absorb"), 9);
+        let remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(10)
+            .arg(60)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 8);
+        assert_eq!(fcall(&mut con, "shield_absorb"), 7);
+        assert_eq!(fcall(&mut con, "shield_check"), 7);
+        assert_eq!(stored_tokens(&mut con, bucket_key), 7);
+    }
+
+    #[test]
+    fn test_help_covers_every_command() {
+        let mut con = establish_connection();
+
+        let commands: Vec<String> = redis::cmd("COMMAND")
+            .arg("LIST")
+            .arg("FILTERBY")
+            .arg("MODULE")
+            .arg("SHIELD")
+            .query(&mut con)
+            .unwrap();
+        assert!(!commands.is_empty());
+        for command in commands {
+            if command.eq_ignore_ascii_case(super::CONTAINER_COMMAND) {
+                continue;
+            }
+            let help: Vec<redis::Value> = redis::cmd(super::HELP_COMMAND)
+                .arg(&command)
+                .query(&mut con)
+                .unwrap();
+            assert_eq!(help.len(), 8, "{}", command);
+        }
+
+        let listed: Vec<(String, String)> =
+            redis::cmd(super::HELP_COMMAND).query(&mut con).unwrap();
+        assert!(listed.iter().any(|(name, _)| name == super::REDIS_COMMAND));
+    }
+
+    #[test]
+    fn test_help_without_prefix() {
+        let mut con = establish_connection();
+
+        let help: Vec<redis::Value> = redis::cmd(super::HELP_COMMAND)
+            .arg("ABSORB")
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(help[0], redis::Value::SimpleString("usage".to_owned()));
+        assert_eq!(
+            help[1],
+            redis::Value::SimpleString(
+                "SHIELD.absorb key [capacity period] [tokens] [option ...]".to_owned()
+            )
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "SHIELD_UNKNOWNCOMMAND: unknown command 'unknown'")]
+    fn test_help_unknown_command() {
+        let mut con = establish_connection();
+
+        let _: Vec<redis::Value> = redis::cmd(super::HELP_COMMAND)
+            .arg("unknown")
+            .query(&mut con)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_shards_split_and_rebalance_the_capacity() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_shards";
+        let shard_keys: Vec<String> = (0..4)
+            .map(|index| format!("{{{}}}:shard:{}", bucket_key, index))
+            .collect();
+
+        let _: () = con.del(bucket_key).unwrap();
+        let _: () = con.del(&shard_keys).unwrap();
+        let _: () = con
+            .del(format!("{{{}}}:shard:rebalanced", bucket_key))
+            .unwrap();
+
+        let remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(40)
+            .arg(60)
+            .arg("SHARDS")
+            .arg(4)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 9);
+
+        let exists: i64 = con.exists(bucket_key).unwrap();
+        assert_eq!(exists, 0);
+        // The first request of the period rebalances the shards
+        let mut tokens: Vec<i64> = shard_keys
+            .iter()
+            .map(|key| stored_tokens(&mut con, key))
+            .collect();
+        tokens.sort_unstable();
+        assert_eq!(tokens, [9, 10, 10, 10]);
+    }
+
+    #[test]
+    fn test_shards_keep_hash_tag() {
+        let mut con = establish_connection();
+        let bucket_key = "{redis-shield::test_key_shards_tagged}:service";
+        let shard_keys: Vec<String> = (0..2)
+            .map(|index| format!("{}:shard:{}", bucket_key, index))
+            .collect();
+
+        let _: () = con.del(&shard_keys).unwrap();
+        let _: () = con.del(format!("{}:shard:rebalanced", bucket_key)).unwrap();
+
+        let _: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(20)
+            .arg(60)
+            .arg("SHARDS")
+            .arg(2)
+            .query(&mut con)
+            .unwrap();
+
+        let exists: i64 = con.exists(&shard_keys).unwrap();
+        assert_eq!(exists, 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "SHIELD_BADSHARDS: shards must not exceed the capacity")]
+    fn test_shards_exceed_capacity() {
+        let mut con = establish_connection();
+
+        let _: () = redis::cmd(super::REDIS_COMMAND)
+            .arg("redis-shield::test_key_shards_too_many")
+            .arg(3)
+            .arg(60)
+            .arg("SHARDS")
+            .arg(4)
+            .query(&mut con)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_parent_must_have_tokens_too() {
+        let mut con = establish_connection();
+        let parent_key = "redis-shield::test_key_parent";
+        let child_key = "redis-shield::test_key_parent_child";
+
+        let _: () = con.del(&[parent_key, child_key]).unwrap();
+        let _: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(parent_key)
+            .arg(3)
+            .arg(60)
+            .query(&mut con)
+            .unwrap();
+
+        let results: Vec<i64> = (0..3)
+            .map(|_| {
+                redis::cmd(super::REDIS_COMMAND)
+                    .arg(child_key)
+                    .arg(10)
+                    .arg(60)
+                    .arg("PARENT")
+                    .arg(parent_key)
+                    .query(&mut con)
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(results, vec![1, 0, -1]);
+        assert_eq!(stored_tokens(&mut con, parent_key), 0);
+        assert_eq!(stored_tokens(&mut con, child_key), 8);
+    }
+
+    #[test]
+    #[should_panic(expected = "SHIELD_BADPARENT: parent has no namespace or stored limit")]
+    fn test_parent_without_limit() {
+        let mut con = establish_connection();
+        let parent_key = "redis-shield::test_key_parent_unknown";
+
+        let _: () = con.del(parent_key).unwrap();
+        let _: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg("redis-shield::test_key_parent_unknown_child")
+            .arg(10)
+            .arg(60)
+            .arg("PARENT")
+            .arg(parent_key)
+            .query(&mut con)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_failure_midway_leaves_buckets_intact() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_staged";
+        let tier_key = "{redis-shield::test_key_staged}:3600";
+        let history_key = "{redis-shield::test_key_staged}:history";
+
+        let _: () = con.del(&[bucket_key, tier_key]).unwrap();
+        // The history is written after the decision, but before the buckets
+        let _: () = con.set(history_key, "garbage").unwrap();
+        let result: redis::RedisResult<i64> = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(10)
+            .arg(60)
+            .arg("TIER")
+            .arg(100)
+            .arg(3600)
+            .arg("HISTORY")
+            .arg(5)
+            .query(&mut con);
+        let _: () = con.del(history_key).unwrap();
+
+        assert_eq!(result.unwrap_err().code(), Some("WRONGTYPE"));
+        let exists: i64 = con.exists(&[bucket_key, tier_key]).unwrap();
+        assert_eq!(exists, 0);
+    }
+
+    #[test]
+    fn test_failing_parent_leaves_buckets_intact() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_staged_child";
+        let tier_key = "{redis-shield::test_key_staged_child}:3600";
+
+        let _: () = con.del(&[bucket_key, tier_key]).unwrap();
+        let _: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(10)
+            .arg(60)
+            .arg("TIER")
+            .arg(100)
+            .arg(3600)
+            .query(&mut con)
+            .unwrap();
+        let result: redis::RedisResult<i64> = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(10)
+            .arg(60)
+            .arg("TIER")
+            .arg(100)
+            .arg(3600)
+            .arg("PARENT")
+            .arg("redis-shield::test_key_staged_missing_parent")
+            .query(&mut con);
+
+        assert_eq!(result.unwrap_err().code(), Some("SHIELD_BADPARENT"));
+        assert_eq!(stored_tokens(&mut con, bucket_key), 9);
+        assert_eq!(stored_tokens(&mut con, tier_key), 99);
+    }
+
+    #[test]
+    fn test_cost_function_computes_tokens() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_cost";
+        let name = "redis-shield::test_cost_double";
+
+        let _: () = con.del(bucket_key).unwrap();
+        let sha1: String = redis::cmd("SCRIPT")
+            .arg("LOAD")
+            .arg("return tonumber(ARGV[1]) * 2")
+            .query(&mut con)
+            .unwrap();
+        let _: () = redis::cmd(super::COST_COMMAND)
+            .arg("SET")
+            .arg(name)
+            .arg("script")
+            .arg(&sha1)
+            .query(&mut con)
+            .unwrap();
+        let function: (String, String) = redis::cmd(super::COST_COMMAND)
+            .arg("GET")
+            .arg(name)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(function, ("script".to_string(), sha1));
+
+        let remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(10)
+            .arg(60)
+            .arg("COST")
+            .arg(name)
+            .arg("ATTR")
+            .arg(3)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 4);
+
+        let deleted: i64 = redis::cmd(super::COST_COMMAND)
+            .arg("DEL")
+            .arg(name)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(deleted, 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "SHIELD_BADCOST: cost is required by ATTR")]
+    fn test_attributes_without_cost() {
+        let mut con = establish_connection();
+
+        let _: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg("redis-shield::test_key_cost_missing")
+            .arg(10)
+            .arg(60)
+            .arg("ATTR")
+            .arg(3)
+            .query(&mut con)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_denial_as_error() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_denial_as_error";
+
+        let _: () = con.del(bucket_key).unwrap();
+        let absorb = |con: &mut redis::Connection| -> redis::RedisResult<i64> {
+            redis::cmd(super::REDIS_COMMAND)
+                .arg(bucket_key)
+                .arg(1)
+                .arg(60)
+                .arg("ONDENY")
+                .arg("error")
+                .query(con)
+        };
+        assert_eq!(absorb(&mut con).unwrap(), 0);
+
+        let error = absorb(&mut con).unwrap_err();
+        assert_eq!(error.code(), Some("THROTTLED"));
+        let (key, retry_after) = error.detail().unwrap().split_once(' ').unwrap();
+        assert_eq!(key, bucket_key);
+        let retry_after: i64 = retry_after.parse().unwrap();
+        assert!(retry_after > 0 && retry_after <= 60000);
+        assert_eq!(stored_tokens(&mut con, bucket_key), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "SHIELD_BADONDENY: ondeny must be sentinel or error")]
+    fn test_unknown_denial() {
+        let mut con = establish_connection();
+
+        let _: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg("redis-shield::test_key_unknown_denial")
+            .arg(10)
+            .arg(60)
+            .arg("ONDENY")
+            .arg("nil")
+            .query(&mut con)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_token_bucket_algorithm() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_algorithm";
+
+        let _: () = con.del(bucket_key).unwrap();
+        let remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(10)
+            .arg(60)
+            .arg("ALGORITHM")
+            .arg("token_bucket")
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 9);
+    }
+
+    #[test]
+    #[should_panic(expected = "SHIELD_BADALGO: unsupported algorithm")]
+    fn test_unregistered_algorithm() {
+        let mut con = establish_connection();
+
+        let _: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg("redis-shield::test_key_algorithm_unknown")
+            .arg(10)
+            .arg(60)
+            .arg("ALGORITHM")
+            .arg("sliding_log")
+            .query(&mut con)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_tiers_are_consumed_together() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_tiers";
+        let tier_key = "{redis-shield::test_key_tiers}:3600";
+
+        let _: () = con.del(bucket_key).unwrap();
+        let _: () = con.del(tier_key).unwrap();
+
+        let mut remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(10)
+            .arg(60)
+            .arg(3)
+            .arg("TIER")
+            .arg(5)
+            .arg(3600)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 2);
+
+        let ttl: i64 = con.pttl(tier_key).unwrap();
+        assert!((3599900..=3600000).contains(&ttl));
+
+        remaining_tokens = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(10)
+            .arg(60)
+            .arg(3)
+            .arg("TIER")
+            .arg(5)
+            .arg(3600)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, -1);
+
+        // The denied request consumed tokens from neither bucket
+        let tokens = stored_tokens(&mut con, bucket_key);
+        assert_eq!(tokens, 7);
+        let tokens = stored_tokens(&mut con, tier_key);
+        assert_eq!(tokens, 2);
+    }
+
+    #[test]
+    fn test_tiers_without_tokens_argument() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_tiers_default_tokens";
+        let tier_key = "redis-shield::test_key_tiers_default_tokens:1";
+
+        let _: () = con.del(bucket_key).unwrap();
+        let _: () = con.del(tier_key).unwrap();
+
+        let remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(300)
+            .arg(60)
+            .arg("tier")
+            .arg(10)
+            .arg(1)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 9);
+    }
+
+    #[test]
+    fn test_simulate_with_tiers() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_simulate_tiers";
+        let tier_key = "{redis-shield::test_key_simulate_tiers}:60";
+
+        let _: () = con.del(bucket_key).unwrap();
+        let _: () = con.del(tier_key).unwrap();
+        let _: () = con.set_ex(tier_key, 0, 60).unwrap();
+
+        let result: Vec<i64> = redis::cmd(super::SIMULATE_COMMAND)
+            .arg(bucket_key)
+            .arg(10)
+            .arg(1)
+            .arg("TIER")
+            .arg(30)
+            .arg(60)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(result[0..2], [0, 0]);
+        // 1 token is refilled in the per-minute tier in 2 seconds
+        assert!((1900..=2000).contains(&result[2]));
+    }
+
+    #[test]
+    #[should_panic(expected = "SHIELD_BADTIERS: tiers must have distinct periods")]
+    fn test_tiers_with_same_period() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_tiers_same_period";
+
+        let _: () = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(10)
+            .arg(60)
+            .arg("TIER")
+            .arg(20)
+            .arg(60)
+            .query(&mut con)
+            .unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "SHIELD_BADCAPACITY: capacity is not positive integer")]
+    fn test_tier_capacity_is_zero() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_tiers_invalid";
+
+        let _: () = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(10)
+            .arg(60)
+            .arg("TIER")
+            .arg(0)
+            .arg(3600)
+            .query(&mut con)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_low_priority_leaves_headroom() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_low_priority";
+
+        let _: () = con.del(bucket_key).unwrap();
+
+        let mut remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(10)
+            .arg(60)
+            .arg(8)
+            .arg("PRIORITY")
+            .arg("low")
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 2);
+
+        remaining_tokens = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(10)
+            .arg(60)
+            .arg("PRIORITY")
+            .arg("low")
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, -1);
+
+        remaining_tokens = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(10)
+            .arg(60)
+            .arg("PRIORITY")
+            .arg("high")
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 1);
+    }
+
+    #[test]
+    fn test_low_priority_with_custom_threshold() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_low_priority_threshold";
+
+        let _: () = con.del(bucket_key).unwrap();
+
+        let mut remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(10)
+            .arg(60)
+            .arg(6)
+            .arg("PRIORITY")
+            .arg("low")
+            .arg("THRESHOLD")
+            .arg(50)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, -1);
+
+        remaining_tokens = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(10)
+            .arg(60)
+            .arg(5)
+            .arg("PRIORITY")
+            .arg("low")
+            .arg("THRESHOLD")
+            .arg(50)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 5);
+    }
+
+    #[test]
+    #[should_panic(expected = "SHIELD_BADPRIORITY: priority must be high, normal or low")]
+    fn test_unknown_priority() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_unknown_priority";
+
+        let _: () = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(10)
+            .arg(60)
+            .arg("PRIORITY")
+            .arg("urgent")
+            .query(&mut con)
+            .unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "SHIELD_BADTHRESHOLD: threshold must not exceed 100")]
+    fn test_threshold_exceeds_capacity() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_threshold_too_big";
+
+        let _: () = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(10)
+            .arg(60)
+            .arg("THRESHOLD")
+            .arg(120)
+            .query(&mut con)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_overdraft_admits_request_in_debt() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_overdraft";
+
+        let _: () = con.del(bucket_key).unwrap();
+
+        let mut remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(10)
+            .arg(60)
+            .arg(13)
+            .arg("OVERDRAFT")
+            .arg(5)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 0);
+
+        let tokens = stored_tokens(&mut con, bucket_key);
+        assert_eq!(tokens, -3);
+
+        remaining_tokens = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(10)
+            .arg(60)
+            .arg(3)
+            .arg("OVERDRAFT")
+            .arg(5)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, -1);
+
+        remaining_tokens = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(10)
+            .arg(60)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, -1);
+    }
+
+    #[test]
+    #[should_panic(expected = "SHIELD_BADOVERDRAFT: overdraft is not non-negative integer")]
+    fn test_overdraft_is_negative_integer() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_overdraft_negative";
+
+        let _: () = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(10)
+            .arg(60)
+            .arg("OVERDRAFT")
+            .arg(-5)
+            .query(&mut con)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_warmup_starts_at_fraction_of_capacity() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_warmup";
+        let warmup_key = "{redis-shield::test_key_warmup}:warmup";
+
+        let _: () = con.del(&[bucket_key, warmup_key]).unwrap();
+
+        let mut remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(100)
+            .arg(60)
+            .arg(11)
+            .arg("WARMUP")
+            .arg(600)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, -1);
+
+        remaining_tokens = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(100)
+            .arg(60)
+            .arg(10)
+            .arg("WARMUP")
+            .arg(600)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 0);
+
+        let ttl: i64 = con.pttl(warmup_key).unwrap();
+        assert!((599000..=600000).contains(&ttl));
+    }
+
+    #[test]
+    fn test_warmup_ignores_existing_bucket() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_warmup_existing";
+        let warmup_key = "{redis-shield::test_key_warmup_existing}:warmup";
+
+        let _: () = con.del(&[bucket_key, warmup_key]).unwrap();
+        let _: () = con.pset_ex(bucket_key, 50, 60000).unwrap();
+
+        let remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(100)
+            .arg(60)
+            .arg(30)
+            .arg("WARMUP")
+            .arg(600)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 20);
+
+        let exists: bool = con.exists(warmup_key).unwrap();
+        assert!(!exists);
+    }
+
+    #[test]
+    fn test_warmup_starts_for_new_field() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_warmup_field";
+        let warmup_key = "{redis-shield::test_key_warmup_field}:warmup";
+
+        let _: () = con.del(&[bucket_key, warmup_key]).unwrap();
+        let _: () = con.hset(bucket_key, "other", "unrelated").unwrap();
+
+        let remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(100)
+            .arg(60)
+            .arg(11)
+            .arg("FIELD")
+            .arg("/orders")
+            .arg("WARMUP")
+            .arg(600)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, -1);
+    }
+
+    #[test]
+    fn test_idempotent_retry_of_denial_is_charged() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_idempotency_denial";
+        let idempotency_key = "{redis-shield::test_key_idempotency_denial}:idempotency:req-1";
+
+        let _: () = con.del(&[bucket_key, idempotency_key]).unwrap();
+
+        let remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(10)
+            .arg(1)
+            .arg(10)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 0);
+
+        let remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(10)
+            .arg(1)
+            .arg(5)
+            .arg("IDEMPOTENCY")
+            .arg("req-1")
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, -1);
+        let exists: bool = con.exists(idempotency_key).unwrap();
+        assert!(!exists);
+
+        thread::sleep(time::Duration::from_millis(600));
+        let remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(10)
+            .arg(1)
+            .arg(5)
+            .arg("IDEMPOTENCY")
+            .arg("req-1")
+            .query(&mut con)
+            .unwrap();
+        assert!(remaining_tokens >= 0);
+    }
+
+    #[test]
+    fn test_idempotency_is_kept_per_member() {
+        let mut con = establish_connection();
+        let group_key = "redis-shield::test_key_idempotency_group";
+        let members = [
+            "redis-shield::test_key_idempotency_member1",
+            "redis-shield::test_key_idempotency_member2",
+        ];
+
+        let _: () = con.del(group_key).unwrap();
+        for member in members {
+            let _: () = con
+                .del(format!("{{{}}}:idempotency:req-1", member))
+                .unwrap();
+        }
+
+        let remaining_tokens: Vec<i64> = members
+            .iter()
+            .map(|member| {
+                redis::cmd(super::REDIS_COMMAND)
+                    .arg(member)
+                    .arg(10)
+                    .arg(60)
+                    .arg(4)
+                    .arg("GROUP")
+                    .arg(group_key)
+                    .arg("IDEMPOTENCY")
+                    .arg("req-1")
+                    .query(&mut con)
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(remaining_tokens, vec![6, 2]);
+    }
+
+    #[test]
+    fn test_idempotent_retry_is_not_charged() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_idempotency";
+        let idempotency_key = "{redis-shield::test_key_idempotency}:idempotency:req-1";
+
+        let _: () = con.del(&[bucket_key, idempotency_key]).unwrap();
+
+        for _ in 0..3 {
+            let remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+                .arg(bucket_key)
+                .arg(10)
+                .arg(60)
+                .arg(4)
+                .arg("IDEMPOTENCY")
+                .arg("req-1")
+                .query(&mut con)
+                .unwrap();
+            assert_eq!(remaining_tokens, 6);
+        }
+
+        let ttl: i64 = con.pttl(idempotency_key).unwrap();
+        assert!((59900..=60000).contains(&ttl));
+
+        let remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(10)
+            .arg(60)
+            .arg(4)
+            .arg("IDEMPOTENCY")
+            .arg("req-2")
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 2);
+    }
+
+    #[test]
+    fn test_soft_limit_warning() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_soft";
+
+        let _: () = con.del(bucket_key).unwrap();
+
+        let mut reply: Vec<i64> = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(10)
+            .arg(60)
+            .arg(8)
+            .arg("SOFT")
+            .arg(80)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(reply, vec![2, 0]);
+
+        reply = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(10)
+            .arg(60)
+            .arg("SOFT")
+            .arg(80)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(reply, vec![1, 1]);
+    }
+
+    #[test]
+    #[should_panic(expected = "SHIELD_BADSOFT: soft must not exceed 100")]
+    fn test_soft_exceeds_capacity() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_soft_too_big";
+
+        let _: () = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(10)
+            .arg(60)
+            .arg("SOFT")
+            .arg(101)
+            .query(&mut con)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_global_caps() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_global_caps";
+
+        // The caps are set way above what other tests use, since they share the server
+        let caps = [
+            ("shield.max-capacity", "capacity"),
+            ("shield.max-tokens-per-call", "tokens"),
+            ("shield.max-period", "period"),
+        ];
+        for (config, name) in caps {
+            let _: () = redis::cmd("CONFIG")
+                .arg("SET")
+                .arg(config)
+                .arg(1_000_000)
+                .query(&mut con)
+                .unwrap();
+
+            let mut args = vec![1_000_000; 3];
+            let index = ["capacity", "period", "tokens"]
+                .iter()
+                .position(|arg| *arg == name)
+                .unwrap();
+            args[index] = 1_000_001;
+            let result: redis::RedisResult<i64> = redis::cmd(super::REDIS_COMMAND)
+                .arg(bucket_key)
+                .arg(&args)
+                .query(&mut con);
+
+            let _: () = redis::cmd("CONFIG")
+                .arg("SET")
+                .arg(config)
+                .arg(0)
+                .query(&mut con)
+                .unwrap();
+
+            let error = result.unwrap_err();
+            assert_eq!(error.code(), Some("SHIELD_TOOLARGE"));
+            assert_eq!(
+                error.detail(),
+                Some(format!("{} exceeds the maximum of 1000000", name).as_str())
+            );
+        }
+    }
+
+    #[test]
+    fn test_global_caps_apply_to_every_command() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_global_caps_commands";
+
+        // Set way above what other tests use, since they share the server
+        let _: () = redis::cmd("CONFIG")
+            .arg("SET")
+            .arg("shield.max-capacity")
+            .arg(1_000_000)
+            .query(&mut con)
+            .unwrap();
+        let results: Vec<redis::RedisResult<redis::Value>> = [
+            redis::cmd(super::BATCH_COMMAND)
+                .arg(bucket_key)
+                .arg(1_000_001)
+                .arg(60)
+                .arg(1)
+                .clone(),
+            redis::cmd(super::SET_COMMAND)
+                .arg(bucket_key)
+                .arg(1_000_001)
+                .arg(60)
+                .arg(1)
+                .clone(),
+            redis::cmd(super::DRAIN_COMMAND)
+                .arg(bucket_key)
+                .arg(1_000_001)
+                .arg(60)
+                .clone(),
+            redis::cmd(super::CHECK_COMMAND)
+                .arg(bucket_key)
+                .arg("KEYS-DONE")
+                .arg(1_000_001)
+                .arg(60)
+                .clone(),
+        ]
+        .iter()
+        .map(|cmd| cmd.query(&mut con))
+        .collect();
+        let _: () = redis::cmd("CONFIG")
+            .arg("SET")
+            .arg("shield.max-capacity")
+            .arg(0)
+            .query(&mut con)
+            .unwrap();
+
+        for result in results {
+            assert_eq!(result.unwrap_err().code(), Some("SHIELD_TOOLARGE"));
+        }
+    }
+
+    #[test]
+    fn test_lenient_recovery_resets_foreign_values() {
+        let mut con = establish_connection();
+        let string_key = "redis-shield::test_key_lenient_recovery";
+        let list_key = "redis-shield::test_key_lenient_recovery_list";
+
+        let _: () = con.del(&[string_key, list_key]).unwrap();
+        let _: () = con.set(string_key, "garbage").unwrap();
+        let _: () = con.rpush(list_key, "value").unwrap();
+        let keys = [string_key, list_key];
+
+        let strict_results: Vec<redis::RedisResult<i64>> = keys
+            .iter()
+            .map(|key| {
+                redis::cmd(super::REDIS_COMMAND)
+                    .arg(key)
+                    .arg(10)
+                    .arg(60)
+                    .query(&mut con)
+            })
+            .collect();
+
+        let _: () = redis::cmd("CONFIG")
+            .arg("SET")
+            .arg("shield.lenient-recovery")
+            .arg("yes")
+            .query(&mut con)
+            .unwrap();
+        let lenient_results: Vec<redis::RedisResult<i64>> = keys
+            .iter()
+            .map(|key| {
+                redis::cmd(super::REDIS_COMMAND)
+                    .arg(key)
+                    .arg(10)
+                    .arg(60)
+                    .query(&mut con)
+            })
+            .collect();
+        let _: () = redis::cmd("CONFIG")
+            .arg("SET")
+            .arg("shield.lenient-recovery")
+            .arg("no")
+            .query(&mut con)
+            .unwrap();
+
+        let codes: Vec<Option<String>> = strict_results
+            .into_iter()
+            .map(|result| result.unwrap_err().code().map(String::from))
+            .collect();
+        assert_eq!(
+            codes,
+            vec![
+                Some("SHIELD_CORRUPT".to_string()),
+                Some("WRONGTYPE".to_string())
+            ]
+        );
+        for (key, result) in keys.iter().zip(lenient_results) {
+            assert_eq!(result.unwrap(), 9);
+            let tokens = stored_tokens(&mut con, key);
+            assert_eq!(tokens, 9);
+        }
+    }
+
+    #[test]
+    fn test_simulate_keeps_foreign_values() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_simulate_foreign";
+
+        let _: () = con.set(bucket_key, "garbage").unwrap();
+
+        let _: () = redis::cmd("CONFIG")
+            .arg("SET")
+            .arg("shield.lenient-recovery")
+            .arg("yes")
+            .query(&mut con)
+            .unwrap();
+        let result: redis::RedisResult<Vec<i64>> = redis::cmd(super::SIMULATE_COMMAND)
+            .arg(bucket_key)
+            .arg(10)
+            .arg(60)
+            .query(&mut con);
+        let _: () = redis::cmd("CONFIG")
+            .arg("SET")
+            .arg("shield.lenient-recovery")
+            .arg("no")
+            .query(&mut con)
+            .unwrap();
+
+        assert_eq!(result.unwrap_err().code(), Some("SHIELD_CORRUPT"));
+        let value: String = con.get(bucket_key).unwrap();
+        assert_eq!(value, "garbage");
+    }
+
+    #[test]
+    fn test_check_keeps_foreign_values() {
+        let mut con = establish_connection();
+        let string_key = "redis-shield::test_key_check_foreign";
+        let list_key = "redis-shield::test_key_check_foreign_list";
+
+        let _: () = con.del(list_key).unwrap();
+        let _: () = con.set(string_key, "garbage").unwrap();
+        let _: () = con.rpush(list_key, "value").unwrap();
+
+        let _: () = redis::cmd("CONFIG")
+            .arg("SET")
+            .arg("shield.lenient-recovery")
+            .arg("yes")
+            .query(&mut con)
+            .unwrap();
+        let results: Vec<redis::RedisResult<Vec<i64>>> = [string_key, list_key]
+            .iter()
+            .map(|key| {
+                redis::cmd(super::CHECK_COMMAND)
+                    .arg(key)
+                    .arg("KEYS-DONE")
+                    .arg(10)
+                    .arg(60)
+                    .query(&mut con)
+            })
+            .collect();
+        let _: () = redis::cmd("CONFIG")
+            .arg("SET")
+            .arg("shield.lenient-recovery")
+            .arg("no")
+            .query(&mut con)
+            .unwrap();
+
+        let codes: Vec<Option<String>> = results
+            .into_iter()
+            .map(|result| result.unwrap_err().code().map(String::from))
+            .collect();
+        assert_eq!(
+            codes,
+            vec![
+                Some("SHIELD_CORRUPT".to_string()),
+                Some("WRONGTYPE".to_string())
+            ]
+        );
+        let value: String = con.get(string_key).unwrap();
+        assert_eq!(value, "garbage");
+        let values: Vec<String> = con.lrange(list_key, 0, -1).unwrap();
+        assert_eq!(values, ["value"]);
+    }
+
+    #[test]
+    fn test_hash_of_fields_is_never_reset() {
+        let mut con = establish_connection();
+        let hash_key = "redis-shield::test_key_fields_admin";
+
+        let _: () = con.del(hash_key).unwrap();
+        let _: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(hash_key)
+            .arg(10)
+            .arg(60)
+            .arg("FIELD")
+            .arg("/orders")
+            .query(&mut con)
+            .unwrap();
+
+        let _: () = redis::cmd("CONFIG")
+            .arg("SET")
+            .arg("shield.lenient-recovery")
+            .arg("yes")
+            .query(&mut con)
+            .unwrap();
+        let result: redis::RedisResult<i64> = redis::cmd(super::SET_COMMAND)
+            .arg(hash_key)
+            .arg(10)
+            .arg(60)
+            .arg(5)
+            .query(&mut con);
+        let _: () = redis::cmd("CONFIG")
+            .arg("SET")
+            .arg("shield.lenient-recovery")
+            .arg("no")
+            .query(&mut con)
+            .unwrap();
+
+        assert_eq!(result.unwrap_err().code(), Some("SHIELD_HASH"));
+        let exists: bool = con.hexists(hash_key, "/orders").unwrap();
+        assert!(exists);
+    }
+
+    #[test]
+    fn test_memory_threshold_without_maxmemory() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_memory_threshold";
+
+        let _: () = con.del(bucket_key).unwrap();
+        let _: () = redis::cmd("CONFIG")
+            .arg("SET")
+            .arg("shield.memory-threshold")
+            .arg(1)
+            .query(&mut con)
+            .unwrap();
+        let result: redis::RedisResult<i64> = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(10)
+            .arg(60)
+            .query(&mut con);
+        let _: () = redis::cmd("CONFIG")
+            .arg("SET")
+            .arg("shield.memory-threshold")
+            .arg(0)
+            .query(&mut con)
+            .unwrap();
+
+        // The test server has no `maxmemory`, so it's never short on memory
+        assert_eq!(result.unwrap(), 9);
+        assert_eq!(stored_tokens(&mut con, bucket_key), 9);
+        let info: String = redis::cmd("INFO").arg("shield").query(&mut con).unwrap();
+        assert!(info.contains("memory_shed:"));
+    }
+
+    #[test]
+    fn test_bytes_unit() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_bytes_unit";
+
+        let _: () = con.del(bucket_key).unwrap();
+
+        let mut remaining_bytes: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg("10gb")
+            .arg(1)
+            .arg("512mb")
+            .arg("UNIT")
+            .arg("bytes")
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_bytes, 10 * 1024 * 1024 * 1024 - 512 * 1024 * 1024);
+
+        remaining_bytes = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg("10gb")
+            .arg(1)
+            .arg("11gb")
+            .arg("UNIT")
+            .arg("bytes")
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_bytes, -1);
+    }
+
+    #[test]
+    #[should_panic(expected = "SHIELD_BADCAPACITY: capacity is not valid size")]
+    fn test_bytes_unit_invalid_size() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_bytes_unit_invalid";
+
+        let _: () = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg("10xb")
+            .arg(1)
+            .arg("UNIT")
+            .arg("bytes")
+            .query(&mut con)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_sample_is_stable_within_window() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_sample";
+
+        let mut admitted = 0;
+        for id in 0..1000 {
+            let first: i64 = redis::cmd(super::SAMPLE_COMMAND)
+                .arg(bucket_key)
+                .arg(30)
+                .arg(3600)
+                .arg(id)
+                .query(&mut con)
+                .unwrap();
+            let second: i64 = redis::cmd(super::SAMPLE_COMMAND)
+                .arg(bucket_key)
+                .arg(30)
+                .arg(3600)
+                .arg(id)
+                .query(&mut con)
+                .unwrap();
+            assert_eq!(first, second);
+            admitted += first;
+        }
+        assert!((200..=400).contains(&admitted));
+    }
+
+    #[test]
+    fn test_sample_edge_percentages() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_sample_edges";
+
+        let none: i64 = redis::cmd(super::SAMPLE_COMMAND)
+            .arg(bucket_key)
+            .arg(0)
+            .arg(60)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(none, 0);
+
+        let all: i64 = redis::cmd(super::SAMPLE_COMMAND)
+            .arg(bucket_key)
+            .arg(100)
+            .arg(60)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(all, 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "SHIELD_BADPERCENT: percent must not exceed 100")]
+    fn test_sample_percent_exceeds_100() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_sample_too_big";
+
+        let _: () = redis::cmd(super::SAMPLE_COMMAND)
+            .arg(bucket_key)
+            .arg(101)
+            .arg(60)
+            .query(&mut con)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_sample_algorithm() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_sample_algorithm";
+
+        for id in 0..100 {
+            let sampled: i64 = redis::cmd(super::SAMPLE_COMMAND)
+                .arg(bucket_key)
+                .arg(30)
+                .arg(3600)
+                .arg(id)
+                .query(&mut con)
+                .unwrap();
+            let remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+                .arg(bucket_key)
+                .arg(30)
+                .arg(3600)
+                .arg("ALGORITHM")
+                .arg("sample")
+                .arg("IDEMPOTENCY")
+                .arg(id)
+                .query(&mut con)
+                .unwrap();
+            assert_eq!(remaining_tokens, sampled - 1);
+        }
+        let exists: bool = con.exists(bucket_key).unwrap();
+        assert!(!exists);
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "SHIELD_BADCAPACITY: capacity must not exceed 100 with ALGORITHM sample"
+    )]
+    fn test_sample_algorithm_percent_exceeds_100() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_sample_algorithm_too_big";
+
+        let _: () = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(101)
+            .arg(60)
+            .arg("ALGORITHM")
+            .arg("sample")
+            .query(&mut con)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_retry_budget() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_retry_budget";
+        let budget_key = "{redis-shield::test_key_retry_budget}:budget";
+
+        let _: () = con.del(&[bucket_key, budget_key]).unwrap();
+
+        let mut remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(100)
+            .arg(60)
+            .arg("KIND")
+            .arg("retry")
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, -1);
+
+        for _ in 0..4 {
+            let _: i64 = redis::cmd(super::REDIS_COMMAND)
+                .arg(bucket_key)
+                .arg(100)
+                .arg(60)
+                .arg("KIND")
+                .arg("primary")
+                .arg("BUDGET")
+                .arg(50)
+                .query(&mut con)
+                .unwrap();
+        }
+
+        for expected in [95, 94, -1] {
+            remaining_tokens = redis::cmd(super::REDIS_COMMAND)
+                .arg(bucket_key)
+                .arg(100)
+                .arg(60)
+                .arg("KIND")
+                .arg("retry")
+                .arg("BUDGET")
+                .arg(50)
+                .query(&mut con)
+                .unwrap();
+            assert_eq!(remaining_tokens, expected);
+        }
+
+        let ttl: i64 = con.pttl(budget_key).unwrap();
+        assert!((59900..=60000).contains(&ttl));
+    }
+
+    #[test]
+    fn test_group_shares_bucket() {
+        let mut con = establish_connection();
+        let group_key = "redis-shield::test_key_group";
+        let members_key = "{redis-shield::test_key_group}:members";
+        let members = [
+            "redis-shield::test_key_group_member_1",
+            "redis-shield::test_key_group_member_2",
+        ];
+
+        let _: () = con.del(&[group_key, members_key]).unwrap();
+
+        for (member, (tokens, expected)) in members.iter().zip([(3, 7), (5, 2)]) {
+            let remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+                .arg(member)
+                .arg(10)
+                .arg(60)
+                .arg(tokens)
+                .arg("GROUP")
+                .arg(group_key)
+                .query(&mut con)
+                .unwrap();
+            assert_eq!(remaining_tokens, expected);
+        }
+        let remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(members[0])
+            .arg(10)
+            .arg(60)
+            .arg(3)
+            .arg("GROUP")
+            .arg(group_key)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, -1);
+
+        let tokens = stored_tokens(&mut con, group_key);
+        assert_eq!(tokens, 2);
+        let exists: bool = con.exists(members[0]).unwrap();
+        assert!(!exists);
+
+        let stats: Vec<(String, i64)> = con.hgetall(members_key).unwrap();
+        assert_eq!(
+            stats,
+            vec![(members[0].to_string(), 3), (members[1].to_string(), 5)]
+        );
+    }
+
+    #[test]
+    fn test_namespace_defaults() {
+        let mut con = establish_connection();
+        let namespace = "redis-shield::test_ns";
+        let bucket_key = "redis-shield::test_ns/test_key";
+
+        let _: () = con.del(bucket_key).unwrap();
+        let _: () = redis::cmd(super::NAMESPACE_COMMAND)
+            .arg("SET")
+            .arg(namespace)
+            .arg("capacity")
+            .arg(10)
+            .arg("period")
+            .arg(60)
+            .query(&mut con)
+            .unwrap();
+
+        let mut remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 9);
+
+        remaining_tokens = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(4)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 5);
+
+        let ttl: i64 = con.pttl(bucket_key).unwrap();
+        assert!((59900..=60000).contains(&ttl));
+
+        let deleted: i64 = redis::cmd(super::NAMESPACE_COMMAND)
+            .arg("DEL")
+            .arg(namespace)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(deleted, 1);
+    }
+
+    #[test]
+    fn test_stored_limit_is_reused() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_stored_limit";
+
+        let _: () = con.del(bucket_key).unwrap();
+
+        let mut remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(10)
+            .arg(60)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 9);
+
+        remaining_tokens = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(4)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 5);
+
+        remaining_tokens = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg("HISTORY")
+            .arg(1)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 4);
+
+        let ttl: i64 = con.pttl(bucket_key).unwrap();
+        assert!((59900..=60000).contains(&ttl));
+    }
+
+    #[test]
+    #[should_panic(expected = "SHIELD_BADCAPACITY: capacity is required")]
+    fn test_stored_limit_of_unknown_key() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_stored_limit_unknown";
+
+        let _: () = con.del(bucket_key).unwrap();
+
+        let _: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(4)
+            .query(&mut con)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_policy_changes_are_picked_up() {
+        let mut con = establish_connection();
+        let policy_key = "shield:policy:redis-shield::test_policy";
+        let bucket_keys = [
+            "redis-shield::test_key_policy_1",
+            "redis-shield::test_key_policy_2",
+        ];
+
+        let _: () = con.del(&bucket_keys).unwrap();
+        let _: () = con
+            .hset_multiple(
+                policy_key,
+                &[
+                    ("capacity", "10"),
+                    ("period", "60"),
+                    ("options", "OVERDRAFT 5"),
+                ],
+            )
+            .unwrap();
+
+        let remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_keys[0])
+            .arg(12)
+            .arg("POLICY")
+            .arg("redis-shield::test_policy")
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 0);
+
+        let _: () = con.hset(policy_key, "capacity", 20).unwrap();
+
+        let remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_keys[1])
+            .arg(12)
+            .arg("POLICY")
+            .arg("redis-shield::test_policy")
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 8);
+
+        let _: () = con.del(policy_key).unwrap();
+
+        let result: redis::RedisResult<i64> = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_keys[1])
+            .arg(30)
+            .arg(60)
+            .arg("POLICY")
+            .arg("redis-shield::test_policy")
+            .query(&mut con);
+        assert_eq!(result.unwrap_err().code(), Some("SHIELD_BADPOLICY"));
+    }
+
+    #[test]
+    fn test_policy_cache_dropped_on_flush() {
+        let mut con = establish_connection();
+        // A database of its own, since the test flushes it
+        let _: () = redis::cmd("SELECT").arg(14).query(&mut con).unwrap();
+        let name = "redis-shield::test_policy_flush";
+        let bucket_key = "redis-shield::test_key_policy_flush";
+
+        let _: () = con
+            .hset_multiple(
+                format!("shield:policy:{}", name),
+                &[("capacity", "10"), ("period", "60")],
+            )
+            .unwrap();
+        let remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg("POLICY")
+            .arg(name)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 9);
+
+        // Flushing doesn't notify about the removed keys
+        let _: () = redis::cmd("FLUSHDB").query(&mut con).unwrap();
+
+        let result: redis::RedisResult<i64> = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg("POLICY")
+            .arg(name)
+            .query(&mut con);
+        assert_eq!(result.unwrap_err().code(), Some("SHIELD_BADPOLICY"));
+    }
+
+    #[test]
+    fn test_policy_set() {
+        let mut con = establish_connection();
+        let name = "redis-shield::test_policy_set";
+        let bucket_key = "redis-shield::test_key_policy_set";
+
+        let _: () = con.del(bucket_key).unwrap();
+        let _: () = con
+            .hset(format!("shield:policy:{}", name), "stale", "field")
+            .unwrap();
+        let reply: String = redis::cmd(super::POLICY_COMMAND)
+            .arg("SET")
+            .arg(name)
+            .arg("capacity")
+            .arg(10)
+            .arg("period")
+            .arg(60)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(reply, "OK");
+
+        let exists: bool = con
+            .hexists(format!("shield:policy:{}", name), "stale")
+            .unwrap();
+        assert!(!exists);
+        let remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg("POLICY")
+            .arg(name)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 9);
+    }
+
+    #[test]
+    #[should_panic(expected = "SHIELD_SYNTAX: syntax error")]
+    fn test_policy_set_unknown_field() {
+        let mut con = establish_connection();
+
+        let _: () = redis::cmd(super::POLICY_COMMAND)
+            .arg("SET")
+            .arg("redis-shield::test_policy_unknown_field")
+            .arg("capacity")
+            .arg(10)
+            .arg("period")
+            .arg(60)
+            .arg("description")
+            .arg("gold tier")
+            .query(&mut con)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_policy_set_invalid_options() {
+        let mut con = establish_connection();
+        let name = "redis-shield::test_policy_invalid_options";
+
+        let _: () = con.del(format!("shield:policy:{}", name)).unwrap();
+        let result: redis::RedisResult<String> = redis::cmd(super::POLICY_COMMAND)
+            .arg("SET")
+            .arg(name)
+            .arg("capacity")
+            .arg(10)
+            .arg("period")
+            .arg(60)
+            .arg("options")
+            .arg("PRIORITY urgent")
+            .query(&mut con);
+        assert_eq!(result.unwrap_err().code(), Some("SHIELD_BADPRIORITY"));
+
+        let exists: bool = con.exists(format!("shield:policy:{}", name)).unwrap();
+        assert!(!exists);
+    }
+
+    #[test]
+    fn test_policy_ignores_unknown_fields() {
+        let mut con = establish_connection();
+        let name = "redis-shield::test_policy_extra_field";
+        let bucket_key = "redis-shield::test_key_policy_extra_field";
+
+        let _: () = con.del(bucket_key).unwrap();
+        let _: () = con
+            .hset_multiple(
+                format!("shield:policy:{}", name),
+                &[
+                    ("capacity", "10"),
+                    ("period", "60"),
+                    ("description", "gold tier"),
+                ],
+            )
+            .unwrap();
+
+        let remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg("POLICY")
+            .arg(name)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 9);
+    }
+
+    #[test]
+    fn test_policy_info() {
+        let mut con = establish_connection();
+        let name = "redis-shield::test_policy_info";
+        let policy_key = format!("shield:policy:{}", name);
+        let bucket_keys = [
+            "redis-shield::test_key_policy_info_1",
+            "redis-shield::test_key_policy_info_2",
+        ];
+
+        let _: () = con.del(&bucket_keys).unwrap();
+        let _: i64 = redis::cmd(super::STATS_COMMAND)
+            .arg("RESET")
+            .arg("POLICY")
+            .arg(name)
+            .query(&mut con)
+            .unwrap();
+        let _: () = con
+            .hset_multiple(&policy_key, &[("capacity", "10"), ("period", "60")])
+            .unwrap();
+
+        for (key, tokens) in [
+            (bucket_keys[0], 5),
+            (bucket_keys[1], 10),
+            (bucket_keys[1], 1),
+        ] {
+            let _: i64 = redis::cmd(super::REDIS_COMMAND)
+                .arg(key)
+                .arg(tokens)
+                .arg("POLICY")
+                .arg(name)
+                .query(&mut con)
+                .unwrap();
+        }
+
+        let info: Vec<(String, i64)> = redis::cmd(super::POLICY_COMMAND)
+            .arg("INFO")
+            .arg(name)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(
+            info,
+            vec![
+                ("keys".to_string(), 2),
+                ("allowed".to_string(), 2),
+                ("denied".to_string(), 1)
+            ]
+        );
+
+        let reset: i64 = redis::cmd(super::STATS_COMMAND)
+            .arg("RESET")
+            .arg("POLICY")
+            .arg(name)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(reset, 1);
+
+        let info: Vec<(String, i64)> = redis::cmd(super::POLICY_COMMAND)
+            .arg("INFO")
+            .arg(name)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(
+            info,
+            vec![
+                ("keys".to_string(), 2),
+                ("allowed".to_string(), 0),
+                ("denied".to_string(), 0)
+            ]
+        );
+
+        // The usage is kept in memory rather than in the keyspace
+        let written: i64 = con
+            .exists(&[
+                format!("{}:keys", policy_key),
+                format!("{}:stats", policy_key),
+            ])
+            .unwrap();
+        assert_eq!(written, 0);
+    }
+
+    #[test]
+    fn test_override_layered_on_policy() {
+        let mut con = establish_connection();
+        let name = "redis-shield::test_policy_override";
+        let policy_key = format!("shield:policy:{}", name);
+        let bucket_key = "redis-shield::test_key_policy_override";
+
+        let _: () = con.del(bucket_key).unwrap();
+        let _: () = con
+            .hset_multiple(&policy_key, &[("capacity", "10"), ("period", "60")])
+            .unwrap();
+
+        let _: () = redis::cmd(super::OVERRIDE_COMMAND)
+            .arg("SET")
+            .arg(bucket_key)
+            .arg("capacity")
+            .arg(500)
+            .query(&mut con)
+            .unwrap();
+
+        let mut remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(100)
+            .arg("POLICY")
+            .arg(name)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 400);
+
+        // Call-time arguments take precedence over the override
+        remaining_tokens = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(1000)
+            .arg(60)
+            .arg(100)
+            .arg("POLICY")
+            .arg(name)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 300);
+
+        let fields: Vec<(String, i64)> = redis::cmd(super::OVERRIDE_COMMAND)
+            .arg("GET")
+            .arg(bucket_key)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(fields, vec![("capacity".to_string(), 500)]);
+
+        let deleted: i64 = redis::cmd(super::OVERRIDE_COMMAND)
+            .arg("DEL")
+            .arg(bucket_key)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(deleted, 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "SHIELD_BADALGO: unsupported algorithm")]
+    fn test_namespace_unsupported_algorithm() {
+        let mut con = establish_connection();
+
+        let _: () = redis::cmd(super::NAMESPACE_COMMAND)
+            .arg("SET")
+            .arg("redis-shield::test_ns_gcra")
+            .arg("capacity")
+            .arg(10)
+            .arg("period")
+            .arg(60)
+            .arg("algorithm")
+            .arg("gcra")
+            .query(&mut con)
+            .unwrap();
+    }
+
+    // Walks the keys matching `pattern` with `SHIELD.gc` and returns the number of collected buckets
+    fn collect_garbage(con: &mut redis::Connection, pattern: &str, idle: Option<i64>) -> i64 {
+        let mut cursor = "0".to_string();
+        let mut total = 0;
+        loop {
+            let mut cmd = redis::cmd(super::GC_COMMAND);
+            cmd.arg(&cursor)
+                .arg("MATCH")
+                .arg(pattern)
+                .arg("COUNT")
+                .arg(100);
+            if let Some(idle) = idle {
+                cmd.arg("IDLE").arg(idle);
+            }
+            let (next, collected): (String, i64) = cmd.query(con).unwrap();
+            total += collected;
+            if next == "0" {
+                return total;
+            }
+            cursor = next;
+        }
+    }
+
+    #[test]
+    fn test_gc_removes_refilled_buckets() {
+        let mut con = establish_connection();
+        let refilled_key = "redis-shield::test_key_gc_refilled";
+        let active_key = "redis-shield::test_key_gc_active";
+        let foreign_key = "redis-shield::test_key_gc_foreign";
+
+        let _: () = con.del(&[refilled_key, active_key, foreign_key]).unwrap();
+        let _: () = con.set(foreign_key, "hello").unwrap();
+
+        for (key, period) in [(refilled_key, 1), (active_key, 60)] {
+            let _: i64 = redis::cmd(super::REDIS_COMMAND)
+                .arg(key)
+                .arg(10)
+                .arg(period)
+                .query(&mut con)
+                .unwrap();
+            let _: () = con.persist(key).unwrap();
+        }
+        thread::sleep(time::Duration::from_millis(1100));
+
+        let collected = collect_garbage(&mut con, "redis-shield::test_key_gc_*", None);
+        assert_eq!(collected, 1);
+
+        let exists: bool = con.exists(refilled_key).unwrap();
+        assert!(!exists);
+        let exists: bool = con.exists(active_key).unwrap();
+        assert!(exists);
+        let exists: bool = con.exists(foreign_key).unwrap();
+        assert!(exists);
+    }
+
+    #[test]
+    fn test_gc_removes_idle_buckets() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_gc_idle";
+        let foreign_key = "redis-shield::test_key_gc_idle_foreign";
+
+        let _: () = con.del(bucket_key).unwrap();
+        let _: () = con.set(foreign_key, 5).unwrap();
+        let _: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(10)
+            .arg(60)
+            .query(&mut con)
+            .unwrap();
+
+        let collected = collect_garbage(&mut con, "redis-shield::test_key_gc_idle*", Some(0));
+        assert_eq!(collected, 1);
+
+        let exists: bool = con.exists(bucket_key).unwrap();
+        assert!(!exists);
+        let exists: bool = con.exists(foreign_key).unwrap();
+        assert!(exists);
+    }
+
+    #[test]
+    fn test_gc_keeps_values_looking_like_state() {
+        let mut con = establish_connection();
+        let foreign_key = "redis-shield::test_key_gc_lookalike";
+
+        let _: () = con.set(foreign_key, "10:30").unwrap();
+
+        let collected = collect_garbage(&mut con, foreign_key, Some(0));
+        assert_eq!(collected, 0);
+
+        let value: String = con.get(foreign_key).unwrap();
+        assert_eq!(value, "10:30");
+    }
+
+    #[test]
+    fn test_gc_returns_cursor() {
+        let mut con = establish_connection();
+
+        let (cursor, _): (String, i64) = redis::cmd(super::GC_COMMAND)
+            .arg(0)
+            .arg("MATCH")
+            .arg("redis-shield::test_key_gc_nothing*")
+            .arg("COUNT")
+            .arg(1)
+            .query(&mut con)
+            .unwrap();
+        assert!(cursor.parse::<u64>().is_ok());
+    }
+
+    #[test]
+    #[should_panic(expected = "SHIELD_SYNTAX: syntax error")]
+    fn test_gc_unknown_option() {
+        let mut con = establish_connection();
+
+        let _: () = redis::cmd(super::GC_COMMAND)
+            .arg(0)
+            .arg("LIMIT")
+            .arg(10)
+            .query(&mut con)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_rename_moves_state() {
+        let mut con = establish_connection();
+        let old_key = "redis-shield::test_key_rename_old";
+        let new_key = "redis-shield::test_key_rename_new";
+        let old_tier_key = format!("{{{}}}:3600", old_key);
+        let new_tier_key = format!("{{{}}}:3600", new_key);
+
+        let _: () = con
+            .del(&[old_key, new_key, &old_tier_key, &new_tier_key])
+            .unwrap();
+        let _: () = con.set(format!("{{{}}}:warmup", new_key), 1).unwrap();
+
+        let remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(old_key)
+            .arg(10)
+            .arg(60)
+            .arg(3)
+            .arg("TIER")
+            .arg(5)
+            .arg(3600)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 2);
+
+        let renamed: i64 = redis::cmd(super::RENAME_COMMAND)
+            .arg(old_key)
+            .arg(new_key)
+            .arg("ALGORITHM")
+            .arg("token_bucket")
+            .arg("TIER")
+            .arg(3600)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(renamed, 1);
+
+        let exists: bool = con.exists(&[old_key, &old_tier_key]).unwrap();
+        assert!(!exists);
+        // The stale warm-up of the new key doesn't survive the rename
+        let exists: bool = con.exists(format!("{{{}}}:warmup", new_key)).unwrap();
+        assert!(!exists);
+        assert_eq!(stored_tokens(&mut con, new_key), 7);
+        assert_eq!(stored_tokens(&mut con, &new_tier_key), 2);
+
+        let ttl: i64 = con.pttl(new_key).unwrap();
+        assert!((59900..=60000).contains(&ttl));
+    }
+
+    #[test]
+    fn test_rename_missing_key() {
+        let mut con = establish_connection();
+        let old_key = "redis-shield::test_key_rename_missing";
+        let new_key = "redis-shield::test_key_rename_untouched";
+
+        let _: () = con.del(old_key).unwrap();
+        let _: () = con.set(new_key, "5:0").unwrap();
+
+        let renamed: i64 = redis::cmd(super::RENAME_COMMAND)
+            .arg(old_key)
+            .arg(new_key)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(renamed, 0);
+
+        let value: String = con.get(new_key).unwrap();
+        assert_eq!(value, "5:0");
+    }
+
+    #[test]
+    fn test_rename_moves_penalty_and_history() {
+        let mut con = establish_connection();
+        let old_key = "redis-shield::test_key_rename_penalty_old";
+        let new_key = "redis-shield::test_key_rename_penalty_new";
+        let companion = |key: &str, part: &str| format!("{{{}}}:{}", key, part);
+
+        let _: () = con.del(&[old_key, new_key]).unwrap();
+        for key in [old_key, new_key] {
+            let _: () = con
+                .del(&[companion(key, "penalty"), companion(key, "history")])
+                .unwrap();
+        }
+
+        for expected in [0, -1] {
+            let remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+                .arg(old_key)
+                .arg(1)
+                .arg(60)
+                .arg("PENALTY")
+                .arg("exponential")
+                .arg(30)
+                .arg("HISTORY")
+                .arg(5)
+                .query(&mut con)
+                .unwrap();
+            assert_eq!(remaining_tokens, expected);
+        }
+
+        let renamed: i64 = redis::cmd(super::RENAME_COMMAND)
+            .arg(old_key)
+            .arg(new_key)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(renamed, 1);
+
+        for part in ["penalty", "history"] {
+            let exists: bool = con.exists(companion(old_key, part)).unwrap();
+            assert!(!exists);
+            let exists: bool = con.exists(companion(new_key, part)).unwrap();
+            assert!(exists);
+        }
+        let history: Vec<Vec<i64>> = redis::cmd(super::HISTORY_COMMAND)
+            .arg(new_key)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(history.len(), 2);
+    }
+
+    #[test]
+    fn test_copy_clones_state() {
+        let mut con = establish_connection();
+        let source_key = "redis-shield::test_key_copy_source";
+        let destination_key = "redis-shield::test_key_copy_destination";
+
+        let _: () = con.del(&[source_key, destination_key]).unwrap();
+        let _: () = con.set(destination_key, "1:0").unwrap();
+
+        let _: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(source_key)
+            .arg(10)
+            .arg(60)
+            .arg(4)
+            .query(&mut con)
+            .unwrap();
+
+        let copied: i64 = redis::cmd(super::COPY_COMMAND)
+            .arg(source_key)
+            .arg(destination_key)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(copied, 1);
+
+        let source: String = con.get(source_key).unwrap();
+        let destination: String = con.get(destination_key).unwrap();
+        assert_eq!(source, destination);
+        let ttl: i64 = con.pttl(destination_key).unwrap();
+        assert!((59900..=60000).contains(&ttl));
+
+        // Both keys are limited independently from now on
+        let remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(destination_key)
+            .arg(10)
+            .arg(60)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 5);
+        assert_eq!(stored_tokens(&mut con, source_key), 6);
+    }
+
+    #[test]
+    fn test_mergekeys_sums_usage() {
+        let mut con = establish_connection();
+        let keys = [
+            "redis-shield::test_key_merge_a",
+            "redis-shield::test_key_merge_b",
+            "redis-shield::test_key_merge_dest",
+        ];
+
+        let _: () = con.del(&keys).unwrap();
+
+        for (key, tokens) in [(keys[0], 3), (keys[1], 4)] {
+            let _: i64 = redis::cmd(super::REDIS_COMMAND)
+                .arg(key)
+                .arg(10)
+                .arg(60)
+                .arg(tokens)
+                .query(&mut con)
+                .unwrap();
+        }
+
+        let mut remaining_tokens: i64 = redis::cmd(super::MERGE_COMMAND)
+            .arg(keys[0])
+            .arg(keys[1])
+            .arg(keys[2])
+            .arg(10)
+            .arg(60)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 3);
+        assert_eq!(stored_tokens(&mut con, keys[2]), 3);
+
+        // The combined usage is capped at the capacity
+        remaining_tokens = redis::cmd(super::MERGE_COMMAND)
+            .arg(keys[0])
+            .arg(keys[2])
+            .arg(keys[2])
+            .arg(10)
+            .arg(60)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 0);
+    }
+
+    #[test]
+    fn test_history_keeps_latest_decisions() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_history";
+        let history_key = format!("{{{}}}:history", bucket_key);
+
+        let _: () = con.del(&[bucket_key, &history_key]).unwrap();
+
+        for tokens in [4, 5, 3, 2] {
+            let _: i64 = redis::cmd(super::REDIS_COMMAND)
+                .arg(bucket_key)
+                .arg(10)
+                .arg(60)
+                .arg(tokens)
+                .arg("HISTORY")
+                .arg(3)
+                .query(&mut con)
+                .unwrap();
+        }
+
+        let history: Vec<Vec<i64>> = redis::cmd(super::HISTORY_COMMAND)
+            .arg(bucket_key)
+            .query(&mut con)
+            .unwrap();
+        let decisions: Vec<&[i64]> = history.iter().map(|entry| &entry[1..]).collect();
+        assert_eq!(decisions, vec![&[2, 0][..], &[3, 0], &[5, 1]]);
+        assert!(history[0][0] >= history[2][0]);
+    }
+
+    #[test]
+    fn test_history_removed_with_expired_bucket() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_history_expired";
+        let history_key = format!("{{{}}}:history", bucket_key);
+
+        let _: () = con.del(&[bucket_key, &history_key]).unwrap();
+
+        let _: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(10)
+            .arg(1)
+            .arg("HISTORY")
+            .arg(5)
+            .query(&mut con)
+            .unwrap();
+        let exists: bool = con.exists(&history_key).unwrap();
+        assert!(exists);
+
+        thread::sleep(time::Duration::from_millis(1100));
+        // Accessing the key makes redis notice it expired
+        let exists: bool = con.exists(bucket_key).unwrap();
+        assert!(!exists);
+
+        let exists: bool = con.exists(&history_key).unwrap();
+        assert!(!exists);
+    }
+
+    #[test]
+    fn test_member_history_removed_with_expired_group() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_history_group_expired";
+        let member = "redis-shield::test_key_history_group_member";
+        let history_key = format!("{{{}}}:history", member);
+        let members_key = format!("{{{}}}:history:members", bucket_key);
+
+        let _: () = con.del(&[bucket_key, &history_key, &members_key]).unwrap();
+
+        let _: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(member)
+            .arg(10)
+            .arg(1)
+            .arg("GROUP")
+            .arg(bucket_key)
+            .arg("HISTORY")
+            .arg(5)
+            .query(&mut con)
+            .unwrap();
+        let exists: bool = con.exists(&history_key).unwrap();
+        assert!(exists);
+
+        thread::sleep(time::Duration::from_millis(1100));
+        // Accessing the key makes redis notice it expired
+        let exists: bool = con.exists(bucket_key).unwrap();
+        assert!(!exists);
+
+        let exists: i64 = con.exists(&[&history_key, &members_key]).unwrap();
+        assert_eq!(exists, 0);
+    }
+
+    #[test]
+    fn test_notify_when_refilled() {
+        let mut con = establish_connection();
+        let mut subscriber = establish_connection();
+        let bucket_key = "redis-shield::test_key_notify";
+        let channel = "redis-shield::test_channel_notify";
+
+        let _: () = con
+            .del(&[bucket_key, &format!("{{{}}}:notify", bucket_key)])
+            .unwrap();
+        let mut pubsub = subscriber.as_pubsub();
+        pubsub.subscribe(channel).unwrap();
+        pubsub
+            .set_read_timeout(Some(time::Duration::from_secs(3)))
+            .unwrap();
+
+        for expected in [0, -1, -1] {
+            let remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+                .arg(bucket_key)
+                .arg(1)
+                .arg(1)
+                .arg("NOTIFY")
+                .arg(channel)
+                .query(&mut con)
+                .unwrap();
+            assert_eq!(remaining_tokens, expected);
+        }
+
+        // Only one message is scheduled for repeated denials
+        let message = pubsub.get_message().unwrap();
+        assert_eq!(message.get_payload::<String>().unwrap(), bucket_key);
+        pubsub
+            .set_read_timeout(Some(time::Duration::from_millis(500)))
+            .unwrap();
+        assert!(pubsub.get_message().is_err());
+    }
+
+    #[test]
+    fn test_headers_output() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_headers";
+
+        let _: () = con.del(bucket_key).unwrap();
+
+        let started_at = server_time(&mut con);
+        let mut headers: Vec<String> = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(30)
+            .arg(60)
+            .arg(27)
+            .arg("OUTPUT")
+            .arg("headers")
+            .query(&mut con)
+            .unwrap();
+        // 27 used tokens are refilled in 54 seconds
+        let reset_at: i64 = headers[7].parse().unwrap();
+        assert!((started_at + 54000..=server_time(&mut con) + 54000).contains(&reset_at));
+        assert_eq!(
+            headers[..7],
+            [
+                "RateLimit-Limit",
+                "30",
+                "RateLimit-Remaining",
+                "3",
+                "RateLimit-Reset",
+                "54",
+                "RateLimit-Reset-At"
+            ]
+        );
+
+        headers = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(30)
+            .arg(60)
+            .arg(5)
+            .arg("OUTPUT")
+            .arg("headers")
+            .query(&mut con)
+            .unwrap();
+        // The deadline doesn't move while the bucket isn't written to
+        let reset_at_again: i64 = headers.remove(7).parse().unwrap();
+        assert!((reset_at - 1..=reset_at + 1).contains(&reset_at_again));
+        // 2 missing tokens are refilled in 4 seconds
+        assert_eq!(
+            headers,
+            vec![
+                "RateLimit-Limit",
+                "30",
+                "RateLimit-Remaining",
+                "0",
+                "RateLimit-Reset",
+                "54",
+                "RateLimit-Reset-At",
+                "Retry-After",
+                "4"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_simple_output() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_simple_output";
+
+        let _: () = con.del(bucket_key).unwrap();
+
+        let absorb = |con: &mut redis::Connection, tokens: i64| -> Vec<i64> {
+            redis::cmd(super::REDIS_COMMAND)
+                .arg(bucket_key)
+                .arg(10)
+                .arg(60)
+                .arg(tokens)
+                .arg("OUTPUT")
+                .arg("simple")
+                .arg("SOFT")
+                .arg(50)
+                .query(con)
+                .unwrap()
+        };
+        // The decision replaces the tokens left, followed by the warning
+        assert_eq!(absorb(&mut con, 6), vec![1, 1]);
+        assert_eq!(absorb(&mut con, 5), vec![0, 1]);
+        assert_eq!(stored_tokens(&mut con, bucket_key), 4);
+    }
+
+    #[test]
+    fn test_min_interval() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_min_interval";
+
+        let _: () = con
+            .del(&[bucket_key, &format!("{{{}}}:interval", bucket_key)])
+            .unwrap();
+
+        for expected in [29, -1] {
+            let remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+                .arg(bucket_key)
+                .arg(30)
+                .arg(60)
+                .arg("MININTERVAL")
+                .arg(500)
+                .query(&mut con)
+                .unwrap();
+            assert_eq!(remaining_tokens, expected);
+        }
+
+        let result: Vec<i64> = redis::cmd(super::SIMULATE_COMMAND)
+            .arg(bucket_key)
+            .arg(30)
+            .arg(60)
+            .arg("MININTERVAL")
+            .arg(500)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(result[0..2], [0, 29]);
+        assert!((1..=500).contains(&result[2]));
+
+        thread::sleep(time::Duration::from_millis(600));
+
+        let remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(30)
+            .arg(60)
+            .arg("MININTERVAL")
+            .arg(500)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 28);
+    }
+
+    #[test]
+    fn test_penalty_doubles_cooldown() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_penalty";
+        let penalty_key = format!("{{{}}}:penalty", bucket_key);
+
+        let _: () = con.del(&[bucket_key, &penalty_key]).unwrap();
+
+        let absorb = |con: &mut redis::Connection| -> i64 {
+            redis::cmd(super::REDIS_COMMAND)
+                .arg(bucket_key)
+                .arg(1)
+                .arg(60)
+                .arg("PENALTY")
+                .arg("exponential")
+                .arg(30)
+                .query(con)
+                .unwrap()
+        };
+        assert_eq!(absorb(&mut con), 0);
+        assert_eq!(absorb(&mut con), -1);
+
+        // The refilled token can't be taken during the cool-down
+        let _: i64 = redis::cmd(super::SET_COMMAND)
+            .arg(bucket_key)
+            .arg(1)
+            .arg(60)
+            .arg(1)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(absorb(&mut con), -1);
+
+        let penalty: String = con.get(&penalty_key).unwrap();
+        assert!(penalty.starts_with("2:"));
+        let result: Vec<i64> = redis::cmd(super::SIMULATE_COMMAND)
+            .arg(bucket_key)
+            .arg(1)
+            .arg(60)
+            .arg("PENALTY")
+            .arg("exponential")
+            .arg(30)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(result[0..2], [0, 1]);
+        assert!((1000..=2000).contains(&result[2]));
+    }
+
+    #[test]
+    #[should_panic(expected = "SHIELD_BADPENALTY: penalty must be exponential")]
+    fn test_unknown_penalty() {
+        let mut con = establish_connection();
+        let _: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg("redis-shield::test_key_penalty")
+            .arg(1)
+            .arg(60)
+            .arg("PENALTY")
+            .arg("linear")
+            .arg(30)
+            .query(&mut con)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_ban_denies_requests_until_lifted() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_banned";
+
+        let _: () = con.del(bucket_key).unwrap();
+        let _: i64 = redis::cmd(super::UNBAN_COMMAND)
+            .arg(bucket_key)
+            .query(&mut con)
+            .unwrap();
+
+        let _: () = redis::cmd(super::BAN_COMMAND)
+            .arg(bucket_key)
+            .arg(60000)
+            .query(&mut con)
+            .unwrap();
+        let remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(30)
+            .arg(60)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, -1);
+        let exists: bool = con.exists(bucket_key).unwrap();
+        assert!(!exists);
+
+        let banned: Vec<(String, i64)> = redis::cmd(super::BANLIST_COMMAND)
+            .arg("MATCH")
+            .arg("redis-shield::test_key_ban*")
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(banned.len(), 1);
+        assert_eq!(banned[0].0, bucket_key);
+        assert!((1..=60000).contains(&banned[0].1));
+        // The key is tracked in its own slot rather than a global index
+        let indexed: bool = con.exists("shield:bans").unwrap();
+        assert!(!indexed);
+
+        let unbanned: i64 = redis::cmd(super::UNBAN_COMMAND)
+            .arg(bucket_key)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(unbanned, 1);
+        let remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(30)
+            .arg(60)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 29);
+    }
+
+    #[cfg(feature = "plugins")]
+    #[test]
+    fn test_ban_applies_to_plugins() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_banned_plugin";
+
+        let _: () = redis::cmd(super::BAN_COMMAND)
+            .arg(bucket_key)
+            .arg(60000)
+            .query(&mut con)
+            .unwrap();
+        let banned: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(30)
+            .arg(60)
+            .arg("ALGORITHM")
+            .arg("unlimited")
+            .query(&mut con)
+            .unwrap();
+        let _: i64 = redis::cmd(super::UNBAN_COMMAND)
+            .arg(bucket_key)
+            .query(&mut con)
+            .unwrap();
+        let admitted: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(30)
+            .arg(60)
+            .arg("ALGORITHM")
+            .arg("unlimited")
+            .query(&mut con)
+            .unwrap();
+
+        assert_eq!(banned, -1);
+        assert_eq!(admitted, 29);
+    }
+
+    #[test]
+    fn test_greylist_delay_grows_with_pressure() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_greylist";
+        let greylist_key = format!("{{{}}}:greylist", bucket_key);
+
+        let _: () = con.del(&[bucket_key, &greylist_key]).unwrap();
+
+        let mut replies = Vec::new();
+        for _ in 0..4 {
+            let reply: Vec<i64> = redis::cmd(super::REDIS_COMMAND)
+                .arg(bucket_key)
+                .arg(2)
+                .arg(60)
+                .arg("GREYLIST")
+                .arg(45000)
+                .query(&mut con)
+                .unwrap();
+            replies.push(reply);
+        }
+        assert_eq!(replies[0], vec![1, 0]);
+        assert_eq!(replies[1], vec![0, 0]);
+        // A token is refilled in 30 seconds, the second greylisted request
+        // waits twice as long, capped at the maximum
+        assert_eq!(replies[2][0], -1);
+        assert!((29900..=30000).contains(&replies[2][1]));
+        assert_eq!(replies[3], vec![-1, 45000]);
+
+        let pressure: i64 = con.get(&greylist_key).unwrap();
+        assert_eq!(pressure, 2);
+    }
+
+    #[test]
+    fn test_refill_step_carries_unfinished_step() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_refill_step";
+
+        let _: () = con.del(bucket_key).unwrap();
+
+        let absorb = |con: &mut redis::Connection, tokens: i64| -> i64 {
+            redis::cmd(super::REDIS_COMMAND)
+                .arg(bucket_key)
+                .arg(10)
+                .arg(2)
+                .arg(tokens)
+                .arg("REFILL_STEP")
+                .arg(1000)
+                .query(con)
+                .unwrap()
+        };
+        assert_eq!(absorb(&mut con, 10), 0);
+
+        thread::sleep(time::Duration::from_millis(500));
+        let result: Vec<i64> = redis::cmd(super::SIMULATE_COMMAND)
+            .arg(bucket_key)
+            .arg(10)
+            .arg(2)
+            .arg("REFILL_STEP")
+            .arg(1000)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(result[0..2], [0, 0]);
+        assert!((1..=500).contains(&result[2]));
+
+        // 5 tokens are refilled once the first step is complete
+        thread::sleep(time::Duration::from_millis(700));
+        assert_eq!(absorb(&mut con, 1), 4);
+
+        // The 200 milliseconds of the second step elapsed before the last
+        // write count towards it
+        thread::sleep(time::Duration::from_millis(900));
+        assert_eq!(absorb(&mut con, 1), 8);
+    }
+
+    #[test]
+    #[should_panic(expected = "SHIELD_BADREFILL_STEP: refill_step must not exceed the period")]
+    fn test_refill_step_exceeds_period() {
+        let mut con = establish_connection();
+        let _: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg("redis-shield::test_key_refill_step")
+            .arg(10)
+            .arg(2)
+            .arg("REFILL_STEP")
+            .arg(3000)
             .query(&mut con)
             .unwrap();
     }
 
     #[test]
-    #[should_panic(
-        expected = "An error was signalled by the server - ResponseError: capacity is not positive integer"
-    )]
-    fn test_capacity_is_negative_integer() {
+    fn test_refill_curves() {
         let mut con = establish_connection();
-        let bucket_key = "redis-shield::test_key_new";
+        let keys = [
+            ("redis-shield::test_key_curve_frontloaded", "frontloaded", 8),
+            ("redis-shield::test_key_curve_backloaded", "backloaded", 2),
+        ];
 
-        let _: () = redis::cmd(super::REDIS_COMMAND)
-            .arg(bucket_key)
-            .arg(-2)
-            .arg(60)
-            .query(&mut con)
-            .unwrap();
+        for (bucket_key, curve, _) in keys {
+            let _: () = con.del(bucket_key).unwrap();
+            let remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+                .arg(bucket_key)
+                .arg(10)
+                .arg(2)
+                .arg(10)
+                .arg("CURVE")
+                .arg(curve)
+                .query(&mut con)
+                .unwrap();
+            assert_eq!(remaining_tokens, 0);
+        }
+
+        // Half of the period later
+        thread::sleep(time::Duration::from_millis(1050));
+
+        for (bucket_key, curve, refilled) in keys {
+            let result: Vec<i64> = redis::cmd(super::SIMULATE_COMMAND)
+                .arg(bucket_key)
+                .arg(10)
+                .arg(2)
+                .arg(refilled)
+                .arg("CURVE")
+                .arg(curve)
+                .query(&mut con)
+                .unwrap();
+            assert_eq!(result[0..2], [1, 0]);
+        }
     }
 
     #[test]
-    #[should_panic(
-        expected = "An error was signalled by the server - ResponseError: period is not positive integer"
-    )]
-    fn test_period_is_string() {
+    fn test_persistent_bucket_has_no_expire() {
         let mut con = establish_connection();
-        let bucket_key = "redis-shield::test_key_new";
+        let bucket_key = "redis-shield::test_key_persistent";
 
-        let _: () = redis::cmd(super::REDIS_COMMAND)
-            .arg(bucket_key)
-            .arg(10)
-            .arg("abc")
-            .query(&mut con)
-            .unwrap();
+        let _: () = con.del(bucket_key).unwrap();
+
+        for expected in [29, 28] {
+            let remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+                .arg(bucket_key)
+                .arg(30)
+                .arg(60)
+                .arg("PERSISTENT")
+                .query(&mut con)
+                .unwrap();
+            assert_eq!(remaining_tokens, expected);
+        }
+
+        let ttl: i64 = con.pttl(bucket_key).unwrap();
+        assert_eq!(ttl, -1);
+        assert_eq!(stored_tokens(&mut con, bucket_key), 28);
     }
 
     #[test]
-    #[should_panic(
-        expected = "An error was signalled by the server - ResponseError: period is not positive integer"
-    )]
-    fn test_period_is_float() {
+    fn test_admin_commands_keep_persistent_buckets() {
         let mut con = establish_connection();
-        let bucket_key = "redis-shield::test_key_new";
+        let bucket_key = "redis-shield::test_key_persistent_admin";
 
-        let _: () = redis::cmd(super::REDIS_COMMAND)
+        let _: () = con.del(bucket_key).unwrap();
+
+        let _: i64 = redis::cmd(super::REDIS_COMMAND)
             .arg(bucket_key)
-            .arg(10)
-            .arg(6.0)
+            .arg(30)
+            .arg(60)
+            .arg("PERSISTENT")
             .query(&mut con)
             .unwrap();
-    }
-
-    #[test]
-    #[should_panic(
-        expected = "An error was signalled by the server - ResponseError: period is not positive integer"
-    )]
-    fn test_period_is_zero() {
-        let mut con = establish_connection();
-        let bucket_key = "redis-shield::test_key_new";
 
-        let _: () = redis::cmd(super::REDIS_COMMAND)
+        let remaining_tokens: i64 = redis::cmd(super::SET_COMMAND)
             .arg(bucket_key)
+            .arg(30)
+            .arg(60)
             .arg(10)
-            .arg(0)
             .query(&mut con)
             .unwrap();
-    }
+        assert_eq!(remaining_tokens, 10);
+        let ttl: i64 = con.pttl(bucket_key).unwrap();
+        assert_eq!(ttl, -1);
 
-    #[test]
-    #[should_panic(
-        expected = "An error was signalled by the server - ResponseError: period is not positive integer"
-    )]
-    fn test_period_is_negative_integer() {
-        let mut con = establish_connection();
-        let bucket_key = "redis-shield::test_key_new";
+        let touched: i64 = redis::cmd(super::TOUCH_COMMAND)
+            .arg(bucket_key)
+            .arg(5000)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(touched, 1);
+        let ttl: i64 = con.pttl(bucket_key).unwrap();
+        assert_eq!(ttl, -1);
 
-        let _: () = redis::cmd(super::REDIS_COMMAND)
+        let _: i64 = redis::cmd(super::DRAIN_COMMAND)
             .arg(bucket_key)
-            .arg(10)
-            .arg(-4)
+            .arg(30)
+            .arg(60)
             .query(&mut con)
             .unwrap();
+        let ttl: i64 = con.pttl(bucket_key).unwrap();
+        assert_eq!(ttl, -1);
+        assert_eq!(stored_tokens(&mut con, bucket_key), 0);
     }
 
     #[test]
-    #[should_panic(
-        expected = "An error was signalled by the server - ResponseError: tokens is not positive integer"
-    )]
-    fn test_tokens_is_string() {
+    fn test_buckets_share_a_hash_with_field() {
         let mut con = establish_connection();
-        let bucket_key = "redis-shield::test_key_new";
+        let entity_key = "redis-shield::test_key_field";
 
-        let _: () = redis::cmd(super::REDIS_COMMAND)
-            .arg(bucket_key)
-            .arg(10)
-            .arg(60)
-            .arg("abc")
+        let _: () = con.del(entity_key).unwrap();
+
+        for (field, capacity, expected) in
+            [("orders", 30, 29), ("search", 10, 9), ("orders", 30, 28)]
+        {
+            let remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+                .arg(entity_key)
+                .arg(capacity)
+                .arg(60)
+                .arg("FIELD")
+                .arg(field)
+                .query(&mut con)
+                .unwrap();
+            assert_eq!(remaining_tokens, expected);
+        }
+
+        let key_type: String = redis::cmd("TYPE").arg(entity_key).query(&mut con).unwrap();
+        assert_eq!(key_type, "hash");
+        let fields: Vec<String> = con.hkeys(entity_key).unwrap();
+        assert_eq!(fields.len(), 2);
+        let value: String = con.hget(entity_key, "orders").unwrap();
+        assert!(value.starts_with("28:"));
+        let ttl: i64 = con.pttl(entity_key).unwrap();
+        assert!(ttl > 0 || ttl == -1);
+
+        // The limit is read from the field when omitted
+        let remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(entity_key)
+            .arg("FIELD")
+            .arg("search")
             .query(&mut con)
             .unwrap();
+        assert_eq!(remaining_tokens, 8);
     }
 
     #[test]
-    #[should_panic(
-        expected = "An error was signalled by the server - ResponseError: tokens is not positive integer"
-    )]
-    fn test_tokens_is_float() {
+    fn test_field_tiers_and_nx() {
         let mut con = establish_connection();
-        let bucket_key = "redis-shield::test_key_new";
+        let entity_key = "redis-shield::test_key_field_tiers";
 
-        let _: () = redis::cmd(super::REDIS_COMMAND)
-            .arg(bucket_key)
-            .arg(10)
+        let _: () = con.del(entity_key).unwrap();
+
+        let remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(entity_key)
+            .arg(30)
             .arg(60)
-            .arg(3.1)
+            .arg("NX")
+            .arg("FIELD")
+            .arg("orders")
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, -2);
+
+        let remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(entity_key)
+            .arg(30)
+            .arg(60)
+            .arg(5)
+            .arg("TIER")
+            .arg(100)
+            .arg(3600)
+            .arg("FIELD")
+            .arg("orders")
             .query(&mut con)
             .unwrap();
+        assert_eq!(remaining_tokens, 25);
+
+        let mut fields: Vec<String> = con.hkeys(entity_key).unwrap();
+        fields.sort();
+        assert_eq!(fields, ["orders", "orders:3600"]);
+        let exists: i64 = con.exists(format!("{{{}}}:3600", entity_key)).unwrap();
+        assert_eq!(exists, 0);
     }
 
     #[test]
-    #[should_panic(
-        expected = "An error was signalled by the server - ResponseError: tokens is not positive integer"
-    )]
-    fn test_tokens_is_zero() {
+    fn test_max_idle_expires_key_before_refill() {
         let mut con = establish_connection();
-        let bucket_key = "redis-shield::test_key_new";
+        let bucket_key = "redis-shield::test_key_max_idle";
 
-        let _: () = redis::cmd(super::REDIS_COMMAND)
+        let _: () = con.del(bucket_key).unwrap();
+
+        let remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
             .arg(bucket_key)
+            .arg(30)
+            .arg(3600)
             .arg(10)
+            .arg("MAXIDLE")
             .arg(60)
-            .arg(0)
             .query(&mut con)
             .unwrap();
-    }
+        assert_eq!(remaining_tokens, 20);
 
-    #[test]
-    #[should_panic(
-        expected = "An error was signalled by the server - ResponseError: tokens is not positive integer"
-    )]
-    fn test_tokens_is_negative_integer() {
-        let mut con = establish_connection();
-        let bucket_key = "redis-shield::test_key_new";
+        let ttl: i64 = con.pttl(bucket_key).unwrap();
+        assert!((59900..=60000).contains(&ttl));
 
-        let _: () = redis::cmd(super::REDIS_COMMAND)
+        // The refill still follows the period while the key exists
+        let remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
             .arg(bucket_key)
+            .arg(30)
+            .arg(3600)
             .arg(10)
+            .arg("MAXIDLE")
             .arg(60)
-            .arg(-9)
             .query(&mut con)
             .unwrap();
+        assert_eq!(remaining_tokens, 10);
+        assert_eq!(stored_tokens(&mut con, bucket_key), 10);
     }
 
     #[test]
-    fn test_bucket_does_not_exist() {
+    fn test_nx_refuses_unknown_key() {
         let mut con = establish_connection();
-        let bucket_key = "redis-shield::test_key_new";
+        let bucket_key = "redis-shield::test_key_nx";
 
         let _: () = con.del(bucket_key).unwrap();
 
@@ -306,38 +6357,65 @@ mod tests {
             .arg(bucket_key)
             .arg(30)
             .arg(60)
+            .arg("NX")
             .query(&mut con)
             .unwrap();
-        assert_eq!(remaining_tokens, 29);
+        assert_eq!(remaining_tokens, -2);
+        let exists: bool = con.exists(bucket_key).unwrap();
+        assert!(!exists);
 
-        let ttl: i64 = con.pttl(bucket_key).unwrap();
-        assert!(ttl >= 59900 && ttl <= 60000);
+        let remaining_tokens: i64 = redis::cmd(super::SET_COMMAND)
+            .arg(bucket_key)
+            .arg(30)
+            .arg(60)
+            .arg(30)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 30);
+
+        let remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(30)
+            .arg(60)
+            .arg(5)
+            .arg("NX")
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 25);
     }
 
     #[test]
-    fn test_bucket_exists_but_has_no_ttl() {
+    #[should_panic(expected = "SHIELD_CONFLICT: capacity or period differs from the stored one")]
+    fn test_strict_config_rejects_different_limit() {
         let mut con = establish_connection();
-        let bucket_key = "redis-shield::test_key_no_expire";
+        let bucket_key = "redis-shield::test_key_strict_error";
 
         let _: () = con.del(bucket_key).unwrap();
-        let _: () = con.set(bucket_key, 2).unwrap();
 
         let remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
             .arg(bucket_key)
             .arg(30)
             .arg(60)
+            .arg("STRICTCONFIG")
+            .arg("error")
             .query(&mut con)
             .unwrap();
         assert_eq!(remaining_tokens, 29);
 
-        let ttl: i64 = con.pttl(bucket_key).unwrap();
-        assert!(ttl >= 59900 && ttl <= 60000);
+        let _: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(30)
+            .arg(120)
+            .arg("STRICTCONFIG")
+            .arg("error")
+            .query(&mut con)
+            .unwrap();
     }
 
     #[test]
-    fn test_multiple_tokens_requested() {
+    fn test_strict_config_resets_bucket() {
         let mut con = establish_connection();
-        let bucket_key = "redis-shield::test_key_multiple_tokens";
+        let bucket_key = "redis-shield::test_key_strict_reset";
 
         let _: () = con.del(bucket_key).unwrap();
 
@@ -345,116 +6423,193 @@ mod tests {
             .arg(bucket_key)
             .arg(30)
             .arg(60)
-            .arg(25)
+            .arg(30)
             .query(&mut con)
             .unwrap();
-        assert_eq!(remaining_tokens, 5);
-    }
-
-    #[test]
-    fn test_bucket_is_overflown() {
-        let mut con = establish_connection();
-        let bucket_key = "redis-shield::test_key_overflown";
-
-        let _: () = con.del(bucket_key).unwrap();
+        assert_eq!(remaining_tokens, 0);
 
+        // The same limit keeps the stored state
         let remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
             .arg(bucket_key)
             .arg(30)
             .arg(60)
-            .arg(31)
+            .arg("STRICTCONFIG")
+            .arg("reset")
             .query(&mut con)
             .unwrap();
         assert_eq!(remaining_tokens, -1);
+
+        let remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+            .arg(bucket_key)
+            .arg(10)
+            .arg(60)
+            .arg("STRICTCONFIG")
+            .arg("reset")
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(remaining_tokens, 9);
+
+        let value: String = con.get(bucket_key).unwrap();
+        assert!(value.ends_with(":10:60:tb"));
     }
 
     #[test]
-    fn test_sequential_requests() {
+    fn test_history_of_unknown_key() {
         let mut con = establish_connection();
-        let bucket_key = "redis-shield::test_key_sequential_requests";
-        let tokens = 2;
-        let period = 60;
+        let bucket_key = "redis-shield::test_key_history_unknown";
 
-        let _: () = con.del(bucket_key).unwrap();
+        let _: () = con.del(format!("{{{}}}:history", bucket_key)).unwrap();
 
-        let mut remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
+        let history: Vec<Vec<i64>> = redis::cmd(super::HISTORY_COMMAND)
             .arg(bucket_key)
-            .arg(tokens)
-            .arg(period)
             .query(&mut con)
             .unwrap();
-        assert_eq!(remaining_tokens, 1);
+        assert!(history.is_empty());
+    }
 
-        let mut ttl: i64 = con.pttl(bucket_key).unwrap();
-        assert!(ttl >= 59900 && ttl <= 60000);
+    #[test]
+    fn test_key_format_defaults() {
+        let mut con = establish_connection();
 
-        remaining_tokens = redis::cmd(super::REDIS_COMMAND)
+        let prefix: Vec<String> = redis::cmd("CONFIG")
+            .arg("GET")
+            .arg("shield.key-prefix")
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(prefix, vec!["shield.key-prefix", "shield"]);
+
+        let separator: Vec<String> = redis::cmd("CONFIG")
+            .arg("GET")
+            .arg("shield.key-separator")
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(separator, vec!["shield.key-separator", ":"]);
+    }
+
+    #[test]
+    fn test_math_helpers_at_extreme_values() {
+        use super::math::{mul_div, mul_div_ceil};
+
+        let values = [i64::MIN, i64::MIN + 1, -1, 0, 1, 2, i64::MAX - 1, i64::MAX];
+        let divisors = [1, 2, 3, 1000, i64::MAX - 1, i64::MAX];
+        let saturate = |value: i128| value.clamp(i64::MIN.into(), i64::MAX.into()) as i64;
+
+        for a in values {
+            for b in values {
+                for c in divisors {
+                    let product = i128::from(a) * i128::from(b);
+                    let floor = mul_div(a.into(), b.into(), c.into());
+                    let ceil = mul_div_ceil(a.into(), b.into(), c.into());
+
+                    assert_eq!(floor, saturate(product / i128::from(c)));
+                    assert_eq!(ceil, saturate(-(-product).div_euclid(c.into())));
+                    assert!(floor == ceil || floor + 1 == ceil);
+                }
+            }
+        }
+        // Differences of two extreme values don't fit into `i64` anymore
+        let missing = i128::from(i64::MAX) - i128::from(i64::MIN);
+        assert_eq!(mul_div(missing, i64::MAX.into(), 1), i64::MAX);
+        assert_eq!(mul_div(-missing, i64::MAX.into(), 1), i64::MIN);
+        assert_eq!(mul_div_ceil(missing, i128::MAX, 1), i64::MAX);
+    }
+
+    #[test]
+    fn test_ttl_jitter_only_extends() {
+        use super::state::jitter;
+
+        assert_eq!(jitter(60000, 0, 12345), 60000);
+        assert_eq!(jitter(60000, 10, 0), 60000);
+        assert_eq!(jitter(60000, 10, 6000), 66000);
+        assert_eq!(jitter(60000, 10, 6001), 60000);
+        assert_eq!(jitter(i64::MAX, 100, u64::MAX), i64::MAX);
+        for seed in [1, 7, 999, u64::MAX / 3, u64::MAX] {
+            assert!((60000..=66000).contains(&jitter(60000, 10, seed)));
+        }
+    }
+
+    #[test]
+    fn test_extreme_capacity_and_period() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_extreme";
+
+        let _: () = con.del(bucket_key).unwrap();
+
+        let mut remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
             .arg(bucket_key)
-            .arg(tokens)
-            .arg(period)
+            .arg(i64::MAX)
+            .arg(i64::MAX)
+            .arg(i64::MAX)
             .query(&mut con)
             .unwrap();
         assert_eq!(remaining_tokens, 0);
 
-        ttl = con.pttl(bucket_key).unwrap();
-        assert!(ttl >= 59900 && ttl <= 60000);
-
         remaining_tokens = redis::cmd(super::REDIS_COMMAND)
             .arg(bucket_key)
-            .arg(tokens)
-            .arg(period)
+            .arg(i64::MAX)
+            .arg(i64::MAX)
+            .arg(i64::MAX)
             .query(&mut con)
             .unwrap();
         assert_eq!(remaining_tokens, -1);
 
-        ttl = con.pttl(bucket_key).unwrap();
-        assert!(ttl >= 59900 && ttl <= 60000);
+        let ttl: i64 = con.pttl(bucket_key).unwrap();
+        assert!(ttl > 0);
     }
 
     #[test]
-    fn test_bucket_refills_with_time() {
+    fn test_extreme_overdraft() {
         let mut con = establish_connection();
-        let bucket_key = "redis-shield::test_key_refill";
-        let tokens = 3;
-        let period = 6;
+        let bucket_key = "redis-shield::test_key_extreme_overdraft";
 
         let _: () = con.del(bucket_key).unwrap();
 
         let mut remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
             .arg(bucket_key)
-            .arg(tokens)
-            .arg(period)
+            .arg(10)
+            .arg(60)
+            .arg(i64::MAX)
+            .arg("OVERDRAFT")
+            .arg(i64::MAX)
             .query(&mut con)
             .unwrap();
-        assert_eq!(remaining_tokens, 2);
-
-        thread::sleep(time::Duration::from_secs(period / 3 + 1));
+        assert_eq!(remaining_tokens, 0);
+        assert_eq!(stored_tokens(&mut con, bucket_key), 10 - i64::MAX);
 
         remaining_tokens = redis::cmd(super::REDIS_COMMAND)
             .arg(bucket_key)
-            .arg(tokens)
-            .arg(period)
+            .arg(10)
+            .arg(60)
             .query(&mut con)
             .unwrap();
-        assert_eq!(remaining_tokens, 2);
+        assert_eq!(remaining_tokens, -1);
 
-        remaining_tokens = redis::cmd(super::REDIS_COMMAND)
+        // Paying down the debt takes longer than `i64` can express
+        let result: Vec<i64> = redis::cmd(super::SIMULATE_COMMAND)
             .arg(bucket_key)
-            .arg(tokens)
-            .arg(period)
-            .arg(2)
+            .arg(10)
+            .arg(60)
             .query(&mut con)
             .unwrap();
-        assert_eq!(remaining_tokens, 0);
+        assert_eq!(result[0..2], [0, 0]);
+        assert!(result[2] > i64::MAX - 60000);
+    }
 
-        thread::sleep(time::Duration::from_secs(6));
+    #[test]
+    fn test_extreme_capacity_with_low_priority() {
+        let mut con = establish_connection();
+        let bucket_key = "redis-shield::test_key_extreme_priority";
 
-        remaining_tokens = redis::cmd(super::REDIS_COMMAND)
+        let _: () = con.del(bucket_key).unwrap();
+
+        let remaining_tokens: i64 = redis::cmd(super::REDIS_COMMAND)
             .arg(bucket_key)
-            .arg(tokens)
-            .arg(period)
+            .arg(i64::MAX)
+            .arg(60)
+            .arg("PRIORITY")
+            .arg("low")
             .query(&mut con)
             .unwrap();
-        assert_eq!(remaining_tokens, 2);
+        assert_eq!(remaining_tokens, i64::MAX - 1);
     }
 }
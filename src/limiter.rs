@@ -0,0 +1,351 @@
+use crate::bucket::Bucket;
+use crate::command_parser::{CommandArgs, Limit, Priority, StrictConfig};
+use crate::curve::Curve;
+use crate::error::{self, error};
+use crate::group::MemberStats;
+use crate::history::History;
+use crate::keys::{companion_key, derived_key};
+use crate::math::{millis, mul_div};
+use crate::notification::Notification;
+use crate::parent;
+use crate::penalty::Penalty;
+use crate::policy::Usage;
+use crate::retry_budget::RetryBudget;
+use crate::shard::{self, Shards};
+use crate::spacing::Spacing;
+use crate::strings;
+use crate::trace;
+use redis_module::{Context, RedisError, RedisString, RedisValue};
+use std::cmp::max;
+
+const OVERFLOWN_RESPONSE: i64 = -1;
+const MAX_THRESHOLD: i64 = 100;
+pub const WARMUP_PART: &[u8] = b"warmup";
+// Share of capacity a bucket starts with when it warms up
+const WARMUP_INITIAL_SHARE: f64 = 0.1;
+
+/// A set of buckets enforcing several limits for the same key,
+/// e.g. 10 requests per second and 300 requests per minute.
+///
+/// The bucket of the first limit is stored under the key itself, each tier
+/// is stored under `<key>:<period>`. With `FIELD` they are stored in the fields
+/// `<field>:<period>` of the hash `key` instead. With `SHARDS` the buckets are
+/// stored under the key of the shard the request draws from. With `PARENT` the
+/// bucket of the parent is checked as well. A request conforms only if every bucket
+/// contains sufficient tokens, in which case the tokens are removed from all
+/// of them. Otherwise none of the buckets is changed.
+///
+/// The buckets are staged in memory and written last, after every other record
+/// of the request, so a failure midway, e.g. of a history clobbered by a foreign
+/// value, never leaves some of them decremented. Writing a bucket can't fail
+/// by itself, since its key was read with the same type before.
+///
+/// A warm-up of a new key is tracked by `<key>:warmup`, which expires
+/// when the buckets reach their full capacity.
+pub struct Limiter<'a> {
+    buckets: Vec<Bucket<'a>>,
+    // Warm-up to start with the next poured request
+    pending_warmup: Option<WarmUp>,
+    // Budget of retries the request is checked against
+    retry_budget: Option<RetryBudget>,
+    // Minimum spacing the request is checked against
+    spacing: Option<Spacing>,
+    // Cool-down imposed by the previous denials
+    penalty: Option<Penalty>,
+    // Statistics of the group member the request belongs to
+    member_stats: Option<MemberStats<'a>>,
+    // Latest decisions kept for the key
+    history: Option<History>,
+    // Notification the denied request asks for
+    notification: Option<Notification<'a>>,
+    // Usage of the policy the request applies
+    policy_usage: Option<Usage<'a>>,
+    // Shards the buckets are split into
+    shards: Option<Shards>,
+    // Redis context used to perform redis commands
+    ctx: &'a Context,
+}
+
+/// Keys the buckets of a request are stored under, besides the key itself.
+pub struct BucketKeys {
+    // Key of the shard the request draws from, if the buckets are split
+    shard: Option<RedisString>,
+    // Keys, or fields with `FIELD`, of the tiers
+    tiers: Vec<RedisString>,
+}
+
+struct WarmUp {
+    key: RedisString,
+    period: i64,
+}
+
+impl<'a> Limiter<'a> {
+    /// Returns the keys the buckets of `command` are stored under besides the key
+    /// itself: the shard picked for the request and the keys, or the fields with
+    /// `FIELD`, of the tiers.
+    pub fn bucket_keys(command: &CommandArgs) -> BucketKeys {
+        let shard = Shards::pick(command);
+        let tier_key = |tier: &Limit| match command.field {
+            // Fields of the hash, which is stored in a single slot anyway
+            Some(field) => derived_key(field, &[tier.period.to_string().as_bytes()]),
+            None => companion_key(
+                shard.as_ref().unwrap_or(command.key),
+                &[tier.period.to_string().as_bytes()],
+            ),
+        };
+        BucketKeys {
+            tiers: command.tiers.iter().map(tier_key).collect(),
+            shard,
+        }
+    }
+
+    /// Instantiates buckets for every limit of `command`.
+    ///
+    /// `keys` must be produced by [`Limiter::bucket_keys`].
+    pub fn new(
+        ctx: &'a Context,
+        command: &CommandArgs<'a>,
+        keys: &'a BucketKeys,
+    ) -> Result<Self, RedisError> {
+        trace::span!("create_limiter");
+        let key = keys.shard.as_ref().unwrap_or(command.key);
+        let capacity = |limit: Limit| shard::share(limit.capacity, command.shards);
+        let limit = command.limit;
+        let mut buckets = vec![match command.field {
+            Some(field) => Bucket::in_field(ctx, key, field, capacity(limit), limit.period)?,
+            None => Bucket::new(ctx, key, capacity(limit), limit.period)?,
+        }];
+        for (tier, name) in command.tiers.iter().zip(&keys.tiers) {
+            buckets.push(match command.field {
+                Some(_) => Bucket::in_field(ctx, key, name, capacity(*tier), tier.period)?,
+                None => Bucket::new(ctx, name, capacity(*tier), tier.period)?,
+            });
+        }
+        for bucket in buckets.iter_mut() {
+            if command.refill_step > 0 || command.curve != Curve::Linear {
+                bucket.set_refill(command.refill_step, command.curve);
+            }
+            if command.priority == Priority::Low {
+                bucket.reserved = mul_div(
+                    bucket.capacity.into(),
+                    (MAX_THRESHOLD - command.threshold).into(),
+                    MAX_THRESHOLD.into(),
+                );
+            }
+            bucket.overdraft = command.overdraft;
+            if command.max_idle > 0 {
+                bucket.max_idle = millis(command.max_idle);
+            }
+            bucket.persistent = Some(command.persistent);
+            if bucket.conflicts() {
+                match command.strict_config {
+                    Some(StrictConfig::Error) => {
+                        return Err(error(
+                            error::CONFLICT,
+                            "capacity or period differs from the stored one",
+                        ))
+                    }
+                    Some(StrictConfig::Reset) => bucket.reset(),
+                    None => {}
+                }
+            }
+        }
+
+        let mut limiter = Self {
+            buckets,
+            pending_warmup: None,
+            retry_budget: RetryBudget::load(ctx, command)?,
+            spacing: Spacing::load(ctx, command)?,
+            penalty: Penalty::load(ctx, command)?,
+            member_stats: MemberStats::new(command),
+            history: History::new(command),
+            notification: Notification::new(command),
+            policy_usage: Usage::new(command),
+            shards: Shards::new(command),
+            ctx,
+        };
+        if command.warmup > 0 {
+            limiter.warm_up(command.key, key, command.field, millis(command.warmup))?;
+        }
+        // The parent is added last, so the options of the request don't apply to it
+        if let Some(parent) = command.parent {
+            limiter.buckets.push(parent::bucket(ctx, parent)?);
+        }
+        Ok(limiter)
+    }
+
+    /// Returns `true` if the bucket of the request's own limit was found in
+    /// redis rather than created.
+    pub fn is_stored(&self) -> bool {
+        self.buckets[0].is_stored()
+    }
+
+    /// Attempts to remove requested number of `tokens` from every bucket.
+    ///
+    /// Returns the number of tokens left in the most restrictive bucket,
+    /// or `-1` if any bucket doesn't contain sufficient tokens.
+    pub fn pour(&mut self, tokens: i64) -> Result<i64, RedisError> {
+        let remaining_tokens = {
+            trace::span!("execute");
+            if let Some(warmup) = self.pending_warmup.take() {
+                self.ctx.call(
+                    "PSETEX",
+                    &[
+                        &warmup.key,
+                        &RedisString::create(None, warmup.period.to_string().as_str()),
+                        strings::one(),
+                    ],
+                )?;
+            }
+            match self.retry_after(tokens) {
+                0 => self.admit(tokens)?,
+                wait => {
+                    match &self.notification {
+                        Some(notification) if wait > 0 => notification.schedule(self.ctx, wait)?,
+                        _ => {}
+                    }
+                    OVERFLOWN_RESPONSE
+                }
+            }
+        };
+        trace::span!("persist");
+        let allowed = remaining_tokens != OVERFLOWN_RESPONSE;
+        if let Some(penalty) = &self.penalty {
+            penalty.record(self.ctx, allowed)?;
+        }
+        if let Some(history) = &self.history {
+            history.record(self.ctx, tokens, allowed)?;
+        }
+        if let Some(policy_usage) = &self.policy_usage {
+            policy_usage.record(self.ctx, allowed)?;
+        }
+        if allowed {
+            for bucket in self.buckets.iter_mut() {
+                bucket.commit()?;
+            }
+        }
+        if let Some(shards) = &self.shards {
+            shards.rebalance(self.ctx)?;
+        }
+        Ok(remaining_tokens)
+    }
+
+    /// Returns the number of milliseconds until every bucket holds at least `tokens`,
+    /// the request fits the retry budget and the minimum spacing, and the cool-down
+    /// of the penalty ends.
+    ///
+    /// `-1` means it never happens, e.g. because `tokens` exceeds some bucket's capacity.
+    pub fn retry_after(&self, tokens: i64) -> i64 {
+        let waits = self
+            .buckets
+            .iter()
+            .map(|bucket| bucket.retry_after(tokens))
+            .chain(self.retry_budget.iter().map(RetryBudget::retry_after))
+            .chain(self.spacing.iter().map(Spacing::retry_after))
+            .chain(self.penalty.iter().map(Penalty::retry_after));
+        if waits.clone().any(|wait| wait == OVERFLOWN_RESPONSE) {
+            OVERFLOWN_RESPONSE
+        } else {
+            waits.max().unwrap_or_default()
+        }
+    }
+
+    /// Returns the number of milliseconds until every bucket is full again.
+    pub fn refill_after(&self) -> i64 {
+        self.buckets
+            .iter()
+            .map(Bucket::refill_after)
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// Returns the Unix time in milliseconds at which every bucket is full again,
+    /// so clients caching a decision get a deadline that doesn't go stale.
+    pub fn reset_at(&self) -> i64 {
+        self.buckets
+            .iter()
+            .map(Bucket::reset_at)
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// Returns `true` if more than `percent` of any bucket's capacity is used.
+    pub fn exceeds(&self, percent: i64) -> bool {
+        self.buckets.iter().any(|bucket| {
+            let used = (bucket.capacity - max(bucket.tokens, 0)) as i128;
+            used * MAX_THRESHOLD as i128 > bucket.capacity as i128 * percent as i128
+        })
+    }
+
+    /// Returns the number of tokens left in the most restrictive bucket.
+    ///
+    /// A bucket in debt has no tokens left.
+    pub fn tokens(&self) -> i64 {
+        self.buckets
+            .iter()
+            .map(|bucket| bucket.tokens)
+            .min()
+            .unwrap_or_default()
+            .max(0)
+    }
+
+    // Stages the removal of `tokens` from every bucket, which `pour` commits
+    fn admit(&mut self, tokens: i64) -> Result<i64, RedisError> {
+        let mut remaining_tokens = i64::MAX;
+        for bucket in self.buckets.iter_mut() {
+            remaining_tokens = remaining_tokens.min(bucket.stage(tokens));
+        }
+        if let Some(retry_budget) = &self.retry_budget {
+            retry_budget.record(self.ctx)?;
+        }
+        if let Some(member_stats) = &self.member_stats {
+            member_stats.record(self.ctx, tokens)?;
+        }
+        if let Some(spacing) = &self.spacing {
+            spacing.record(self.ctx)?;
+        }
+        Ok(remaining_tokens)
+    }
+
+    /// Caps every bucket according to the progress of the warm-up of `key`.
+    ///
+    /// The buckets start at a fraction of their capacity, which grows linearly
+    /// to the full capacity over `period` milliseconds. A key is considered new,
+    /// and its warm-up starts over, when `bucket_key` doesn't exist, which differs
+    /// from `key` for a split bucket, or its `field` with `FIELD`.
+    fn warm_up(
+        &mut self,
+        key: &RedisString,
+        bucket_key: &RedisString,
+        field: Option<&RedisString>,
+        period: i64,
+    ) -> Result<(), RedisError> {
+        let warmup_key = companion_key(key, &[WARMUP_PART]);
+        let exists = |ctx: &Context| match field {
+            Some(field) => ctx.call("HEXISTS", &[bucket_key, field]),
+            None => ctx.call("EXISTS", &[bucket_key]),
+        };
+
+        let progress = match self.ctx.call("PTTL", &[&warmup_key])? {
+            RedisValue::Integer(ttl) if ttl > 0 => {
+                (period - ttl.min(period)) as f64 / period as f64
+            }
+            _ => match exists(self.ctx)? {
+                RedisValue::Integer(0) => {
+                    self.pending_warmup = Some(WarmUp {
+                        key: warmup_key,
+                        period,
+                    });
+                    0.0
+                }
+                _ => return Ok(()),
+            },
+        };
+
+        let share = WARMUP_INITIAL_SHARE + (1.0 - WARMUP_INITIAL_SHARE) * progress;
+        for bucket in self.buckets.iter_mut() {
+            bucket.cap(max(1, (bucket.capacity as f64 * share) as i64));
+        }
+        Ok(())
+    }
+}
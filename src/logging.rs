@@ -0,0 +1,91 @@
+use redis_module::Context;
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+/// Value of a key that can't be parsed, reset in lenient mode.
+pub const CORRUPTED: &str = "corrupted";
+/// Key holding a value of the wrong type, reset in lenient mode.
+pub const WRONG_TYPE: &str = "wrongtype";
+/// Notification that couldn't be published.
+pub const NOTIFICATION: &str = "notification";
+
+// Shortest interval between two warnings of the same class for the same key
+const INTERVAL: Duration = Duration::from_secs(10);
+// Number of class-key pairs remembered, beyond which the stale ones are forgotten
+const MAX_TRACKED: usize = 4096;
+
+// Class of a warning and the key it's about
+type Subject = (&'static str, Vec<u8>);
+
+// Time the last warning about a subject was logged at, and the number
+// of warnings suppressed since then
+static LOGGED: LazyLock<Mutex<HashMap<Subject, (Instant, u64)>>> = LazyLock::new(Default::default);
+
+/// Logs a warning of `class` about `key`, unless one was logged less than
+/// 10 seconds ago, so a key that's hammered while it's broken doesn't flood
+/// the log. The next warning logged tells how many were suppressed.
+///
+/// `message` is only built for the warnings that are logged.
+pub fn warn(ctx: &Context, class: &'static str, key: &[u8], message: impl FnOnce() -> String) {
+    match admit(class, key, Instant::now()) {
+        None => {}
+        Some(0) => ctx.log_warning(&format!("redis-shield: {}", message())),
+        Some(suppressed) => ctx.log_warning(&format!(
+            "redis-shield: {} ({} similar warnings suppressed)",
+            message(),
+            suppressed
+        )),
+    }
+}
+
+// Returns the number of warnings suppressed since the last one about the
+// same subject if a warning may be logged `now`, `None` if it's suppressed
+fn admit(class: &'static str, key: &[u8], now: Instant) -> Option<u64> {
+    let mut logged = LOGGED.lock().unwrap();
+    match logged.get_mut(&(class, key.to_vec())) {
+        Some((logged_at, suppressed)) if now.duration_since(*logged_at) < INTERVAL => {
+            *suppressed += 1;
+            None
+        }
+        Some((logged_at, suppressed)) => {
+            *logged_at = now;
+            Some(std::mem::take(suppressed))
+        }
+        None => {
+            if logged.len() >= MAX_TRACKED {
+                logged.retain(|_, (logged_at, _)| now.duration_since(*logged_at) < INTERVAL);
+            }
+            if logged.len() < MAX_TRACKED {
+                logged.insert((class, key.to_vec()), (now, 0));
+            }
+            Some(0)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_warnings_within_interval_are_suppressed_and_counted() {
+        let key = b"redis-shield::test_key_logging";
+        let logged_at = Instant::now();
+
+        assert_eq!(admit(CORRUPTED, key, logged_at), Some(0));
+        assert_eq!(
+            admit(CORRUPTED, key, logged_at + Duration::from_secs(1)),
+            None
+        );
+        assert_eq!(
+            admit(CORRUPTED, key, logged_at + Duration::from_secs(5)),
+            None
+        );
+        // Other classes and keys are throttled on their own
+        assert_eq!(admit(WRONG_TYPE, key, logged_at), Some(0));
+
+        assert_eq!(admit(CORRUPTED, key, logged_at + INTERVAL), Some(2));
+        assert_eq!(admit(CORRUPTED, key, logged_at + INTERVAL), None);
+    }
+}
@@ -0,0 +1,97 @@
+use crate::error;
+use redis_module::configuration::{ConfigurationContext, ConfigurationValue};
+use redis_module::{RedisError, RedisString};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const OFF: &str = "off";
+const ALLOW: &str = "allow";
+const DENY: &str = "deny";
+
+/// Canned decision `SHIELD.absorb` and `SHIELD.absorbbatch` reply with in
+/// static mode, without reading or writing any key, e.g. while the keyspace
+/// is migrated or during an incident.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    Off,
+    AllowAll,
+    DenyAll,
+}
+
+impl Mode {
+    pub fn parse(name: &str) -> Result<Self, RedisError> {
+        match name.to_ascii_lowercase().as_str() {
+            OFF => Ok(Self::Off),
+            ALLOW => Ok(Self::AllowAll),
+            DENY => Ok(Self::DenyAll),
+            _ => Err(error::error(
+                error::SYNTAX,
+                "maintenance mode must be allow, deny or off",
+            )),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Off => OFF,
+            Self::AllowAll => ALLOW,
+            Self::DenyAll => DENY,
+        }
+    }
+}
+
+/// Static mode of the module, set by `CONFIG SET shield.maintenance` or
+/// `SHIELD.maintenance`, the latter optionally for a limited time.
+///
+/// It's held in memory, so it's neither replicated nor persisted.
+pub struct Maintenance {
+    // Mode and the instant it ends at, if it's temporary
+    state: Mutex<(Mode, Option<Instant>)>,
+}
+
+pub static MAINTENANCE: Maintenance = Maintenance {
+    state: Mutex::new((Mode::Off, None)),
+};
+
+impl Maintenance {
+    /// Returns the current mode, `Off` once a temporary one ended.
+    pub fn mode(&self) -> Mode {
+        self.status().0
+    }
+
+    /// Returns the current mode with the time left until it ends,
+    /// `None` if it doesn't.
+    pub fn status(&self) -> (Mode, Option<Duration>) {
+        let mut state = self.state.lock().unwrap();
+        match state.1 {
+            Some(ends_at) => match ends_at.checked_duration_since(Instant::now()) {
+                Some(left) if !left.is_zero() => (state.0, Some(left)),
+                _ => {
+                    *state = (Mode::Off, None);
+                    (Mode::Off, None)
+                }
+            },
+            None => (state.0, None),
+        }
+    }
+
+    /// Switches to `mode`, for `ttl` if given. `Off` is never temporary.
+    pub fn enter(&self, mode: Mode, ttl: Option<Duration>) {
+        let ends_at = match mode {
+            Mode::Off => None,
+            _ => ttl.map(|ttl| Instant::now() + ttl),
+        };
+        *self.state.lock().unwrap() = (mode, ends_at);
+    }
+}
+
+impl ConfigurationValue<RedisString> for Maintenance {
+    fn get(&self, _ctx: &ConfigurationContext) -> RedisString {
+        RedisString::create(None, self.mode().name())
+    }
+
+    fn set(&self, _ctx: &ConfigurationContext, value: RedisString) -> Result<(), RedisError> {
+        self.enter(Mode::parse(&value.to_string_lossy())?, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,49 @@
+// Capacities, periods and token counts come straight from the clients, so
+// they may be anywhere in the `i64` range, e.g. a bandwidth limit in bytes
+// per day. Intermediate results are computed in `i128` and saturate at the
+// bounds of `i64` instead of overflowing, which would otherwise silently
+// admit or deny requests.
+
+const MILLS_IN_SEC: i64 = 1000;
+
+/// Longest duration in milliseconds, longer ones are shortened to it.
+///
+/// It's about 146 million years, so adding it to the current time can't
+/// overflow, and redis accepts it as a TTL.
+pub const MAX_MILLIS: i64 = i64::MAX / 2;
+
+/// Returns `a * b / c` rounded towards zero, saturated to the `i64` range.
+pub fn mul_div(a: i128, b: i128, c: i128) -> i64 {
+    match a.checked_mul(b) {
+        Some(product) => saturate(product / c),
+        None => saturate_sign(a.signum() * b.signum() * c.signum()),
+    }
+}
+
+/// Returns `a * b / c` rounded up, saturated to the `i64` range.
+///
+/// `c` must be positive.
+pub fn mul_div_ceil(a: i128, b: i128, c: i128) -> i64 {
+    match a.checked_mul(b) {
+        Some(product) => saturate(-(-product).div_euclid(c)),
+        None => saturate_sign(a.signum() * b.signum()),
+    }
+}
+
+/// Converts seconds to milliseconds, capped at [`MAX_MILLIS`].
+pub fn millis(seconds: i64) -> i64 {
+    seconds.saturating_mul(MILLS_IN_SEC).min(MAX_MILLIS)
+}
+
+/// Narrows `value` to the `i64` range.
+pub fn saturate(value: i128) -> i64 {
+    value.clamp(i128::from(i64::MIN), i128::from(i64::MAX)) as i64
+}
+
+fn saturate_sign(sign: i128) -> i64 {
+    if sign < 0 {
+        i64::MIN
+    } else {
+        i64::MAX
+    }
+}
@@ -0,0 +1,36 @@
+use crate::config;
+use redis_module::raw;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Requests that would have created a bucket while redis was short on memory.
+static SHED: AtomicU64 = AtomicU64::new(0);
+
+/// Returns whether redis uses more than `shield.memory-threshold` percent
+/// of its `maxmemory`. Never the case without a `maxmemory`.
+pub fn under_pressure() -> bool {
+    let threshold = config::memory_threshold();
+    if threshold == 0 {
+        return false;
+    }
+    let Some(used_memory_ratio) = (unsafe { raw::RedisModule_GetUsedMemoryRatio }) else {
+        return false;
+    };
+    // The ratio is 0 without a `maxmemory`
+    let ratio = unsafe { used_memory_ratio() };
+    ratio * 100.0 > threshold as f32
+}
+
+/// Accounts for a request refused a new bucket, and returns whether it's
+/// admitted anyway according to `shield.memory-fail-open`.
+pub fn shed() -> bool {
+    SHED.fetch_add(1, Ordering::Relaxed);
+    config::memory_fail_open()
+}
+
+pub fn shed_count() -> u64 {
+    SHED.load(Ordering::Relaxed)
+}
+
+pub fn reset_shed_count() {
+    SHED.store(0, Ordering::Relaxed);
+}
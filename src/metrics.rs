@@ -0,0 +1,123 @@
+use crate::aggregator;
+use crate::latency;
+use crate::memory;
+use linkme::distributed_slice;
+use redis_module::server_events::INFO_COMMAND_HANDLER_LIST;
+use redis_module::{InfoContext, RedisResult};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Decisions made by `SHIELD.absorb` for token bucket limiters.
+pub static TOKEN_BUCKET: Counters = Counters::new();
+
+/// Module-wide counters of the decisions made by an algorithm since the
+/// module was loaded.
+///
+/// They are updated with relaxed atomics on the command path, which costs
+/// next to nothing and takes no locks.
+pub struct Counters {
+    allowed: AtomicU64,
+    denied: AtomicU64,
+    // Requests that failed, e.g. because of invalid arguments
+    errors: AtomicU64,
+    // Requests that found the state of their key
+    hits: AtomicU64,
+    // Requests that created the state of their key
+    misses: AtomicU64,
+}
+
+impl Counters {
+    const fn new() -> Self {
+        Self {
+            allowed: AtomicU64::new(0),
+            denied: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Accounts for a request that was allowed or denied.
+    pub fn decide(&self, allowed: bool) {
+        let counter = if allowed { &self.allowed } else { &self.denied };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Accounts for a request that failed.
+    pub fn fail(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Accounts for a request that found the state of its key, or had to
+    /// create it because it never existed or expired.
+    pub fn look_up(&self, found: bool) {
+        let counter = if found { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn allowed(&self) -> u64 {
+        self.allowed.load(Ordering::Relaxed)
+    }
+
+    pub fn denied(&self) -> u64 {
+        self.denied.load(Ordering::Relaxed)
+    }
+
+    pub fn errors(&self) -> u64 {
+        self.errors.load(Ordering::Relaxed)
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    fn reset(&self) {
+        for counter in [
+            &self.allowed,
+            &self.denied,
+            &self.errors,
+            &self.hits,
+            &self.misses,
+        ] {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Zeroes every counter of the module, i.e. the ones reported by `INFO`
+/// and the keys tracked for `SHIELD.top`, e.g. to align them with a deploy.
+pub fn reset() {
+    TOKEN_BUCKET.reset();
+    latency::TOKEN_BUCKET.reset();
+    aggregator::clear();
+    aggregator::reset_dropped();
+    memory::reset_shed_count();
+}
+
+/// Adds the `shield_decisions` section to `INFO`, e.g.
+///
+///     token_bucket_allowed:1000
+///     token_bucket_denied:24
+///     token_bucket_errors:0
+///     token_bucket_hits:980
+///     token_bucket_misses:44
+///     aggregator_dropped:0
+///     memory_shed:0
+#[distributed_slice(INFO_COMMAND_HANDLER_LIST)]
+fn info(ctx: &InfoContext, _for_crash_report: bool) -> RedisResult<()> {
+    ctx.builder()
+        .add_section("decisions")
+        .field("token_bucket_allowed", TOKEN_BUCKET.allowed())?
+        .field("token_bucket_denied", TOKEN_BUCKET.denied())?
+        .field("token_bucket_errors", TOKEN_BUCKET.errors())?
+        .field("token_bucket_hits", TOKEN_BUCKET.hits())?
+        .field("token_bucket_misses", TOKEN_BUCKET.misses())?
+        .field("aggregator_dropped", aggregator::dropped())?
+        .field("memory_shed", memory::shed_count())?
+        .build_section()?
+        .build_info()?;
+    Ok(())
+}
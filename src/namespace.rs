@@ -0,0 +1,132 @@
+use crate::command_parser::{limit_omitted, parse_positive_integer};
+use crate::error::{self, bad_argument, error};
+use crate::keys::namespace_key;
+use crate::recovery;
+use redis_module::{Context, RedisError, RedisString, RedisValue};
+
+const SEPARATOR: u8 = b'/';
+const CAPACITY_FIELD: &str = "capacity";
+const PERIOD_FIELD: &str = "period";
+const ALGORITHM_FIELD: &str = "algorithm";
+const ALGORITHM: &str = "token_bucket";
+
+/// Defaults shared by all keys of a namespace, e.g. `api/user:42` belongs to `api`.
+///
+/// Namespaces centralize the configuration of many call sites: a key of a defined
+/// namespace inherits its capacity and period, so they are omitted from the command.
+/// A namespace is stored in the `<prefix>:ns:<name>` hash.
+pub struct Namespace {
+    // Maximum bucket's capacity
+    pub capacity: i64,
+    // Replenish period in seconds
+    pub period: i64,
+}
+
+impl Namespace {
+    /// Parses field-value pairs, e.g. `capacity 100 period 60`.
+    ///
+    /// Both the capacity and the period are required.
+    pub fn parse(args: &[RedisString]) -> Result<Self, RedisError> {
+        if args.len() % 2 != 0 {
+            return Err(error(error::SYNTAX, "syntax error"));
+        }
+
+        let (mut capacity, mut period) = (None, None);
+        for pair in args.chunks(2) {
+            let field = pair[0].to_string_lossy().to_ascii_lowercase();
+            match field.as_str() {
+                CAPACITY_FIELD => capacity = Some(parse_positive_integer("capacity", &pair[1])?),
+                PERIOD_FIELD => period = Some(parse_positive_integer("period", &pair[1])?),
+                ALGORITHM_FIELD if pair[1].to_string_lossy() == ALGORITHM => {}
+                ALGORITHM_FIELD => return Err(error(error::BAD_ALGO, "unsupported algorithm")),
+                _ => return Err(error(error::SYNTAX, "syntax error")),
+            }
+        }
+
+        Ok(Self {
+            capacity: capacity.ok_or_else(|| bad_argument("capacity", "is required"))?,
+            period: period.ok_or_else(|| bad_argument("period", "is required"))?,
+        })
+    }
+
+    /// Returns the namespace called `name`, or `None` if it isn't defined.
+    pub fn load(ctx: &Context, name: &[u8]) -> Result<Option<Self>, RedisError> {
+        let key = namespace_key(name);
+        let fields = [
+            &key,
+            &RedisString::create(None, CAPACITY_FIELD),
+            &RedisString::create(None, PERIOD_FIELD),
+        ];
+        let RedisValue::Array(values) = recovery::call(ctx, "HMGET", &fields)? else {
+            return Ok(None);
+        };
+
+        let (Some(RedisValue::SimpleString(capacity)), Some(RedisValue::SimpleString(period))) =
+            (values.first(), values.get(1))
+        else {
+            return Ok(None);
+        };
+        match (capacity.parse(), period.parse()) {
+            (Ok(capacity), Ok(period)) if capacity > 0 && period > 0 => {
+                Ok(Some(Self { capacity, period }))
+            }
+            _ => {
+                recovery::corrupted(ctx, &key)?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Returns the namespace `key` belongs to, or `None` if it has no defined one.
+    pub fn of_key(ctx: &Context, key: &[u8]) -> Result<Option<Self>, RedisError> {
+        match key.iter().position(|byte| *byte == SEPARATOR) {
+            Some(separator) => Self::load(ctx, &key[..separator]),
+            None => Ok(None),
+        }
+    }
+
+    pub fn save(&self, ctx: &Context, name: &[u8]) -> Result<(), RedisError> {
+        ctx.call(
+            "HSET",
+            &[
+                &namespace_key(name),
+                &RedisString::create(None, CAPACITY_FIELD),
+                &RedisString::create(None, self.capacity.to_string().as_str()),
+                &RedisString::create(None, PERIOD_FIELD),
+                &RedisString::create(None, self.period.to_string().as_str()),
+                &RedisString::create(None, ALGORITHM_FIELD),
+                &RedisString::create(None, ALGORITHM),
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Removes the namespace called `name`. Returns `true` if it was defined.
+    pub fn delete(ctx: &Context, name: &[u8]) -> Result<bool, RedisError> {
+        let deleted = ctx.call("DEL", &[&namespace_key(name)])?;
+        Ok(deleted == RedisValue::Integer(1))
+    }
+}
+
+/// Inserts the capacity and period of the key's namespace into the arguments
+/// of `SHIELD.absorb` and alike, i.e. `<key> [<tokens>] [options]` becomes
+/// `<key> <capacity> <period> [<tokens>] [options]`.
+///
+/// The arguments are returned unchanged if the key has no defined namespace,
+/// or the capacity and period are given explicitly.
+pub fn expand(ctx: &Context, mut args: Vec<RedisString>) -> Result<Vec<RedisString>, RedisError> {
+    let Some(key) = args.get(1) else {
+        return Ok(args);
+    };
+    if !limit_omitted(&args) {
+        return Ok(args);
+    }
+    if let Some(namespace) = Namespace::of_key(ctx, key.as_slice())? {
+        let limit = [
+            RedisString::create(None, namespace.capacity.to_string().as_str()),
+            RedisString::create(None, namespace.period.to_string().as_str()),
+        ];
+        args.splice(2..2, limit);
+    }
+    Ok(args)
+}
@@ -0,0 +1,72 @@
+use crate::command_parser::CommandArgs;
+use crate::keys::companion_key;
+use crate::logging;
+use crate::recovery;
+use crate::strings;
+use redis_module::{Context, RedisError, RedisString, RedisValue};
+use std::time::Duration;
+
+pub const NOTIFY_PART: &[u8] = b"notify";
+
+/// Tells a denied client when retrying is worthwhile.
+///
+/// Once the buckets have refilled enough for the denied request, the key
+/// is published to `channel`. A RESP3 client subscribed to the channel gets
+/// it as a push message on the same connection it sends requests over.
+///
+/// The `<key>:notify` marker, which expires along with the wait, makes sure
+/// only one message is scheduled for a key at a time.
+pub struct Notification<'a> {
+    // Key the message is published for, i.e. the key of the request
+    key: &'a RedisString,
+    // Channel the key is published to
+    channel: &'a RedisString,
+}
+
+impl<'a> Notification<'a> {
+    /// Returns `None` if `command` doesn't ask to be notified.
+    pub fn new(command: &CommandArgs<'a>) -> Option<Self> {
+        Some(Self {
+            key: command.member.unwrap_or(command.key),
+            channel: command.notify?,
+        })
+    }
+
+    /// Publishes the key in `wait` milliseconds, unless it's already scheduled.
+    pub fn schedule(&self, ctx: &Context, wait: i64) -> Result<(), RedisError> {
+        let marker = companion_key(self.key, &[NOTIFY_PART]);
+        let scheduled = recovery::call(
+            ctx,
+            "SET",
+            &[
+                &marker,
+                strings::one(),
+                strings::px_option(),
+                &RedisString::create(None, wait.to_string().as_str()),
+                strings::nx_option(),
+            ],
+        )?;
+        if scheduled == RedisValue::Null {
+            return Ok(());
+        }
+
+        let message = (
+            self.channel.as_slice().to_vec(),
+            self.key.as_slice().to_vec(),
+        );
+        ctx.create_timer(Duration::from_millis(wait as u64), publish, message);
+        Ok(())
+    }
+}
+
+fn publish(ctx: &Context, (channel, key): (Vec<u8>, Vec<u8>)) {
+    if let Err(err) = ctx.call("PUBLISH", &[channel.as_slice(), key.as_slice()]) {
+        logging::warn(ctx, logging::NOTIFICATION, &channel, || {
+            format!(
+                "failed to publish a notification to {}: {}",
+                String::from_utf8_lossy(&channel),
+                err
+            )
+        });
+    }
+}
@@ -0,0 +1,114 @@
+use crate::command_parser::parse_positive_integer;
+use crate::error::{self, error};
+use crate::keys::companion_key;
+use crate::recovery;
+use redis_module::{Context, RedisError, RedisString, RedisValue};
+
+pub const OVERRIDE_PART: &[u8] = b"override";
+const CAPACITY_FIELD: &str = "capacity";
+const PERIOD_FIELD: &str = "period";
+const OPTIONS_FIELD: &str = "options";
+
+/// Fields of a policy overridden for a single key, e.g. a higher capacity
+/// for a VIP customer on the `gold` policy.
+///
+/// An override is stored in the `<key>:override` hash. It only applies to
+/// requests naming a policy, and is itself overridden by the arguments
+/// given to the command.
+pub struct Override {
+    // Maximum bucket's capacity
+    pub capacity: Option<i64>,
+    // Replenish period in seconds
+    pub period: Option<i64>,
+    // Options applied after the policy's ones, separated by whitespace
+    pub options: Option<String>,
+}
+
+impl Override {
+    /// Parses field-value pairs, e.g. `capacity 500 options "PRIORITY high"`.
+    pub fn parse(args: &[RedisString]) -> Result<Self, RedisError> {
+        if args.is_empty() || args.len() % 2 != 0 {
+            return Err(error(error::SYNTAX, "syntax error"));
+        }
+
+        let mut fields = Self {
+            capacity: None,
+            period: None,
+            options: None,
+        };
+        for pair in args.chunks(2) {
+            let field = pair[0].to_string_lossy().to_ascii_lowercase();
+            match field.as_str() {
+                CAPACITY_FIELD => {
+                    fields.capacity = Some(parse_positive_integer("capacity", &pair[1])?)
+                }
+                PERIOD_FIELD => fields.period = Some(parse_positive_integer("period", &pair[1])?),
+                OPTIONS_FIELD => fields.options = Some(pair[1].to_string_lossy()),
+                _ => return Err(error(error::SYNTAX, "syntax error")),
+            }
+        }
+        Ok(fields)
+    }
+
+    /// Returns the override of `key`, or `None` if it has none.
+    pub fn load(ctx: &Context, key: &RedisString) -> Result<Option<Self>, RedisError> {
+        let override_key = companion_key(key, &[OVERRIDE_PART]);
+        let fields = [
+            &override_key,
+            &RedisString::create(None, CAPACITY_FIELD),
+            &RedisString::create(None, PERIOD_FIELD),
+            &RedisString::create(None, OPTIONS_FIELD),
+        ];
+        let RedisValue::Array(values) = recovery::call(ctx, "HMGET", &fields)? else {
+            return Ok(None);
+        };
+
+        let value = |index: usize| match values.get(index) {
+            Some(RedisValue::SimpleString(value)) => Some(value.clone()),
+            _ => None,
+        };
+        let number = |index: usize| match value(index) {
+            Some(value) => match value.parse() {
+                Ok(number) if number > 0 => Ok(Some(number)),
+                _ => Err(()),
+            },
+            None => Ok(None),
+        };
+        match (number(0), number(1), value(2)) {
+            (Ok(None), Ok(None), None) => Ok(None),
+            (Ok(capacity), Ok(period), options) => Ok(Some(Self {
+                capacity,
+                period,
+                options,
+            })),
+            _ => {
+                recovery::corrupted(ctx, &override_key)?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Writes the overridden fields, keeping the other ones of an existing override.
+    pub fn save(&self, ctx: &Context, key: &RedisString) -> Result<(), RedisError> {
+        let mut args = vec![companion_key(key, &[OVERRIDE_PART])];
+        let fields = [
+            (CAPACITY_FIELD, self.capacity.map(|n| n.to_string())),
+            (PERIOD_FIELD, self.period.map(|n| n.to_string())),
+            (OPTIONS_FIELD, self.options.clone()),
+        ];
+        for (field, value) in fields {
+            if let Some(value) = value {
+                args.push(RedisString::create(None, field));
+                args.push(RedisString::create(None, value.as_str()));
+            }
+        }
+        recovery::call(ctx, "HSET", &args.iter().collect::<Vec<_>>())?;
+        Ok(())
+    }
+
+    /// Removes the override of `key`. Returns `true` if it had one.
+    pub fn delete(ctx: &Context, key: &RedisString) -> Result<bool, RedisError> {
+        let deleted = ctx.call("DEL", &[&companion_key(key, &[OVERRIDE_PART])])?;
+        Ok(deleted == RedisValue::Integer(1))
+    }
+}
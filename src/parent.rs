@@ -0,0 +1,22 @@
+use crate::bucket::Bucket;
+use crate::error::bad_argument;
+use crate::namespace::Namespace;
+use crate::state::{now, State};
+use redis_module::{Context, RedisError, RedisString};
+
+/// Returns the bucket of the `PARENT` a request also draws from, e.g. the
+/// bucket of the whole tenant a user belongs to.
+///
+/// The parent is limited by its namespace or, failing that, by the limit its
+/// bucket was stored with, so the children can't tell it a different one.
+/// A parent with neither is an error rather than an unlimited bucket.
+pub fn bucket<'a>(ctx: &'a Context, key: &'a RedisString) -> Result<Bucket<'a>, RedisError> {
+    let (capacity, period) = match Namespace::of_key(ctx, key.as_slice())? {
+        Some(namespace) => (namespace.capacity, namespace.period),
+        None => match State::load(ctx, key, now(ctx)?)?.and_then(|state| state.limit) {
+            Some(limit) => (limit.capacity, limit.period),
+            None => return Err(bad_argument("parent", "has no namespace or stored limit")),
+        },
+    };
+    Bucket::new(ctx, key, capacity, period)
+}
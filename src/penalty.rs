@@ -0,0 +1,99 @@
+use crate::command_parser::CommandArgs;
+use crate::keys::companion_key;
+use crate::math::millis;
+use crate::recovery;
+use crate::state;
+use redis_module::{Context, RedisError, RedisString, RedisValue};
+
+pub const PENALTY_PART: &[u8] = b"penalty";
+const FIELD_SEPARATOR: char = ':';
+// Cool-down in milliseconds after the first denial, doubled by every next one
+const INITIAL_COOLDOWN: i64 = 1000;
+
+/// Cool-down during which all requests of a key are denied, regardless of the
+/// tokens refilled meanwhile. Deters clients that ignore `Retry-After`.
+///
+/// The number of consecutive denials and the end of the cool-down are stored
+/// under `<key>:penalty` as `<denials>:<until>`. Every denial, including the
+/// ones during the cool-down, restarts it twice as long, up to the maximum.
+/// An admitted request clears the penalty, so does a quiet period as long
+/// as the maximum cool-down.
+pub struct Penalty {
+    // Key the penalty is stored under
+    key: RedisString,
+    // Longest cool-down in milliseconds
+    max_cooldown: i64,
+    // Number of consecutive denials
+    denials: u32,
+    // Unix time in milliseconds the cool-down ends at
+    until: i64,
+    // Current Unix time in milliseconds
+    now: i64,
+}
+
+impl Penalty {
+    /// Fetches the penalty of the key.
+    ///
+    /// Returns `None` if `command` doesn't penalize denials.
+    pub fn load(ctx: &Context, command: &CommandArgs) -> Result<Option<Self>, RedisError> {
+        if command.penalty == 0 {
+            return Ok(None);
+        }
+        let mut penalty = Self {
+            key: companion_key(command.member.unwrap_or(command.key), &[PENALTY_PART]),
+            max_cooldown: millis(command.penalty),
+            denials: 0,
+            until: 0,
+            now: state::now(ctx)?,
+        };
+
+        if let RedisValue::SimpleString(value) = recovery::call(ctx, "GET", &[&penalty.key])? {
+            let decoded = value
+                .split_once(FIELD_SEPARATOR)
+                .and_then(|(denials, until)| Some((denials.parse().ok()?, until.parse().ok()?)));
+            match decoded {
+                Some((denials, until)) => (penalty.denials, penalty.until) = (denials, until),
+                None => recovery::corrupted(ctx, &penalty.key)?,
+            }
+        }
+        Ok(Some(penalty))
+    }
+
+    /// Returns the number of milliseconds until the cool-down ends.
+    pub fn retry_after(&self) -> i64 {
+        self.until
+            .saturating_sub(self.now)
+            .clamp(0, self.max_cooldown)
+    }
+
+    /// Accounts for a decision made for the key.
+    pub fn record(&self, ctx: &Context, allowed: bool) -> Result<(), RedisError> {
+        if allowed {
+            if self.denials > 0 {
+                ctx.call("DEL", &[&self.key])?;
+            }
+            return Ok(());
+        }
+
+        let cooldown = INITIAL_COOLDOWN
+            .saturating_mul(2i64.saturating_pow(self.denials))
+            .min(self.max_cooldown);
+        let until = self.now.saturating_add(cooldown);
+        let value = format!(
+            "{}{}{}",
+            self.denials.saturating_add(1),
+            FIELD_SEPARATOR,
+            until
+        );
+        let ttl = cooldown.saturating_add(self.max_cooldown);
+        ctx.call(
+            "PSETEX",
+            &[
+                &self.key,
+                &RedisString::create(None, ttl.to_string().as_str()),
+                &RedisString::create(None, value.as_str()),
+            ],
+        )?;
+        Ok(())
+    }
+}
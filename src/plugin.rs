@@ -0,0 +1,152 @@
+use crate::command_parser::CommandArgs;
+use crate::error::{self, error};
+#[cfg(feature = "plugins")]
+use crate::keys::companion_key;
+#[cfg(feature = "plugins")]
+use crate::recent::{self, Decision};
+#[cfg(feature = "plugins")]
+use linkme::distributed_slice;
+#[cfg(feature = "plugins")]
+use redis_module::Context;
+use redis_module::{RedisError, RedisResult, RedisString};
+#[cfg(feature = "plugins")]
+use std::time::Instant;
+
+/// Name of the built-in algorithm, which needs no `ALGORITHM` option.
+pub const TOKEN_BUCKET: &str = "token_bucket";
+
+/// Rate limiting algorithm provided by another crate, which `SHIELD.absorb`
+/// applies to requests naming its keyword, e.g. `ALGORITHM sliding_log`.
+///
+/// A crate building its own module on top of this one registers algorithms
+/// at link time, so they're available as soon as the module is loaded:
+///
+///     #[linkme::distributed_slice(redis_shield::ALGORITHMS)]
+///     static SLIDING_LOG: &dyn redis_shield::Algorithm = &SlidingLog;
+///
+/// The state of a key is stored under `<key>:<suffix>`, so it never collides
+/// with the key's token bucket.
+#[cfg(feature = "plugins")]
+pub trait Algorithm: Sync {
+    /// Value of the `ALGORITHM` option selecting the algorithm.
+    fn keyword(&self) -> &'static str;
+
+    /// Part appended to the keys the algorithm stores its state under.
+    fn suffix(&self) -> &'static str;
+
+    /// Takes `tokens` from the state stored under `key` for a limit of `capacity`
+    /// tokens per `period` seconds. Returns the number of tokens left, or `-1`
+    /// if the request is denied.
+    fn absorb(
+        &self,
+        ctx: &Context,
+        key: &RedisString,
+        capacity: i64,
+        period: i64,
+        tokens: i64,
+    ) -> Result<i64, RedisError>;
+}
+
+/// Algorithms registered by other crates.
+#[cfg(feature = "plugins")]
+#[distributed_slice]
+pub static ALGORITHMS: [&'static dyn Algorithm] = [..];
+
+/// Returns the key the state of `command`'s key is stored under by the
+/// `ALGORITHM` `name`.
+#[cfg_attr(not(feature = "plugins"), allow(unused_variables))]
+pub fn state_key(command: &CommandArgs, name: &RedisString) -> Result<RedisString, RedisError> {
+    #[cfg(feature = "plugins")]
+    if let Some(algorithm) = find(name) {
+        return Ok(companion_key(command.key, &[algorithm.suffix().as_bytes()]));
+    }
+    Err(error(error::BAD_ALGO, "unsupported algorithm"))
+}
+
+/// Returns the reply of `SHIELD.absorb` for a request naming the `ALGORITHM`
+/// `name`, which only gets the request's limit and tokens.
+///
+/// Bans, `NX` and the memory threshold are applied by the caller beforehand.
+#[cfg_attr(not(feature = "plugins"), allow(unused_variables))]
+pub fn absorb(
+    ctx: &redis_module::Context,
+    command: &CommandArgs,
+    name: &RedisString,
+) -> RedisResult {
+    #[cfg(feature = "plugins")]
+    if let Some(algorithm) = find(name) {
+        let key = companion_key(command.key, &[algorithm.suffix().as_bytes()]);
+        let limit = command.limit;
+        let started = Instant::now();
+        let remaining_tokens =
+            algorithm.absorb(ctx, &key, limit.capacity, limit.period, command.tokens)?;
+        recent::record(Decision::new(
+            command.member.unwrap_or(command.key),
+            algorithm.keyword(),
+            remaining_tokens >= 0,
+            command.tokens,
+            started.elapsed(),
+        ));
+        return Ok(crate::tokens_reply(
+            command.output,
+            remaining_tokens,
+            None,
+            None,
+        ));
+    }
+    Err(error(error::BAD_ALGO, "unsupported algorithm"))
+}
+
+/// Returns the suffixes of the keys the registered algorithms store states under.
+pub fn suffixes() -> Vec<&'static str> {
+    #[cfg(feature = "plugins")]
+    let suffixes = ALGORITHMS
+        .iter()
+        .map(|algorithm| algorithm.suffix())
+        .collect();
+    #[cfg(not(feature = "plugins"))]
+    let suffixes = Vec::new();
+    suffixes
+}
+
+#[cfg(feature = "plugins")]
+fn find(name: &RedisString) -> Option<&'static dyn Algorithm> {
+    ALGORITHMS
+        .iter()
+        .find(|algorithm| {
+            name.to_string_lossy()
+                .eq_ignore_ascii_case(algorithm.keyword())
+        })
+        .copied()
+}
+
+// Algorithm registered by debug builds, so the tests cover the dispatch to
+// a registered one. It keeps no state and admits every request.
+#[cfg(all(feature = "plugins", debug_assertions))]
+struct Unlimited;
+
+#[cfg(all(feature = "plugins", debug_assertions))]
+#[distributed_slice(ALGORITHMS)]
+static UNLIMITED: &dyn Algorithm = &Unlimited;
+
+#[cfg(all(feature = "plugins", debug_assertions))]
+impl Algorithm for Unlimited {
+    fn keyword(&self) -> &'static str {
+        "unlimited"
+    }
+
+    fn suffix(&self) -> &'static str {
+        "unlimited"
+    }
+
+    fn absorb(
+        &self,
+        _ctx: &Context,
+        _key: &RedisString,
+        capacity: i64,
+        _period: i64,
+        tokens: i64,
+    ) -> Result<i64, RedisError> {
+        Ok(capacity - tokens)
+    }
+}
@@ -0,0 +1,336 @@
+use crate::command_parser::{
+    limit_omitted, parse_command_args, parse_positive_integer, CommandArgs,
+};
+use crate::error::{self, bad_argument, error};
+use crate::keys::policy_key;
+use crate::math::millis;
+use crate::overrides::Override;
+use crate::recovery;
+use crate::state;
+use redis_module::configuration::ConfigurationContext;
+use redis_module::{Context, NotifyEvent, RedisError, RedisString, RedisValue};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+const POLICY_OPTION: &str = "POLICY";
+const CAPACITY_FIELD: &str = "capacity";
+const PERIOD_FIELD: &str = "period";
+const OPTIONS_FIELD: &str = "options";
+const ALGORITHM_FIELD: &str = "algorithm";
+const ALGORITHM: &str = "token_bucket";
+// Number of policies cached, beyond which the first ones are dropped
+const MAX_CACHED: usize = 1024;
+
+// Policies read so far by the key they are stored under. An entry is dropped
+// once its key changes. Undefined policies aren't cached, so a client naming
+// random policies can't fill the cache.
+static POLICIES: Mutex<BTreeMap<Vec<u8>, Policy>> = Mutex::new(BTreeMap::new());
+// Prefix of the keys policies are stored under, e.g. `shield:policy:`, rebuilt
+// whenever the settings it's made of change. Empty until they're loaded.
+static KEYS_PREFIX: Mutex<Vec<u8>> = Mutex::new(Vec::new());
+// Number of keys tracked per policy, beyond which new ones aren't counted
+// until the tracked ones are fully refilled
+const MAX_TRACKED_KEYS: usize = 65536;
+// Usage of the policies by name, see `Usage`
+static USAGE: Mutex<BTreeMap<Vec<u8>, Tracked>> = Mutex::new(BTreeMap::new());
+
+/// Limit and options shared by all requests naming the policy, e.g. `POLICY gold`.
+///
+/// A policy is stored in the `<prefix>:policy:<name>` hash with the `capacity`,
+/// `period` and optional `options` fields, e.g. `PRIORITY high OVERDRAFT 5`.
+/// It's written by `SHIELD.policy SET`, which validates the fields, or with
+/// plain hash commands, in which case fields the module doesn't know are
+/// ignored. Policies are cached in memory, so requests don't read them
+/// from the keyspace every time.
+#[derive(Clone)]
+pub struct Policy {
+    // Maximum bucket's capacity
+    capacity: i64,
+    // Replenish period in seconds
+    period: i64,
+    // Options applied to every request, separated by whitespace
+    options: String,
+}
+
+impl Policy {
+    /// Parses field-value pairs, e.g. `capacity 100 period 60 options "PRIORITY high"`.
+    pub fn parse(args: &[RedisString]) -> Result<Self, RedisError> {
+        if args.len() % 2 != 0 {
+            return Err(error(error::SYNTAX, "syntax error"));
+        }
+
+        let (mut capacity, mut period, mut options) = (None, None, String::new());
+        for pair in args.chunks(2) {
+            let field = pair[0].to_string_lossy().to_ascii_lowercase();
+            match field.as_str() {
+                CAPACITY_FIELD => capacity = Some(parse_positive_integer("capacity", &pair[1])?),
+                PERIOD_FIELD => period = Some(parse_positive_integer("period", &pair[1])?),
+                OPTIONS_FIELD => options = pair[1].to_string_lossy(),
+                ALGORITHM_FIELD if pair[1].to_string_lossy() == ALGORITHM => {}
+                ALGORITHM_FIELD => return Err(error(error::BAD_ALGO, "unsupported algorithm")),
+                _ => return Err(error(error::SYNTAX, "syntax error")),
+            }
+        }
+
+        let policy = Self {
+            capacity: capacity.ok_or_else(|| bad_argument("capacity", "is required"))?,
+            period: period.ok_or_else(|| bad_argument("period", "is required"))?,
+            options,
+        };
+        policy.check_options()?;
+        Ok(policy)
+    }
+
+    // Parses the options the way the requests applying the policy do, so an
+    // invalid one fails here rather than every request
+    fn check_options(&self) -> Result<(), RedisError> {
+        let (capacity, period) = (self.capacity.to_string(), self.period.to_string());
+        let mut args = vec![
+            crate::REDIS_COMMAND.as_bytes(),
+            POLICY_OPTION.as_bytes(),
+            capacity.as_bytes(),
+            period.as_bytes(),
+        ];
+        args.extend(self.options.split_whitespace().map(str::as_bytes));
+        parse_command_args(&args)?;
+        Ok(())
+    }
+
+    /// Stores the policy as `name`, replacing its previous fields.
+    pub fn save(&self, ctx: &Context, name: &[u8]) -> Result<(), RedisError> {
+        let key = policy_key(name);
+        ctx.call("DEL", &[&key])?;
+        let mut args = vec![
+            key,
+            RedisString::create(None, CAPACITY_FIELD),
+            RedisString::create(None, self.capacity.to_string().as_str()),
+            RedisString::create(None, PERIOD_FIELD),
+            RedisString::create(None, self.period.to_string().as_str()),
+        ];
+        if !self.options.is_empty() {
+            args.push(RedisString::create(None, OPTIONS_FIELD));
+            args.push(RedisString::create(None, self.options.as_str()));
+        }
+        ctx.call("HSET", args.iter().collect::<Vec<_>>().as_slice())?;
+        Ok(())
+    }
+
+    /// Returns the policy called `name`, or `None` if it isn't defined.
+    fn load(ctx: &Context, name: &RedisString) -> Result<Option<Self>, RedisError> {
+        let key = policy_key(name.as_slice());
+        if let Some(policy) = POLICIES.lock().unwrap().get(key.as_slice()) {
+            return Ok(Some(policy.clone()));
+        }
+
+        let policy = match recovery::call(ctx, "HGETALL", &[&key])? {
+            RedisValue::Array(values) => Self::decode(&values),
+            _ => None,
+        };
+        let policy = match policy {
+            Some(policy) => Some(policy),
+            None if ctx.call("EXISTS", &[&key])? == RedisValue::Integer(1) => {
+                recovery::corrupted(ctx, &key)?;
+                None
+            }
+            None => None,
+        };
+        if let Some(policy) = &policy {
+            let mut policies = POLICIES.lock().unwrap();
+            if policies.len() >= MAX_CACHED {
+                policies.pop_first();
+            }
+            policies.insert(key.as_slice().to_vec(), policy.clone());
+        }
+        Ok(policy)
+    }
+
+    fn decode(values: &[RedisValue]) -> Option<Self> {
+        let (mut capacity, mut period, mut options) = (None, None, String::new());
+        for pair in values.chunks(2) {
+            let [RedisValue::SimpleString(field), RedisValue::SimpleString(value)] = pair else {
+                return None;
+            };
+            match field.as_str() {
+                CAPACITY_FIELD => capacity = value.parse().ok().filter(|n| *n > 0),
+                PERIOD_FIELD => period = value.parse().ok().filter(|n| *n > 0),
+                OPTIONS_FIELD => options = value.clone(),
+                ALGORITHM_FIELD if value != ALGORITHM => return None,
+                // Left for other tools, e.g. a description
+                _ => {}
+            }
+        }
+        Some(Self {
+            capacity: capacity?,
+            period: period?,
+            options,
+        })
+    }
+}
+
+/// Inserts the options of the policy named by `POLICY <name>` into the arguments
+/// of `SHIELD.absorb` and alike, and its capacity and period after the key,
+/// unless they are given explicitly.
+///
+/// The fields of the key's override take precedence over the policy's ones.
+/// The options are inserted right before `POLICY`, so options following it
+/// take precedence over both.
+pub fn expand(ctx: &Context, mut args: Vec<RedisString>) -> Result<Vec<RedisString>, RedisError> {
+    let position = args
+        .iter()
+        .skip(2)
+        .position(|arg| arg.to_string_lossy().eq_ignore_ascii_case(POLICY_OPTION));
+    let Some(index) = position.map(|position| position + 2) else {
+        return Ok(args);
+    };
+    let Some(name) = args.get(index + 1) else {
+        return Err(bad_argument("policy", "is required"));
+    };
+    let Some(mut policy) = Policy::load(ctx, name)? else {
+        return Err(bad_argument("policy", "is not defined"));
+    };
+    if let Some(fields) = Override::load(ctx, &args[1])? {
+        policy.capacity = fields.capacity.unwrap_or(policy.capacity);
+        policy.period = fields.period.unwrap_or(policy.period);
+        if let Some(options) = fields.options {
+            policy.options = format!("{} {}", policy.options, options);
+        }
+    }
+
+    let options: Vec<RedisString> = policy
+        .options
+        .split_whitespace()
+        .map(|option| RedisString::create(None, option))
+        .collect();
+    args.splice(index..index, options);
+    if limit_omitted(&args) {
+        let limit = [
+            RedisString::create(None, policy.capacity.to_string().as_str()),
+            RedisString::create(None, policy.period.to_string().as_str()),
+        ];
+        args.splice(2..2, limit);
+    }
+    Ok(args)
+}
+
+/// Handles keyspace events, dropping the cached policy stored under `key`
+/// if there is one, so the next request reads its latest version.
+///
+/// Keys outside of the policies' prefix are skipped without taking the cache's lock.
+pub fn on_changed(_ctx: &Context, _event_type: NotifyEvent, _event: &str, key: &[u8]) {
+    if !key.starts_with(&KEYS_PREFIX.lock().unwrap()) {
+        return;
+    }
+    POLICIES.lock().unwrap().remove(key);
+}
+
+/// Rebuilds the prefix of the policies' keys once `shield.key-prefix` or
+/// `shield.key-separator` is set, including when the module is loaded.
+pub fn on_prefix_changed(_ctx: &ConfigurationContext, _name: &str, _value: &'static Mutex<String>) {
+    *KEYS_PREFIX.lock().unwrap() = policy_key(&[]).as_slice().to_vec();
+}
+
+/// Drops all cached policies, e.g. once the keyspace is wiped.
+pub fn clear_cache() {
+    POLICIES.lock().unwrap().clear();
+}
+
+/// Usage of a policy, i.e. the keys currently applying it and the number
+/// of decisions made for them.
+///
+/// The usage is kept in memory, like the metrics, so tracking it doesn't
+/// write to the keyspace. Each key is tracked until its bucket would be fully
+/// refilled, and the counts are local to the instance the requests run on.
+pub struct Usage<'a> {
+    // Name of the policy
+    name: &'a [u8],
+    // Key the request is made for
+    key: &'a RedisString,
+    // Replenish period of the request's bucket in milliseconds
+    period: i64,
+}
+
+// Keys applying a policy and the decisions made for them
+#[derive(Default)]
+struct Tracked {
+    // Time the bucket of each key is fully refilled at, in milliseconds
+    keys: HashMap<Vec<u8>, i64>,
+    allowed: i64,
+    denied: i64,
+}
+
+impl Tracked {
+    // Forgets the keys whose buckets are fully refilled by `now`
+    fn prune(&mut self, now: i64) {
+        self.keys.retain(|_, until| *until > now);
+    }
+}
+
+impl<'a> Usage<'a> {
+    /// Returns `None` if `command` doesn't apply a policy.
+    pub fn new(command: &CommandArgs<'a>) -> Option<Self> {
+        Some(Self {
+            name: command.policy?.as_slice(),
+            key: command.member.unwrap_or(command.key),
+            period: millis(command.limit.period),
+        })
+    }
+
+    /// Accounts for a decision made for the key.
+    pub fn record(&self, ctx: &Context, allowed: bool) -> Result<(), RedisError> {
+        let now = state::now(ctx)?;
+        let until = now.saturating_add(self.period);
+        let mut usage = USAGE.lock().unwrap();
+        if !usage.contains_key(self.name) {
+            usage.insert(self.name.to_vec(), Tracked::default());
+        }
+        let tracked = usage.get_mut(self.name).unwrap();
+
+        match tracked.keys.get_mut(self.key.as_slice()) {
+            Some(tracked_until) => *tracked_until = (*tracked_until).max(until),
+            None => {
+                if tracked.keys.len() >= MAX_TRACKED_KEYS {
+                    tracked.prune(now);
+                }
+                if tracked.keys.len() < MAX_TRACKED_KEYS {
+                    tracked.keys.insert(self.key.as_slice().to_vec(), until);
+                }
+            }
+        }
+        if allowed {
+            tracked.allowed += 1;
+        } else {
+            tracked.denied += 1;
+        }
+        Ok(())
+    }
+
+    /// Returns the number of keys currently applying the policy called `name`,
+    /// and the numbers of allowed and denied requests.
+    pub fn info(ctx: &Context, name: &[u8]) -> Result<(i64, i64, i64), RedisError> {
+        let now = state::now(ctx)?;
+        let mut usage = USAGE.lock().unwrap();
+        let Some(tracked) = usage.get_mut(name) else {
+            return Ok((0, 0, 0));
+        };
+        tracked.prune(now);
+        let count = i64::try_from(tracked.keys.len()).unwrap_or(i64::MAX);
+        Ok((count, tracked.allowed, tracked.denied))
+    }
+
+    /// Zeroes the numbers of allowed and denied requests of the policy called
+    /// `name`, e.g. to align them with a deploy. Returns `true` if any was counted.
+    pub fn reset(name: &[u8]) -> bool {
+        let mut usage = USAGE.lock().unwrap();
+        let Some(tracked) = usage.get_mut(name) else {
+            return false;
+        };
+        let counted = tracked.allowed > 0 || tracked.denied > 0;
+        (tracked.allowed, tracked.denied) = (0, 0);
+        counted
+    }
+}
+
+/// Forgets the usage of all policies, e.g. once the keyspace is wiped.
+pub fn clear_usage() {
+    USAGE.lock().unwrap().clear();
+}
@@ -0,0 +1,64 @@
+use crate::config;
+use redis_module::RedisString;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Duration;
+
+static RECENT: Mutex<VecDeque<Decision>> = Mutex::new(VecDeque::new());
+
+/// Decision made by `SHIELD.absorb`, as kept for `SHIELD.recent`.
+#[derive(Clone)]
+pub struct Decision {
+    pub key: Vec<u8>,
+    pub algorithm: &'static str,
+    pub allowed: bool,
+    // Number of tokens the request asked for
+    pub tokens: i64,
+    // Time the limiter took to decide
+    pub latency: Duration,
+}
+
+impl Decision {
+    pub fn new(
+        key: &RedisString,
+        algorithm: &'static str,
+        allowed: bool,
+        tokens: i64,
+        latency: Duration,
+    ) -> Self {
+        Self {
+            key: key.as_slice().to_vec(),
+            algorithm,
+            allowed,
+            tokens,
+            latency,
+        }
+    }
+}
+
+/// Keeps `decision` in a ring of the last `shield.recent-decisions` ones,
+/// dropping the oldest once it's full.
+pub fn record(decision: Decision) {
+    let capacity = usize::try_from(config::recent_decisions()).unwrap_or(0);
+    if capacity == 0 {
+        return;
+    }
+    let mut recent = RECENT.lock().unwrap();
+    // The ring shrinks when the setting is lowered
+    while recent.len() >= capacity {
+        recent.pop_front();
+    }
+    recent.push_back(decision);
+}
+
+/// Returns up to `count` of the last decisions, the most recent first.
+pub fn last(count: usize) -> Vec<Decision> {
+    RECENT
+        .lock()
+        .unwrap()
+        .iter()
+        .rev()
+        .take(count)
+        .cloned()
+        .collect()
+}
@@ -0,0 +1,120 @@
+use crate::config;
+use crate::error::{self, error};
+use crate::logging;
+use redis_module::{Context, RedisError, RedisResult, RedisString, RedisValue};
+use std::cell::Cell;
+
+const WRONGTYPE_PREFIX: &str = "WRONGTYPE";
+const HASH_TYPE: &str = "hash";
+
+thread_local! {
+    // Whether the running command must not write, see `read_only`
+    static READ_ONLY: Cell<bool> = const { Cell::new(false) };
+}
+
+// The module's state can be clobbered by anything writing to its keys,
+// e.g. a stray `SET` or `HSET`. Such foreign values are handled in one way
+// everywhere: by default the request fails, while in lenient mode
+// a warning is logged and the state starts over. Readonly commands fail
+// either way, since they can't remove anything. A hash is never removed,
+// since it may hold the buckets of every `FIELD` of an entity.
+
+/// Runs `f` for a readonly command, for which a foreign value fails the
+/// request even in lenient mode, instead of being removed.
+pub fn read_only<T>(f: impl FnOnce() -> T) -> T {
+    let previous = READ_ONLY.replace(true);
+    let result = f();
+    READ_ONLY.set(previous);
+    result
+}
+
+/// Performs `command` on the key passed as the first of `args`.
+///
+/// If the key holds a value of the wrong type, the request fails with the usual
+/// `WRONGTYPE` error, or in lenient mode the key is removed and `command` is retried.
+/// A hash fails the request with `SHIELD_HASH` in either mode.
+pub fn call(ctx: &Context, command: &str, args: &[&RedisString]) -> RedisResult {
+    match ctx.call(command, args) {
+        Err(RedisError::String(message))
+            if message.starts_with(WRONGTYPE_PREFIX) && is_hash(ctx, args[0]) =>
+        {
+            Err(error(
+                error::HASH,
+                format!(
+                    "{} holds a hash, e.g. of buckets stored with FIELD",
+                    args[0]
+                ),
+            ))
+        }
+        Err(RedisError::String(message)) if message.starts_with(WRONGTYPE_PREFIX) && lenient() => {
+            reset(
+                ctx,
+                args[0],
+                logging::WRONG_TYPE,
+                "holds a value of the wrong type",
+            )?;
+            ctx.call(command, args)
+        }
+        result => result,
+    }
+}
+
+/// Handles a value stored under `key` that can't be parsed.
+///
+/// Fails with `SHIELD_CORRUPT`, or in lenient mode removes the key,
+/// so the caller can proceed as if it didn't exist.
+pub fn corrupted(ctx: &Context, key: &RedisString) -> Result<(), RedisError> {
+    if lenient() {
+        reset(ctx, key, logging::CORRUPTED, "holds an invalid value")
+    } else {
+        Err(error(
+            error::CORRUPT,
+            format!("invalid value stored under {}", key),
+        ))
+    }
+}
+
+/// Handles a value stored in `field` of the hash `key` that can't be parsed,
+/// like [`corrupted`], but only removes the field in lenient mode.
+pub fn corrupted_field(
+    ctx: &Context,
+    key: &RedisString,
+    field: &RedisString,
+) -> Result<(), RedisError> {
+    if lenient() {
+        logging::warn(ctx, logging::CORRUPTED, key.as_slice(), || {
+            format!(
+                "field {} of {} holds an invalid value, resetting it",
+                field, key
+            )
+        });
+        ctx.call("HDEL", &[key, field])?;
+        Ok(())
+    } else {
+        Err(error(
+            error::CORRUPT,
+            format!("invalid value stored in field {} of {}", field, key),
+        ))
+    }
+}
+
+fn is_hash(ctx: &Context, key: &RedisString) -> bool {
+    matches!(ctx.call("TYPE", &[key]), Ok(RedisValue::SimpleString(key_type)) if key_type == HASH_TYPE)
+}
+
+fn lenient() -> bool {
+    config::lenient_recovery() && !READ_ONLY.get()
+}
+
+fn reset(
+    ctx: &Context,
+    key: &RedisString,
+    class: &'static str,
+    reason: &str,
+) -> Result<(), RedisError> {
+    logging::warn(ctx, class, key.as_slice(), || {
+        format!("{} {}, resetting it", key, reason)
+    });
+    ctx.call("DEL", &[key])?;
+    Ok(())
+}
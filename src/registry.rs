@@ -0,0 +1,822 @@
+//! Central description of the module's commands: their handlers, arity, flags
+//! and keys, which they and the subcommands of the `SHIELD` container command
+//! are registered with, and their arguments and examples, returned by `SHIELD.help`.
+
+use crate::command_parser::key_positions;
+use redis_module::{decode_args, raw, Context, RedisError, RedisResult, RedisString, Status};
+use std::ffi::CString;
+use std::os::raw::c_int;
+
+pub type Handler = fn(&Context, Vec<RedisString>) -> RedisResult;
+
+pub struct Command {
+    pub name: &'static str,
+    pub handler: Handler,
+    /// Number of arguments including the command name, as Redis counts it:
+    /// negative for at least that many
+    pub arity: i64,
+    /// Flags the command is registered with, e.g. `write deny-oom fast`
+    pub flags: &'static str,
+    pub keys: Keys,
+    pub usage: &'static str,
+    pub summary: &'static str,
+    pub arguments: &'static [Argument],
+    pub examples: &'static [&'static str],
+}
+
+/// Positions of the keys in the arguments of a command.
+pub enum Keys {
+    None,
+    /// From the first to the last position, counted from the end if negative,
+    /// with a step, like the key positions the command is registered with
+    Range(i32, i32, i32),
+    /// The key and the `GROUP` of `SHIELD.absorb` and alike, see `key_positions`
+    Parsed,
+}
+
+pub struct Argument {
+    pub name: &'static str,
+    pub description: &'static str,
+}
+
+const fn argument(name: &'static str, description: &'static str) -> Argument {
+    Argument { name, description }
+}
+
+const KEY: Argument = argument("key", "bucket identifier, e.g. a user id or an IP address");
+const CAPACITY: Argument = argument("capacity", "maximum number of tokens in the bucket");
+const PERIOD: Argument = argument("period", "seconds it takes to refill an empty bucket");
+const RESERVATION_ID: Argument = argument("id", "reservation id returned by SHIELD.reserve");
+
+pub static COMMANDS: &[Command] = &[
+    Command {
+        name: crate::REDIS_COMMAND,
+        handler: crate::redis_command,
+        arity: -2,
+        flags: "write deny-oom fast getkeys-api",
+        keys: Keys::Parsed,
+        usage: "SHIELD.absorb key [capacity period] [tokens] [option ...]",
+        summary:
+            "Takes tokens from the bucket, returning the number of tokens left or -1 if denied",
+        arguments: &[
+            KEY,
+            CAPACITY,
+            PERIOD,
+            argument("tokens", "number of tokens to take, 1 by default"),
+            argument(
+                "TIER capacity period",
+                "additional limit consumed together with the first one",
+            ),
+            argument("PRIORITY high|normal|low", "priority class of the request"),
+            argument(
+                "THRESHOLD percent",
+                "share of the capacity low-priority requests may use, 80 by default",
+            ),
+            argument(
+                "OVERDRAFT tokens",
+                "number of tokens the bucket may go negative by",
+            ),
+            argument(
+                "WARMUP seconds",
+                "time a new bucket takes to ramp up to its full capacity",
+            ),
+            argument(
+                "IDEMPOTENCY id",
+                "request id whose retries get the original result",
+            ),
+            argument(
+                "SOFT percent",
+                "share of the capacity whose use is reported as a warning",
+            ),
+            argument("UNIT requests|bytes", "unit the tokens are counted in"),
+            argument(
+                "KIND primary|retry",
+                "checks the request against a retry budget",
+            ),
+            argument(
+                "BUDGET percent",
+                "retries admitted per primary requests, 10 by default",
+            ),
+            argument(
+                "GROUP name",
+                "group whose shared bucket the request draws from",
+            ),
+            argument(
+                "HISTORY n",
+                "number of recent decisions kept for SHIELD.history",
+            ),
+            argument(
+                "NOTIFY channel",
+                "channel the key is published to once a denied request would be admitted",
+            ),
+            argument(
+                "OUTPUT headers|simple",
+                "replies with the rate limit HTTP headers, or 1 if allowed and 0 if denied, instead",
+            ),
+            argument("MININTERVAL ms", "minimum time between admitted requests"),
+            argument(
+                "MAXIDLE seconds",
+                "expires the bucket once it wasn't written to for this long",
+            ),
+            argument("NX", "refuses to create the bucket if it doesn't exist yet"),
+            argument(
+                "STRICTCONFIG error|reset",
+                "fails the request, or resets the bucket, if its limit differs",
+            ),
+            argument(
+                "POLICY name",
+                "applies the limit and options of a stored policy",
+            ),
+            argument(
+                "PENALTY exponential seconds",
+                "cool-down after a denial, doubling up to seconds",
+            ),
+            argument(
+                "GREYLIST ms",
+                "suggests over-limit requests a delay of up to ms instead",
+            ),
+            argument(
+                "REFILL_STEP ms",
+                "refills tokens in discrete steps of ms milliseconds",
+            ),
+            argument(
+                "CURVE linear|frontloaded|backloaded",
+                "shape of the refill across the period",
+            ),
+            argument("PERSISTENT", "stores the bucket without an expire"),
+            argument("FIELD name", "stores the bucket in a field of the hash key"),
+            argument(
+                "SHARDS n",
+                "splits the bucket into n keys sharing its capacity",
+            ),
+            argument(
+                "PARENT key",
+                "bucket, e.g. of the tenant, that must have tokens too",
+            ),
+            argument(
+                "COST name",
+                "function computing the tokens from the attributes",
+            ),
+            argument("ATTR value", "attribute passed to the cost function"),
+            argument(
+                "ALGORITHM keyword",
+                "algorithm registered by a plugin, token_bucket by default",
+            ),
+            argument(
+                "ONDENY sentinel|error",
+                "reply -1 or a THROTTLED error to denied requests",
+            ),
+        ],
+        examples: &[
+            "SHIELD.absorb user123 30 60",
+            "SHIELD.absorb user123 30 60 5 TIER 1000 3600",
+            "SHIELD.absorb user123 1 POLICY gold",
+        ],
+    },
+    Command {
+        name: crate::BATCH_COMMAND,
+        handler: crate::batch_command,
+        arity: -5,
+        flags: "write deny-oom fast",
+        keys: Keys::Range(1, 1, 1),
+        usage: "SHIELD.absorbbatch key capacity period count [tokens_each]",
+        summary: "Absorbs requests one after another until one is denied",
+        arguments: &[
+            KEY,
+            CAPACITY,
+            PERIOD,
+            argument("count", "number of requests in the batch"),
+            argument(
+                "tokens_each",
+                "number of tokens taken by each request, 1 by default",
+            ),
+        ],
+        examples: &["SHIELD.absorbbatch user123 30 60 10 2"],
+    },
+    Command {
+        name: crate::RESERVE_COMMAND,
+        handler: crate::reserve_command,
+        arity: -5,
+        flags: "write deny-oom fast",
+        keys: Keys::Range(1, 1, 1),
+        usage: "SHIELD.reserve key capacity period tokens [ttl]",
+        summary: "Holds tokens until the reservation is committed, canceled or expires",
+        arguments: &[
+            KEY,
+            CAPACITY,
+            PERIOD,
+            argument("tokens", "number of tokens to reserve"),
+            argument(
+                "ttl",
+                "milliseconds the reservation is held for, 30000 by default",
+            ),
+        ],
+        examples: &["SHIELD.reserve user123 30 60 5 10000"],
+    },
+    Command {
+        name: crate::COMMIT_COMMAND,
+        handler: crate::commit_command,
+        arity: 2,
+        flags: "write fast",
+        // The id starts with the hash tag of the bucket, which routes it to its slot
+        keys: Keys::Range(1, 1, 1),
+        usage: "SHIELD.commit id",
+        summary: "Keeps the reserved tokens consumed",
+        arguments: &[RESERVATION_ID],
+        examples: &["SHIELD.commit {user123}9f3c61a2b0d4e857"],
+    },
+    Command {
+        name: crate::CANCEL_COMMAND,
+        handler: crate::cancel_command,
+        arity: 2,
+        flags: "write fast",
+        // The id starts with the hash tag of the bucket, which routes it to its slot
+        keys: Keys::Range(1, 1, 1),
+        usage: "SHIELD.cancel id",
+        summary: "Returns the reserved tokens to the bucket",
+        arguments: &[RESERVATION_ID],
+        examples: &["SHIELD.cancel {user123}9f3c61a2b0d4e857"],
+    },
+    Command {
+        name: crate::SET_COMMAND,
+        handler: crate::set_command,
+        arity: 5,
+        flags: "write deny-oom fast",
+        keys: Keys::Range(1, 1, 1),
+        usage: "SHIELD.set key capacity period remaining",
+        summary: "Overrides the number of tokens left in the bucket",
+        arguments: &[
+            KEY,
+            CAPACITY,
+            PERIOD,
+            argument("remaining", "number of tokens to leave in the bucket"),
+        ],
+        examples: &["SHIELD.set user123 30 60 10"],
+    },
+    Command {
+        name: crate::TOUCH_COMMAND,
+        handler: crate::touch_command,
+        arity: 3,
+        flags: "write fast",
+        keys: Keys::Range(1, 1, 1),
+        usage: "SHIELD.touch key ttl",
+        summary: "Sets the TTL of the bucket without consuming tokens",
+        arguments: &[
+            KEY,
+            argument("ttl", "milliseconds until the bucket expires"),
+        ],
+        examples: &["SHIELD.touch user123 90000"],
+    },
+    Command {
+        name: crate::DRAIN_COMMAND,
+        handler: crate::drain_command,
+        arity: 4,
+        flags: "write deny-oom fast",
+        keys: Keys::Range(1, 1, 1),
+        usage: "SHIELD.drain key capacity period",
+        summary: "Removes all tokens left in the bucket",
+        arguments: &[KEY, CAPACITY, PERIOD],
+        examples: &["SHIELD.drain user123 30 60"],
+    },
+    Command {
+        name: crate::SIMULATE_COMMAND,
+        handler: crate::simulate_command,
+        arity: -2,
+        flags: "readonly fast getkeys-api",
+        keys: Keys::Parsed,
+        usage: "SHIELD.simulate key [capacity period] [tokens] [option ...]",
+        summary: "Checks a request like SHIELD.absorb without changing the buckets",
+        arguments: &[
+            KEY,
+            CAPACITY,
+            PERIOD,
+            argument("tokens", "number of tokens to take, 1 by default"),
+            argument("option", "any option of SHIELD.absorb"),
+        ],
+        examples: &["SHIELD.simulate user123 30 60 1"],
+    },
+    Command {
+        name: crate::CHECK_COMMAND,
+        handler: crate::check_command,
+        arity: -5,
+        flags: "readonly fast",
+        keys: Keys::Range(1, -4, 1),
+        usage: "SHIELD.check key [key ...] KEYS-DONE capacity period",
+        summary: "Returns the number of tokens left in many buckets",
+        arguments: &[
+            argument("key", "bucket identifiers"),
+            argument("KEYS-DONE", "end of the keys"),
+            CAPACITY,
+            PERIOD,
+        ],
+        examples: &["SHIELD.check user1 user2 KEYS-DONE 30 60"],
+    },
+    Command {
+        name: crate::SAMPLE_COMMAND,
+        handler: crate::sample_command,
+        arity: -4,
+        flags: "readonly fast",
+        keys: Keys::None,
+        usage: "SHIELD.sample key percent period [id]",
+        summary: "Admits a stable share of the requests in every window",
+        arguments: &[
+            KEY,
+            argument("percent", "share of the requests to admit"),
+            argument("period", "seconds of a window"),
+            argument("id", "request id, admitted consistently within a window"),
+        ],
+        examples: &["SHIELD.sample user123 10 60 req-42"],
+    },
+    Command {
+        name: crate::NAMESPACE_COMMAND,
+        handler: crate::namespace_command,
+        arity: -3,
+        flags: "write deny-oom",
+        keys: Keys::None,
+        usage: "SHIELD.ns SET|GET|DEL name [field value ...]",
+        summary: "Defines, returns or removes a namespace of keys sharing a limit",
+        arguments: &[
+            argument("SET|GET|DEL", "subcommand"),
+            argument("name", "namespace, the prefix of its keys before '/'"),
+            argument(
+                "field value",
+                "limit and options of the namespace, required by SET",
+            ),
+        ],
+        examples: &[
+            "SHIELD.ns SET api capacity 100 period 60",
+            "SHIELD.ns GET api",
+        ],
+    },
+    Command {
+        name: crate::COST_COMMAND,
+        handler: crate::cost_command,
+        arity: -3,
+        flags: "write deny-oom",
+        keys: Keys::None,
+        usage: "SHIELD.cost SET|GET|DEL name [function name|script sha1]",
+        summary: "Defines, returns or removes a function computing the cost of requests",
+        arguments: &[
+            argument("SET|GET|DEL", "subcommand"),
+            argument("name", "cost function, as named by the COST option"),
+            argument(
+                "function name|script sha1",
+                "Redis Function or loaded Lua script called, required by SET",
+            ),
+        ],
+        examples: &[
+            "SHIELD.cost SET payload function payload_cost",
+            "SHIELD.cost GET payload",
+        ],
+    },
+    Command {
+        name: crate::GC_COMMAND,
+        handler: crate::gc_command,
+        arity: -2,
+        flags: "write",
+        keys: Keys::None,
+        usage: "SHIELD.gc cursor [MATCH pattern] [COUNT n] [IDLE ms]",
+        summary: "Removes buckets whose TTL has run out or that are idle, a SCAN step at a time",
+        arguments: &[
+            argument("cursor", "0 to start, then the cursor returned by the previous call"),
+            argument("MATCH pattern", "glob-style pattern of the keys to check"),
+            argument("COUNT n", "number of keys to check per call, 1000 by default"),
+            argument("IDLE ms", "also removes buckets unused for this long"),
+        ],
+        examples: &["SHIELD.gc 0 MATCH user:* IDLE 3600000"],
+    },
+    Command {
+        name: crate::RENAME_COMMAND,
+        handler: crate::rename_command,
+        arity: -3,
+        flags: "write",
+        keys: Keys::Range(1, 2, 1),
+        usage: "SHIELD.rename key newkey [TIER period ...] [SHARDS n]",
+        summary: "Moves the bucket and its companion keys, preserving their TTLs",
+        arguments: &[
+            argument("key", "current bucket identifier"),
+            argument("newkey", "new bucket identifier"),
+            argument("TIER period", "period of a tier to move along"),
+            argument("SHARDS n", "number of shards to move along"),
+        ],
+        examples: &["SHIELD.rename user@example.com user123 TIER 3600"],
+    },
+    Command {
+        name: crate::COPY_COMMAND,
+        handler: crate::copy_command,
+        arity: -3,
+        flags: "write deny-oom",
+        keys: Keys::Range(1, 2, 1),
+        usage: "SHIELD.copy source destination [TIER period ...] [SHARDS n]",
+        summary: "Copies the bucket and its companion keys, preserving their TTLs",
+        arguments: &[
+            argument("source", "bucket to copy"),
+            argument("destination", "bucket to create"),
+            argument("TIER period", "period of a tier to copy along"),
+            argument("SHARDS n", "number of shards to copy along"),
+        ],
+        examples: &["SHIELD.copy user123 shadow:user123"],
+    },
+    Command {
+        name: crate::MERGE_COMMAND,
+        handler: crate::merge_command,
+        arity: 6,
+        flags: "write deny-oom",
+        keys: Keys::Range(1, 3, 1),
+        usage: "SHIELD.mergekeys key1 key2 destination capacity period",
+        summary: "Sums the tokens used in two buckets into a destination bucket",
+        arguments: &[
+            argument("key1", "first source bucket"),
+            argument("key2", "second source bucket"),
+            argument("destination", "bucket receiving the usage of both"),
+            CAPACITY,
+            PERIOD,
+        ],
+        examples: &["SHIELD.mergekeys user1 user2 account42 30 60"],
+    },
+    Command {
+        name: crate::HISTORY_COMMAND,
+        handler: crate::history_command,
+        arity: 2,
+        flags: "readonly",
+        keys: Keys::Range(1, 1, 1),
+        usage: "SHIELD.history key",
+        summary: "Returns the decisions kept by the HISTORY option, the latest first",
+        arguments: &[KEY],
+        examples: &["SHIELD.history user123"],
+    },
+    Command {
+        name: crate::POLICY_COMMAND,
+        handler: crate::policy_command,
+        arity: -3,
+        flags: "write",
+        keys: Keys::None,
+        usage: "SHIELD.policy SET|INFO name [field value ...]",
+        summary: "Defines a policy, or reports its usage",
+        arguments: &[
+            argument("SET|INFO", "subcommand"),
+            argument("name", "policy, stored as shield:policy:<name>"),
+            argument("field value", "capacity, period and options, required by SET"),
+        ],
+        examples: &[
+            "SHIELD.policy SET gold capacity 100 period 60",
+            "SHIELD.policy INFO gold",
+        ],
+    },
+    Command {
+        name: crate::OVERRIDE_COMMAND,
+        handler: crate::override_command,
+        arity: -3,
+        flags: "write deny-oom fast",
+        keys: Keys::Range(2, 2, 1),
+        usage: "SHIELD.override SET|GET|DEL key [field value ...]",
+        summary: "Overrides the policy fields applied to a single key",
+        arguments: &[
+            argument("SET|GET|DEL", "subcommand"),
+            KEY,
+            argument("field value", "policy fields to override, required by SET"),
+        ],
+        examples: &["SHIELD.override SET user123 capacity 500"],
+    },
+    Command {
+        name: crate::BAN_COMMAND,
+        handler: crate::ban_command,
+        arity: 3,
+        flags: "write deny-oom fast",
+        keys: Keys::Range(1, 1, 1),
+        usage: "SHIELD.ban key ttl",
+        summary: "Denies all requests of the key until the ban expires",
+        arguments: &[KEY, argument("ttl", "milliseconds the ban lasts")],
+        examples: &["SHIELD.ban user123 3600000"],
+    },
+    Command {
+        name: crate::UNBAN_COMMAND,
+        handler: crate::unban_command,
+        arity: 2,
+        flags: "write fast",
+        keys: Keys::Range(1, 1, 1),
+        usage: "SHIELD.unban key",
+        summary: "Lifts the ban of the key",
+        arguments: &[KEY],
+        examples: &["SHIELD.unban user123"],
+    },
+    Command {
+        name: crate::BANLIST_COMMAND,
+        handler: crate::banlist_command,
+        arity: -1,
+        flags: "readonly",
+        keys: Keys::None,
+        usage: "SHIELD.banlist [MATCH pattern]",
+        summary: "Returns the banned keys and the milliseconds until their bans expire",
+        arguments: &[argument("MATCH pattern", "glob-style pattern of the keys")],
+        examples: &["SHIELD.banlist MATCH user:*"],
+    },
+    Command {
+        name: crate::EXPORT_COMMAND,
+        handler: crate::export_command,
+        arity: 2,
+        flags: "readonly",
+        keys: Keys::Range(1, 1, 1),
+        usage: "SHIELD.export key",
+        summary: "Returns the state of the bucket as a JSON document",
+        arguments: &[KEY],
+        examples: &["SHIELD.export user123"],
+    },
+    Command {
+        name: crate::IMPORT_COMMAND,
+        handler: crate::import_command,
+        arity: -3,
+        flags: "write deny-oom",
+        keys: Keys::Range(1, 1, 1),
+        usage: "SHIELD.import key state [REPLACE]",
+        summary: "Writes a state produced by SHIELD.export",
+        arguments: &[
+            KEY,
+            argument("state", "JSON document returned by SHIELD.export"),
+            argument("REPLACE", "overwrites an existing key"),
+        ],
+        examples: &["SHIELD.import user123 '{\"version\":1,...}' REPLACE"],
+    },
+    Command {
+        name: crate::DEBUG_COMMAND,
+        handler: crate::debug_command,
+        arity: -3,
+        flags: "readonly",
+        keys: Keys::Range(2, 2, 1),
+        usage: "SHIELD.debug STATE|OBJECT key [ALGORITHM name]",
+        summary: "Returns the decoded state or the storage details of a bucket",
+        arguments: &[
+            argument("STATE|OBJECT", "subcommand"),
+            KEY,
+            argument(
+                "ALGORITHM name",
+                "algorithm of the bucket, token_bucket by default",
+            ),
+        ],
+        examples: &["SHIELD.debug STATE user123", "SHIELD.debug OBJECT user123"],
+    },
+    Command {
+        name: crate::BENCH_COMMAND,
+        handler: crate::bench_command,
+        arity: 3,
+        flags: "write deny-oom",
+        keys: Keys::None,
+        usage: "SHIELD.bench algorithm iterations",
+        summary: "Measures the throughput and allocations of an algorithm in-process",
+        arguments: &[
+            argument("algorithm", "algorithm to evaluate, e.g. token_bucket"),
+            argument("iterations", "number of requests to evaluate"),
+        ],
+        examples: &["SHIELD.bench token_bucket 100000"],
+    },
+    Command {
+        name: crate::TOP_COMMAND,
+        handler: crate::top_command,
+        arity: -1,
+        flags: "readonly",
+        keys: Keys::None,
+        usage: "SHIELD.top [COUNT n]",
+        summary: "Returns the keys with the most denials",
+        arguments: &[argument(
+            "COUNT n",
+            "number of keys to return, 10 by default",
+        )],
+        examples: &["SHIELD.top COUNT 5"],
+    },
+    Command {
+        name: crate::RECENT_COMMAND,
+        handler: crate::recent_command,
+        arity: -1,
+        flags: "readonly fast",
+        keys: Keys::None,
+        usage: "SHIELD.recent [count]",
+        summary: "Returns the last decisions of the limiters",
+        arguments: &[argument(
+            "count",
+            "number of decisions to return, 10 by default",
+        )],
+        examples: &["SHIELD.recent 20"],
+    },
+    Command {
+        name: crate::STATS_COMMAND,
+        handler: crate::stats_command,
+        arity: -2,
+        flags: "write",
+        keys: Keys::None,
+        usage: "SHIELD.stats RESET [ALL|key|POLICY name]",
+        summary: "Zeroes the counters of the module, a tracked key or a policy",
+        arguments: &[
+            argument("RESET", "subcommand"),
+            argument("ALL", "every counter of the module, the default"),
+            argument("key", "key tracked for SHIELD.top"),
+            argument("POLICY name", "decisions counted for the policy"),
+        ],
+        examples: &["SHIELD.stats RESET", "SHIELD.stats RESET POLICY gold"],
+    },
+    Command {
+        name: crate::MAINTENANCE_COMMAND,
+        handler: crate::maintenance_command,
+        arity: -1,
+        flags: "fast",
+        keys: Keys::None,
+        usage: "SHIELD.maintenance [allow|deny|off [TTL seconds]]",
+        summary: "Replies to every request with a canned decision, or returns the current mode",
+        arguments: &[
+            argument(
+                "allow|deny|off",
+                "admit every request, deny every request, or evaluate them again",
+            ),
+            argument(
+                "TTL seconds",
+                "time after which requests are evaluated again",
+            ),
+        ],
+        examples: &["SHIELD.maintenance allow TTL 300", "SHIELD.maintenance"],
+    },
+    Command {
+        name: crate::FUNCTIONS_COMMAND,
+        handler: crate::functions_command,
+        arity: 2,
+        flags: "readonly",
+        keys: Keys::None,
+        usage: "SHIELD.functions EXPORT",
+        summary: "Returns a Redis Functions library implementing the token bucket in Lua",
+        arguments: &[argument("EXPORT", "subcommand")],
+        examples: &["SHIELD.functions EXPORT"],
+    },
+    Command {
+        name: crate::VERSION_COMMAND,
+        handler: crate::version_command,
+        arity: 1,
+        flags: "readonly fast",
+        keys: Keys::None,
+        usage: "SHIELD.version",
+        summary: "Returns the version, commit, profile and features of the build",
+        arguments: &[],
+        examples: &["SHIELD.version"],
+    },
+    Command {
+        name: crate::HELP_COMMAND,
+        handler: crate::help_command,
+        arity: -1,
+        flags: "readonly fast",
+        keys: Keys::None,
+        usage: "SHIELD.help [command]",
+        summary: "Lists the commands, or describes one of them",
+        arguments: &[argument(
+            "command",
+            "command to describe, with or without the SHIELD. prefix",
+        )],
+        examples: &["SHIELD.help", "SHIELD.help absorb"],
+    },
+];
+
+impl Command {
+    /// Name of the command without the `SHIELD.` prefix, e.g. `absorb`.
+    pub fn subcommand(&self) -> &'static str {
+        self.name
+            .split_once('.')
+            .map_or(self.name, |(_, name)| name)
+    }
+
+    /// Returns whether `args`, including the command name, match the arity.
+    pub fn accepts(&self, args: &[RedisString]) -> bool {
+        let count = args.len() as i64;
+        if self.arity < 0 {
+            count >= -self.arity
+        } else {
+            count == self.arity
+        }
+    }
+
+    /// Returns the positions of the keys in `args`, including the command name.
+    pub fn key_positions(&self, args: &[RedisString]) -> Vec<usize> {
+        match self.keys {
+            Keys::None => Vec::new(),
+            Keys::Range(first, last, step) => {
+                let last = if last < 0 {
+                    args.len() as i64 + i64::from(last)
+                } else {
+                    i64::from(last).min(args.len() as i64 - 1)
+                };
+                (i64::from(first)..=last)
+                    .step_by(step as usize)
+                    .map(|position| position as usize)
+                    .collect()
+            }
+            Keys::Parsed => key_positions(args),
+        }
+    }
+}
+
+impl Keys {
+    // First and last positions and step of the keys, as the command is registered with
+    fn spec(&self) -> (i32, i32, i32) {
+        match *self {
+            Keys::None => (0, 0, 0),
+            Keys::Range(first, last, step) => (first, last, step),
+            // The other keys are reported through the getkeys-api
+            Keys::Parsed => (1, 1, 1),
+        }
+    }
+}
+
+/// Creates every command of the table, and the `SHIELD` container command with
+/// every command as its subcommand, e.g. `SHIELD ABSORB`. Both get the flags
+/// and key positions given in the table, so read-only subcommands are served
+/// by replicas like the commands themselves.
+pub fn register_commands(ctx: &Context, _args: &[RedisString]) -> Status {
+    let container = CString::new(crate::CONTAINER_COMMAND).unwrap();
+    let created = unsafe {
+        // A container has no handler of its own, Redis dispatches to the subcommands
+        raw::RedisModule_CreateCommand.unwrap()(
+            ctx.ctx,
+            container.as_ptr(),
+            None,
+            c"".as_ptr(),
+            0,
+            0,
+            0,
+        )
+    };
+    if created == raw::Status::Err as c_int {
+        return Status::Err;
+    }
+    let parent = unsafe { raw::RedisModule_GetCommand.unwrap()(ctx.ctx, container.as_ptr()) };
+
+    // Positions of a subcommand's arguments count the container's name too
+    let shift = |position: i32| if position > 0 { position + 1 } else { position };
+    for command in COMMANDS {
+        let name = CString::new(command.name).unwrap();
+        let subcommand = CString::new(command.subcommand()).unwrap();
+        let flags = CString::new(command.flags).unwrap();
+        let (first, last, step) = command.keys.spec();
+        let created = unsafe {
+            [
+                raw::RedisModule_CreateCommand.unwrap()(
+                    ctx.ctx,
+                    name.as_ptr(),
+                    Some(dispatch),
+                    flags.as_ptr(),
+                    first,
+                    last,
+                    step,
+                ),
+                raw::RedisModule_CreateSubcommand.unwrap()(
+                    parent,
+                    subcommand.as_ptr(),
+                    Some(dispatch_subcommand),
+                    flags.as_ptr(),
+                    shift(first),
+                    shift(last),
+                    step,
+                ),
+            ]
+        };
+        if created.contains(&(raw::Status::Err as c_int)) {
+            return Status::Err;
+        }
+    }
+    Status::Ok
+}
+
+// Runs the command named by the first argument
+extern "C" fn dispatch(
+    ctx: *mut raw::RedisModuleCtx,
+    argv: *mut *mut raw::RedisModuleString,
+    argc: c_int,
+) -> c_int {
+    let context = Context::new(ctx);
+    let args = decode_args(ctx, argv, argc);
+    let reply = match args.first().and_then(|name| find(&name.to_string_lossy())) {
+        Some(command) => (command.handler)(&context, args),
+        None => Err(RedisError::Str("ERR unknown command")),
+    };
+    context.reply(reply) as c_int
+}
+
+// Runs the subcommand of `SHIELD` named by the second argument
+extern "C" fn dispatch_subcommand(
+    ctx: *mut raw::RedisModuleCtx,
+    argv: *mut *mut raw::RedisModuleString,
+    argc: c_int,
+) -> c_int {
+    let context = Context::new(ctx);
+    let args = decode_args(ctx, argv, argc);
+    let reply = crate::container_command(&context, args);
+    context.reply(reply) as c_int
+}
+
+/// Finds a command by its name, ignoring the case and the `SHIELD.` prefix.
+pub fn find(name: &str) -> Option<&'static Command> {
+    COMMANDS.iter().find(|command| {
+        command.name.eq_ignore_ascii_case(name) || command.subcommand().eq_ignore_ascii_case(name)
+    })
+}
+
+/// Finds a subcommand of the `SHIELD` container command, ignoring the case.
+pub fn find_subcommand(name: &str) -> Option<&'static Command> {
+    COMMANDS
+        .iter()
+        .find(|command| command.subcommand().eq_ignore_ascii_case(name))
+}
@@ -0,0 +1,149 @@
+use crate::bucket::Bucket;
+use crate::keys::{reservation_key, slot_tag};
+use crate::recovery;
+use crate::state;
+use redis_module::{Context, RedisError, RedisString, RedisValue};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+const KEY_FIELD: &str = "key";
+const CAPACITY_FIELD: &str = "capacity";
+const PERIOD_FIELD: &str = "period";
+const TOKENS_FIELD: &str = "tokens";
+
+/// Tokens taken from a bucket while the work they pay for is in progress,
+/// e.g. a payment that may still be aborted.
+///
+/// A reservation is stored in a hash in the slot of its bucket, e.g.
+/// `{user123}:reservation:<id>`, which expires after a short TTL. Its id
+/// carries the hash tag of the bucket, so the commands taking it can route it. Committing it keeps the tokens consumed,
+/// canceling it returns them to the bucket. A reservation that expires
+/// before either happens counts as committed.
+pub struct Reservation {
+    // Key of the bucket the tokens were taken from
+    key: RedisString,
+    // Maximum bucket's capacity
+    capacity: i64,
+    // Replenish period in seconds
+    period: i64,
+    // Number of reserved tokens
+    tokens: i64,
+}
+
+impl Reservation {
+    /// Takes `tokens` from the bucket and holds them for `ttl` milliseconds.
+    ///
+    /// Returns the id of the reservation, e.g. `{user123}9f3c...`, or `None`
+    /// if the bucket doesn't contain sufficient tokens.
+    pub fn reserve(
+        ctx: &Context,
+        key: &RedisString,
+        capacity: i64,
+        period: i64,
+        tokens: i64,
+        ttl: i64,
+    ) -> Result<Option<Vec<u8>>, RedisError> {
+        let mut bucket = Bucket::new(ctx, key, capacity, period)?;
+        if bucket.pour(tokens)? < 0 {
+            return Ok(None);
+        }
+
+        let now = state::now(ctx)?;
+        let key_field = RedisString::create(None, KEY_FIELD);
+        let mut attempt = 0;
+        let (id, reservation_key) = loop {
+            let mut hasher = RandomState::new().build_hasher();
+            hasher.write(key.as_slice());
+            hasher.write_i64(now);
+            hasher.write_i64(attempt);
+            let hash = format!("{:016x}", hasher.finish());
+            let id = match slot_tag(key.as_slice()) {
+                Some(tag) => [b"{", tag, b"}", hash.as_bytes()].concat(),
+                None => hash.into_bytes(),
+            };
+            let reservation_key = reservation_key(&id);
+            // Claims the id unless another reservation holds it. The key is
+            // stored as is, since it may not be valid UTF-8
+            let claimed = recovery::call(ctx, "HSETNX", &[&reservation_key, &key_field, key])?;
+            if claimed != RedisValue::Integer(0) {
+                break (id, reservation_key);
+            }
+            attempt += 1;
+        };
+
+        let mut args = vec![reservation_key.clone()];
+        let fields = [
+            (CAPACITY_FIELD, capacity.to_string()),
+            (PERIOD_FIELD, period.to_string()),
+            (TOKENS_FIELD, tokens.to_string()),
+        ];
+        for (field, value) in fields {
+            args.push(RedisString::create(None, field));
+            args.push(RedisString::create(None, value.as_str()));
+        }
+        recovery::call(ctx, "HSET", &args.iter().collect::<Vec<_>>())?;
+        ctx.call(
+            "PEXPIRE",
+            &[
+                &reservation_key,
+                &RedisString::create(None, ttl.to_string().as_str()),
+            ],
+        )?;
+        Ok(Some(id))
+    }
+
+    /// Removes the reservation `id` and returns it, or `None` if it doesn't
+    /// exist, e.g. because it was already committed, canceled or expired.
+    pub fn take(ctx: &Context, id: &RedisString) -> Result<Option<Self>, RedisError> {
+        let reservation_key = reservation_key(id.as_slice());
+        let fields = [
+            &reservation_key,
+            &RedisString::create(None, KEY_FIELD),
+            &RedisString::create(None, CAPACITY_FIELD),
+            &RedisString::create(None, PERIOD_FIELD),
+            &RedisString::create(None, TOKENS_FIELD),
+        ];
+        let RedisValue::Array(values) = recovery::call(ctx, "HMGET", &fields)? else {
+            return Ok(None);
+        };
+        if values.iter().all(|value| *value == RedisValue::Null) {
+            return Ok(None);
+        }
+
+        let value = |index: usize| match values.get(index) {
+            Some(RedisValue::SimpleString(value)) => Some(value.as_bytes()),
+            Some(RedisValue::StringBuffer(value)) => Some(value.as_slice()),
+            _ => None,
+        };
+        let number = |index: usize| {
+            std::str::from_utf8(value(index)?)
+                .ok()?
+                .parse()
+                .ok()
+                .filter(|n: &i64| *n > 0)
+        };
+        match (value(0), number(1), number(2), number(3)) {
+            (Some(key), Some(capacity), Some(period), Some(tokens)) => {
+                ctx.call("DEL", &[&reservation_key])?;
+                Ok(Some(Self {
+                    key: RedisString::create_from_slice(std::ptr::null_mut(), key),
+                    capacity,
+                    period,
+                    tokens,
+                }))
+            }
+            _ => {
+                recovery::corrupted(ctx, &reservation_key)?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Returns the reserved tokens to the bucket, which can't exceed its capacity.
+    ///
+    /// Returns the number of tokens left in the bucket.
+    pub fn cancel(&self, ctx: &Context) -> Result<i64, RedisError> {
+        let mut bucket = Bucket::new(ctx, &self.key, self.capacity, self.period)?;
+        bucket.refund(self.tokens)
+    }
+}
@@ -0,0 +1,124 @@
+use crate::command_parser::{CommandArgs, Kind};
+use crate::keys::companion_key;
+use crate::math::millis;
+use crate::recovery;
+use crate::strings;
+use redis_module::{Context, RedisError, RedisString, RedisValue};
+
+const MAX_PERCENT: i64 = 100;
+const OVERFLOWN_RESPONSE: i64 = -1;
+pub const BUDGET_PART: &[u8] = b"budget";
+const PRIMARY_FIELD: &str = "primary";
+const RETRY_FIELD: &str = "retry";
+
+/// Limits retries to a percentage of primary requests admitted in the same window,
+/// e.g. retries may not exceed 10% of successes. Protects backends from retry storms
+/// in a way plain rate limits can't express.
+///
+/// Both counters are stored in the `<key>:budget` hash, which expires at the end
+/// of the window. The window starts with the first admitted request and lasts
+/// one period of the bucket.
+pub struct RetryBudget {
+    // Key of the hash the counters are stored in
+    key: RedisString,
+    // Whether the request is a primary one or a retry
+    kind: Kind,
+    // Percentage of primary requests that may be retried
+    percent: i64,
+    // Length of the window in milliseconds
+    period: i64,
+    // Number of primary requests admitted in the window
+    primary: i64,
+    // Number of retries admitted in the window
+    retries: i64,
+    // Milliseconds left until the end of the window
+    ttl: i64,
+}
+
+impl RetryBudget {
+    /// Fetches the counters of the current window.
+    ///
+    /// Returns `None` if `command` doesn't specify the kind of the request.
+    pub fn load(ctx: &Context, command: &CommandArgs) -> Result<Option<Self>, RedisError> {
+        let Some(kind) = command.kind else {
+            return Ok(None);
+        };
+        let mut budget = Self {
+            key: companion_key(command.key, &[BUDGET_PART]),
+            kind,
+            percent: command.budget,
+            period: millis(command.limit.period),
+            primary: 0,
+            retries: 0,
+            ttl: 0,
+        };
+
+        let fields = [
+            &budget.key,
+            &RedisString::create(None, PRIMARY_FIELD),
+            &RedisString::create(None, RETRY_FIELD),
+        ];
+        if let RedisValue::Array(counters) = recovery::call(ctx, "HMGET", &fields)? {
+            match (counter(counters.first()), counter(counters.get(1))) {
+                (Some(primary), Some(retries)) => {
+                    budget.primary = primary;
+                    budget.retries = retries;
+                }
+                _ => recovery::corrupted(ctx, &budget.key)?,
+            }
+        }
+        if let RedisValue::Integer(ttl) = ctx.call("PTTL", &[&budget.key])? {
+            budget.ttl = ttl;
+        }
+        Ok(Some(budget))
+    }
+
+    /// Returns the number of milliseconds until the request fits the budget.
+    ///
+    /// Primary requests always fit. `-1` means a retry doesn't fit until more
+    /// primary requests are admitted.
+    pub fn retry_after(&self) -> i64 {
+        match self.kind {
+            Kind::Primary => 0,
+            Kind::Retry
+                if (i128::from(self.retries) + 1) * i128::from(MAX_PERCENT)
+                    <= i128::from(self.primary) * i128::from(self.percent) =>
+            {
+                0
+            }
+            Kind::Retry if self.ttl > 0 => self.ttl,
+            Kind::Retry => OVERFLOWN_RESPONSE,
+        }
+    }
+
+    /// Counts an admitted request, starting a new window if needed.
+    pub fn record(&self, ctx: &Context) -> Result<(), RedisError> {
+        let field = match self.kind {
+            Kind::Primary => PRIMARY_FIELD,
+            Kind::Retry => RETRY_FIELD,
+        };
+        recovery::call(
+            ctx,
+            "HINCRBY",
+            &[&self.key, &RedisString::create(None, field), strings::one()],
+        )?;
+        if self.ttl <= 0 {
+            ctx.call(
+                "PEXPIRE",
+                &[
+                    &self.key,
+                    &RedisString::create(None, self.period.to_string().as_str()),
+                ],
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns `None` if the counter can't be parsed.
+fn counter(value: Option<&RedisValue>) -> Option<i64> {
+    match value {
+        Some(RedisValue::SimpleString(value)) => value.parse().ok(),
+        _ => Some(0),
+    }
+}
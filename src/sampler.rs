@@ -0,0 +1,63 @@
+use crate::math::millis;
+use redis_module::RedisString;
+
+/// Keyword of the sampler as the `ALGORITHM` of `SHIELD.absorb`.
+pub const ALGORITHM: &str = "sample";
+
+const MAX_PERCENT: u64 = 100;
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// Probabilistic limiter admitting `percent` of requests without keeping any state.
+///
+/// The decision is a deterministic hash of the key, an optional request id and
+/// the current window, which serves as a rotating salt. It's stable within
+/// a window of `period` and across Redis instances, and a different subset
+/// of requests is admitted in the next window. Useful for gradual rollouts
+/// and load-shedding, where exact counting is overkill.
+pub struct Sampler<'a> {
+    // Key the requests are sampled for
+    pub key: &'a RedisString,
+    // Percentage of admitted requests
+    pub percent: i64,
+    // Length of a window in milliseconds
+    pub period: i64,
+}
+
+impl<'a> Sampler<'a> {
+    pub fn new(key: &'a RedisString, percent: i64, period: i64) -> Self {
+        Self {
+            key,
+            percent,
+            period: millis(period),
+        }
+    }
+
+    /// Returns whether `algorithm` names the sampler.
+    pub fn is_named(algorithm: &RedisString) -> bool {
+        algorithm
+            .as_slice()
+            .eq_ignore_ascii_case(ALGORITHM.as_bytes())
+    }
+
+    /// Returns `true` if the request identified by `id` is admitted in the window
+    /// `now`, in milliseconds, falls in.
+    pub fn admits(&self, id: Option<&RedisString>, now: i64) -> bool {
+        let window = now / self.period;
+        let mut hash = fnv1a(FNV_OFFSET_BASIS, self.key.as_slice());
+        if let Some(id) = id {
+            hash = fnv1a(hash, id.as_slice());
+        }
+        hash = fnv1a(hash, &window.to_le_bytes());
+
+        hash % MAX_PERCENT < self.percent as u64
+    }
+}
+
+// FNV-1a is used instead of the std hasher, whose output isn't guaranteed
+// to be the same across Rust releases.
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(FNV_PRIME)
+    })
+}
@@ -0,0 +1,112 @@
+use crate::bucket::Bucket;
+use crate::command_parser::CommandArgs;
+use crate::keys::companion_key;
+use crate::math::millis;
+use crate::strings;
+use redis_module::{Context, RedisError, RedisString, RedisValue};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub const SHARD_PART: &[u8] = b"shard";
+pub const REBALANCED_PART: &[u8] = b"rebalanced";
+
+// Number of shards picked so far, hashed with the key so consecutive
+// requests draw from different shards
+static PICKED: AtomicU64 = AtomicU64::new(0);
+
+/// Split of a hot bucket into `SHARDS` buckets stored under `{<key>}:shard:<i>`,
+/// each with an equal share of the capacity, so concurrent requests don't all
+/// write the same key. Every request draws from a shard picked by hashing.
+///
+/// The shards share the key's hash tag, so they're stored in its cluster slot
+/// and rebalanced by a single command. Spreading them over slots would take
+/// the client routing every request by a shard key it doesn't pass, and
+/// rebalancing keys across nodes, so a limit is split over nodes on the client.
+///
+/// Shards drift apart when requests are spread unevenly, so once per period
+/// their tokens are evened out. `{<key>}:shard:rebalanced` expires at the end
+/// of the period the shards were last rebalanced in.
+pub struct Shards {
+    // Keys the shards are stored under
+    keys: Vec<RedisString>,
+    // Key marking the current period as rebalanced
+    marker: RedisString,
+    // Capacity of every shard
+    capacity: i64,
+    // Period of the first limit in seconds
+    period: i64,
+}
+
+impl Shards {
+    /// Returns `None` if `command` doesn't split its buckets.
+    pub fn new(command: &CommandArgs) -> Option<Self> {
+        if command.shards <= 1 {
+            return None;
+        }
+        Some(Self {
+            keys: (0..command.shards)
+                .map(|index| shard_key(command.key, index))
+                .collect(),
+            marker: companion_key(command.key, &[SHARD_PART, REBALANCED_PART]),
+            capacity: share(command.limit.capacity, command.shards),
+            period: command.limit.period,
+        })
+    }
+
+    /// Returns the key of the shard the request draws from,
+    /// or `None` if `command` doesn't split its buckets.
+    pub fn pick(command: &CommandArgs) -> Option<RedisString> {
+        if command.shards <= 1 {
+            return None;
+        }
+        let mut hasher = DefaultHasher::new();
+        hasher.write(command.key.as_slice());
+        hasher.write_u64(PICKED.fetch_add(1, Ordering::Relaxed));
+        let index = hasher.finish() % command.shards as u64;
+        Some(shard_key(command.key, index as i64))
+    }
+
+    /// Evens out the tokens left in the shards, unless they were already
+    /// rebalanced in the current period.
+    ///
+    /// The refill of every shard starts over, like after `SHIELD.set`.
+    pub fn rebalance(&self, ctx: &Context) -> Result<(), RedisError> {
+        let marked = ctx.call(
+            "SET",
+            &[
+                &self.marker,
+                strings::one(),
+                strings::px_option(),
+                &RedisString::create(None, millis(self.period).to_string().as_str()),
+                strings::nx_option(),
+            ],
+        )?;
+        if marked == RedisValue::Null {
+            return Ok(());
+        }
+
+        let mut buckets = self
+            .keys
+            .iter()
+            .map(|key| Bucket::new(ctx, key, self.capacity, self.period))
+            .collect::<Result<Vec<_>, _>>()?;
+        let total: i64 = buckets.iter().map(|bucket| bucket.tokens.max(0)).sum();
+        let count = buckets.len() as i64;
+        for (index, bucket) in buckets.iter_mut().enumerate() {
+            // The remainder goes to the first shards
+            let extra = i64::from((index as i64) < total % count);
+            bucket.set(total / count + extra)?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the capacity of every one of `shards` buckets a limit is split into.
+pub fn share(capacity: i64, shards: i64) -> i64 {
+    capacity / shards.max(1)
+}
+
+fn shard_key(key: &RedisString, index: i64) -> RedisString {
+    companion_key(key, &[SHARD_PART, index.to_string().as_bytes()])
+}
@@ -0,0 +1,105 @@
+use crate::command_parser::Limit;
+use crate::error::{self, error};
+use crate::math::MAX_MILLIS;
+use crate::state::{self, State};
+use redis_module::{Context, RedisError, RedisString};
+use serde::{Deserialize, Serialize};
+
+const SNAPSHOT_VERSION: u8 = 1;
+const ALGORITHM: &str = "token_bucket";
+const MIN_TTL: i64 = 0;
+
+/// Self-describing copy of a bucket's stored state.
+///
+/// It is used to move limiters between Redis instances (e.g. during blue-green
+/// migrations) without resetting everyone's limits. The stored token count and
+/// the remaining TTL are captured as-is, so the refill progress is preserved.
+/// `exported_at` lets the importing side account for the time spent in transit.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Snapshot {
+    // Format version of the snapshot
+    pub version: u8,
+    // Rate limiting algorithm the state belongs to
+    pub algorithm: String,
+    // Number of tokens stored in the bucket, negative while it pays down an overdraft
+    pub tokens: i64,
+    // Remaining time to live of the bucket in milliseconds
+    pub ttl: i64,
+    // Unix time in milliseconds when the snapshot was taken
+    pub exported_at: i64,
+    // Capacity and period the bucket was stored with, if known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub capacity: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub period: Option<i64>,
+}
+
+impl Snapshot {
+    /// Reads the state stored under `key`.
+    ///
+    /// Returns `None` when the key does not exist.
+    pub fn capture(ctx: &Context, key: &RedisString) -> Result<Option<Self>, RedisError> {
+        let now = state::now(ctx)?;
+        let state = match State::load(ctx, key, now)? {
+            Some(state) => state,
+            None => return Ok(None),
+        };
+
+        Ok(Some(Self {
+            version: SNAPSHOT_VERSION,
+            algorithm: ALGORITHM.to_string(),
+            tokens: state.tokens,
+            ttl: state.ttl(now),
+            exported_at: now,
+            capacity: state.limit.map(|limit| limit.capacity),
+            period: state.limit.map(|limit| limit.period),
+        }))
+    }
+
+    /// Parses and validates a snapshot produced by [`Snapshot::to_json`].
+    pub fn from_json(blob: &str) -> Result<Self, RedisError> {
+        let snapshot: Self = serde_json::from_str(blob)
+            .map_err(|_| error(error::BAD_SNAPSHOT, "invalid snapshot"))?;
+
+        if snapshot.version != SNAPSHOT_VERSION {
+            return Err(error(error::BAD_SNAPSHOT, "unsupported snapshot version"));
+        }
+        if snapshot.algorithm != ALGORITHM {
+            return Err(error(error::BAD_ALGO, "unsupported snapshot algorithm"));
+        }
+        if snapshot.ttl < MIN_TTL {
+            return Err(error(error::BAD_SNAPSHOT, "invalid snapshot"));
+        }
+        // The limit is restored as a whole, and no bucket holds more than its capacity
+        match (snapshot.capacity, snapshot.period) {
+            (Some(capacity), Some(period))
+                if capacity > 0 && period > 0 && snapshot.tokens <= capacity => {}
+            (None, None) => {}
+            _ => return Err(error(error::BAD_SNAPSHOT, "invalid snapshot")),
+        }
+        Ok(snapshot)
+    }
+
+    pub fn to_json(&self) -> Result<String, RedisError> {
+        serde_json::to_string(self).map_err(|e| RedisError::String(format!("ERR {}", e)))
+    }
+
+    /// Writes the state under `key`.
+    ///
+    /// The TTL is shortened by the time elapsed since the snapshot was taken.
+    /// If the bucket would have been fully refilled by now, the key is removed
+    /// instead, which is equivalent to a full bucket.
+    pub fn restore(&self, ctx: &Context, key: &RedisString) -> Result<(), RedisError> {
+        let now = state::now(ctx)?;
+        let elapsed = now.saturating_sub(self.exported_at).max(0);
+        let state = State {
+            tokens: self.tokens,
+            expires_at: now + self.ttl.min(MAX_MILLIS) - elapsed,
+            limit: match (self.capacity, self.period) {
+                (Some(capacity), Some(period)) => Some(Limit { capacity, period }),
+                _ => None,
+            },
+        };
+        state.save(ctx, key, now)
+    }
+}
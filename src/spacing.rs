@@ -0,0 +1,69 @@
+use crate::command_parser::CommandArgs;
+use crate::keys::companion_key;
+use crate::recovery;
+use crate::state;
+use redis_module::{Context, RedisError, RedisString, RedisValue};
+
+pub const INTERVAL_PART: &[u8] = b"interval";
+
+/// Minimum spacing between admitted requests of the same key, in the spirit
+/// of GCRA. Stops tight retry loops that still fit inside the bucket.
+///
+/// The time of the last admitted request is stored under `<key>:interval`,
+/// which expires once the next request may be admitted.
+pub struct Spacing {
+    // Key the time of the last admitted request is stored under
+    key: RedisString,
+    // Minimum number of milliseconds between two admitted requests
+    interval: i64,
+    // Current Unix time in milliseconds
+    now: i64,
+    // Milliseconds left until the next request may be admitted
+    wait: i64,
+}
+
+impl Spacing {
+    /// Fetches the time of the last admitted request.
+    ///
+    /// Returns `None` if `command` doesn't require spacing.
+    pub fn load(ctx: &Context, command: &CommandArgs) -> Result<Option<Self>, RedisError> {
+        if command.min_interval == 0 {
+            return Ok(None);
+        }
+        let mut spacing = Self {
+            key: companion_key(command.member.unwrap_or(command.key), &[INTERVAL_PART]),
+            interval: command.min_interval,
+            now: state::now(ctx)?,
+            wait: 0,
+        };
+
+        if let RedisValue::SimpleString(last) = recovery::call(ctx, "GET", &[&spacing.key])? {
+            match last.parse::<i64>() {
+                Ok(last) => {
+                    let next = last.saturating_add(spacing.interval);
+                    spacing.wait = next.saturating_sub(spacing.now).clamp(0, spacing.interval);
+                }
+                Err(_) => recovery::corrupted(ctx, &spacing.key)?,
+            }
+        }
+        Ok(Some(spacing))
+    }
+
+    /// Returns the number of milliseconds until the next request may be admitted.
+    pub fn retry_after(&self) -> i64 {
+        self.wait
+    }
+
+    /// Stores the time of an admitted request.
+    pub fn record(&self, ctx: &Context) -> Result<(), RedisError> {
+        ctx.call(
+            "PSETEX",
+            &[
+                &self.key,
+                &RedisString::create(None, self.interval.to_string().as_str()),
+                &RedisString::create(None, self.now.to_string().as_str()),
+            ],
+        )?;
+        Ok(())
+    }
+}
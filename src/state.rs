@@ -0,0 +1,346 @@
+use crate::command_parser::{self, limit_omitted, Limit};
+use crate::config;
+use crate::error::bad_argument;
+use crate::math::{mul_div, MAX_MILLIS};
+use crate::recovery;
+use crate::strings;
+use redis_module::{Context, RedisError, RedisString, RedisValue};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+const FIELD_SEPARATOR: char = ':';
+// Last field of the states written by the module, telling them apart from
+// foreign values that happen to look the same, e.g. `10:30`
+const MARKER: &str = "tb";
+const MILLS_IN_SEC: i64 = 1000;
+const MICROS_IN_MILLI: i64 = 1000;
+const MIN_TTL: i64 = 0;
+// `PTTL` of a key without an expire
+const NO_EXPIRE: i64 = -1;
+const MAX_PERCENT: i64 = 100;
+// First version expiring hash fields on their own
+const FIELD_EXPIRY_VERSION: (i32, i32) = (7, 4);
+
+/// State of a bucket as it is stored in redis,
+/// `<tokens>:<expires_at>:<capacity>:<period>:tb`.
+///
+/// `expires_at` is the Unix time in milliseconds at which the bucket's TTL
+/// runs out, i.e. the refill is derived from the stored timestamp instead of
+/// the key's TTL, so an external `PEXPIRE` or `PERSIST` doesn't corrupt it.
+/// The key's TTL is still set to let redis evict idle buckets.
+///
+/// The capacity and period the bucket was written with tell whether a request
+/// uses the same limit, and are left out if unknown. Values written by older
+/// versions hold the bare number of tokens, in which case `expires_at` is
+/// derived from the key's TTL.
+pub struct State {
+    // Number of tokens stored by the last write, negative while paying down an overdraft
+    pub tokens: i64,
+    // Unix time in milliseconds at which the bucket's TTL runs out
+    pub expires_at: i64,
+    // Limit the bucket was written with, `None` if unknown
+    pub limit: Option<Limit>,
+}
+
+impl State {
+    /// Reads the state stored under `key`.
+    ///
+    /// Returns `None` when the key does not exist, or when it held
+    /// an invalid value that was removed in lenient mode.
+    pub fn load(ctx: &Context, key: &RedisString, now: i64) -> Result<Option<Self>, RedisError> {
+        let value = match recovery::call(ctx, "GET", &[key])? {
+            RedisValue::SimpleString(value) => value,
+            _ => return Ok(None),
+        };
+        if let Some(state) = Self::decode(&value) {
+            return Ok(Some(state));
+        }
+
+        match value.parse::<i64>() {
+            Ok(tokens) => {
+                // Starting with Redis 2.8 the return value of PTTL in case of error changed:
+                //     - The command returns -2 if the key does not exist.
+                //     - The command returns -1 if the key exists but has no associated expire.
+                let ttl = match ctx.call("PTTL", &[key])? {
+                    RedisValue::Integer(ttl) => ttl.max(MIN_TTL),
+                    _ => MIN_TTL,
+                };
+                Ok(Some(Self {
+                    tokens,
+                    expires_at: now.saturating_add(ttl),
+                    limit: None,
+                }))
+            }
+            Err(_) => {
+                recovery::corrupted(ctx, key)?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Reads the state stored in `field` of the hash `key`.
+    ///
+    /// Returns `None` when the field does not exist, or when it held
+    /// an invalid value that was removed in lenient mode.
+    pub fn load_field(
+        ctx: &Context,
+        key: &RedisString,
+        field: &RedisString,
+    ) -> Result<Option<Self>, RedisError> {
+        let value = match recovery::call(ctx, "HGET", &[key, field])? {
+            RedisValue::SimpleString(value) => value,
+            _ => return Ok(None),
+        };
+        match Self::decode(&value) {
+            // Fields are never written in the format of older versions
+            Some(state) => Ok(Some(state)),
+            None => {
+                recovery::corrupted_field(ctx, key, field)?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Parses a value in the `<tokens>:<expires_at>[:<capacity>:<period>]:tb` format.
+    ///
+    /// Returns `None` for anything else, including bare numbers of tokens and
+    /// foreign values without the marker, e.g. `10:30`.
+    pub fn decode(value: &str) -> Option<Self> {
+        let mut fields: Vec<&str> = value.split(FIELD_SEPARATOR).collect();
+        if fields.pop() != Some(MARKER) {
+            return None;
+        }
+        let limit = match fields[..] {
+            [_, _] => None,
+            [_, _, capacity, period] => Some(Limit {
+                capacity: capacity.parse().ok()?,
+                period: period.parse().ok()?,
+            }),
+            _ => return None,
+        };
+        Some(Self {
+            tokens: fields[0].parse().ok()?,
+            expires_at: fields[1].parse().ok()?,
+            limit,
+        })
+    }
+
+    fn encode(&self) -> String {
+        let mut value = format!("{}{}{}", self.tokens, FIELD_SEPARATOR, self.expires_at);
+        if let Some(limit) = self.limit {
+            value = format!(
+                "{value}{sep}{}{sep}{}",
+                limit.capacity,
+                limit.period,
+                sep = FIELD_SEPARATOR
+            );
+        }
+        format!("{value}{FIELD_SEPARATOR}{MARKER}")
+    }
+
+    /// Returns the number of milliseconds left until the bucket's TTL runs out.
+    pub fn ttl(&self, now: i64) -> i64 {
+        self.expires_at.saturating_sub(now).max(MIN_TTL)
+    }
+
+    /// Writes the state under `key`, expiring it along with the bucket's TTL.
+    ///
+    /// The key's TTL is extended by a random jitter if `shield.ttl-jitter` is set.
+    /// A state that has already expired is removed instead.
+    pub fn save(&self, ctx: &Context, key: &RedisString, now: i64) -> Result<(), RedisError> {
+        self.save_capped(ctx, key, now, MAX_MILLIS)
+    }
+
+    /// Writes the state like [`State::save`], but lets the key expire
+    /// in `max_ttl` milliseconds at the latest.
+    ///
+    /// The stored `expires_at` is kept intact, so the refill of the bucket
+    /// doesn't change while the key exists.
+    pub fn save_capped(
+        &self,
+        ctx: &Context,
+        key: &RedisString,
+        now: i64,
+        max_ttl: i64,
+    ) -> Result<(), RedisError> {
+        let ttl = self.ttl(now);
+        if ttl <= MIN_TTL {
+            ctx.call("DEL", &[key])?;
+        } else {
+            let key_ttl = self.jittered_ttl(key, now, max_ttl);
+            let value = self.encode();
+            ctx.call(
+                "PSETEX",
+                &[
+                    key,
+                    &RedisString::create(None, key_ttl.to_string().as_str()),
+                    &RedisString::create(None, value.as_str()),
+                ],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Writes the state under `key` without an expire, e.g. for long-lived quotas.
+    ///
+    /// The refill is still computed from the stored `expires_at`.
+    pub fn save_persistent(&self, ctx: &Context, key: &RedisString) -> Result<(), RedisError> {
+        let value = self.encode();
+        ctx.call("SET", &[key, &RedisString::create(None, value.as_str())])?;
+        Ok(())
+    }
+
+    /// Writes the state in `field` of the hash `key`, like [`State::save_capped`].
+    ///
+    /// Redis 7.4 expires the field on its own. Older versions can only expire
+    /// the whole hash, so its TTL is extended to cover the field, while the refill
+    /// of every field still follows its own `expires_at`.
+    pub fn save_field(
+        &self,
+        ctx: &Context,
+        key: &RedisString,
+        field: &RedisString,
+        now: i64,
+        max_ttl: i64,
+    ) -> Result<(), RedisError> {
+        if self.ttl(now) <= MIN_TTL {
+            ctx.call("HDEL", &[key, field])?;
+            return Ok(());
+        }
+        let ttl = self.jittered_ttl(field, now, max_ttl);
+        let ttl_arg = RedisString::create(None, ttl.to_string().as_str());
+        let value = self.encode();
+        ctx.call(
+            "HSET",
+            &[key, field, &RedisString::create(None, value.as_str())],
+        )?;
+        if expires_fields(ctx) {
+            ctx.call(
+                "HPEXPIRE",
+                &[
+                    key,
+                    &ttl_arg,
+                    strings::fields_option(),
+                    strings::one(),
+                    field,
+                ],
+            )?;
+        } else {
+            let current = match ctx.call("PTTL", &[key])? {
+                RedisValue::Integer(ttl) => ttl,
+                _ => MIN_TTL,
+            };
+            // `-1` for a hash without an expire, e.g. one just created by the write
+            if current < ttl {
+                ctx.call("PEXPIRE", &[key, &ttl_arg])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the state in `field` of the hash `key` without an expire.
+    ///
+    /// Before Redis 7.4 the whole hash is persisted, including its other fields.
+    pub fn save_field_persistent(
+        &self,
+        ctx: &Context,
+        key: &RedisString,
+        field: &RedisString,
+    ) -> Result<(), RedisError> {
+        let value = self.encode();
+        ctx.call(
+            "HSET",
+            &[key, field, &RedisString::create(None, value.as_str())],
+        )?;
+        if expires_fields(ctx) {
+            ctx.call(
+                "HPERSIST",
+                &[key, strings::fields_option(), strings::one(), field],
+            )?;
+        } else {
+            ctx.call("PERSIST", &[key])?;
+        }
+        Ok(())
+    }
+
+    /// Returns the TTL to store the state with under `name`, extended by a random
+    /// jitter if `shield.ttl-jitter` is set, and capped at `max_ttl` milliseconds.
+    fn jittered_ttl(&self, name: &RedisString, now: i64, max_ttl: i64) -> i64 {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write(name.as_slice());
+        jitter(self.ttl(now), config::ttl_jitter(), hasher.finish())
+            .min(max_ttl)
+            .min(MAX_MILLIS)
+    }
+}
+
+/// Returns `false` if `key` exists without an expire, e.g. a `PERSISTENT` bucket.
+pub fn has_expire(ctx: &Context, key: &RedisString) -> Result<bool, RedisError> {
+    Ok(ctx.call("PTTL", &[key])? != RedisValue::Integer(NO_EXPIRE))
+}
+
+/// Returns `true` if the server expires hash fields on their own.
+fn expires_fields(ctx: &Context) -> bool {
+    ctx.get_redis_version()
+        .is_ok_and(|version| (version.major, version.minor) >= FIELD_EXPIRY_VERSION)
+}
+
+/// Extends `ttl` by up to `percent` of it, picked by `seed`.
+pub fn jitter(ttl: i64, percent: i64, seed: u64) -> i64 {
+    let max = mul_div(ttl.into(), percent.into(), MAX_PERCENT.into());
+    if max <= 0 {
+        return ttl;
+    }
+    ttl.saturating_add((seed % (max as u64 + 1)) as i64)
+}
+
+/// Inserts the capacity and period the key's bucket was stored with into the
+/// arguments of `SHIELD.absorb` and alike, when they are omitted, i.e.
+/// `<key> [<tokens>] [options]` becomes `<key> <capacity> <period> [<tokens>] [options]`.
+///
+/// Omitting the limit for a bucket that doesn't exist, or was written
+/// by an older version, is an error.
+pub fn expand(ctx: &Context, mut args: Vec<RedisString>) -> Result<Vec<RedisString>, RedisError> {
+    let Some(key) = args.get(1) else {
+        return Ok(args);
+    };
+    if !limit_omitted(&args) {
+        return Ok(args);
+    }
+
+    let stored = match command_parser::field(&args) {
+        Some(field) => State::load_field(ctx, key, field)?,
+        None => State::load(ctx, key, now(ctx)?)?,
+    };
+    let stored = stored.and_then(|state| state.limit);
+    let Some(limit) = stored else {
+        return Err(bad_argument("capacity", "is required"));
+    };
+    let limit = [
+        RedisString::create(None, limit.capacity.to_string().as_str()),
+        RedisString::create(None, limit.period.to_string().as_str()),
+    ];
+    args.splice(2..2, limit);
+    Ok(args)
+}
+
+/// Returns the current Unix time in milliseconds according to redis.
+///
+/// The server's clock is used rather than the local one, so every client
+/// of the same instance observes the same refill.
+pub fn now(ctx: &Context) -> Result<i64, RedisError> {
+    let parts = match ctx.call("TIME", &[] as &[&str])? {
+        RedisValue::Array(parts) => parts,
+        _ => return Err(RedisError::Str("ERR unexpected TIME reply")),
+    };
+    let mut numbers = parts.iter().map(|part| match part {
+        RedisValue::SimpleString(n) => n.parse::<i64>().ok(),
+        RedisValue::BulkString(n) => n.parse::<i64>().ok(),
+        RedisValue::Integer(n) => Some(*n),
+        _ => None,
+    });
+    match (numbers.next().flatten(), numbers.next().flatten()) {
+        (Some(secs), Some(micros)) => Ok(secs * MILLS_IN_SEC + micros / MICROS_IN_MILLI),
+        _ => Err(RedisError::Str("ERR unexpected TIME reply")),
+    }
+}
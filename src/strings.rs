@@ -0,0 +1,33 @@
+use redis_module::RedisString;
+
+// Constant arguments of the commands called by the module, created once per
+// thread, in practice by the main thread, instead of on every call. They are
+// never freed, like the module itself.
+macro_rules! cached {
+    ($($name:ident => $value:expr,)*) => {
+        $(
+            pub fn $name() -> &'static RedisString {
+                thread_local! {
+                    static STRING: &'static RedisString =
+                        Box::leak(Box::new(RedisString::create(None, $value)));
+                }
+                STRING.with(|string| *string)
+            }
+        )*
+    };
+}
+
+cached! {
+    zero => "0",
+    one => "1",
+    minus_one => "-1",
+    min_score => "-inf",
+    match_option => "MATCH",
+    count_option => "COUNT",
+    px_option => "PX",
+    nx_option => "NX",
+    replace_option => "REPLACE",
+    fields_option => "FIELDS",
+    idletime_subcommand => "IDLETIME",
+    usage_subcommand => "USAGE",
+}
@@ -0,0 +1,67 @@
+use std::env;
+use std::env::consts::{DLL_PREFIX, DLL_SUFFIX};
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::OnceLock;
+use std::{thread, time};
+
+// Stops the server once the tests exit, closing the script's stdin
+const SUPERVISOR_SCRIPT: &str = r#"redis-server "$@" & server=$!; read _; kill $server"#;
+const STARTUP_ATTEMPTS: u32 = 50;
+const STARTUP_DELAY: time::Duration = time::Duration::from_millis(100);
+
+static URL: OnceLock<String> = OnceLock::new();
+
+/// Returns the URL of a `redis-server` child process with the module loaded,
+/// started by the first call, so the tests don't need a server set up beforehand.
+///
+/// The module is loaded from `SHIELD_MODULE`, or from the debug build in the
+/// crate's `target` directory, which has to be built with `cargo build` first.
+pub fn url() -> String {
+    URL.get_or_init(start).clone()
+}
+
+fn start() -> String {
+    let module = env::var_os("SHIELD_MODULE").map_or_else(
+        || {
+            PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+                .join("target/debug")
+                .join(format!("{DLL_PREFIX}redis_shield{DLL_SUFFIX}"))
+        },
+        PathBuf::from,
+    );
+    assert!(
+        module.exists(),
+        "{} not found, run `cargo build` or set SHIELD_MODULE",
+        module.display()
+    );
+
+    // The port is released right away for redis-server to bind
+    let port = TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .expect("no free port for redis-server")
+        .port()
+        .to_string();
+    let supervisor = Command::new("sh")
+        .args(["-c", SUPERVISOR_SCRIPT, "sh"])
+        .args(["--port", &port, "--save", "", "--appendonly", "no"])
+        .arg("--loadmodule")
+        .arg(&module)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()
+        .expect("failed to start redis-server");
+    // Dropping the handle would close stdin and stop the server right away
+    std::mem::forget(supervisor);
+
+    let url = format!("redis://127.0.0.1:{port}/1");
+    let client = redis::Client::open(url.as_str()).unwrap();
+    for _ in 0..STARTUP_ATTEMPTS {
+        if client.get_connection().is_ok() {
+            return url;
+        }
+        thread::sleep(STARTUP_DELAY);
+    }
+    panic!("redis-server didn't start on port {port}");
+}
@@ -0,0 +1,16 @@
+/// Enters a `tracing` span called `$name` until the end of the enclosing
+/// block, so embedders building their own binaries can see where the time
+/// of a request goes, e.g.
+///
+///     trace::span!("persist");
+///
+/// Expands to nothing without the `tracing` feature, leaving the command
+/// path as it is.
+macro_rules! span {
+    ($name:literal) => {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!($name).entered();
+    };
+}
+
+pub(crate) use span;
@@ -0,0 +1,153 @@
+use crate::command_parser::parse_positive_integer;
+use crate::error::{self, error};
+use crate::group::MEMBERS_PART;
+use crate::history::{self, HISTORY_PART};
+use crate::keys::companion_key;
+use crate::limiter::WARMUP_PART;
+use crate::notification::NOTIFY_PART;
+use crate::overrides::OVERRIDE_PART;
+use crate::penalty::PENALTY_PART;
+use crate::plugin;
+use crate::retry_budget::BUDGET_PART;
+use crate::shard::{REBALANCED_PART, SHARD_PART};
+use crate::spacing::INTERVAL_PART;
+use crate::strings;
+use redis_module::{Context, RedisError, RedisString, RedisValue};
+
+const ALGORITHM_OPTION: &str = "algorithm";
+const TIER_OPTION: &str = "tier";
+const SHARDS_OPTION: &str = "shards";
+const ALGORITHM: &str = "token_bucket";
+
+/// Moves or copies the state of a limiter from one key to another.
+///
+/// Besides the bucket itself, the companion keys are carried over:
+/// the warm-up, the retry budget, the statistics of a group's members,
+/// the override of a policy, the decision history, the penalty, the spacing
+/// interval, the pending notification, the states of the registered algorithms,
+/// the given number of shards and the buckets of the tiers given by their
+/// periods. Records of idempotent requests are bound to their keys and stay behind.
+pub struct Transfer {
+    // Periods of the tiers whose buckets are carried over
+    tiers: Vec<i64>,
+    // Number of shards carried over
+    shards: i64,
+}
+
+impl Transfer {
+    /// Parses option-value pairs, e.g. `ALGORITHM token_bucket TIER 3600 SHARDS 4`.
+    pub fn parse(args: &[RedisString]) -> Result<Self, RedisError> {
+        if args.len() % 2 != 0 {
+            return Err(error(error::SYNTAX, "syntax error"));
+        }
+
+        let mut transfer = Self {
+            tiers: Vec::new(),
+            shards: 0,
+        };
+        for pair in args.chunks(2) {
+            let option = pair[0].to_string_lossy().to_ascii_lowercase();
+            match option.as_str() {
+                ALGORITHM_OPTION if pair[1].to_string_lossy() == ALGORITHM => {}
+                ALGORITHM_OPTION => return Err(error(error::BAD_ALGO, "unsupported algorithm")),
+                TIER_OPTION => transfer
+                    .tiers
+                    .push(parse_positive_integer("period", &pair[1])?),
+                SHARDS_OPTION => transfer.shards = parse_positive_integer("shards", &pair[1])?,
+                _ => return Err(error(error::SYNTAX, "syntax error")),
+            }
+        }
+        Ok(transfer)
+    }
+
+    /// Moves the state stored under `source` to `destination`, preserving TTLs.
+    ///
+    /// The previous state of `destination` is replaced entirely. Returns `false`,
+    /// leaving both keys intact, if `source` doesn't hold a bucket.
+    pub fn rename(
+        &self,
+        ctx: &Context,
+        source: &RedisString,
+        destination: &RedisString,
+    ) -> Result<bool, RedisError> {
+        self.transfer(ctx, source, destination, false)
+    }
+
+    /// Copies the state stored under `source` to `destination`, preserving TTLs.
+    ///
+    /// Works like [`Transfer::rename`], except the state of `source` is kept.
+    pub fn copy(
+        &self,
+        ctx: &Context,
+        source: &RedisString,
+        destination: &RedisString,
+    ) -> Result<bool, RedisError> {
+        self.transfer(ctx, source, destination, true)
+    }
+
+    fn transfer(
+        &self,
+        ctx: &Context,
+        source: &RedisString,
+        destination: &RedisString,
+        keep_source: bool,
+    ) -> Result<bool, RedisError> {
+        if ctx.call("EXISTS", &[source])? != RedisValue::Integer(1) {
+            return Ok(false);
+        }
+        if source.as_slice() == destination.as_slice() {
+            return Ok(true);
+        }
+
+        for (from, to) in self.keys(source, destination) {
+            if ctx.call("EXISTS", &[&from])? != RedisValue::Integer(1) {
+                ctx.call("DEL", &[&to])?;
+            } else if keep_source {
+                ctx.call("COPY", &[&from, &to, strings::replace_option()])?;
+            } else {
+                ctx.call("RENAME", &[&from, &to])?;
+            }
+        }
+        Ok(true)
+    }
+
+    // Pairs of the keys of the limiter's state under `source` and `destination`
+    fn keys(
+        &self,
+        source: &RedisString,
+        destination: &RedisString,
+    ) -> Vec<(RedisString, RedisString)> {
+        let pair = |parts: &[&[u8]]| {
+            (
+                companion_key(source, parts),
+                companion_key(destination, parts),
+            )
+        };
+
+        let mut keys = vec![(source.clone(), destination.clone())];
+        for part in [
+            WARMUP_PART,
+            BUDGET_PART,
+            MEMBERS_PART,
+            OVERRIDE_PART,
+            HISTORY_PART,
+            PENALTY_PART,
+            INTERVAL_PART,
+            NOTIFY_PART,
+        ] {
+            keys.push(pair(&[part]));
+        }
+        keys.push(pair(&[HISTORY_PART, history::MEMBERS_PART]));
+        keys.push(pair(&[SHARD_PART, REBALANCED_PART]));
+        for index in 0..self.shards {
+            keys.push(pair(&[SHARD_PART, index.to_string().as_bytes()]));
+        }
+        for suffix in plugin::suffixes() {
+            keys.push(pair(&[suffix.as_bytes()]));
+        }
+        for period in &self.tiers {
+            keys.push(pair(&[period.to_string().as_bytes()]));
+        }
+        keys
+    }
+}